@@ -1,82 +1,180 @@
+use crate::config::{Config, Confirmations, DetectionConfig};
 use crate::data_storage::Data;
-use crate::errors::{DiscordInteractionError, Error};
+use crate::diagnostics::{self, Diagnostics, Failure, RecentFailures, Runtime, RuntimeCounters};
+use crate::errors::{DatabaseError, DiscordInteractionError, Error};
+use crate::guild_settings::EffectiveSettings;
+use crate::health::Health;
+use crate::image_processing::{self, ProcessedImage};
+use crate::message_cache::MessageCache;
+use crate::recent_reposts::RecentReposts;
+use crate::startup_settle::StartupSettle;
+use crate::storage::Storage;
+use crate::worker_pool::WorkerPool;
 
 use chrono::Utc;
-use hyper::{body::HttpBody, client::HttpConnector, Client as HyperClient, Uri};
+use hyper::{
+    body::HttpBody,
+    client::HttpConnector,
+    header::{self, HeaderValue},
+    Body, Client as HyperClient, Request, StatusCode, Uri,
+};
 use hyper_rustls::HttpsConnector;
 
-use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder};
-use twilight_gateway::Cluster;
-use twilight_http::{request::prelude::RequestReactionType, Client};
+use twilight_cache_inmemory::{InMemoryCache, ResourceType};
+use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder, ImageSource};
+use twilight_gateway::{shard::Stage, Cluster, Intents};
+use twilight_http::{api_error::ApiError, error::ErrorType, Client};
 use twilight_model::gateway::payload::UpdatePresence;
 use twilight_model::{
-    channel::{Message, ReactionType},
+    application::{
+        callback::{CallbackData, InteractionResponse},
+        component::{button::ButtonStyle, ActionRow, Button, Component},
+        interaction::{Interaction, MessageComponentInteraction},
+    },
+    channel::{
+        embed::Embed,
+        message::{AllowedMentions, MessageFlags},
+        Channel, GuildChannel, Message,
+    },
     gateway::{
-        payload::ReactionAdd,
+        event::Event,
+        payload::{ChannelCreate, MessageCreate},
         presence::{ActivityType, MinimalActivity, Status},
     },
-    id::{ChannelId, MessageId, UserId},
+    guild::Permissions,
+    id::{ChannelId, GuildId, MessageId, RoleId, UserId},
 };
 use twilight_standby::Standby;
 
+use tokio_stream::StreamExt;
+
 use std::{
+    collections::{HashSet, VecDeque},
     convert::TryInto,
+    future::Future,
     str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 type WebClient = HyperClient<HttpsConnector<HttpConnector>>;
 
+/// Gateway events the bot subscribes to.
+pub const INTENTS: Intents =
+    Intents::from_bits_truncate(Intents::GUILDS.bits() | Intents::GUILD_MESSAGES.bits());
+
 pub enum ConfirmationAction {
     IgnoreImage,
+    ForgetImage,
+    PurgeImages,
+    NearMatch,
 }
 
 impl ConfirmationAction {
-    const CONFIRMED: &'static str = "✅";
-    const CANCELED: &'static str = "❌";
+    const CONFIRM_ID: &'static str = "confirm_yes";
+    const CANCEL_ID: &'static str = "confirm_no";
+    const NOT_A_MODERATOR: &'static str = "Only moderators can answer this.";
     const TIMED_OUT: &'static str = "Waiting period elapsed, moving on";
+    const REPLY_PROMPT: &'static str = "Reply with yes or no.";
 
     const fn as_str(&self) -> &'static str {
         match self {
             Self::IgnoreImage => "Do you want to ignore this image?",
+            Self::ForgetImage => {
+                "Do you want me to forget this image, and how many times it was seen?"
+            }
+            Self::PurgeImages => {
+                "Do you want me to delete every stored image like that, and how many times they were seen?"
+            }
+            Self::NearMatch => "This looks a lot like an image I've seen before. Is it a repost?",
         }
     }
 }
 
 #[derive(Clone)] // cheap
 pub struct Context {
+    /// The settings this copy of the context handles events with.
+    pub config: Arc<Config>,
+    /// The settings most recently loaded, which new events are handled with.
+    latest_config: Arc<Mutex<Arc<Config>>>,
     pub data: Data,
     web_client: WebClient,
     discord_client: Client,
     cluster: Cluster,
     pub standby: Standby,
+    pub cache: InMemoryCache,
     id: UserId,
+    owner: UserId,
     total_seen: Arc<AtomicUsize>,
+    recent_failures: Arc<Mutex<RecentFailures>>,
+    pub counters: Arc<RuntimeCounters>,
+    started: Instant,
+    /// When the last gateway event came in, in seconds since the Unix epoch, or 0 before the first one.
+    last_event: Arc<AtomicU64>,
+    forbidden_channels: Arc<Mutex<ForbiddenChannels>>,
+    /// Where images get hashed, away from the async runtime.
+    pub hashing: Arc<WorkerPool>,
+    recent_reposts: Arc<Mutex<RecentReposts>>,
+    /// Messages replies can point at, so they don't have to be fetched again.
+    messages: Arc<Mutex<MessageCache>>,
+    checked_messages: Arc<Mutex<CheckedMessages>>,
+    startup: Arc<Mutex<StartupSettle>>,
 }
 
 impl Context {
+    const RECENT_FAILURE_CAPACITY: usize = 10;
+    const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(10);
+
     pub fn init(
+        config: Config,
         me: UserId,
+        owner: UserId,
         data: Data,
         web_client: WebClient,
         discord_client: Client,
         cluster: Cluster,
     ) -> Self {
         let standby = Standby::new();
+        // Guild ownership and roles are needed to check who can moderate the bot, and channels to know
+        // which are NSFW.
+        let cache = InMemoryCache::builder()
+            .resource_types(ResourceType::GUILD | ResourceType::ROLE | ResourceType::CHANNEL)
+            .build();
         let seen_so_far = data.total_seen();
+        let hashing = WorkerPool::new(config.hashing_threads);
+        let recent_reposts = RecentReposts::new(config.reply.quick_delete_window);
+        let messages = MessageCache::new(config.message_cache_size);
+        let startup = StartupSettle::new(config.reply.startup_settle, cluster.shards().len());
+
+        let config = Arc::new(config);
 
         Self {
+            latest_config: Arc::new(Mutex::new(config.clone())),
+            config,
             data,
             web_client,
             discord_client,
             cluster,
             standby,
+            cache,
             id: me,
+            owner,
             total_seen: Arc::new(AtomicUsize::new(seen_so_far)),
+            recent_failures: Arc::new(Mutex::new(RecentFailures::new(
+                Self::RECENT_FAILURE_CAPACITY,
+            ))),
+            counters: Arc::new(RuntimeCounters::default()),
+            started: Instant::now(),
+            last_event: Arc::new(AtomicU64::new(0)),
+            forbidden_channels: Arc::new(Mutex::new(ForbiddenChannels::default())),
+            hashing: Arc::new(hashing),
+            recent_reposts: Arc::new(Mutex::new(recent_reposts)),
+            messages: Arc::new(Mutex::new(messages)),
+            checked_messages: Arc::new(Mutex::new(CheckedMessages::default())),
+            startup: Arc::new(Mutex::new(startup)),
         }
     }
 
@@ -97,6 +195,233 @@ impl Context {
         self.id == other
     }
 
+    pub fn is_owner(&self, other: UserId) -> bool {
+        self.owner == other
+    }
+
+    /// Resolves the settings that currently apply in a guild.
+    pub fn effective_settings(
+        &self,
+        guild_id: GuildId,
+    ) -> Result<EffectiveSettings, DatabaseError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("clocks are wobbly")
+            .as_secs();
+
+        let settings = self.data.guild_settings(guild_id.0)?;
+        Ok(settings.effective(&self.config, now))
+    }
+
+    /// Checks if a guild member is allowed to moderate the bot, based on their roles and guild-level permissions.
+    pub fn is_moderator(
+        &self,
+        guild_id: GuildId,
+        user: UserId,
+        roles: &[RoleId],
+        mod_role: Option<RoleId>,
+    ) -> bool {
+        if self.is_owner(user) {
+            return true;
+        }
+
+        if matches!(self.cache.guild(guild_id), Some(guild) if guild.owner_id == user) {
+            return true;
+        }
+
+        // The @everyone role shares its ID with the guild.
+        let everyone = RoleId(guild_id.0);
+        let permissions = roles
+            .iter()
+            .chain(std::iter::once(&everyone))
+            .filter_map(|id| self.cache.role(*id))
+            .map(|role| role.permissions);
+
+        is_authorized(mod_role, roles, permissions)
+    }
+
+    pub fn diagnostics(&self) -> Result<Diagnostics, DatabaseError> {
+        Ok(Diagnostics {
+            storage: self.data.storage_stats()?,
+            resident_memory: diagnostics::resident_memory(),
+            recent_failures: self.recent_failures().len(),
+            hashing_threads: self.hashing.workers(),
+            hashing_queue_depth: self.hashing.queue_depth(),
+        })
+    }
+
+    pub fn event_received(&self) {
+        self.last_event
+            .store(crate::seconds_since_epoch(), Ordering::Relaxed);
+    }
+
+    /// How the gateway connection and database are doing, for health checks.
+    pub fn health(&self) -> Health {
+        let connected = self
+            .cluster
+            .info()
+            .values()
+            .filter(|shard| shard.stage() == Stage::Connected)
+            .count();
+
+        Health {
+            shards_connected: connected,
+            shards: self.cluster.shards().len(),
+            database_open: self.data.check().is_ok(),
+            last_event: match self.last_event.load(Ordering::Relaxed) {
+                0 => None,
+                at => Some(at),
+            },
+        }
+    }
+
+    /// If the bot has given up on replying in a channel it isn't allowed to send messages in.
+    pub fn replies_muted(&self, channel: ChannelId) -> bool {
+        self.config.reply.mute_forbidden_channels
+            && self
+                .forbidden_channels
+                .lock()
+                .expect("forbidden channels lock was poisoned")
+                .contains(channel)
+    }
+
+    /// Remembers that the bot wasn't allowed to send a message in a channel, warning about it once.
+    pub fn reply_forbidden(&self, channel: ChannelId) {
+        let first = self
+            .forbidden_channels
+            .lock()
+            .expect("forbidden channels lock was poisoned")
+            .insert(channel);
+
+        if first {
+            tracing::warn!(
+                "Missing permissions to send messages in channel {}, not warning about it again",
+                channel
+            );
+        }
+    }
+
+    /// Remembers that images from a message are being checked, returning if they weren't already.
+    pub fn first_check(&self, message: MessageId) -> bool {
+        self.checked_messages
+            .lock()
+            .expect("checked messages lock was poisoned")
+            .insert(message)
+    }
+
+    /// Decodes and hashes an image on the hashing pool.
+    ///
+    /// The image is queued right away, so several can be hashed at once before any are awaited.
+    pub fn process_image(
+        &self,
+        image: Vec<u8>,
+        config: &DetectionConfig,
+    ) -> impl Future<Output = Result<ProcessedImage, Error>> {
+        let config = config.clone();
+        self.hashing
+            .run(move || image_processing::process_image(image, &config))
+    }
+
+    /// Like [Context::process_image], but exact copies of images already recorded by `data` aren't decoded again.
+    pub fn process_posted_image(
+        &self,
+        image: Vec<u8>,
+        data: &Data,
+    ) -> impl Future<Output = Result<ProcessedImage, Error>> {
+        let data = data.clone();
+        self.hashing.run(move || {
+            image_processing::process_known_image(image, data.config(), |content| {
+                Ok(data.hash_of_content(content)?)
+            })
+        })
+    }
+
+    /// What the bot has done since it started.
+    pub fn runtime(&self) -> Runtime {
+        self.counters.snapshot(self.started.elapsed().as_secs())
+    }
+
+    /// Remembers that an image couldn't be tracked, passing the error back to the caller.
+    pub fn record_failure(&self, url: &str, error: Error) -> Error {
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("clocks are wobbly")
+            .as_secs();
+
+        self.recent_failures().push(Failure {
+            url: url.to_string(),
+            kind: error.kind(),
+            at,
+        });
+
+        error
+    }
+
+    pub fn recent_failures(&self) -> MutexGuard<'_, RecentFailures> {
+        self.recent_failures
+            .lock()
+            .expect("bug: a thread panicked while recording a failure")
+    }
+
+    fn startup(&self) -> MutexGuard<'_, StartupSettle> {
+        self.startup
+            .lock()
+            .expect("bug: a thread panicked while tracking startup")
+    }
+
+    /// Swaps in the settings that can change while the bot runs from a freshly loaded config.
+    ///
+    /// Events that come in afterwards are handled with them, once the event loop calls
+    /// [Context::refresh_config].
+    pub fn reload_config(&self, loaded: Config) {
+        let mut latest = self
+            .latest_config
+            .lock()
+            .expect("bug: a thread panicked while reloading settings");
+        *latest = Arc::new(latest.reloaded(loaded));
+    }
+
+    /// Picks up reloaded settings, if there are any.
+    ///
+    /// Handlers already running keep the settings they started with, so a message is never handled with a mix.
+    pub fn refresh_config(&mut self) {
+        let latest = self
+            .latest_config
+            .lock()
+            .expect("bug: a thread panicked while reloading settings")
+            .clone();
+
+        if !Arc::ptr_eq(&latest, &self.config) {
+            self.data = self.data.with_detection_config(latest.detection.clone());
+            self.config = latest;
+        }
+    }
+
+    /// Disconnects every shard, so no more events come in.
+    pub fn shut_down(&self) {
+        self.cluster.down();
+    }
+
+    pub fn shard_ready(&self, shard: u64) {
+        self.startup()
+            .shard_ready(shard, crate::seconds_since_epoch());
+    }
+
+    pub fn guild_streamed(&self) {
+        self.startup().guild_streamed(crate::seconds_since_epoch());
+    }
+
+    /// If startup's burst of events is over, and reposts can be replied to.
+    pub fn startup_settled(&self) -> bool {
+        self.startup().settled(crate::seconds_since_epoch())
+    }
+
+    pub fn recent_reposts(&self) -> MutexGuard<'_, RecentReposts> {
+        self.recent_reposts
+            .lock()
+            .expect("bug: a thread panicked while tracking reposts")
+    }
+
     pub async fn send_message<M: AsRef<str>>(
         &self,
         message: M,
@@ -122,21 +447,29 @@ impl Context {
             .map_err(DiscordInteractionError::Deserialize)
     }
 
+    /// Sends `description` with an embed linking back to the previous image, showing it as a thumbnail if
+    /// there's a usable URL for it.
     pub async fn send_embed(
         &self,
         description: String,
         jump_link: String,
+        thumbnail: Option<&str>,
         channel_id: ChannelId,
     ) -> Result<Message, DiscordInteractionError> {
-        let embed = EmbedBuilder::new()
+        let mut embed = EmbedBuilder::new()
             .timestamp(Utc::now().to_rfc3339())
             .field(
                 EmbedFieldBuilder::new("Previous Image", jump_link)
                     .inline()
                     .build(),
-            )
-            .build()
-            .expect("bug: embed had too many contents");
+            );
+
+        // URLs Discord wouldn't load are left out rather than failing the whole reply.
+        if let Some(thumbnail) = thumbnail.and_then(|url| ImageSource::url(url).ok()) {
+            embed = embed.thumbnail(thumbnail);
+        }
+
+        let embed = embed.build().expect("bug: embed had too many contents");
 
         self.discord_client
             .create_message(channel_id)
@@ -152,89 +485,438 @@ impl Context {
             .map_err(DiscordInteractionError::Deserialize)
     }
 
+    /// Sends embeds on their own, which have to fit in Discord's limits together.
+    pub async fn send_embeds(
+        &self,
+        embeds: Vec<Embed>,
+        channel_id: ChannelId,
+    ) -> Result<Message, DiscordInteractionError> {
+        self.discord_client
+            .create_message(channel_id)
+            .embeds(&embeds)
+            .expect("bug: embed content was > 6000")
+            .exec()
+            .await
+            .map_err(DiscordInteractionError::SendingMessage)?
+            .model()
+            .await
+            .map_err(DiscordInteractionError::Deserialize)
+    }
+
+    /// Sends a message to a user's DMs.
+    pub async fn send_direct_message(
+        &self,
+        message: &str,
+        user: UserId,
+    ) -> Result<Message, DiscordInteractionError> {
+        let channel = self
+            .discord_client
+            .create_private_channel(user)
+            .exec()
+            .await
+            .map_err(DiscordInteractionError::SendingMessage)?
+            .model()
+            .await
+            .map_err(DiscordInteractionError::Deserialize)?;
+
+        self.send_message(message, channel.id, None).await
+    }
+
+    /// Sends a message with a button that opens a link.
+    pub async fn send_link_button(
+        &self,
+        message: String,
+        label: &str,
+        url: String,
+        channel_id: ChannelId,
+    ) -> Result<Message, DiscordInteractionError> {
+        let button = Component::ActionRow(ActionRow {
+            components: vec![Component::Button(Button {
+                custom_id: None,
+                disabled: false,
+                emoji: None,
+                label: Some(label.to_string()),
+                style: ButtonStyle::Link,
+                url: Some(url),
+            })],
+        });
+
+        self.discord_client
+            .create_message(channel_id)
+            .content(&message)
+            .expect("bug: message content was > 2000")
+            .components(&[button])
+            .expect("bug: message components were invalid")
+            .exec()
+            .await
+            .map_err(DiscordInteractionError::SendingMessage)?
+            .model()
+            .await
+            .map_err(DiscordInteractionError::Deserialize)
+    }
+
+    /// Fetches a message, unless it was seen or fetched recently enough to still be cached.
     pub async fn get_message(
         &self,
         channel: ChannelId,
         message: MessageId,
     ) -> Result<Message, DiscordInteractionError> {
-        self.discord_client
+        if let Some(cached) = self.message_cache().get(message) {
+            return Ok(cached);
+        }
+
+        let fetched = self
+            .discord_client
             .message(channel, message)
             .exec()
             .await
             .map_err(DiscordInteractionError::FetchingMessage)?
             .model()
             .await
-            .map_err(DiscordInteractionError::Deserialize)
+            .map_err(DiscordInteractionError::Deserialize)?;
+
+        self.message_cache().insert(fetched.clone());
+        Ok(fetched)
+    }
+
+    /// If a channel is marked NSFW. Threads are if the channel they're in is.
+    pub async fn is_nsfw(&self, channel: ChannelId) -> Result<bool, DiscordInteractionError> {
+        let parent = match self.guild_channel(channel).await? {
+            Some(GuildChannel::Text(text)) => return Ok(text.nsfw),
+            Some(GuildChannel::PublicThread(thread)) => thread.parent_id,
+            Some(GuildChannel::PrivateThread(thread)) => thread.parent_id,
+            Some(GuildChannel::NewsThread(thread)) => thread.parent_id,
+            _ => None,
+        };
+
+        match parent {
+            Some(parent) => match self.guild_channel(parent).await? {
+                Some(GuildChannel::Text(text)) => Ok(text.nsfw),
+                _ => Ok(false),
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Looks up a guild channel, fetching and caching it if it isn't cached yet.
+    async fn guild_channel(
+        &self,
+        channel: ChannelId,
+    ) -> Result<Option<GuildChannel>, DiscordInteractionError> {
+        if let Some(cached) = self.cache.guild_channel(channel) {
+            return Ok(Some(cached));
+        }
+
+        let fetched = self
+            .discord_client
+            .channel(channel)
+            .exec()
+            .await
+            .map_err(DiscordInteractionError::FetchingChannel)?
+            .model()
+            .await
+            .map_err(DiscordInteractionError::Deserialize)?;
+
+        match fetched {
+            Channel::Guild(guild_channel) => {
+                self.cache
+                    .update(&ChannelCreate(Channel::Guild(guild_channel.clone())));
+                Ok(Some(guild_channel))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn message_cache(&self) -> MutexGuard<'_, MessageCache> {
+        self.messages
+            .lock()
+            .expect("bug: a thread panicked while caching messages")
+    }
+
+    pub async fn delete_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<(), DiscordInteractionError> {
+        self.discord_client
+            .delete_message(channel, message)
+            .exec()
+            .await
+            .map_err(DiscordInteractionError::DeletingMessage)?;
+
+        Ok(())
     }
 
     pub async fn confirm_action(
         &self,
         action: ConfirmationAction,
         channel: ChannelId,
+        mod_role: Option<RoleId>,
+    ) -> Result<bool, DiscordInteractionError> {
+        match self.config.reply.confirmations {
+            Confirmations::Buttons => {
+                let msg = self
+                    .discord_client
+                    .create_message(channel)
+                    .content(action.as_str())
+                    .expect("bug: message content was > 2000")
+                    .components(&[confirmation_buttons()])
+                    .expect("bug: message components were invalid")
+                    .exec()
+                    .await
+                    .map_err(DiscordInteractionError::SendingMessage)?
+                    .model()
+                    .await
+                    .map_err(DiscordInteractionError::Deserialize)?;
+
+                self.wait_for_confirmation_button(msg.id, channel, mod_role)
+                    .await
+            }
+            Confirmations::Text => {
+                let prompt = format!("{} {}", action.as_str(), ConfirmationAction::REPLY_PROMPT);
+                self.send_message(&prompt, channel, None).await?;
+
+                self.wait_for_confirmation_reply(channel, mod_role).await
+            }
+        }
+    }
+
+    async fn wait_for_confirmation_button(
+        &self,
+        message: MessageId,
+        channel: ChannelId,
+        mod_role: Option<RoleId>,
     ) -> Result<bool, DiscordInteractionError> {
-        let msg = self.send_message(action.as_str(), channel, None).await?;
+        let mut clicks = self
+            .standby
+            .wait_for_event_stream(move |event: &Event| match event {
+                Event::InteractionCreate(interaction) => match &interaction.0 {
+                    Interaction::MessageComponent(click) => click.message.id == message,
+                    _ => false,
+                },
+                _ => false,
+            });
+
+        let answer = async {
+            while let Some(event) = clicks.next().await {
+                let click = match event {
+                    Event::InteractionCreate(interaction) => match interaction.0 {
+                        Interaction::MessageComponent(click) => click,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+
+                let answer = match check_button_for_confirmation(&click.data.custom_id) {
+                    Some(answer) => answer,
+                    None => continue,
+                };
+
+                if !self.clicked_by_moderator(&click, mod_role) {
+                    let response = InteractionResponse::ChannelMessageWithSource(callback_data(
+                        ConfirmationAction::NOT_A_MODERATOR.to_string(),
+                        None,
+                        Some(MessageFlags::EPHEMERAL),
+                    ));
+                    self.respond_to_interaction(&click, &response).await?;
+                    continue;
+                }
+
+                // Takes the buttons away, so the question can't be answered twice.
+                let label = if answer { "Yes" } else { "No" };
+                let response = InteractionResponse::UpdateMessage(callback_data(
+                    format!("{} **{}**", click.message.content, label),
+                    Some(Vec::new()),
+                    None,
+                ));
+                self.respond_to_interaction(&click, &response).await?;
+
+                return Ok(answer);
+            }
 
-        let reaction = RequestReactionType::Unicode {
-            name: ConfirmationAction::CONFIRMED,
+            unreachable!("bug: standby (and context?) was dropped while waiting for a button")
         };
-        self.discord_client
-            .create_reaction(channel, msg.id, &reaction)
-            .exec()
-            .await
-            .map_err(DiscordInteractionError::ReactionHandling)?;
 
-        let reaction = RequestReactionType::Unicode {
-            name: ConfirmationAction::CANCELED,
+        match tokio::time::timeout(Self::CONFIRMATION_TIMEOUT, answer).await {
+            Ok(answer) => answer,
+            Err(_) => {
+                let removed = self
+                    .discord_client
+                    .update_message(channel, message)
+                    .components(Some(&[]))
+                    .expect("bug: message components were invalid")
+                    .exec()
+                    .await;
+
+                if let Err(e) = removed {
+                    tracing::warn!("Couldn't remove confirmation buttons: {:?}", e);
+                }
+
+                self.confirmation_timed_out(channel).await
+            }
+        }
+    }
+
+    fn clicked_by_moderator(
+        &self,
+        click: &MessageComponentInteraction,
+        mod_role: Option<RoleId>,
+    ) -> bool {
+        let user = match click.author_id() {
+            Some(user) => user,
+            None => return false,
         };
+
+        let roles = click.member.as_ref().map_or(&[][..], |m| &m.roles);
+        match click.guild_id {
+            Some(guild) => self.is_moderator(guild, user, roles, mod_role),
+            None => true,
+        }
+    }
+
+    async fn respond_to_interaction(
+        &self,
+        interaction: &MessageComponentInteraction,
+        response: &InteractionResponse,
+    ) -> Result<(), DiscordInteractionError> {
         self.discord_client
-            .create_reaction(channel, msg.id, &reaction)
+            .interaction_callback(interaction.id, &interaction.token, response)
             .exec()
             .await
-            .map_err(DiscordInteractionError::ReactionHandling)?;
+            .map_err(DiscordInteractionError::RespondingToInteraction)?;
 
-        let me = self.id;
+        Ok(())
+    }
+
+    async fn wait_for_confirmation_reply(
+        &self,
+        channel: ChannelId,
+        mod_role: Option<RoleId>,
+    ) -> Result<bool, DiscordInteractionError> {
+        let context = self.clone();
         let fut = self
             .standby
-            .wait_for_reaction(msg.id, move |event: &ReactionAdd| {
-                if event.user_id == me {
+            .wait_for_message(channel, move |event: &MessageCreate| {
+                if context.is_me(event.author.id) {
                     return false;
                 }
 
-                check_emote_name_for_confirmation(&event.emoji).is_some()
+                let roles = event.member.as_ref().map_or(&[][..], |m| &m.roles);
+                let moderator = match event.guild_id {
+                    Some(guild) => context.is_moderator(guild, event.author.id, roles, mod_role),
+                    None => true,
+                };
+
+                moderator && check_text_for_confirmation(&event.content).is_some()
             });
 
-        match tokio::time::timeout(Duration::from_secs(10), fut).await {
-            Ok(Ok(_)) => Ok(true),
+        match tokio::time::timeout(Self::CONFIRMATION_TIMEOUT, fut).await {
+            Ok(Ok(reply)) => Ok(check_text_for_confirmation(&reply.content) == Some(true)),
             Ok(_) => {
-                unreachable!("bug: standby (and context?) was dropped while waiting for reaction")
-            }
-            Err(_) => {
-                self.send_message(ConfirmationAction::TIMED_OUT, channel, None)
-                    .await?;
-
-                Ok(false)
+                unreachable!("bug: standby (and context?) was dropped while waiting for a reply")
             }
+            Err(_) => self.confirmation_timed_out(channel).await,
         }
     }
 
-    pub async fn download_image(&self, url: &str) -> Result<Vec<u8>, Error> {
-        let uri = Uri::from_str(url).expect("invalid URL");
+    async fn confirmation_timed_out(
+        &self,
+        channel: ChannelId,
+    ) -> Result<bool, DiscordInteractionError> {
+        self.send_message(ConfirmationAction::TIMED_OUT, channel, None)
+            .await?;
+
+        Ok(false)
+    }
+
+    /// Downloads an image, refusing anything larger than `max_size` bytes.
+    ///
+    /// Each attempt gives up after the configured timeout, and network failures are retried
+    /// a few times in case they're a hiccup.
+    pub async fn download_image(&self, url: &str, max_size: u64) -> Result<Vec<u8>, Error> {
+        let config = &self.config.download;
+        let timeout = Duration::from_secs(config.timeout);
+
+        retry_with_backoff(
+            config.attempts,
+            DOWNLOAD_RETRY_DELAY,
+            Error::is_transient,
+            || async {
+                tokio::time::timeout(timeout, self.download_once(url, max_size))
+                    .await
+                    .unwrap_or(Err(Error::TimedOut))
+            },
+        )
+        .await
+    }
+
+    /// Downloads an image once, refusing anything larger than `max_size` bytes.
+    ///
+    /// Anything that announces itself as too large isn't downloaded at all, and anything that
+    /// turns out to be too large stops downloading once it's past the limit.
+    async fn download_once(&self, url: &str, max_size: u64) -> Result<Vec<u8>, Error> {
+        let mut uri = Uri::from_str(url).expect("invalid URL");
+        let mut redirects = 0;
+
+        let response = loop {
+            let request = Request::get(uri.clone())
+                .header(header::USER_AGENT, USER_AGENT)
+                .body(Body::empty())
+                .expect("bug: invalid download request");
+            let response = self.web_client.request(request).await?;
+            let next = redirect_target(
+                &uri,
+                response.status(),
+                response.headers().get(header::LOCATION),
+            )?;
+
+            match next {
+                Some(_) if redirects == MAX_REDIRECTS => return Err(Error::BadRedirect),
+                Some(next) => {
+                    tracing::debug!("Following a redirect from {} to {}", uri, next);
+                    uri = next;
+                    redirects += 1;
+                }
+                None => break response,
+            }
+        };
 
-        let response = self.web_client.get(uri.clone()).await?;
         let size = response
             .size_hint()
             .exact()
             .unwrap_or_else(|| response.size_hint().lower());
 
-        let mut image = Vec::with_capacity(size.try_into().map_err(|_| Error::ContentTooLarge)?);
+        if size > max_size {
+            return Err(Error::ContentTooLarge);
+        }
+
+        read_capped(response.into_body(), size, max_size).await
+    }
+
+    /// Finds the GIF a Tenor or Giphy page shows, so it can be downloaded and checked like any other.
+    pub async fn resolve_gif_link(&self, link: &GifLink<'_>) -> Result<String, Error> {
+        match link {
+            GifLink::Giphy(id) => Ok(format!("https://media.giphy.com/media/{}/giphy.gif", id)),
+            GifLink::Tenor(page) => {
+                let page = self.download_image(page, MAX_PAGE_SIZE).await?;
+                page_image(&String::from_utf8_lossy(&page)).ok_or(Error::MediaNotFound)
+            }
+        }
+    }
+
+    /// Finds every file a Reddit post shows, so they can be downloaded and checked like any other image.
+    pub async fn resolve_reddit_link(&self, link: &RedditLink<'_>) -> Result<Vec<String>, Error> {
+        let listing = format!("https://www.reddit.com/by_id/t3_{}.json", link.id);
+        let listing = self.download_image(&listing, MAX_PAGE_SIZE).await?;
+        let listing: serde_json::Value =
+            serde_json::from_slice(&listing).map_err(|_| Error::MediaNotFound)?;
 
-        let mut body = response.into_body();
-        while let Some(bytes) = body.data().await {
-            let bytes = bytes?;
-            image.extend(bytes);
+        let media = reddit_media(&listing, self.config.detection.video_thumbnails);
+        if media.is_empty() {
+            return Err(Error::MediaNotFound);
         }
 
-        Ok(image)
+        Ok(media)
     }
 
     pub async fn change_status(
@@ -252,6 +934,323 @@ impl Context {
     }
 }
 
+/// Reads a body that's expected to be `expected` bytes long, failing as soon as it goes past `max_size`.
+async fn read_capped<B>(mut body: B, expected: u64, max_size: u64) -> Result<Vec<u8>, Error>
+where
+    B: HttpBody<Data = hyper::body::Bytes, Error = hyper::Error> + Unpin,
+{
+    let capacity = expected.min(max_size);
+    let mut image = Vec::with_capacity(capacity.try_into().map_err(|_| Error::ContentTooLarge)?);
+
+    while let Some(bytes) = body.data().await {
+        let bytes = bytes?;
+        if (image.len() + bytes.len()) as u64 > max_size {
+            return Err(Error::ContentTooLarge);
+        }
+
+        image.extend(bytes);
+    }
+
+    Ok(image)
+}
+
+/// What downloads say they come from. Some sites, like Reddit, turn away requests without one.
+const USER_AGENT: &str = concat!(
+    "repost-me-not/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/BlackHoleFox/repost-me-not)"
+);
+
+/// Most redirects followed while downloading an image.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Where a response redirects to, if it's a redirect.
+///
+/// Redirects have to stay on the same scheme, so an image fetched over HTTPS can't be sent to plain HTTP.
+fn redirect_target(
+    current: &Uri,
+    status: StatusCode,
+    location: Option<&HeaderValue>,
+) -> Result<Option<Uri>, Error> {
+    if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+
+    let location = location
+        .and_then(|l| l.to_str().ok())
+        .ok_or(Error::BadRedirect)?;
+    let scheme = current.scheme_str().ok_or(Error::BadRedirect)?;
+    let authority = current.authority().ok_or(Error::BadRedirect)?;
+
+    let target = if location.contains("://") {
+        location.to_string()
+    } else if let Some(rest) = location.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        // Relative to the directory of the current path.
+        let path = current.path();
+        let directory = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}://{}{}{}", scheme, authority, directory, location)
+    };
+
+    let target = Uri::from_str(&target).map_err(|_| Error::BadRedirect)?;
+    if target.scheme_str() != Some(scheme) {
+        return Err(Error::BadRedirect);
+    }
+
+    Ok(Some(target))
+}
+
+/// Largest page, in bytes, that's downloaded to find the media it shows.
+const MAX_PAGE_SIZE: u64 = 1024 * 1024;
+
+/// A link to a GIF's page on Tenor or Giphy, rather than to the GIF itself.
+#[derive(Debug, PartialEq)]
+pub enum GifLink<'a> {
+    /// Giphy's media URLs are built from the ID at the end of the page's URL, so this is that ID.
+    Giphy(&'a str),
+    /// Tenor's aren't, so this is the page that has to be fetched to find it.
+    Tenor(&'a str),
+}
+
+impl<'a> GifLink<'a> {
+    /// Recognizes `tenor.com/view/...` and `giphy.com/gifs/...` links.
+    pub fn parse(url: &'a str) -> Option<Self> {
+        // The page gets downloaded as is, so it has to be a valid URL.
+        Uri::from_str(url).ok()?;
+
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))?;
+        let rest = rest.split(['?', '#']).next()?;
+        let (host, path) = rest.split_once('/')?;
+        let host = host.strip_prefix("www.").unwrap_or(host);
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+
+        match host {
+            // Pages can have a language first, like `tenor.com/en-GB/view/...`.
+            "tenor.com" => segments
+                .skip_while(|s| *s != "view")
+                .nth(1)
+                .map(|_| Self::Tenor(url)),
+            "giphy.com" if segments.next() == Some("gifs") => {
+                let id = segments.next_back()?.rsplit('-').next()?;
+                if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return None;
+                }
+
+                Some(Self::Giphy(id))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A link to a Reddit post, rather than to the files in it.
+#[derive(Debug, PartialEq)]
+pub struct RedditLink<'a> {
+    pub url: &'a str,
+    /// The post's ID, which is all that's needed to look it up.
+    pub id: &'a str,
+}
+
+impl<'a> RedditLink<'a> {
+    /// Recognizes links to posts on `reddit.com`, galleries, and `redd.it` short links.
+    pub fn parse(url: &'a str) -> Option<Self> {
+        Uri::from_str(url).ok()?;
+
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))?;
+        let rest = rest.split(['?', '#']).next()?;
+        let (host, path) = rest.split_once('/')?;
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let host = host.to_ascii_lowercase();
+        let id = if host == "redd.it" {
+            match segments.as_slice() {
+                [id] => *id,
+                _ => return None,
+            }
+        } else if host == "reddit.com" || host.ends_with(".reddit.com") {
+            match segments.as_slice() {
+                ["r", _, "comments", id, ..] | ["comments", id, ..] | ["gallery", id] => *id,
+                _ => return None,
+            }
+        } else {
+            return None;
+        };
+
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+
+        Some(Self { url, id })
+    }
+}
+
+/// Every file a Reddit post shows, from its listing JSON.
+///
+/// Crossposts show what the post they came from does. Videos only have a still preview,
+/// which is only used if videos are checked by their thumbnails.
+fn reddit_media(listing: &serde_json::Value, video_thumbnails: bool) -> Vec<String> {
+    fn post_media(post: &serde_json::Value, video_thumbnails: bool) -> Vec<String> {
+        if let Some(parent) = post["crosspost_parent_list"].get(0) {
+            return post_media(parent, video_thumbnails);
+        }
+
+        // Galleries list their files in order, and each one is on i.redd.it under its ID.
+        if let Some(items) = post["gallery_data"]["items"].as_array() {
+            return items
+                .iter()
+                .filter_map(|item| {
+                    let id = item["media_id"].as_str()?;
+                    let extension = post["media_metadata"][id]["m"]
+                        .as_str()?
+                        .strip_prefix("image/")?;
+                    let valid =
+                        |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric());
+                    if !valid(id) || !valid(extension) {
+                        return None;
+                    }
+
+                    Some(format!("https://i.redd.it/{}.{}", id, extension))
+                })
+                .collect();
+        }
+
+        let linked = post["url_overridden_by_dest"]
+            .as_str()
+            .or_else(|| post["url"].as_str());
+        let preview = post["preview"]["images"][0]["source"]["url"].as_str();
+
+        let url = match (linked, preview) {
+            (Some(url), _) if url.starts_with("https://i.redd.it/") => url,
+            (_, Some(preview)) if post["is_video"] == true && video_thumbnails => preview,
+            _ => return Vec::new(),
+        };
+
+        // Reddit escapes the URLs in its JSON like they're going into HTML.
+        let url = url.replace("&amp;", "&");
+        if Uri::from_str(&url).is_err() {
+            return Vec::new();
+        }
+
+        vec![url]
+    }
+
+    post_media(&listing["data"]["children"][0]["data"], video_thumbnails)
+}
+
+/// The image a page shows in previews of it, from its `og:image` tag.
+fn page_image(html: &str) -> Option<String> {
+    html.split("<meta").skip(1).find_map(|tag| {
+        let tag = tag.split('>').next()?;
+        if !tag.contains("property=\"og:image\"") {
+            return None;
+        }
+
+        let url = tag.split("content=\"").nth(1)?.split('"').next()?;
+        let url = url.replace("&amp;", "&");
+        // It's downloaded next, so anything that isn't a plain HTTPS URL is ignored.
+        if !url.starts_with("https://") || Uri::from_str(&url).is_err() {
+            return None;
+        }
+
+        Some(url)
+    })
+}
+
+/// How long to wait before retrying a download, doubling each time.
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How many times requests the bot can't start without are tried before giving up.
+const STARTUP_ATTEMPTS: u32 = 5;
+/// How long to wait before the first retry of a startup request, doubling each time.
+const STARTUP_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Fetches the bot's own user ID, retrying in case Discord or the network are having a moment.
+pub async fn current_user_id(client: &Client) -> Result<UserId, Error> {
+    retry_with_backoff(
+        STARTUP_ATTEMPTS,
+        STARTUP_RETRY_DELAY,
+        |_| true,
+        || async {
+            let user = client
+                .current_user()
+                .exec()
+                .await
+                .map_err(DiscordInteractionError::FetchingCurrentUser)?
+                .model()
+                .await
+                .map_err(DiscordInteractionError::Deserialize)?;
+
+            Ok(user.id)
+        },
+    )
+    .await
+}
+
+/// Fetches the ID of the user who owns the bot's application, retrying like [current_user_id].
+pub async fn application_owner_id(client: &Client) -> Result<UserId, Error> {
+    retry_with_backoff(
+        STARTUP_ATTEMPTS,
+        STARTUP_RETRY_DELAY,
+        |_| true,
+        || async {
+            let application = client
+                .current_user_application()
+                .exec()
+                .await
+                .map_err(DiscordInteractionError::FetchingCurrentUser)?
+                .model()
+                .await
+                .map_err(DiscordInteractionError::Deserialize)?;
+
+            Ok(application.owner.id)
+        },
+    )
+    .await
+}
+
+/// Runs `f` until it succeeds, has failed `attempts` times, or fails in a way `retryable` says
+/// won't get better, waiting twice as long after each failure.
+async fn retry_with_backoff<T, F, Fut>(
+    attempts: u32,
+    initial_delay: Duration,
+    retryable: fn(&Error) -> bool,
+    mut f: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut delay = initial_delay;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts && retryable(&e) => {
+                tracing::warn!(
+                    "Attempt {} of {} failed, retrying in {:?}: {:?}",
+                    attempt,
+                    attempts,
+                    delay,
+                    e
+                );
+
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 pub fn presence_builder(message: String, status: Status) -> UpdatePresence {
     let activity = MinimalActivity {
         kind: ActivityType::Watching,
@@ -263,140 +1262,464 @@ pub fn presence_builder(message: String, status: Status) -> UpdatePresence {
     UpdatePresence::new(vec![activity], false, None, status).unwrap()
 }
 
-fn check_emote_name_for_confirmation(emote: &ReactionType) -> Option<bool> {
-    let name = match emote {
-        ReactionType::Unicode { name } => name,
-        ReactionType::Custom { name: Some(n), .. } => n,
-        _ => return None,
+/// A member can moderate the bot if they have the guild's mod role, or can manage messages anyway.
+fn is_authorized(
+    mod_role: Option<RoleId>,
+    member_roles: &[RoleId],
+    role_permissions: impl IntoIterator<Item = Permissions>,
+) -> bool {
+    matches!(mod_role, Some(role) if member_roles.contains(&role)) || can_moderate(role_permissions)
+}
+
+fn can_moderate(role_permissions: impl IntoIterator<Item = Permissions>) -> bool {
+    let combined = role_permissions
+        .into_iter()
+        .fold(Permissions::empty(), |all, role| all | role);
+
+    combined.intersects(Permissions::ADMINISTRATOR | Permissions::MANAGE_MESSAGES)
+}
+
+/// The messages images were most recently checked from, so crossposts of them aren't counted twice.
+#[derive(Default)]
+pub struct CheckedMessages {
+    order: VecDeque<MessageId>,
+    ids: HashSet<MessageId>,
+}
+
+impl CheckedMessages {
+    const CAPACITY: usize = 1000;
+
+    /// Remembers a message, returning if it wasn't already known.
+    pub fn insert(&mut self, message: MessageId) -> bool {
+        if !self.ids.insert(message) {
+            return false;
+        }
+
+        self.order.push_back(message);
+        if self.order.len() > Self::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+/// Channels the bot found out it isn't allowed to send messages in.
+#[derive(Default)]
+pub struct ForbiddenChannels(HashSet<ChannelId>);
+
+impl ForbiddenChannels {
+    /// Remembers a channel, returning if it wasn't already known.
+    pub fn insert(&mut self, channel: ChannelId) -> bool {
+        self.0.insert(channel)
+    }
+
+    pub fn contains(&self, channel: ChannelId) -> bool {
+        self.0.contains(&channel)
+    }
+}
+
+/// If an error came from Discord refusing a request because the bot is missing permissions.
+pub fn is_missing_permissions(error: &Error) -> bool {
+    let error = match error {
+        Error::InteractionError(e) => match &**e {
+            DiscordInteractionError::SendingMessage(e)
+            | DiscordInteractionError::DeletingMessage(e)
+            | DiscordInteractionError::RespondingToInteraction(e) => e,
+            _ => return false,
+        },
+        _ => return false,
     };
 
-    let check = match &**name {
-        ConfirmationAction::CONFIRMED => true,
-        ConfirmationAction::CANCELED => false,
-        name if name.contains("yes") => true,
-        name if name.contains("Yes") => true,
-        // "no" seems like it would have more false positives with .contains().
-        name if name.starts_with("no") => false,
-        name if name.starts_with("No") => false,
-        _ => return None,
+    match error.kind() {
+        ErrorType::Response { status, error, .. } => {
+            let code = match error {
+                ApiError::General(general) => Some(general.code.num()),
+                _ => None,
+            };
+
+            missing_permissions_response(status.raw(), code)
+        }
+        _ => false,
+    }
+}
+
+/// If an API response means the bot is missing permissions or access to something.
+fn missing_permissions_response(status: u16, code: Option<u64>) -> bool {
+    // Missing Access, and Missing Permissions.
+    const PERMISSION_CODES: &[u64] = &[50001, 50013];
+
+    match code {
+        Some(code) => PERMISSION_CODES.contains(&code),
+        None => status == 403,
+    }
+}
+
+/// The Yes and No buttons a confirmation is answered with.
+fn confirmation_buttons() -> Component {
+    let button = |id: &str, label: &str, style| {
+        Component::Button(Button {
+            custom_id: Some(id.to_string()),
+            disabled: false,
+            emoji: None,
+            label: Some(label.to_string()),
+            style,
+            url: None,
+        })
     };
 
-    Some(check)
+    Component::ActionRow(ActionRow {
+        components: vec![
+            button(ConfirmationAction::CONFIRM_ID, "Yes", ButtonStyle::Success),
+            button(ConfirmationAction::CANCEL_ID, "No", ButtonStyle::Danger),
+        ],
+    })
+}
+
+fn callback_data(
+    content: String,
+    components: Option<Vec<Component>>,
+    flags: Option<MessageFlags>,
+) -> CallbackData {
+    CallbackData {
+        allowed_mentions: Some(AllowedMentions::default()),
+        components,
+        content: Some(content),
+        embeds: Vec::new(),
+        flags,
+        tts: None,
+    }
+}
+
+fn check_button_for_confirmation(custom_id: &str) -> Option<bool> {
+    match custom_id {
+        ConfirmationAction::CONFIRM_ID => Some(true),
+        ConfirmationAction::CANCEL_ID => Some(false),
+        _ => None,
+    }
+}
+
+fn check_text_for_confirmation(content: &str) -> Option<bool> {
+    match content.trim().to_lowercase().as_str() {
+        "yes" | "y" => Some(true),
+        "no" | "n" => Some(false),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use twilight_model::{channel::ReactionType, id::EmojiId};
-
     use super::*;
 
-    const ACCEPT_AS_YES: &[&str] = &[
-        ConfirmationAction::CONFIRMED,
-        "yes",
-        "Yes",
-        "Yesdoit",
-        "yesplease",
-        "yes_yes_yes",
-        "yesmam",
-        "yeserp",
-        "please_yes_doit",
-        "wow_Yes_please",
-    ];
+    #[test]
+    fn permission_errors_classified() {
+        assert!(missing_permissions_response(403, Some(50013)));
+        assert!(missing_permissions_response(403, Some(50001)));
+        assert!(missing_permissions_response(403, None));
+        assert!(!missing_permissions_response(403, Some(40005)));
+        assert!(!missing_permissions_response(404, Some(10003)));
+        assert!(!missing_permissions_response(500, None));
+
+        assert!(!is_missing_permissions(&Error::ContentTooLarge));
+        assert!(!is_missing_permissions(&Error::from(
+            DiscordInteractionError::MessageNotFound
+        )));
+    }
+
+    #[test]
+    fn forbidden_channels_reported_once() {
+        let mut forbidden = ForbiddenChannels::default();
+        assert!(!forbidden.contains(ChannelId(1)));
 
-    const ACCEPT_AS_NO: &[&str] = &["no", "No", "NoWhywouldIwantthat"];
+        assert!(forbidden.insert(ChannelId(1)));
+        assert!(!forbidden.insert(ChannelId(1)));
+        assert!(forbidden.insert(ChannelId(2)));
 
-    const IGNORED_EMOJIS: &[&str] = &["wow_nope", "wow_really_Nope"];
+        assert!(forbidden.contains(ChannelId(1)));
+        assert!(!forbidden.contains(ChannelId(3)));
+    }
 
     #[test]
-    fn confirmation_emojis_as_yes() {
-        for name in ACCEPT_AS_YES {
-            let as_unicode = ReactionType::Unicode {
-                name: name.to_string(),
-            };
-            let as_custom = ReactionType::Custom {
-                animated: false,
-                id: EmojiId(1),
-                name: Some(name.to_string()),
-            };
+    fn moderation_permissions() {
+        assert!(can_moderate(vec![
+            Permissions::SEND_MESSAGES,
+            Permissions::MANAGE_MESSAGES
+        ]));
+        assert!(can_moderate(vec![Permissions::ADMINISTRATOR]));
+        assert!(!can_moderate(vec![
+            Permissions::SEND_MESSAGES | Permissions::ADD_REACTIONS
+        ]));
+        assert!(!can_moderate(Vec::new()));
+    }
 
-            assert_eq!(
-                check_emote_name_for_confirmation(&as_unicode),
-                Some(true),
-                "{} wasn't accepted",
-                name
-            );
-            assert_eq!(
-                check_emote_name_for_confirmation(&as_custom),
-                Some(true),
-                "{} wasn't accepted",
-                name
-            );
+    #[test]
+    fn checked_messages_remembered() {
+        let mut checked = CheckedMessages::default();
+        assert!(checked.insert(MessageId(1)));
+        assert!(!checked.insert(MessageId(1)));
+
+        // The oldest are forgotten once it's full.
+        for id in 2..=CheckedMessages::CAPACITY as u64 + 1 {
+            assert!(checked.insert(MessageId(id)));
         }
+        assert!(checked.insert(MessageId(1)));
+        assert!(!checked.insert(MessageId(3)));
     }
 
     #[test]
-    fn confirmation_emojis_as_no() {
-        for name in ACCEPT_AS_NO {
-            let as_unicode = ReactionType::Unicode {
-                name: name.to_string(),
-            };
-            let as_custom = ReactionType::Custom {
-                animated: true,
-                id: EmojiId(1),
-                name: Some(name.to_string()),
-            };
+    fn mod_role_authorization() {
+        let mod_role = Some(RoleId(5));
+        let plain = || vec![Permissions::SEND_MESSAGES];
+        let manager = || vec![Permissions::MANAGE_MESSAGES];
 
-            assert_eq!(
-                check_emote_name_for_confirmation(&as_unicode),
-                Some(false),
-                "{} wasn't accepted",
-                name
-            );
-            assert_eq!(
-                check_emote_name_for_confirmation(&as_custom),
-                Some(false),
-                "{} wasn't accepted",
-                name
-            );
-        }
+        // Having the mod role is enough without any permissions.
+        assert!(is_authorized(mod_role, &[RoleId(4), RoleId(5)], plain()));
+        // So is being able to manage messages without the role.
+        assert!(is_authorized(mod_role, &[RoleId(4)], manager()));
+        assert!(is_authorized(None, &[RoleId(5)], manager()));
+        assert!(is_authorized(mod_role, &[RoleId(5)], manager()));
+
+        // Neither means no.
+        assert!(!is_authorized(mod_role, &[RoleId(4)], plain()));
+        assert!(!is_authorized(None, &[RoleId(5)], plain()));
+        assert!(!is_authorized(mod_role, &[], Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn downloads_capped_while_streaming() {
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                if sender.send_data(vec![0; 100].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // The body didn't say how large it was, but it's cut off once it goes past the limit.
+        assert!(matches!(
+            read_capped(body, 0, 250).await,
+            Err(Error::ContentTooLarge)
+        ));
+
+        let image = read_capped(hyper::Body::from(vec![1; 250]), 250, 250)
+            .await
+            .unwrap();
+        assert_eq!(image.len(), 250);
     }
 
     #[test]
-    fn confirmation_emojis_ignored() {
-        for name in IGNORED_EMOJIS {
-            let as_unicode = ReactionType::Unicode {
-                name: name.to_string(),
-            };
-            let as_custom = ReactionType::Custom {
-                animated: true,
-                id: EmojiId(1),
-                name: Some(name.to_string()),
-            };
+    fn gif_links_recognized() {
+        let tenor = "https://tenor.com/view/cat-typing-gif-12002898";
+        assert_eq!(GifLink::parse(tenor), Some(GifLink::Tenor(tenor)));
+        let localized = "https://tenor.com/en-GB/view/cat-typing-gif-12002898";
+        assert_eq!(GifLink::parse(localized), Some(GifLink::Tenor(localized)));
+        assert_eq!(
+            GifLink::parse("https://giphy.com/gifs/cat-funny-JIX9t2j0ZTN9S"),
+            Some(GifLink::Giphy("JIX9t2j0ZTN9S"))
+        );
+        assert_eq!(
+            GifLink::parse("https://www.giphy.com/gifs/JIX9t2j0ZTN9S?utm_source=share"),
+            Some(GifLink::Giphy("JIX9t2j0ZTN9S"))
+        );
 
-            assert_eq!(
-                check_emote_name_for_confirmation(&as_unicode),
-                None,
-                "{} was wrongly accepted",
-                name
-            );
-            assert_eq!(
-                check_emote_name_for_confirmation(&as_custom),
-                None,
-                "{} was wrongly accepted",
-                name
-            );
-        }
+        assert_eq!(GifLink::parse("https://tenor.com/view/"), None);
+        assert_eq!(GifLink::parse("https://tenor.com/search/cat-gifs"), None);
+        assert_eq!(GifLink::parse("https://giphy.com/explore/cat"), None);
+        assert_eq!(GifLink::parse("https://giphy.com/gifs/"), None);
+        assert_eq!(GifLink::parse("https://example.com/view/cat"), None);
+        assert_eq!(GifLink::parse("tenor.com/view/cat-gif-1"), None);
+    }
+
+    #[test]
+    fn reddit_links_recognized() {
+        let id = |url| RedditLink::parse(url).map(|link| link.id);
+        assert_eq!(
+            id("https://www.reddit.com/r/cats/comments/abc123/look_at_him/"),
+            Some("abc123")
+        );
+        assert_eq!(id("https://old.reddit.com/comments/abc123"), Some("abc123"));
+        assert_eq!(id("https://reddit.com/gallery/abc123"), Some("abc123"));
+        assert_eq!(id("https://redd.it/abc123?share=1"), Some("abc123"));
+
+        assert_eq!(id("https://i.redd.it/abc123.png"), None);
+        assert_eq!(id("https://www.reddit.com/r/cats/"), None);
+        assert_eq!(id("https://www.reddit.com/r/cats/comments/ab%20c/"), None);
+        assert_eq!(id("https://notreddit.com/comments/abc123"), None);
+    }
+
+    #[test]
+    fn reddit_media_found() {
+        let listing = |post: serde_json::Value| serde_json::json!({ "data": { "children": [{ "data": post }] } });
+
+        let image = listing(serde_json::json!({
+            "url_overridden_by_dest": "https://i.redd.it/cat.jpg",
+            "is_video": false,
+        }));
+        assert_eq!(
+            reddit_media(&image, true),
+            vec!["https://i.redd.it/cat.jpg"]
+        );
+
+        let gallery = listing(serde_json::json!({
+            "url": "https://www.reddit.com/gallery/abc123",
+            "gallery_data": { "items": [{ "media_id": "two" }, { "media_id": "one" }, { "media_id": "../x" }] },
+            "media_metadata": {
+                "one": { "m": "image/png" },
+                "two": { "m": "image/jpg" },
+                "../x": { "m": "image/png" },
+            },
+        }));
+        assert_eq!(
+            reddit_media(&gallery, true),
+            vec!["https://i.redd.it/two.jpg", "https://i.redd.it/one.png"]
+        );
+
+        let crosspost = listing(serde_json::json!({
+            "url": "https://www.reddit.com/r/cats/comments/abc123/",
+            "crosspost_parent_list": [{ "url": "https://i.redd.it/original.gif" }],
+        }));
+        assert_eq!(
+            reddit_media(&crosspost, true),
+            vec!["https://i.redd.it/original.gif"]
+        );
+
+        let video = listing(serde_json::json!({
+            "url": "https://v.redd.it/abc123",
+            "is_video": true,
+            "preview": { "images": [{ "source": { "url": "https://preview.redd.it/abc.jpg?width=640&amp;s=sig" } }] },
+        }));
+        assert_eq!(
+            reddit_media(&video, true),
+            vec!["https://preview.redd.it/abc.jpg?width=640&s=sig"]
+        );
+        assert!(reddit_media(&video, false).is_empty());
+
+        let text =
+            listing(serde_json::json!({ "url": "https://www.reddit.com/r/cats/comments/abc123/" }));
+        assert!(reddit_media(&text, true).is_empty());
+        assert!(reddit_media(&serde_json::json!({}), true).is_empty());
+    }
+
+    #[test]
+    fn gif_found_on_page() {
+        let page = r#"<html><head>
+            <meta name="twitter:card" content="player">
+            <meta class="dynamic" property="og:image" content="https://media1.tenor.com/m/abc/cat-typing.gif?a=1&amp;b=2">
+            <meta property="og:image" content="https://media1.tenor.com/m/def/other.gif">
+        </head></html>"#;
+        assert_eq!(
+            page_image(page).as_deref(),
+            Some("https://media1.tenor.com/m/abc/cat-typing.gif?a=1&b=2")
+        );
 
-        let with_no_name = ReactionType::Custom {
-            animated: false,
-            id: EmojiId(1),
-            name: None,
+        assert_eq!(page_image("<html><body>cat</body></html>"), None);
+        assert_eq!(
+            page_image(r#"<meta property="og:image" content="http://media.tenor.com/cat.gif">"#),
+            None
+        );
+        assert_eq!(
+            page_image(r#"<meta property="og:image" content="javascript:alert(1)">"#),
+            None
+        );
+    }
+
+    #[test]
+    fn redirects_resolved() {
+        let current = Uri::from_static("https://example.com/images/cat.png?size=large");
+        let target = |status, location: &'static str| {
+            redirect_target(&current, status, Some(&HeaderValue::from_static(location)))
+                .map(|uri| uri.map(|uri| uri.to_string()))
         };
 
         assert_eq!(
-            check_emote_name_for_confirmation(&with_no_name),
-            None,
-            "emote with no name was wrongly accepted"
+            target(StatusCode::FOUND, "https://cdn.example.net/cat.png").unwrap(),
+            Some("https://cdn.example.net/cat.png".to_string())
+        );
+        assert_eq!(
+            target(StatusCode::MOVED_PERMANENTLY, "/full/cat.png").unwrap(),
+            Some("https://example.com/full/cat.png".to_string())
+        );
+        assert_eq!(
+            target(StatusCode::TEMPORARY_REDIRECT, "//cdn.example.net/cat.png").unwrap(),
+            Some("https://cdn.example.net/cat.png".to_string())
         );
+        assert_eq!(
+            target(StatusCode::SEE_OTHER, "cat-2.png").unwrap(),
+            Some("https://example.com/images/cat-2.png".to_string())
+        );
+
+        // Downgrading to plain HTTP, or redirecting nowhere, isn't followed.
+        assert!(matches!(
+            target(StatusCode::FOUND, "http://example.com/cat.png"),
+            Err(Error::BadRedirect)
+        ));
+        assert!(matches!(
+            redirect_target(&current, StatusCode::FOUND, None),
+            Err(Error::BadRedirect)
+        ));
+
+        assert!(target(StatusCode::OK, "/elsewhere.png").unwrap().is_none());
+        assert!(redirect_target(&current, StatusCode::NOT_MODIFIED, None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn startup_requests_retried() {
+        let calls = AtomicUsize::new(0);
+        let flaky = || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(Error::from(DiscordInteractionError::MessageNotFound)),
+                _ => Ok(UserId(42)),
+            }
+        };
+
+        let id = retry_with_backoff(3, Duration::from_millis(1), |_| true, flaky).await;
+        assert_eq!(id.unwrap(), UserId(42));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        calls.store(0, Ordering::SeqCst);
+        let id = retry_with_backoff(2, Duration::from_millis(1), |_| true, flaky).await;
+        assert!(id.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Failures that won't get better aren't retried at all.
+        calls.store(0, Ordering::SeqCst);
+        let id = retry_with_backoff(3, Duration::from_millis(1), Error::is_transient, flaky).await;
+        assert!(id.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn confirmation_button_answers() {
+        let buttons = match confirmation_buttons() {
+            Component::ActionRow(row) => row.components,
+            _ => panic!("confirmation buttons weren't in a row"),
+        };
+
+        let answers: Vec<Option<bool>> = buttons
+            .iter()
+            .map(|button| match button {
+                Component::Button(Button {
+                    custom_id: Some(id),
+                    ..
+                }) => check_button_for_confirmation(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(answers, [Some(true), Some(false)]);
+
+        // Buttons on other messages, like link buttons, aren't answers.
+        assert_eq!(check_button_for_confirmation("yes"), None);
+    }
+
+    #[test]
+    fn text_confirmations() {
+        assert_eq!(check_text_for_confirmation("Yes"), Some(true));
+        assert_eq!(check_text_for_confirmation(" n "), Some(false));
+        assert_eq!(check_text_for_confirmation("yes but no"), None);
     }
 }
@@ -0,0 +1,705 @@
+use std::time::Duration;
+
+/// Commands the bot understands when it's mentioned in a message.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Ignore the image in the referenced message from repost checking.
+    Ignore,
+    /// Delete everything stored about the image in the referenced message.
+    Forget,
+    /// Report how long the bot has been running, and what it's done since.
+    Uptime,
+    /// Turn repost checking in the guild back on, or off while still answering commands.
+    SetEnabled(bool),
+    /// List who's made the most reposts in the guild.
+    Leaderboard,
+    /// Sum up the images tracked in the guild and the reposts found among them.
+    Stats,
+    /// Report storage and memory usage of the bot.
+    Diagnostics,
+    /// List images that recently failed to download or decode.
+    Failures,
+    /// Turn raid mode on for a duration, or off if there's no duration.
+    RaidMode(Option<Duration>),
+    /// Change the largest image the guild checks, or go back to the default.
+    MaxImageSize(Option<u64>),
+    /// Turn callouts for reposts from other channels on or off, or go back to the default.
+    CrossChannelReplies(Option<bool>),
+    /// Change how many images can be stored for the guild, or go back to the default.
+    RecordQuota(Option<u64>),
+    /// Let a role moderate the bot, or stop letting any role do so.
+    ModRole(Option<u64>),
+    /// Only check images in these channels, or in every channel if there aren't any.
+    AllowedChannels(Vec<u64>),
+    /// Never check images in these channels.
+    DeniedChannels(Vec<u64>),
+    /// Leave images in NSFW channels alone or check them, or go back to the default.
+    SkipNsfw(Option<bool>),
+    /// Count people reposting their own images without replying or call them out, or go back to the default.
+    IgnoreOwnReposts(Option<bool>),
+    /// Compare the image in the referenced message against the stored image from a linked message.
+    Verify(JumpLink),
+    /// Call out the image in a linked message again, like after the bot's reply was deleted.
+    Resend(JumpLink),
+    /// List every time an image was posted, from a linked message or the image the command is sent with or replies to.
+    History(Option<JumpLink>),
+    /// Delete the guild's images that haven't been seen in a while, optionally only from a channel or poster.
+    Purge {
+        older_than: Duration,
+        channel_id: Option<u64>,
+        author_id: Option<u64>,
+    },
+    /// Move images tracked in one channel over to another.
+    RemapChannel { old: u64, new: u64 },
+    /// Report which storage format version the database is on.
+    DatabaseVersion,
+    /// List the guilds images are tracked in, and how many.
+    Guilds,
+    /// Show how far apart stored hashes are, split into this many buckets.
+    Histogram(usize),
+    /// Run the image at a URL through detection and report every stage, without recording it.
+    TestUrl(String),
+    /// Read the config file again and apply the settings that can change while the bot runs.
+    Reload,
+}
+
+/// Who is allowed to run a command.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum Privilege {
+    Anyone,
+    /// Members who can moderate messages in the guild.
+    Moderator,
+    /// Only the bot's owner.
+    Owner,
+}
+
+impl Command {
+    /// How long raid mode lasts if a duration isn't given.
+    const DEFAULT_RAID_DURATION: Duration = Duration::from_secs(60 * 60);
+
+    /// Histogram buckets used if a count isn't given, making each one 9 distances wide.
+    const DEFAULT_HISTOGRAM_BUCKETS: usize = 57;
+
+    /// Most buckets a histogram can be split into, so the reply stays readable.
+    const MAX_HISTOGRAM_BUCKETS: usize = 128;
+
+    /// Finds the first command keyword in a message's content, along with its arguments.
+    pub fn parse(content: &str) -> Option<Self> {
+        let mut words = content.split_whitespace();
+
+        while let Some(word) = words.next() {
+            let command = match word {
+                "ignore" => Self::Ignore,
+                "forget" => Self::Forget,
+                "uptime" => Self::Uptime,
+                "enable" => Self::SetEnabled(true),
+                "disable" => Self::SetEnabled(false),
+                "leaderboard" => Self::Leaderboard,
+                "stats" => Self::Stats,
+                "diag" => Self::Diagnostics,
+                "failures" => Self::Failures,
+                "guilds" => Self::Guilds,
+                "dbversion" => Self::DatabaseVersion,
+                "reload" => Self::Reload,
+                "raid-mode" => match words.next()? {
+                    "on" => match words.next() {
+                        Some(duration) => Self::RaidMode(Some(parse_duration(duration)?)),
+                        None => Self::RaidMode(Some(Self::DEFAULT_RAID_DURATION)),
+                    },
+                    "off" => Self::RaidMode(None),
+                    _ => return None,
+                },
+                "max-image-size" => match words.next()? {
+                    "default" => Self::MaxImageSize(None),
+                    size => Self::MaxImageSize(Some(parse_size(size)?)),
+                },
+                "record-quota" => match words.next()? {
+                    "default" => Self::RecordQuota(None),
+                    quota => Self::RecordQuota(Some(quota.parse().ok()?)),
+                },
+                "cross-channel" => match words.next()? {
+                    "on" => Self::CrossChannelReplies(Some(true)),
+                    "off" => Self::CrossChannelReplies(Some(false)),
+                    "default" => Self::CrossChannelReplies(None),
+                    _ => return None,
+                },
+                "allow-channels" => match words.next()? {
+                    "all" => Self::AllowedChannels(Vec::new()),
+                    first => Self::AllowedChannels(parse_channels(first, words.by_ref())?),
+                },
+                "deny-channels" => match words.next()? {
+                    "none" => Self::DeniedChannels(Vec::new()),
+                    first => Self::DeniedChannels(parse_channels(first, words.by_ref())?),
+                },
+                "skip-nsfw" => match words.next()? {
+                    "on" => Self::SkipNsfw(Some(true)),
+                    "off" => Self::SkipNsfw(Some(false)),
+                    "default" => Self::SkipNsfw(None),
+                    _ => return None,
+                },
+                "own-reposts" => match words.next()? {
+                    "ignore" => Self::IgnoreOwnReposts(Some(true)),
+                    "callout" => Self::IgnoreOwnReposts(Some(false)),
+                    "default" => Self::IgnoreOwnReposts(None),
+                    _ => return None,
+                },
+                "mod-role" => match words.next()? {
+                    "clear" => Self::ModRole(None),
+                    role => Self::ModRole(Some(parse_role(role)?)),
+                },
+                "verify" => Self::Verify(parse_jump_link(words.next()?)?),
+                "resend" => Self::Resend(parse_jump_link(words.next()?)?),
+                "history" => Self::History(words.next().and_then(parse_jump_link)),
+                "purge" => {
+                    let older_than = parse_duration(words.next()?)?;
+                    let (mut channel_id, mut author_id) = (None, None);
+                    for word in words.by_ref() {
+                        if let Some(channel) = parse_channel_mention(word) {
+                            channel_id = Some(channel);
+                        } else if let Some(user) = parse_user_mention(word) {
+                            author_id = Some(user);
+                        } else {
+                            break;
+                        }
+                    }
+
+                    Self::Purge {
+                        older_than,
+                        channel_id,
+                        author_id,
+                    }
+                }
+                "remap-channel" => Self::RemapChannel {
+                    old: parse_channel(words.next()?)?,
+                    new: parse_channel(words.next()?)?,
+                },
+                "histogram" => match words.next() {
+                    Some(buckets) => match buckets.parse() {
+                        Ok(buckets @ 1..=Self::MAX_HISTOGRAM_BUCKETS) => Self::Histogram(buckets),
+                        _ => return None,
+                    },
+                    None => Self::Histogram(Self::DEFAULT_HISTOGRAM_BUCKETS),
+                },
+                "test-url" => Self::TestUrl(parse_url(words.next()?)?.to_string()),
+                _ => continue,
+            };
+
+            return Some(command);
+        }
+
+        None
+    }
+
+    pub const fn privilege(&self) -> Privilege {
+        match self {
+            Self::Ignore | Self::Uptime | Self::Leaderboard | Self::Stats | Self::History(_) => {
+                Privilege::Anyone
+            }
+            Self::Forget
+            | Self::SetEnabled(_)
+            | Self::RaidMode(_)
+            | Self::MaxImageSize(_)
+            | Self::CrossChannelReplies(_)
+            | Self::RecordQuota(_)
+            | Self::ModRole(_)
+            | Self::AllowedChannels(_)
+            | Self::DeniedChannels(_)
+            | Self::SkipNsfw(_)
+            | Self::IgnoreOwnReposts(_)
+            | Self::Verify(_)
+            | Self::Resend(_)
+            | Self::Purge { .. }
+            | Self::RemapChannel { .. } => Privilege::Moderator,
+            Self::Diagnostics
+            | Self::Failures
+            | Self::Guilds
+            | Self::DatabaseVersion
+            | Self::Histogram(_)
+            | Self::TestUrl(_)
+            | Self::Reload => Privilege::Owner,
+        }
+    }
+}
+
+/// Parses a duration like `30m`, `1h`, or `2d`.
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let split = input.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = input.split_at(split);
+
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    amount.checked_mul(unit_secs).map(Duration::from_secs)
+}
+
+/// Parses a channel mention like `<#1234>`, or a bare channel ID.
+pub fn parse_channel(input: &str) -> Option<u64> {
+    let id = input
+        .strip_prefix("<#")
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(input);
+
+    id.parse().ok()
+}
+
+/// Parses channels in a row, starting with `first`, up to the first word that isn't one.
+fn parse_channels<'a>(first: &str, words: impl Iterator<Item = &'a str>) -> Option<Vec<u64>> {
+    let mut channels = vec![parse_channel(first)?];
+    channels.extend(words.map_while(parse_channel));
+    channels.sort_unstable();
+    channels.dedup();
+
+    Some(channels)
+}
+
+/// Parses only a channel mention like `<#1234>`, so it isn't mistaken for another kind of ID.
+fn parse_channel_mention(input: &str) -> Option<u64> {
+    parse_id(input.strip_prefix("<#")?.strip_suffix('>')?)
+}
+
+/// Parses a user mention like `<@1234>`, including the `<@!1234>` form used for nicknames.
+fn parse_user_mention(input: &str) -> Option<u64> {
+    let id = input.strip_prefix("<@")?.strip_suffix('>')?;
+    parse_id(id.strip_prefix('!').unwrap_or(id))
+}
+
+/// Parses a role mention like `<@&123>`, or a plain role ID.
+pub fn parse_role(input: &str) -> Option<u64> {
+    let id = input
+        .strip_prefix("<@&")
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(input);
+
+    id.parse().ok()
+}
+
+/// The IDs in a message's jump link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JumpLink {
+    pub guild_id: u64,
+    pub channel_id: u64,
+    pub message_id: u64,
+}
+
+/// Parses a message's jump link like `https://discord.com/channels/1/2/3`.
+///
+/// The legacy `discordapp.com` domain and the `canary.` and `ptb.` clients' links are accepted too,
+/// and the link can be wrapped in `<>` to stop it from embedding.
+pub fn parse_jump_link(input: &str) -> Option<JumpLink> {
+    let input = input
+        .strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(input);
+    let input = input
+        .strip_prefix("https://")
+        .or_else(|| input.strip_prefix("http://"))
+        .unwrap_or(input);
+
+    let (host, path) = input.split_once('/')?;
+    let domain = host
+        .strip_prefix("canary.")
+        .or_else(|| host.strip_prefix("ptb."))
+        .unwrap_or(host);
+    if !matches!(domain, "discord.com" | "discordapp.com") {
+        return None;
+    }
+
+    match path.split('/').collect::<Vec<_>>()[..] {
+        ["channels", guild, channel, message] => Some(JumpLink {
+            guild_id: parse_id(guild)?,
+            channel_id: parse_id(channel)?,
+            message_id: parse_id(message)?,
+        }),
+        _ => None,
+    }
+}
+
+/// Parses a snowflake, which is only ever digits.
+fn parse_id(input: &str) -> Option<u64> {
+    if input.is_empty() || !input.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    input.parse().ok()
+}
+
+/// Parses a web URL, which can be wrapped in `<>` to stop it from embedding.
+pub fn parse_url(input: &str) -> Option<&str> {
+    let url = input
+        .strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(input);
+
+    let host = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    if host.is_empty() || host.starts_with('/') {
+        return None;
+    }
+
+    Some(url)
+}
+
+/// Parses a size in bytes like `512KB` or `20MB`.
+pub fn parse_size(input: &str) -> Option<u64> {
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+
+    let amount: u64 = amount.parse().ok()?;
+    let unit_bytes = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" | "KIB" => 1024,
+        "MB" | "MIB" => 1024 * 1024,
+        _ => return None,
+    };
+
+    amount.checked_mul(unit_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> ignore this please"),
+            Some(Command::Ignore)
+        );
+        assert_eq!(Command::parse("<@1234> forget"), Some(Command::Forget));
+        assert_eq!(Command::parse("<@!1234> diag"), Some(Command::Diagnostics));
+        assert_eq!(Command::parse("<@1234> failures"), Some(Command::Failures));
+        assert_eq!(Command::parse("<@1234> guilds"), Some(Command::Guilds));
+        assert_eq!(Command::parse("<@1234> uptime"), Some(Command::Uptime));
+        assert_eq!(
+            Command::parse("<@1234> leaderboard"),
+            Some(Command::Leaderboard)
+        );
+        assert_eq!(Command::parse("<@1234> stats"), Some(Command::Stats));
+        assert_eq!(Command::parse("<@1234> reload"), Some(Command::Reload));
+        assert_eq!(
+            Command::parse("<@1234> disable"),
+            Some(Command::SetEnabled(false))
+        );
+        assert_eq!(
+            Command::parse("<@1234> enable"),
+            Some(Command::SetEnabled(true))
+        );
+        assert_eq!(
+            Command::parse("<@1234> dbversion"),
+            Some(Command::DatabaseVersion)
+        );
+        assert_eq!(Command::parse("<@1234> hello there"), None);
+        assert_eq!(Command::parse("ignored"), None);
+    }
+
+    #[test]
+    fn raid_mode_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> raid-mode on 1h"),
+            Some(Command::RaidMode(Some(Duration::from_secs(3600))))
+        );
+        assert_eq!(
+            Command::parse("<@1234> raid-mode on"),
+            Some(Command::RaidMode(Some(Command::DEFAULT_RAID_DURATION)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> raid-mode off"),
+            Some(Command::RaidMode(None))
+        );
+        assert_eq!(Command::parse("<@1234> raid-mode on soon"), None);
+        assert_eq!(Command::parse("<@1234> raid-mode maybe"), None);
+    }
+
+    #[test]
+    fn histogram_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> histogram"),
+            Some(Command::Histogram(Command::DEFAULT_HISTOGRAM_BUCKETS))
+        );
+        assert_eq!(
+            Command::parse("<@1234> histogram 16"),
+            Some(Command::Histogram(16))
+        );
+        assert_eq!(Command::parse("<@1234> histogram 0"), None);
+        assert_eq!(Command::parse("<@1234> histogram 1000"), None);
+    }
+
+    #[test]
+    fn cross_channel_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> cross-channel off"),
+            Some(Command::CrossChannelReplies(Some(false)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> cross-channel default"),
+            Some(Command::CrossChannelReplies(None))
+        );
+        assert_eq!(Command::parse("<@1234> cross-channel maybe"), None);
+    }
+
+    #[test]
+    fn mod_role_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> mod-role <@&5678>"),
+            Some(Command::ModRole(Some(5678)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> mod-role 5678"),
+            Some(Command::ModRole(Some(5678)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> mod-role clear"),
+            Some(Command::ModRole(None))
+        );
+        assert_eq!(Command::parse("<@1234> mod-role <#5678>"), None);
+        assert_eq!(Command::parse("<@1234> mod-role"), None);
+    }
+
+    #[test]
+    fn jump_link_parsing() {
+        let expected = Some(JumpLink {
+            guild_id: 1,
+            channel_id: 2,
+            message_id: 3,
+        });
+
+        for link in [
+            "https://discord.com/channels/1/2/3",
+            "https://discordapp.com/channels/1/2/3",
+            "https://canary.discord.com/channels/1/2/3",
+            "https://ptb.discord.com/channels/1/2/3",
+            "https://canary.discordapp.com/channels/1/2/3",
+            "https://ptb.discordapp.com/channels/1/2/3",
+            "http://discord.com/channels/1/2/3",
+            "discord.com/channels/1/2/3",
+            "<https://discord.com/channels/1/2/3>",
+        ] {
+            assert_eq!(parse_jump_link(link), expected, "{} wasn't parsed", link);
+        }
+
+        for link in [
+            "https://discord.com/channels/1/2",
+            "https://discord.com/channels/1/2/3/4",
+            "https://discord.com/channels/@me/2/3",
+            "https://discord.com/channels/1/2/+3",
+            "https://discord.com/guilds/1/2/3",
+            "https://notdiscord.com/channels/1/2/3",
+            "https://discord.com.evil.example/channels/1/2/3",
+            "https://beta.discord.com/channels/1/2/3",
+            "https://example.com/discord.com/channels/1/2/3",
+        ] {
+            assert_eq!(parse_jump_link(link), None, "{} was wrongly parsed", link);
+        }
+    }
+
+    #[test]
+    fn verify_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> verify https://discord.com/channels/1/2/3"),
+            Some(Command::Verify(JumpLink {
+                guild_id: 1,
+                channel_id: 2,
+                message_id: 3,
+            }))
+        );
+        assert_eq!(
+            Command::parse("<@1234> verify <https://discordapp.com/channels/1/2/3>"),
+            Some(Command::Verify(JumpLink {
+                guild_id: 1,
+                channel_id: 2,
+                message_id: 3,
+            }))
+        );
+        assert_eq!(
+            Command::parse("<@1234> verify https://discord.com/channels/1/2"),
+            None
+        );
+        assert_eq!(
+            Command::parse("<@1234> verify https://example.com/1/2/3"),
+            None
+        );
+    }
+
+    #[test]
+    fn resend_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> resend https://discord.com/channels/1/2/3"),
+            Some(Command::Resend(JumpLink {
+                guild_id: 1,
+                channel_id: 2,
+                message_id: 3,
+            }))
+        );
+        assert_eq!(Command::parse("<@1234> resend"), None);
+        assert_eq!(Command::parse("<@1234> resend 3"), None);
+    }
+
+    #[test]
+    fn history_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> history https://discord.com/channels/1/2/3"),
+            Some(Command::History(Some(JumpLink {
+                guild_id: 1,
+                channel_id: 2,
+                message_id: 3,
+            })))
+        );
+        assert_eq!(
+            Command::parse("<@1234> history"),
+            Some(Command::History(None))
+        );
+        assert_eq!(Command::History(None).privilege(), Privilege::Anyone);
+    }
+
+    #[test]
+    fn purge_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> purge 30d"),
+            Some(Command::Purge {
+                older_than: Duration::from_secs(30 * 24 * 60 * 60),
+                channel_id: None,
+                author_id: None,
+            })
+        );
+        assert_eq!(
+            Command::parse("<@1234> purge 1h <@!55> <#66> please"),
+            Some(Command::Purge {
+                older_than: Duration::from_secs(60 * 60),
+                channel_id: Some(66),
+                author_id: Some(55),
+            })
+        );
+        // Role mentions aren't posters.
+        assert_eq!(
+            Command::parse("<@1234> purge 1h <@&55>"),
+            Some(Command::Purge {
+                older_than: Duration::from_secs(60 * 60),
+                channel_id: None,
+                author_id: None,
+            })
+        );
+        assert_eq!(Command::parse("<@1234> purge"), None);
+        assert_eq!(Command::parse("<@1234> purge soon"), None);
+    }
+
+    #[test]
+    fn remap_channel_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> remap-channel <#111> 222"),
+            Some(Command::RemapChannel { old: 111, new: 222 })
+        );
+        assert_eq!(Command::parse("<@1234> remap-channel <#111>"), None);
+        assert_eq!(Command::parse("<@1234> remap-channel <@111> <#222>"), None);
+    }
+
+    #[test]
+    fn channel_list_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> allow-channels <#222> 111 <#222> please"),
+            Some(Command::AllowedChannels(vec![111, 222]))
+        );
+        assert_eq!(
+            Command::parse("<@1234> allow-channels all"),
+            Some(Command::AllowedChannels(Vec::new()))
+        );
+        assert_eq!(
+            Command::parse("<@1234> deny-channels <#333>"),
+            Some(Command::DeniedChannels(vec![333]))
+        );
+        assert_eq!(
+            Command::parse("<@1234> deny-channels none"),
+            Some(Command::DeniedChannels(Vec::new()))
+        );
+        assert_eq!(Command::parse("<@1234> allow-channels"), None);
+        assert_eq!(Command::parse("<@1234> deny-channels memes"), None);
+
+        assert_eq!(
+            Command::parse("<@1234> skip-nsfw on"),
+            Some(Command::SkipNsfw(Some(true)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> skip-nsfw default"),
+            Some(Command::SkipNsfw(None))
+        );
+        assert_eq!(Command::parse("<@1234> skip-nsfw"), None);
+
+        assert_eq!(
+            Command::parse("<@1234> own-reposts ignore"),
+            Some(Command::IgnoreOwnReposts(Some(true)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> own-reposts callout"),
+            Some(Command::IgnoreOwnReposts(Some(false)))
+        );
+        assert_eq!(Command::parse("<@1234> own-reposts yes"), None);
+    }
+
+    #[test]
+    fn test_url_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> test-url https://example.com/cat.png"),
+            Some(Command::TestUrl("https://example.com/cat.png".to_string()))
+        );
+        assert_eq!(
+            Command::parse("<@1234> test-url <http://example.com/cat.png>"),
+            Some(Command::TestUrl("http://example.com/cat.png".to_string()))
+        );
+        assert_eq!(Command::parse("<@1234> test-url"), None);
+        assert_eq!(Command::parse("<@1234> test-url example.com/cat.png"), None);
+        assert_eq!(
+            Command::parse("<@1234> test-url ftp://example.com/cat.png"),
+            None
+        );
+        assert_eq!(Command::parse("<@1234> test-url https://"), None);
+        assert_eq!(
+            Command::TestUrl(String::new()).privilege(),
+            Privilege::Owner
+        );
+    }
+
+    #[test]
+    fn size_parsing() {
+        assert_eq!(
+            Command::parse("<@1234> max-image-size 20MB"),
+            Some(Command::MaxImageSize(Some(20 * 1024 * 1024)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> max-image-size default"),
+            Some(Command::MaxImageSize(None))
+        );
+
+        assert_eq!(
+            Command::parse("<@1234> record-quota 5000"),
+            Some(Command::RecordQuota(Some(5000)))
+        );
+        assert_eq!(
+            Command::parse("<@1234> record-quota default"),
+            Some(Command::RecordQuota(None))
+        );
+        assert_eq!(Command::parse("<@1234> record-quota lots"), None);
+
+        assert_eq!(parse_size("512kb"), Some(512 * 1024));
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("3GB"), None);
+        assert_eq!(parse_size("MB"), None);
+    }
+
+    #[test]
+    fn duration_parsing() {
+        assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2d"), Some(Duration::from_secs(172800)));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("5w"), None);
+    }
+}
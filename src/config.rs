@@ -0,0 +1,1109 @@
+use std::{cell::RefCell, collections::HashMap, fmt::Debug, net::SocketAddr, str::FromStr};
+
+use image::ImageFormat;
+use toml::{value::Table, Value};
+
+/// The config file read if `CONFIG_FILE` isn't set.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Runtime settings for the bot, read from the environment (or `.env`).
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub detection: DetectionConfig,
+    pub download: DownloadConfig,
+    pub reply: ReplyConfig,
+    pub storage: StorageConfig,
+    /// If seen counts are checked against recorded occurrences, and fixed, when the bot starts.
+    pub repair_counts_on_startup: bool,
+    /// Key database exports are signed and verified with, instead of only being checksummed.
+    pub export_signing_key: Option<String>,
+    /// How many images can be hashed at the same time, and how many threads build the hash index at startup.
+    pub hashing_threads: usize,
+    /// How many recent messages are kept around for finding what replies point at.
+    pub message_cache_size: usize,
+    /// Token the bot logs in to Discord with.
+    pub discord_token: Option<String>,
+    /// Where to answer health checks on `/healthz`, if anywhere.
+    pub health_check_address: Option<SocketAddr>,
+    /// How log lines are written.
+    pub log_format: LogFormat,
+    /// Settings that still work but should be changed, to log once logging is set up.
+    pub warnings: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            detection: DetectionConfig::default(),
+            download: DownloadConfig::default(),
+            reply: ReplyConfig::default(),
+            storage: StorageConfig::default(),
+            repair_counts_on_startup: false,
+            export_signing_key: None,
+            hashing_threads: std::thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            message_cache_size: 1000,
+            discord_token: None,
+            health_check_address: None,
+            log_format: LogFormat::default(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings from the environment (or `.env`) and the config file, with the environment winning.
+    ///
+    /// The file is `config.toml`, unless `CONFIG_FILE` names another one. The default file doesn't have to exist.
+    pub fn load() -> Self {
+        Self::try_load().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like [Config::load], but says what's wrong instead of panicking, for reloading while the bot runs.
+    pub fn try_load() -> Result<Self, String> {
+        let (path, required) = match std::env::var("CONFIG_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_FILE.to_string(), false),
+        };
+
+        let file = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| format!("config file {} isn't valid: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => Table::new(),
+            Err(e) => return Err(format!("couldn't read config file {}: {}", path, e)),
+        };
+
+        Self::from_sources(file)
+    }
+
+    /// Reads each setting from its environment variable, or from `file` if that isn't set.
+    fn from_sources(mut file: Table) -> Result<Self, String> {
+        let defaults = Self::default();
+        let discord = Section::new("discord", &mut file)?;
+        let storage = Section::new("storage", &mut file)?;
+        let detection = Section::new("detection", &mut file)?;
+        let download = Section::new("download", &mut file)?;
+        let replies = Section::new("replies", &mut file)?;
+        let health = Section::new("health", &mut file)?;
+        let logging = Section::new("logging", &mut file)?;
+
+        if let Some(name) = file.keys().next() {
+            return Err(format!("unknown section [{}] in the config file", name));
+        }
+
+        let mut warnings = Vec::new();
+        if replies.is_set_to("CONFIRMATIONS", "reactions") {
+            warnings.push(
+                "CONFIRMATIONS=\"reactions\" is deprecated and works like \"buttons\", so set that instead"
+                    .to_string(),
+            );
+        }
+
+        let mut config = Self {
+            detection: DetectionConfig {
+                animation_matching: detection
+                    .var("ANIMATION_MATCHING", defaults.detection.animation_matching),
+                similar_inherits_ignored: detection.var(
+                    "SIMILAR_INHERITS_IGNORED",
+                    defaults.detection.similar_inherits_ignored,
+                ),
+                similarity_threshold: detection.var(
+                    "SIMILARITY_THRESHOLD",
+                    defaults.detection.similarity_threshold,
+                ),
+                raid_similarity_threshold: detection.var(
+                    "RAID_SIMILARITY_THRESHOLD",
+                    defaults.detection.raid_similarity_threshold,
+                ),
+                alpha_background: detection
+                    .var("ALPHA_BACKGROUND", defaults.detection.alpha_background),
+                min_dimension: detection
+                    .var("MIN_IMAGE_DIMENSION", defaults.detection.min_dimension),
+                small_images: detection.var("SMALL_IMAGES", defaults.detection.small_images),
+                match_rotations: detection
+                    .var("MATCH_ROTATIONS", defaults.detection.match_rotations),
+                format_thresholds: detection.var("FORMAT_THRESHOLDS", FormatThresholds::default()),
+                min_color_variance: detection.optional_var("MIN_COLOR_VARIANCE"),
+                min_confidence: detection.optional_var("MIN_CONFIDENCE"),
+                dedupe_within_message: detection.var(
+                    "DEDUPE_WITHIN_MESSAGE",
+                    defaults.detection.dedupe_within_message,
+                ),
+                confirm_grace: detection.var("CONFIRM_GRACE", defaults.detection.confirm_grace),
+                all_images: detection.var("ALL_IMAGES", defaults.detection.all_images),
+                count_cooldown: detection.var("COUNT_COOLDOWN", defaults.detection.count_cooldown),
+                known_image_updates: detection.var(
+                    "KNOWN_IMAGE_UPDATES",
+                    defaults.detection.known_image_updates,
+                ),
+                crossposts: detection.var("CROSSPOSTS", defaults.detection.crossposts),
+                video_thumbnails: detection
+                    .var("VIDEO_THUMBNAILS", defaults.detection.video_thumbnails),
+                ffmpeg_path: detection.optional_var("FFMPEG_PATH"),
+                gif_links: detection.var("GIF_LINKS", defaults.detection.gif_links),
+                reddit_links: detection.var("REDDIT_LINKS", defaults.detection.reddit_links),
+                link_reposts: detection.var("LINK_REPOSTS", defaults.detection.link_reposts),
+                skip_nsfw_channels: detection
+                    .var("SKIP_NSFW_CHANNELS", defaults.detection.skip_nsfw_channels),
+                repost_window: detection.optional_var("REPOST_WINDOW"),
+            },
+            download: DownloadConfig {
+                max_image_size: download.var("MAX_IMAGE_SIZE", defaults.download.max_image_size),
+                max_image_size_limit: download.var(
+                    "MAX_IMAGE_SIZE_LIMIT",
+                    defaults.download.max_image_size_limit,
+                ),
+                supported_extensions: download.var(
+                    "SUPPORTED_EXTENSIONS",
+                    defaults.download.supported_extensions,
+                ),
+                denied_extensions: download
+                    .var("DENIED_EXTENSIONS", defaults.download.denied_extensions),
+                timeout: download.var("DOWNLOAD_TIMEOUT", defaults.download.timeout),
+                attempts: download.var("DOWNLOAD_ATTEMPTS", defaults.download.attempts),
+            },
+            reply: ReplyConfig {
+                thread_reposts: replies.var("THREAD_REPOSTS", defaults.reply.thread_reposts),
+                count_style: replies.var("COUNT_STYLE", defaults.reply.count_style),
+                confirmations: replies.var("CONFIRMATIONS", defaults.reply.confirmations),
+                first_offense: replies.var("FIRST_OFFENSE", defaults.reply.first_offense),
+                cross_channel_replies: replies.var(
+                    "CROSS_CHANNEL_REPLIES",
+                    defaults.reply.cross_channel_replies,
+                ),
+                ignore_own_reposts: replies
+                    .var("IGNORE_OWN_REPOSTS", defaults.reply.ignore_own_reposts),
+                max_embed_fields: replies.var("MAX_EMBED_FIELDS", defaults.reply.max_embed_fields),
+                link_buttons: replies.var("LINK_BUTTONS", defaults.reply.link_buttons),
+                mute_forbidden_channels: replies.var(
+                    "MUTE_FORBIDDEN_CHANNELS",
+                    defaults.reply.mute_forbidden_channels,
+                ),
+                quick_delete_window: replies
+                    .var("QUICK_DELETE_WINDOW", defaults.reply.quick_delete_window),
+                startup_settle: replies.var("STARTUP_SETTLE", defaults.reply.startup_settle),
+                // Setting it to nothing skips the welcome.
+                welcome_message: match replies.optional_var::<String>("WELCOME_MESSAGE") {
+                    Some(message) if message.trim().is_empty() => None,
+                    Some(message) => Some(message),
+                    None => defaults.reply.welcome_message,
+                },
+                dry_run: replies.var("DRY_RUN", defaults.reply.dry_run),
+                audit_channel: replies.optional_var("AUDIT_CHANNEL"),
+            },
+            storage: StorageConfig {
+                path: storage.var("DATABASE_PATH", defaults.storage.path),
+                cache_capacity: storage.var("DB_CACHE_CAPACITY", defaults.storage.cache_capacity),
+                flush_every_ms: storage.var("DB_FLUSH_EVERY_MS", defaults.storage.flush_every_ms),
+                count_batch_ms: storage.var("DB_COUNT_BATCH_MS", defaults.storage.count_batch_ms),
+                purge_removed_guilds_after: storage.optional_var("PURGE_REMOVED_GUILDS_AFTER"),
+                image_retention: storage.optional_var("IMAGE_RETENTION"),
+                guild_record_quota: storage.optional_var("GUILD_RECORD_QUOTA"),
+                guild_record_quota_limit: storage.optional_var("GUILD_RECORD_QUOTA_LIMIT"),
+                quota_policy: storage.var("GUILD_QUOTA_POLICY", defaults.storage.quota_policy),
+            },
+            repair_counts_on_startup: storage.var(
+                "REPAIR_COUNTS_ON_STARTUP",
+                defaults.repair_counts_on_startup,
+            ),
+            export_signing_key: storage.optional_var("EXPORT_SIGNING_KEY"),
+            hashing_threads: detection.var("HASHING_THREADS", defaults.hashing_threads),
+            message_cache_size: discord.var("MESSAGE_CACHE_SIZE", defaults.message_cache_size),
+            discord_token: discord.optional_var("DISCORD_TOKEN"),
+            health_check_address: health.optional_var("HEALTH_CHECK_ADDRESS"),
+            log_format: logging.var("LOG_FORMAT", defaults.log_format),
+            warnings,
+        };
+
+        if config.detection.ffmpeg_path.is_none() {
+            let videos = crate::image_processing::VIDEO_EXTENSIONS
+                .iter()
+                .filter(|extension| config.download.allows_extension(extension))
+                .copied()
+                .collect::<Vec<_>>();
+
+            if !videos.is_empty() {
+                config.warnings.push(format!(
+                    "SUPPORTED_EXTENSIONS has {}, but videos can't be decoded without FFMPEG_PATH",
+                    videos.join(", ")
+                ));
+            }
+        }
+
+        for section in [
+            discord, storage, detection, download, replies, health, logging,
+        ] {
+            section.finish()?;
+        }
+
+        Ok(config)
+    }
+
+    /// Takes the settings that can change while the bot runs from `loaded`, keeping the rest.
+    ///
+    /// Storage, the Discord connection, and hashing threads are only set up at startup.
+    pub fn reloaded(&self, loaded: Config) -> Self {
+        Self {
+            detection: loaded.detection,
+            download: loaded.download,
+            reply: loaded.reply,
+            ..self.clone()
+        }
+    }
+}
+
+/// Settings for how images are hashed and compared.
+#[derive(Debug, Clone)]
+pub struct DetectionConfig {
+    pub animation_matching: AnimationMatching,
+    /// If an image that's similar to an ignored one is also ignored.
+    ///
+    /// When enabled, the new image's hash is aliased to the ignored record. Otherwise
+    /// it's recorded on its own and can be called out as a repost later.
+    pub similar_inherits_ignored: bool,
+    /// Maximum hash distance for two images to be considered the same.
+    pub similarity_threshold: u32,
+    /// The more aggressive similarity threshold used while a guild is in raid mode.
+    pub raid_similarity_threshold: u32,
+    /// Thresholds used instead of the usual one when matching images stored in certain formats.
+    pub format_thresholds: FormatThresholds,
+    pub alpha_background: AlphaBackground,
+    /// Images narrower or shorter than this many pixels are considered small.
+    pub min_dimension: u32,
+    pub small_images: SmallImages,
+    /// Images with less color variance than this are skipped, since nearly solid images hash alike.
+    ///
+    /// It's the average variance of the red, green, and blue channels, from 0 to about 16000.
+    pub min_color_variance: Option<f32>,
+    /// If images are also matched when rotated by 90, 180, or 270 degrees.
+    ///
+    /// This makes comparing each image four times as expensive.
+    pub match_rotations: bool,
+    /// How confident a match has to be, from 0 to 1, when combining detection signals.
+    ///
+    /// If this isn't set, images only need to be within the similarity threshold.
+    pub min_confidence: Option<f32>,
+    /// If identical images in the same message are recorded once.
+    ///
+    /// Otherwise, the second copy is called out as a repost of the first.
+    pub dedupe_within_message: bool,
+    /// How far past the similarity threshold an image can be and still be asked about.
+    ///
+    /// Images this close to a stored one are recorded as new, and someone is asked if they're a repost.
+    /// Zero turns this off.
+    pub confirm_grace: u32,
+    pub known_image_updates: KnownImageUpdates,
+    pub crossposts: Crossposts,
+    /// If videos are checked using the thumbnail Discord generates of their first frame.
+    ///
+    /// Videos that are downloaded themselves, when their extension is supported, are checked by their
+    /// keyframes instead.
+    pub video_thumbnails: bool,
+    /// The ffmpeg program videos are decoded with, if they're decoded.
+    ///
+    /// Without it, only images are decoded, and videos can only be checked by their thumbnails.
+    pub ffmpeg_path: Option<String>,
+    /// If Tenor and Giphy links are checked using the GIF on their page.
+    ///
+    /// Links Discord already shows an image for are checked by that instead.
+    pub gif_links: bool,
+    /// If links to Reddit posts are checked using every file in the post, which is looked up on Reddit.
+    ///
+    /// They replace the single preview Discord shows of a post.
+    pub reddit_links: bool,
+    /// If links to anything other than images are tracked too, and called out when they're shared again.
+    ///
+    /// Links are compared after dropping tracking parameters and other differences that don't change where they go.
+    pub link_reposts: bool,
+    /// If every image in a message is checked, from its embeds, attachments and stickers,
+    /// rather than just the first one found.
+    ///
+    /// They're hashed at the same time, as far as the hashing threads allow.
+    pub all_images: bool,
+    /// Seconds after an image was last counted before reposts of it count again.
+    ///
+    /// Reposts inside the cooldown are still called out. Zero counts every repost.
+    pub count_cooldown: u64,
+    /// If images in channels marked NSFW are left alone, unless a guild changes it.
+    pub skip_nsfw_channels: bool,
+    /// Seconds after an image was first posted before posting it again isn't a repost anymore, if ever.
+    ///
+    /// Once it's past, the next sighting is recorded as the image's first one instead of being called out.
+    pub repost_window: Option<u64>,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self {
+            animation_matching: AnimationMatching::default(),
+            similar_inherits_ignored: true,
+            similarity_threshold: 8,
+            raid_similarity_threshold: 12,
+            format_thresholds: FormatThresholds::default(),
+            alpha_background: AlphaBackground::default(),
+            min_dimension: 0,
+            small_images: SmallImages::default(),
+            min_color_variance: None,
+            match_rotations: false,
+            min_confidence: None,
+            confirm_grace: 0,
+            known_image_updates: KnownImageUpdates::default(),
+            crossposts: Crossposts::default(),
+            video_thumbnails: false,
+            ffmpeg_path: None,
+            gif_links: true,
+            reddit_links: true,
+            link_reposts: false,
+            all_images: true,
+            count_cooldown: 0,
+            skip_nsfw_channels: false,
+            repost_window: None,
+            dedupe_within_message: true,
+        }
+    }
+}
+
+/// Settings for fetching images.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Largest image, in bytes, that will be downloaded unless a guild changes it.
+    pub max_image_size: u64,
+    /// Hard limit on the image size any guild can allow, in bytes.
+    pub max_image_size_limit: u64,
+    /// File extensions of images that are downloaded and checked.
+    pub supported_extensions: Extensions,
+    /// File extensions that are never checked, even if they're supported.
+    pub denied_extensions: Extensions,
+    /// Seconds an attempt at downloading an image can take before it's given up on.
+    pub timeout: u64,
+    /// How many times downloading an image is tried, if it keeps failing or timing out.
+    pub attempts: u32,
+}
+
+impl DownloadConfig {
+    /// If images with this file extension should be checked.
+    pub fn allows_extension(&self, extension: &str) -> bool {
+        self.supported_extensions.contains(extension) && !self.denied_extensions.contains(extension)
+    }
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_image_size: 8 * 1024 * 1024,
+            max_image_size_limit: 50 * 1024 * 1024,
+            supported_extensions: Extensions::new(&["png", "jpg", "jpeg", "gif", "webp"]),
+            denied_extensions: Extensions::default(),
+            timeout: 30,
+            attempts: 3,
+        }
+    }
+}
+
+/// A list of file extensions, written like `png,jpg,gif`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extensions(Vec<String>);
+
+impl Extensions {
+    pub fn new(extensions: &[&str]) -> Self {
+        Self(
+            extensions
+                .iter()
+                .map(|ext| ext.to_ascii_lowercase())
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, extension: &str) -> bool {
+        self.0.iter().any(|ext| ext.eq_ignore_ascii_case(extension))
+    }
+}
+
+impl FromStr for Extensions {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let extensions: Vec<&str> = s
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.'))
+            .filter(|ext| !ext.is_empty())
+            .collect();
+
+        Ok(Self::new(&extensions))
+    }
+}
+
+/// Settings for the database's memory use and durability.
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    /// Where the database is kept.
+    pub path: String,
+    /// How much of the database, in bytes, is kept cached in memory.
+    pub cache_capacity: u64,
+    /// How often writes are flushed to disk, in milliseconds. Zero turns off periodic flushing.
+    pub flush_every_ms: u64,
+    /// How long repost count increments are held in memory before being written, in milliseconds.
+    ///
+    /// Zero writes every increment right away. Increments still held when the bot is killed are lost.
+    pub count_batch_ms: u64,
+    /// Seconds after the bot is removed from a guild before that guild's images and settings are deleted.
+    ///
+    /// If this isn't set, they're kept forever.
+    pub purge_removed_guilds_after: Option<u64>,
+    /// Seconds an image can go without being seen before it's deleted, so old images stop being called out.
+    ///
+    /// If this isn't set, they're kept forever.
+    pub image_retention: Option<u64>,
+    /// Most images any guild can have stored, unless it changes its own quota.
+    pub guild_record_quota: Option<u64>,
+    /// Hard limit on the images any guild can have stored, even if it asks for more.
+    pub guild_record_quota_limit: Option<u64>,
+    pub quota_policy: QuotaPolicy,
+}
+
+impl StorageConfig {
+    /// Checks the settings make sense, returning what's wrong if they don't.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.cache_capacity == 0 {
+            return Err("the database cache capacity can't be zero");
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        // The same as sled's own defaults.
+        Self {
+            path: "./storage".to_string(),
+            cache_capacity: 1024 * 1024 * 1024,
+            flush_every_ms: 500,
+            count_batch_ms: 0,
+            purge_removed_guilds_after: None,
+            image_retention: None,
+            guild_record_quota: None,
+            guild_record_quota_limit: None,
+            quota_policy: QuotaPolicy::default(),
+        }
+    }
+}
+
+const DEFAULT_WELCOME_MESSAGE: &str = "Hi! I call out images that were already posted here. \
+Reply to an image or my callout with a mention and `ignore` to stop calling it out. \
+Moderators can mention me with `raid-mode`, `cross-channel`, `max-image-size`, or `mod-role` to set me up.";
+
+/// Settings for how reposts are called out.
+#[derive(Debug, Clone)]
+pub struct ReplyConfig {
+    pub thread_reposts: ThreadReposts,
+    pub count_style: CountStyle,
+    pub confirmations: Confirmations,
+    pub first_offense: FirstOffense,
+    /// If reposts of images first posted in another channel are called out, unless a guild changes it.
+    pub cross_channel_replies: bool,
+    /// If people reposting their own images are left alone, unless a guild changes it. They're still counted.
+    pub ignore_own_reposts: bool,
+    /// Most fields in each embed of a long list, which Discord caps at 25.
+    pub max_embed_fields: usize,
+    /// If callouts for reposts from other channels link to the original with a button, instead of an embed.
+    pub link_buttons: bool,
+    /// If the bot stops replying in a channel after finding out it can't send messages there, until it restarts.
+    pub mute_forbidden_channels: bool,
+    /// What the bot posts in a guild's system channel when it joins, if anything.
+    pub welcome_message: Option<String>,
+    /// Seconds after a repost is counted during which deleting it takes back the count and the bot's reply.
+    ///
+    /// Zero turns this off.
+    pub quick_delete_window: u64,
+    /// Seconds to wait after every shard is ready, and guilds stop streaming in, before replying to reposts.
+    ///
+    /// Reposts are still counted meanwhile. Zero replies right away.
+    pub startup_settle: u64,
+    /// If reposts are recorded as usual, but what the bot would have replied or deleted is only reported.
+    ///
+    /// Commands are still answered.
+    pub dry_run: bool,
+    /// Channel dry run reports are posted in, besides the log.
+    pub audit_channel: Option<u64>,
+}
+
+impl Default for ReplyConfig {
+    fn default() -> Self {
+        Self {
+            thread_reposts: ThreadReposts::default(),
+            count_style: CountStyle::default(),
+            confirmations: Confirmations::default(),
+            first_offense: FirstOffense::default(),
+            cross_channel_replies: true,
+            ignore_own_reposts: false,
+            max_embed_fields: 25,
+            link_buttons: false,
+            mute_forbidden_channels: true,
+            quick_delete_window: 0,
+            startup_settle: 0,
+            welcome_message: Some(DEFAULT_WELCOME_MESSAGE.to_string()),
+            dry_run: false,
+            audit_channel: None,
+        }
+    }
+}
+
+/// How someone's very first repost in a guild is handled. Every one after that is called out as usual.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FirstOffense {
+    /// Call it out like any other.
+    #[default]
+    Callout,
+    /// Send the poster a private heads-up instead.
+    HeadsUp,
+    /// Let it slide without a reply.
+    Quiet,
+}
+
+impl FromStr for FirstOffense {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "callout" => Ok(Self::Callout),
+            "heads_up" => Ok(Self::HeadsUp),
+            "quiet" => Ok(Self::Quiet),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Similarity thresholds for images stored in particular formats, like `jpeg=10,png=6`.
+///
+/// Lossy formats pick up more differences each time they're recompressed, so they can need looser thresholds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormatThresholds(HashMap<ImageFormat, u32>);
+
+impl FormatThresholds {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The loosest threshold of any format, or zero if there aren't any.
+    pub fn largest(&self) -> u32 {
+        self.0.values().copied().max().unwrap_or(0)
+    }
+
+    /// The threshold for an image stored in `format`, or `default` if it doesn't have its own.
+    pub fn threshold_for(&self, format: Option<ImageFormat>, default: u32) -> u32 {
+        format
+            .and_then(|format| self.0.get(&format).copied())
+            .unwrap_or(default)
+    }
+}
+
+impl FromStr for FormatThresholds {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (format, threshold) = pair.split_once('=').ok_or(())?;
+                let format = ImageFormat::from_extension(format.trim()).ok_or(())?;
+                let threshold = threshold.trim().parse().map_err(|_| ())?;
+
+                Ok((format, threshold))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// What happens when an image is reposted in a thread started from the message it was first posted in.
+///
+/// That's usually someone discussing the original, rather than a repost.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ThreadReposts {
+    /// Call it out like any other repost.
+    Normal,
+    /// Mention it without the usual callout.
+    #[default]
+    Soft,
+    /// Don't call it out at all.
+    Skip,
+}
+
+impl FromStr for ThreadReposts {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "soft" => Ok(Self::Soft),
+            "skip" => Ok(Self::Skip),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the number of times an image was seen is described in a callout.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CountStyle {
+    /// Every time it was posted, including the repost being called out.
+    Total,
+    /// Only the times it was posted before, so the first repost was seen "once before".
+    #[default]
+    Previous,
+}
+
+impl FromStr for CountStyle {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "total" => Ok(Self::Total),
+            "previous" => Ok(Self::Previous),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the bot asks people to confirm an action.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Confirmations {
+    /// Press a Yes or No button under the question.
+    #[default]
+    Buttons,
+    /// Always reply with yes or no.
+    Text,
+}
+
+impl FromStr for Confirmations {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // Reactions were replaced by buttons, and old configs are warned about in `Config::from_sources`.
+            "buttons" | "reactions" => Ok(Self::Buttons),
+            "text" => Ok(Self::Text),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How animated images are handled relative to static ones.
+///
+/// A still thumbnail and the full animation it came from may or may not match
+/// under blockhash, depending on what the animation starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AnimationMatching {
+    /// Like [AnimationMatching::FirstFrame], but a few more frames spread across the animation are
+    /// hashed, matched, and stored too. A copy that was cut or re-encoded to start on another frame
+    /// is still caught.
+    #[default]
+    Frames,
+    /// Animations are hashed by their first frame, so they're compared
+    /// against stored static images and a still --> animated repost is caught.
+    FirstFrame,
+    /// Animated images aren't tracked at all.
+    Skip,
+}
+
+impl FromStr for AnimationMatching {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frames" => Ok(Self::Frames),
+            "first_frame" => Ok(Self::FirstFrame),
+            "skip" => Ok(Self::Skip),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What happens to a stored image's details when it's posted again.
+///
+/// Who first posted an image, when, and if it's ignored never change.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum KnownImageUpdates {
+    /// Keep pointing at where the image was first posted.
+    #[default]
+    Preserve,
+    /// Point at where the image was posted most recently, so the next callout replies to the latest copy.
+    LatestLocation,
+}
+
+impl FromStr for KnownImageUpdates {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(Self::Preserve),
+            "latest_location" => Ok(Self::LatestLocation),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What happens to a new image from a guild that already has as many stored as its quota allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum QuotaPolicy {
+    /// Delete the guild's image that was seen least recently to make room.
+    #[default]
+    EvictOldest,
+    /// Don't store the new image.
+    Refuse,
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "evict_oldest" => Ok(Self::EvictOldest),
+            "refuse" => Ok(Self::Refuse),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    /// Readable lines, for a terminal.
+    #[default]
+    Text,
+    /// A JSON object per line, with fields like `guild_id` and `image_hash` kept separate for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What happens to images crossposted from an announcement channel that a channel follows.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Crossposts {
+    /// Crossposts aren't checked, since they were posted in another server.
+    #[default]
+    Ignore,
+    /// Crossposts are checked like any other message, but each announcement only once.
+    Track,
+}
+
+impl FromStr for Crossposts {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "track" => Ok(Self::Track),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How images smaller than the minimum dimension are handled.
+///
+/// Small images are often emotes or thumbnails, which aren't worth tracking on their own
+/// but can still be a shrunk down copy of something that is.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SmallImages {
+    /// Small images aren't tracked at all.
+    #[default]
+    Skip,
+    /// Small images are checked against what's already stored, but never stored themselves.
+    MatchOnly,
+}
+
+impl FromStr for SmallImages {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "match_only" => Ok(Self::MatchOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What transparent images are composited over before hashing.
+///
+/// Blockhash doesn't consider transparency consistently, so flattening images first
+/// makes a sticker with and without its background hash the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AlphaBackground {
+    /// Hash images as they're decoded.
+    #[default]
+    None,
+    White,
+    Black,
+}
+
+impl AlphaBackground {
+    /// The color to composite over, if any.
+    pub const fn color(self) -> Option<[u8; 3]> {
+        match self {
+            Self::None => None,
+            Self::White => Some([255, 255, 255]),
+            Self::Black => Some([0, 0, 0]),
+        }
+    }
+}
+
+impl FromStr for AlphaBackground {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "white" => Ok(Self::White),
+            "black" => Ok(Self::Black),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A section of the config file, like `[detection]`.
+///
+/// Settings in it are named like their environment variables, in lowercase, and the environment variable wins
+/// if both are set.
+struct Section {
+    name: &'static str,
+    /// Settings that haven't been read yet.
+    table: RefCell<Table>,
+    /// The first setting that couldn't be parsed, if any.
+    error: RefCell<Option<String>>,
+}
+
+impl Section {
+    fn new(name: &'static str, file: &mut Table) -> Result<Self, String> {
+        let table = match file.remove(name) {
+            Some(Value::Table(table)) => table,
+            Some(_) => return Err(format!("[{}] in the config file isn't a section", name)),
+            None => Table::new(),
+        };
+
+        Ok(Self {
+            name,
+            table: RefCell::new(table),
+            error: RefCell::new(None),
+        })
+    }
+
+    /// Reads a setting, falling back to `default` if neither the environment nor the file has it.
+    fn var<T: FromStr>(&self, key: &str, default: T) -> T
+    where
+        T::Err: Debug,
+    {
+        self.optional_var(key).unwrap_or(default)
+    }
+
+    /// If a setting is written as `value`, without reading it.
+    fn is_set_to(&self, key: &str, value: &str) -> bool {
+        match std::env::var(key) {
+            Ok(set) => set == value,
+            Err(_) => self
+                .table
+                .borrow()
+                .get(&key.to_ascii_lowercase())
+                .and_then(setting_text)
+                .is_some_and(|set| set == value),
+        }
+    }
+
+    /// Reads a setting, for ones that are off unless they're set.
+    ///
+    /// Unparsable values are treated as unset, and reported by [Section::finish].
+    fn optional_var<T: FromStr>(&self, key: &str) -> Option<T>
+    where
+        T::Err: Debug,
+    {
+        let name = key.to_ascii_lowercase();
+        let in_file = self.table.borrow_mut().remove(&name);
+
+        let parsed = match (std::env::var(key), in_file) {
+            (Ok(value), _) => value
+                .parse()
+                .map_err(|e| format!("invalid value for {}: {:?}", key, e)),
+            (Err(_), Some(value)) => match setting_text(&value).map(|text| text.parse()) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(e)) => Err(format!("invalid value for {}.{}: {:?}", self.name, name, e)),
+                None => Err(format!(
+                    "invalid value for {}.{}: {}",
+                    self.name, name, value
+                )),
+            },
+            (Err(_), None) => return None,
+        };
+
+        match parsed {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    /// Reports the first setting that couldn't be parsed, or anything in the file that isn't a setting,
+    /// since that's always a typo.
+    fn finish(self) -> Result<(), String> {
+        if let Some(e) = self.error.into_inner() {
+            return Err(e);
+        }
+
+        match self.table.into_inner().keys().next() {
+            Some(name) => Err(format!(
+                "unknown setting {}.{} in the config file",
+                self.name, name
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A setting from the config file written the way its environment variable would be.
+///
+/// Lists are joined with commas, and tables are written like `jpeg=10,png=6`.
+fn setting_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Array(values) => values
+            .iter()
+            .map(setting_text)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        Value::Table(table) => table
+            .iter()
+            .map(|(key, value)| Some(format!("{}={}", key, setting_text(value)?)))
+            .collect::<Option<Vec<_>>>()
+            .map(|pairs| pairs.join(",")),
+        Value::Datetime(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(text: &str) -> Table {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn settings_from_file() {
+        let config = Config::from_sources(file(
+            r#"
+            [discord]
+            message_cache_size = 20
+
+            [storage]
+            database_path = "/var/lib/repost-me-not"
+
+            [detection]
+            similarity_threshold = 5
+            format_thresholds = { jpeg = 12, png = 4 }
+
+            [download]
+            supported_extensions = ["png", "jpg"]
+
+            [replies]
+            dry_run = true
+            audit_channel = 1234
+
+            [logging]
+            log_format = "json"
+            "#,
+        ))
+        .unwrap();
+
+        assert_eq!(config.message_cache_size, 20);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert!(config.reply.dry_run);
+        assert_eq!(config.reply.audit_channel, Some(1234));
+        assert_eq!(config.storage.path, "/var/lib/repost-me-not");
+        assert_eq!(config.detection.similarity_threshold, 5);
+        assert_eq!(
+            config.detection.format_thresholds,
+            "jpeg=12,png=4".parse().unwrap()
+        );
+        assert_eq!(
+            config.download.supported_extensions,
+            Extensions::new(&["png", "jpg"])
+        );
+    }
+
+    #[test]
+    fn empty_file_is_defaults() {
+        let config = Config::from_sources(Table::new()).unwrap();
+        assert_eq!(config.storage.path, Config::default().storage.path);
+    }
+
+    fn error(text: &str) -> String {
+        Config::from_sources(file(text)).unwrap_err()
+    }
+
+    #[test]
+    fn mistakes_reported() {
+        assert_eq!(
+            error("[detection]\nsimliarity_threshold = 5"),
+            "unknown setting detection.simliarity_threshold in the config file"
+        );
+        assert_eq!(
+            error("[dtection]\nsimilarity_threshold = 5"),
+            "unknown section [dtection] in the config file"
+        );
+        assert!(error("[discord]\nmessage_cache_size = \"lots\"")
+            .starts_with("invalid value for discord.message_cache_size"));
+    }
+
+    #[test]
+    fn old_confirmations_still_read() {
+        let config =
+            Config::from_sources(file("[replies]\nconfirmations = \"reactions\"")).unwrap();
+        assert_eq!(config.reply.confirmations, Confirmations::Buttons);
+        assert_eq!(config.warnings.len(), 1);
+
+        let config = Config::from_sources(file("[replies]\nconfirmations = \"buttons\"")).unwrap();
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn videos_without_ffmpeg_warned_about() {
+        let text = "[download]\nsupported_extensions = \"png,mp4,webm\"";
+        let config = Config::from_sources(file(text)).unwrap();
+        assert_eq!(
+            config.warnings,
+            vec!["SUPPORTED_EXTENSIONS has mp4, webm, but videos can't be decoded without FFMPEG_PATH"]
+        );
+
+        let text = format!("{}\n[detection]\nffmpeg_path = \"ffmpeg\"", text);
+        assert!(Config::from_sources(file(&text))
+            .unwrap()
+            .warnings
+            .is_empty());
+    }
+
+    #[test]
+    fn reload_keeps_startup_settings() {
+        let running = Config::default();
+        let mut loaded = Config::default();
+        loaded.detection.similarity_threshold = 3;
+        loaded.storage.path = "somewhere/else".to_string();
+
+        let reloaded = running.reloaded(loaded);
+        assert_eq!(reloaded.detection.similarity_threshold, 3);
+        assert_eq!(reloaded.storage.path, running.storage.path);
+    }
+}
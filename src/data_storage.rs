@@ -1,7 +1,12 @@
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 use core::pin::Pin;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::errors::{DatabaseError, Error};
+use crate::config::{Config, DetectionConfig, KnownImageUpdates, QuotaPolicy};
+use crate::errors::{DatabaseError, Error, TransferError};
+use crate::hash_index::HashIndex;
+use crate::storage::Storage;
 
 #[cfg(test)]
 use bytecheck::CheckBytes;
@@ -14,14 +19,93 @@ use rkyv::{
     Archive, Deserialize, Serialize,
 };
 
-const CURRENT_VERSION: u8 = 1;
-
-const PTR_SIZE: usize = core::mem::size_of::<usize>();
+const CURRENT_VERSION: u8 = 6;
 
 mod migrations {
-    use super::{Data, DatabaseError};
+    use super::{image_processing, ArchivedSeenImage, Data, DatabaseError, SeenImage};
+    use core::convert::TryInto;
+    use core::mem::offset_of;
+    use std::collections::HashMap;
+
+    #[cfg(test)]
+    use bytecheck::CheckBytes;
+    use rkyv::{
+        de::deserializers::SharedDeserializeMap,
+        ser::{serializers::WriteSerializer, Serializer},
+        string::ArchivedString,
+        Archive, Deserialize,
+    };
+    use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+    use sled::{Batch, IVec, Tree};
+
+    /// Everything a migration writes, which isn't written until the migration finishes.
+    ///
+    /// The changes are written in the same transaction as the version moving past the migration, so a
+    /// migration is either done and recorded as done, or not done at all.
+    #[derive(Default)]
+    pub(super) struct Changes {
+        trees: HashMap<IVec, (Tree, Batch)>,
+    }
+
+    impl Changes {
+        /// The writes to make to `tree`.
+        pub(super) fn to(&mut self, tree: &Tree) -> &mut Batch {
+            &mut self
+                .trees
+                .entry(tree.name())
+                .or_insert_with(|| (tree.clone(), Batch::default()))
+                .1
+        }
+
+        /// Writes every change in one transaction.
+        pub(super) fn apply(self) -> Result<(), DatabaseError> {
+            let (trees, batches): (Vec<Tree>, Vec<Batch>) = self.trees.into_values().unzip();
+
+            trees
+                .as_slice()
+                .transaction(|trees| {
+                    for (tree, batch) in trees.iter().zip(&batches) {
+                        tree.apply_batch(batch)?;
+                    }
+
+                    Ok::<_, ConflictableTransactionError<()>>(())
+                })
+                .map_err(|e| match e {
+                    TransactionError::Storage(e) => DatabaseError::Recording(e),
+                    TransactionError::Abort(()) => unreachable!("migrations never abort"),
+                })
+        }
+    }
+
+    /// Rewrites every stored image with `convert`, which is given its database ID and stored bytes and
+    /// returns what to store instead.
+    pub(super) fn rewrite_images<F>(data: &Data, convert: F) -> Result<Changes, DatabaseError>
+    where
+        F: Fn(&IVec, &[u8]) -> Result<Vec<u8>, DatabaseError>,
+    {
+        let mut changes = Changes::default();
+        for entry in data.stored_images.iter() {
+            let (id, old) = entry.map_err(DatabaseError::Accessing)?;
+            let new = convert(&id, &old)?;
+            changes.to(&data.stored_images).insert(id, new);
+        }
+
+        Ok(changes)
+    }
+
+    /// Archives a record the way images are stored.
+    pub(super) fn serialize<T>(value: &T) -> Vec<u8>
+    where
+        T: rkyv::Serialize<WriteSerializer<Vec<u8>>>,
+    {
+        let mut serializer = WriteSerializer::new(Vec::new());
+        serializer
+            .serialize_value(value)
+            .expect("bug: serialization failed");
+        serializer.into_inner()
+    }
 
-    fn inital_version(data: &Data) -> Result<(), DatabaseError> {
+    fn inital_version(data: &Data) -> Result<Changes, DatabaseError> {
         data.db
             .open_tree(Data::STORAGE_TREE)
             .map_err(DatabaseError::Initalizing)?;
@@ -32,28 +116,338 @@ mod migrations {
             .open_tree(Data::HASH_TREE)
             .map_err(DatabaseError::Initalizing)?;
 
+        Ok(Changes::default())
+    }
+
+    /// Moves every hash under the guild its image was attributed to, so images only match within a guild.
+    ///
+    /// Hashes of images that were never attributed to a guild go under guild zero.
+    fn guild_scoped_hashes(data: &Data) -> Result<Changes, DatabaseError> {
+        let mut guilds = HashMap::new();
+        for key in data.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let (guild_id, id) = key.split_at(core::mem::size_of::<u64>());
+            guilds.insert(id.to_vec(), guild_id.to_vec());
+        }
+
+        // Only hashes from before this migration are missing a guild.
+        let mut changes = Changes::default();
+        let batch = changes.to(&data.seen_hashes);
+        for entry in data.seen_hashes.iter() {
+            let (hash, id) = entry.map_err(DatabaseError::Accessing)?;
+            if hash.len() != image_processing::HASH_BYTES {
+                continue;
+            }
+
+            let unknown_guild = 0u64.to_be_bytes().to_vec();
+            let guild_id = guilds.get(id.as_ref()).unwrap_or(&unknown_guild);
+
+            batch.insert([&guild_id[..], &hash].concat(), id);
+            batch.remove(hash);
+        }
+
+        Ok(changes)
+    }
+
+    /// How images were stored before they knew which guild they were first seen in.
+    #[derive(Archive, Deserialize)]
+    #[cfg_attr(test, derive(rkyv::Serialize), archive_attr(derive(CheckBytes)))]
+    pub(super) struct SeenImageV2 {
+        pub ignored: bool,
+        pub author: String,
+        pub sent: u64,
+        pub original_message_id: u64,
+        pub channel_id: u64,
+    }
+
+    /// Rewrites every stored image with the guild it was first seen in, which is the one it was attributed to.
+    ///
+    /// Images that were never attributed to a guild get guild zero, like their hashes.
+    fn guild_in_images(data: &Data) -> Result<Changes, DatabaseError> {
+        let mut guilds = HashMap::new();
+        for key in data.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let (guild_id, id) = key.split_at(core::mem::size_of::<u64>());
+            guilds.entry(id.to_vec()).or_insert_with(|| {
+                u64::from_be_bytes(guild_id.try_into().expect("bug: wrong number of bytes"))
+            });
+        }
+
+        rewrite_images(data, |id, old| {
+            let old: SeenImageV2 = Data::read_archived::<SeenImageV2>(old)
+                .deserialize(&mut SharedDeserializeMap::new())
+                .expect("deserialization can never fail");
+
+            Ok(serialize(&SeenImageV3 {
+                ignored: old.ignored,
+                author: old.author,
+                sent: old.sent,
+                original_message_id: old.original_message_id,
+                channel_id: old.channel_id,
+                guild_id: guilds.get(id.as_ref()).copied().unwrap_or(0),
+            }))
+        })
+    }
+
+    /// How images were stored before they knew who posted them, other than by name.
+    #[derive(Archive, Deserialize, rkyv::Serialize)]
+    #[cfg_attr(test, archive_attr(derive(CheckBytes)))]
+    pub(super) struct SeenImageV3 {
+        pub ignored: bool,
+        pub author: String,
+        pub sent: u64,
+        pub original_message_id: u64,
+        pub channel_id: u64,
+        pub guild_id: u64,
+    }
+
+    /// Rewrites every stored image with room for the ID of who posted it.
+    ///
+    /// Only names were stored before, so existing images keep going by those.
+    fn author_ids(data: &Data) -> Result<Changes, DatabaseError> {
+        rewrite_images(data, |_, old| {
+            let old: SeenImageV3 = Data::read_archived::<SeenImageV3>(old)
+                .deserialize(&mut SharedDeserializeMap::new())
+                .expect("deserialization can never fail");
+
+            Ok(serialize(&SeenImageV4 {
+                ignored: old.ignored,
+                author: old.author,
+                author_id: 0,
+                sent: old.sent,
+                original_message_id: old.original_message_id,
+                channel_id: old.channel_id,
+                guild_id: old.guild_id,
+            }))
+        })
+    }
+
+    /// How images were stored before they knew where they were downloaded from.
+    #[derive(Archive, Deserialize, rkyv::Serialize)]
+    #[cfg_attr(test, archive_attr(derive(CheckBytes)))]
+    pub(super) struct SeenImageV4 {
+        pub ignored: bool,
+        pub author: String,
+        pub author_id: u64,
+        pub sent: u64,
+        pub original_message_id: u64,
+        pub channel_id: u64,
+        pub guild_id: u64,
+    }
+
+    /// Rewrites every stored image with room for where it was downloaded from, which isn't known for existing ones.
+    fn source_urls(data: &Data) -> Result<Changes, DatabaseError> {
+        rewrite_images(data, |_, old| {
+            let old: SeenImageV4 = Data::read_archived::<SeenImageV4>(old)
+                .deserialize(&mut SharedDeserializeMap::new())
+                .expect("deserialization can never fail");
+
+            Ok(serialize(&SeenImage {
+                ignored: old.ignored,
+                author: old.author,
+                author_id: old.author_id,
+                sent: old.sent,
+                original_message_id: old.original_message_id,
+                channel_id: old.channel_id,
+                guild_id: old.guild_id,
+                source_url: None,
+            }))
+        })
+    }
+
+    /// Stops tying the database to the kind of machine that made it. IDs and counts are little-endian now,
+    /// like stored images, instead of native-endian, and the size of `usize` isn't checked since nothing
+    /// stored depends on it.
+    ///
+    /// On little-endian machines both encodings are the same bytes, so only the recorded `usize` goes.
+    /// Databases made on a big-endian machine have every ID, count, and image rewritten byte-swapped.
+    fn little_endian(data: &Data) -> Result<Changes, DatabaseError> {
+        let mut changes = Changes::default();
+        if made_big_endian(data)? {
+            swap_to_little_endian(data, &mut changes)?;
+        }
+        changes.to(&data.db).remove(Data::PTR_SIZE_KEY);
+
+        Ok(changes)
+    }
+
+    /// If the database was made on a big-endian machine, going by the `usize` it recorded.
+    ///
+    /// A size is never zero, so its first byte only is on big-endian machines. Databases without one
+    /// were made on this machine.
+    fn made_big_endian(data: &Data) -> Result<bool, DatabaseError> {
+        let ptr_size = data
+            .db
+            .get(Data::PTR_SIZE_KEY)
+            .map_err(DatabaseError::Accessing)?;
+
+        Ok(match ptr_size {
+            Some(size) => size.first() == Some(&0),
+            None => cfg!(target_endian = "big"),
+        })
+    }
+
+    /// Byte-swaps everything that used to be stored native-endian, for a database made on a big-endian machine.
+    pub(super) fn swap_to_little_endian(
+        data: &Data,
+        changes: &mut Changes,
+    ) -> Result<(), DatabaseError> {
+        const ID: usize = core::mem::size_of::<u64>();
+
+        // Database ID --> image
+        rekey(&data.stored_images, changes, |key, value| {
+            let mut image = value.to_vec();
+            image_to_little_endian(&mut image);
+            (swap_u64(key), image)
+        })?;
+        // Database ID --> count
+        rekey(&data.seen_counts, changes, |key, value| {
+            (swap_u64(key), swap_u64(value))
+        })?;
+        // Guild ID + hash --> database ID
+        rekey(&data.seen_hashes, changes, |key, value| {
+            (key.to_vec(), swap_u64(value))
+        })?;
+        // Database ID + occurrence ID --> occurrence
+        rekey(&data.occurrences, changes, |key, value| {
+            let (id, occurrence) = key.split_at(ID);
+            ([&swap_u64(id)[..], occurrence].concat(), value.to_vec())
+        })?;
+        // Guild ID + database ID --> nothing
+        rekey(&data.guild_images, changes, |key, value| {
+            let (guild_id, id) = key.split_at(ID);
+            ([guild_id, &swap_u64(id)[..]].concat(), value.to_vec())
+        })?;
+        // Database ID --> format
+        rekey(&data.image_formats, changes, |key, value| {
+            (swap_u64(key), value.to_vec())
+        })?;
+        // Guild ID + user ID --> count
+        rekey(&data.offenders, changes, |key, value| {
+            (key.to_vec(), swap_u64(value))
+        })?;
+
+        Ok(())
+    }
+
+    /// Rewrites every entry of `tree` with what `convert` returns for its key and value.
+    ///
+    /// Every old key is removed before the new ones are written, so one that's another entry's new key is kept.
+    fn rekey<F>(tree: &Tree, changes: &mut Changes, convert: F) -> Result<(), DatabaseError>
+    where
+        F: Fn(&[u8], &[u8]) -> (Vec<u8>, Vec<u8>),
+    {
+        let mut rewritten = Vec::new();
+        let batch = changes.to(tree);
+        for entry in tree.iter() {
+            let (key, value) = entry.map_err(DatabaseError::Accessing)?;
+            rewritten.push(convert(&key, &value));
+            batch.remove(key);
+        }
+
+        for (key, value) in rewritten {
+            batch.insert(key, value);
+        }
+
         Ok(())
     }
 
-    type Migration = fn(&Data) -> Result<(), DatabaseError>;
-    pub(super) const MIGRATORS: &[Migration] = &[inital_version];
+    fn swap_u64(bytes: &[u8]) -> Vec<u8> {
+        let value = u64::from_be_bytes(bytes.try_into().expect("bug: wrong number of bytes"));
+        value.to_le_bytes().to_vec()
+    }
+
+    /// Byte-swaps an image archived on a big-endian machine, before archives were little-endian everywhere.
+    ///
+    /// Archives are laid out the same either way; only the integers in them are swapped. That's the
+    /// archived image's own integers and the lengths of strings that weren't short enough to inline, since
+    /// rkyv always stores string offsets little-endian.
+    pub(super) fn image_to_little_endian(archive: &mut [u8]) {
+        fn swap(archive: &mut [u8], at: usize, width: usize) {
+            archive[at..at + width].reverse();
+        }
+
+        fn swap_string(archive: &mut [u8], at: usize) {
+            // rkyv tells the two apart by the top bit of the last byte, which is either an inline string's
+            // length or part of the offset, so it reads the same on both.
+            let last = at + core::mem::size_of::<ArchivedString>() - 1;
+            if archive[last] & 0x80 != 0 {
+                swap(archive, at, core::mem::size_of::<u32>());
+            }
+        }
+
+        let root = archive.len() - core::mem::size_of::<ArchivedSeenImage>();
+        for field in [
+            offset_of!(ArchivedSeenImage, author_id),
+            offset_of!(ArchivedSeenImage, sent),
+            offset_of!(ArchivedSeenImage, original_message_id),
+            offset_of!(ArchivedSeenImage, channel_id),
+            offset_of!(ArchivedSeenImage, guild_id),
+        ] {
+            swap(archive, root + field, core::mem::size_of::<u64>());
+        }
+
+        swap_string(archive, root + offset_of!(ArchivedSeenImage, author));
+
+        // An archived `Option` is a one byte tag, then what it holds at that's alignment.
+        let source_url = root + offset_of!(ArchivedSeenImage, source_url);
+        if archive[source_url] == 1 {
+            swap_string(
+                archive,
+                source_url + core::mem::align_of::<ArchivedString>(),
+            );
+        }
+    }
+
+    pub(super) type Migration = fn(&Data) -> Result<Changes, DatabaseError>;
+    /// Each migration and its name, in the order they're applied.
+    pub(super) const MIGRATORS: &[(&str, Migration)] = &[
+        ("inital_version", inital_version),
+        ("guild_scoped_hashes", guild_scoped_hashes),
+        ("guild_in_images", guild_in_images),
+        ("author_ids", author_ids),
+        ("source_urls", source_urls),
+        ("little_endian", little_endian),
+    ];
 }
-use migrations::MIGRATORS;
+use migrations::{Migration, MIGRATORS};
 use sled::IVec;
 
-use crate::image_processing::{self, ImageHash};
+use crate::guild_settings::GuildSettings;
+use crate::image_processing::{self, ContentHash, Hashes, ImageHash};
+use crate::transfer::{self, Dump, DumpedGuild, DumpedImage, DumpedLink, DumpedOffender};
+use image::ImageFormat;
 
 #[derive(Clone)]
 pub struct Data {
+    config: Arc<DetectionConfig>,
+    /// The guild new images are recorded in, if known.
+    guild: Option<u64>,
+    /// Most images that can be stored for the guild, if there's a limit.
+    record_quota: Option<u64>,
+    quota_policy: QuotaPolicy,
     db: sled::Db,
     stored_images: sled::Tree,
     seen_counts: sled::Tree,
     seen_hashes: sled::Tree,
+    guild_settings: sled::Tree,
+    occurrences: sled::Tree,
+    guild_images: sled::Tree,
+    known_guilds: sled::Tree,
+    offenders: sled::Tree,
+    image_formats: sled::Tree,
+    content_hashes: sled::Tree,
+    seen_links: sled::Tree,
+    /// Every key in `seen_hashes`, for finding similar hashes without comparing against all of them.
+    hash_index: Arc<RwLock<HashIndex>>,
+    /// Count increments waiting to be written, if they're batched.
+    pending_counts: Option<Arc<PendingCounts>>,
 }
 
 impl Data {
     // --------- Keys -------------------
     const VERSION_KEY: &'static [u8] = b"version";
+    /// Size of `usize` on the machine that made the database, which was checked before storage was little-endian.
     const PTR_SIZE_KEY: &'static [u8] = b"usize";
 
     // --------- Trees ------------------
@@ -64,17 +458,46 @@ impl Data {
     const SEEN_COUNT_TREE: &'static [u8] = b"seen_count";
     /// Mapping of image hash --> database ID
     const HASH_TREE: &'static [u8] = b"hash_tree";
+    /// Mapping of guild ID --> JSON guild settings
+    const GUILD_SETTINGS_TREE: &'static [u8] = b"guild_settings";
+    /// Mapping of database ID + occurrence ID --> JSON occurrence of an image
+    const OCCURRENCE_TREE: &'static [u8] = b"occurrences";
+    /// Mapping of guild ID + database ID --> nothing, for each image first seen in a guild
+    const GUILD_IMAGES_TREE: &'static [u8] = b"guild_images";
+    /// Mapping of guild ID --> nothing, or when the bot was removed from it, for each guild the bot has been in
+    const KNOWN_GUILDS_TREE: &'static [u8] = b"known_guilds";
+    /// Mapping of guild ID + user ID --> how many of their reposts were found
+    const OFFENDERS_TREE: &'static [u8] = b"offenders";
+    /// Mapping of database ID --> extension of the format the image was stored from, if it was known
+    const IMAGE_FORMATS_TREE: &'static [u8] = b"image_formats";
+    /// Mapping of guild ID + SHA-256 of an image file --> the hash it was recorded with
+    const CONTENT_TREE: &'static [u8] = b"content_hashes";
+    /// Mapping of guild ID + canonical link --> who shared it first, and how many times it's been shared
+    const LINK_TREE: &'static [u8] = b"seen_links";
+
+    pub fn init(db_path: &str, config: &Config) -> Result<Self, DatabaseError> {
+        config
+            .storage
+            .validate()
+            .map_err(DatabaseError::InvalidConfig)?;
+
+        let flush_every_ms = match config.storage.flush_every_ms {
+            0 => None,
+            ms => Some(ms),
+        };
+        let sled_config = sled::Config::new()
+            .cache_capacity(config.storage.cache_capacity)
+            .flush_every_ms(flush_every_ms);
 
-    pub fn init(db_path: &str) -> Result<Self, DatabaseError> {
         #[cfg(not(test))]
-        let db = sled::Config::new()
+        let db = sled_config
             .path(db_path)
             .open()
             .map_err(DatabaseError::Initalizing)?;
 
         #[cfg(test)]
         let db = {
-            let mut config = sled::Config::new().temporary(true);
+            let mut config = sled_config.temporary(true);
 
             if !db_path.is_empty() {
                 config = config.path(db_path)
@@ -83,22 +506,6 @@ impl Data {
             config.open().unwrap()
         };
 
-        // Check that there aren't any 32/64 bit mixups
-        match db
-            .get(Self::PTR_SIZE_KEY)
-            .map_err(DatabaseError::Initalizing)?
-        {
-            Some(ptr_size) => {
-                if ptr_size.len() != PTR_SIZE {
-                    panic!("attempted to open a database from an arch with a different usize")
-                }
-            }
-            None => {
-                db.insert(Self::PTR_SIZE_KEY, &PTR_SIZE.to_ne_bytes())
-                    .map_err(DatabaseError::Initalizing)?;
-            }
-        };
-
         let version = match db
             .get(Self::VERSION_KEY)
             .map_err(DatabaseError::Initalizing)?
@@ -111,35 +518,108 @@ impl Data {
             }
         };
 
-        if version < CURRENT_VERSION {
+        if version > CURRENT_VERSION {
             panic!("uhhh, time travel?")
         }
 
+        let seen_counts = db
+            .open_tree(Self::SEEN_COUNT_TREE)
+            .map_err(DatabaseError::Initalizing)?;
+        let pending_counts = match config.storage.count_batch_ms {
+            0 => None,
+            _ => Some(Arc::new(PendingCounts {
+                seen_counts: seen_counts.clone(),
+                counts: Mutex::new(HashMap::new()),
+            })),
+        };
+
         let data = Self {
+            config: Arc::new(config.detection.clone()),
+            guild: None,
+            record_quota: None,
+            quota_policy: config.storage.quota_policy,
             stored_images: db
                 .open_tree(Self::STORAGE_TREE)
                 .map_err(DatabaseError::Initalizing)?,
-            seen_counts: db
-                .open_tree(Self::SEEN_COUNT_TREE)
-                .map_err(DatabaseError::Initalizing)?,
+            seen_counts,
             seen_hashes: db
                 .open_tree(Self::HASH_TREE)
                 .map_err(DatabaseError::Initalizing)?,
+            guild_settings: db
+                .open_tree(Self::GUILD_SETTINGS_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            occurrences: db
+                .open_tree(Self::OCCURRENCE_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            guild_images: db
+                .open_tree(Self::GUILD_IMAGES_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            known_guilds: db
+                .open_tree(Self::KNOWN_GUILDS_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            offenders: db
+                .open_tree(Self::OFFENDERS_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            image_formats: db
+                .open_tree(Self::IMAGE_FORMATS_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            content_hashes: db
+                .open_tree(Self::CONTENT_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            seen_links: db
+                .open_tree(Self::LINK_TREE)
+                .map_err(DatabaseError::Initalizing)?,
+            hash_index: Arc::default(),
+            pending_counts,
             db,
         };
 
-        // V1 --> `inital_version()` --> Skips nothing.
-        // V2 --> `migration_v2()`   --> Skips `inital_version()`
-        // V3 --> `migration_v3()`   --> Skips `inital_version()` and `migration_v2()`.
-        for migration in MIGRATORS.iter().skip(usize::from(version - 1)) {
-            migration(&data)?;
-        }
+        // A database on version N has had the first N migrations run, so only the rest are.
+        // V1 --> `guild_scoped_hashes()` and everything after --> Skips `inital_version()`.
+        // V2 --> `guild_in_images()` and everything after.
+        // V3 --> `author_ids()`, `source_urls()`, `little_endian()`.
+        // V4 --> `source_urls()`, `little_endian()`.
+        // V5 --> `little_endian()`.
+        // V6 --> Nothing left to run.
+        data.run_migrations(version, MIGRATORS)?;
+
+        // The index isn't stored, since it's quick to rebuild from the hashes that are.
+        let start = std::time::Instant::now();
+        let index = HashIndex::build(data.seen_hashes.iter().keys(), config.hashing_threads)
+            .map_err(DatabaseError::Initalizing)?;
+        tracing::info!(
+            "Indexed {} hashes in {}ms",
+            index.len(),
+            start.elapsed().as_millis()
+        );
+        *data.index_mut() = index;
 
         Ok(data)
     }
 
+    /// Runs each of `migrators` a database on `version` hasn't had yet, in order.
+    ///
+    /// Each migration's changes are written in one transaction with the version moving past it, so one that
+    /// fails or is interrupted leaves its trees as they were, and only it has to be run again.
+    fn run_migrations(
+        &self,
+        version: u8,
+        migrators: &[(&str, Migration)],
+    ) -> Result<(), DatabaseError> {
+        for (ran, (name, migration)) in migrators.iter().enumerate().skip(usize::from(version)) {
+            tracing::info!("Running database migration {}", name);
+            let mut changes = migration(self)?;
+
+            let version = u8::try_from(ran + 1).expect("bug: too many migrations");
+            changes.to(&self.db).insert(Self::VERSION_KEY, &[version]);
+            changes.apply()?;
+        }
+
+        Ok(())
+    }
+
     fn read_int(bytes: &[u8]) -> u64 {
-        u64::from_ne_bytes(bytes.try_into().expect("bug: wrong number of bytes"))
+        u64::from_le_bytes(bytes.try_into().expect("bug: wrong number of bytes"))
     }
 
     // Ensure that the buffers used are correct
@@ -154,43 +634,231 @@ impl Data {
 
     #[cfg(not(test))]
     fn read_archived<T: Archive>(buf: &[u8]) -> &T::Archived {
-        // SAFETY: The data was stored in a buffer on its own and was on the same endian.
+        // SAFETY: The data was stored in a buffer on its own, and archives are little-endian everywhere.
         unsafe { rkyv::archived_root::<T>(buf) }
     }
 
-    pub fn record_image(
+    /// The detection settings images are recorded with.
+    pub fn config(&self) -> &DetectionConfig {
+        &self.config
+    }
+
+    /// Returns a handle to the same database that matches images with a different similarity threshold.
+    pub fn with_similarity_threshold(&self, threshold: u32) -> Self {
+        let mut config = DetectionConfig::clone(&self.config);
+        config.similarity_threshold = threshold;
+        self.with_detection_config(config)
+    }
+
+    /// Returns a handle to the same database that matches images with different detection settings.
+    pub fn with_detection_config(&self, config: DetectionConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            ..self.clone()
+        }
+    }
+
+    /// If a new hash and a stored one belong to the same image, under the configured matching rules.
+    fn is_match(&self, new: &ImageHash, seen: &[u8], threshold: u32) -> bool {
+        match self.config.min_confidence {
+            Some(min_confidence) => {
+                // Only the perceptual hash is stored, so that's the only signal there is to compare.
+                let signals = image_processing::Signals {
+                    distance: image_processing::hash_distance(new, seen),
+                    ..Default::default()
+                };
+
+                image_processing::repost_confidence(&signals) >= min_confidence
+            }
+            None => image_processing::similar_enough(new, seen, threshold),
+        }
+    }
+
+    /// The similarity threshold for matching a stored image, which can be overridden for the format it was stored from.
+    fn threshold_for(&self, id: &[u8], threshold: u32) -> Result<u32, DatabaseError> {
+        if self.config.format_thresholds.is_empty() {
+            return Ok(threshold);
+        }
+
+        let format = self
+            .image_formats
+            .get(id)
+            .map_err(DatabaseError::Accessing)?
+            .and_then(|extension| {
+                ImageFormat::from_extension(std::str::from_utf8(&extension).ok()?)
+            });
+
+        Ok(self
+            .config
+            .format_thresholds
+            .threshold_for(format, threshold))
+    }
+
+    fn index(&self) -> std::sync::RwLockReadGuard<'_, HashIndex> {
+        self.hash_index
+            .read()
+            .expect("bug: a thread panicked while indexing hashes")
+    }
+
+    fn index_mut(&self) -> std::sync::RwLockWriteGuard<'_, HashIndex> {
+        self.hash_index
+            .write()
+            .expect("bug: a thread panicked while indexing hashes")
+    }
+
+    /// Adds a hash to `seen_hashes`, pointing at the database ID of the image it belongs to.
+    fn insert_hash(&self, key: Vec<u8>, id: &[u8]) -> Result<(), DatabaseError> {
+        self.seen_hashes
+            .insert(&key, id)
+            .map_err(DatabaseError::Recording)?;
+        self.index_mut().insert(&key);
+
+        Ok(())
+    }
+
+    /// Points the later frames of an animation at its record too, unless they already belong to one.
+    fn insert_frames(&self, hashes: &Hashes, id: &[u8]) -> Result<(), DatabaseError> {
+        for frame in &hashes.frames {
+            let key = self.hash_key(frame.as_bytes());
+            if !self
+                .seen_hashes
+                .contains_key(&key)
+                .map_err(DatabaseError::Recording)?
+            {
+                self.insert_hash(key, id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How far away a stored hash can be and still match, or be close enough to ask about.
+    fn search_radius(&self, threshold: u32) -> u32 {
+        let threshold = match self.config.min_confidence {
+            Some(min_confidence) => (0..=image_processing::MAX_DISTANCE)
+                .take_while(|&distance| {
+                    let signals = image_processing::Signals {
+                        distance,
+                        ..Default::default()
+                    };
+
+                    image_processing::repost_confidence(&signals) >= min_confidence
+                })
+                .last()
+                .unwrap_or(0),
+            None => threshold.max(self.config.format_thresholds.largest()),
+        };
+
+        threshold + self.config.confirm_grace
+    }
+
+    /// Where hashes recorded through this handle are kept in `seen_hashes`.
+    ///
+    /// Hash keys start with the guild they were recorded in, or zero if that isn't known,
+    /// so images only match others from the same guild.
+    fn hash_prefix(&self) -> [u8; 8] {
+        self.guild.unwrap_or(0).to_be_bytes()
+    }
+
+    fn hash_key(&self, hash: &[u8]) -> Vec<u8> {
+        [&self.hash_prefix()[..], hash].concat()
+    }
+
+    /// The hash in a `seen_hashes` key, without the guild it was recorded in.
+    fn unprefixed(key: &[u8]) -> &[u8] {
+        &key[core::mem::size_of::<u64>()..]
+    }
+
+    /// Returns a handle to the same database that attributes new images to a guild,
+    /// and only matches them against images from that guild.
+    pub fn for_guild(&self, guild_id: u64) -> Self {
+        Self {
+            guild: Some(guild_id),
+            ..self.clone()
+        }
+    }
+
+    /// Limits how many images are stored for the guild new images are recorded in.
+    pub fn with_record_quota(&self, quota: Option<u64>) -> Self {
+        Self {
+            record_quota: quota,
+            ..self.clone()
+        }
+    }
+
+    /// Records a sighting of an image, returning what it was a repost of if anything.
+    ///
+    /// This is the entry point for recording images from anywhere, whether or not they came
+    /// from a Discord message. An exact hash match is a repost of whatever that hash belongs to.
+    /// Otherwise, the first stored image within the similarity threshold of the hash, or of any of its
+    /// variants or frames, is considered the original and the new hash becomes an alias of it. If nothing matches,
+    /// `properties` is stored as a new image.
+    pub fn record_raw(
+        &self,
+        image: impl Into<Hashes>,
+        properties: SeenImage,
+    ) -> Result<PreviouslySeen, Error> {
+        self.record(&image.into(), properties, true)
+    }
+
+    /// Like [Data::record_raw], but an image that doesn't match anything isn't stored.
+    ///
+    /// Reposts of known images are still counted.
+    pub fn match_raw(
+        &self,
+        image: impl Into<Hashes>,
+        properties: SeenImage,
+    ) -> Result<PreviouslySeen, Error> {
+        self.record(&image.into(), properties, false)
+    }
+
+    fn record(
         &self,
-        image_hash: &ImageHash,
+        hashes: &Hashes,
         properties: SeenImage,
+        store_new: bool,
     ) -> Result<PreviouslySeen, Error> {
+        let seen = self.record_hashes(hashes, properties, store_new)?;
+
+        if let Some(content) = &hashes.content {
+            self.remember_content(content, &hashes.hash)?;
+        }
+
+        Ok(seen)
+    }
+
+    fn record_hashes(
+        &self,
+        hashes: &Hashes,
+        properties: SeenImage,
+        store_new: bool,
+    ) -> Result<PreviouslySeen, Error> {
+        let image_hash = &hashes.hash;
+        let threshold = self.config.similarity_threshold;
+
         // See if we know about this exact image already.
         if let Some(id_of_existing) = self
             .seen_hashes
-            .get(image_hash.as_bytes())
+            .get(self.hash_key(image_hash.as_bytes()))
             .map_err(DatabaseError::Recording)?
         {
-            // If we do, increment and return the times its been seen
-            let times_seen =
-                self.seen_counts
-                    .update_and_fetch(&id_of_existing, |old| {
-                        let mut new = Self::read_int(old.expect(
-                            "bug: database ID pointed at a hash but no seen_count was found",
-                        ));
-                        new += 1;
-
-                        Some(IVec::from(&new.to_ne_bytes()))
-                    })
-                    .map_err(DatabaseError::Recording)?
-                    .expect("bug: record_image update_and_fetch returned None");
-
-            // Then return it to the caller.
-            let times_seen = Self::read_int(&times_seen);
             let old = self
                 .stored_images
                 .get(&id_of_existing)
                 .map_err(DatabaseError::Recording)?
                 .expect("bug: database ID pointed at dead image");
 
+            if self.outside_repost_window(&old, &properties) {
+                self.insert_frames(hashes, &id_of_existing)?;
+                self.restart_sightings(&id_of_existing, &old, properties)?;
+                return Ok(PreviouslySeen::No);
+            }
+
+            // If we do, increment and return the times its been seen
+            let times_seen = self.count_sighting(&id_of_existing, &properties)?;
+            self.insert_frames(hashes, &id_of_existing)?;
+
+            // Then return it to the caller.
             let start = std::time::Instant::now();
             let mut deserializer = SharedDeserializeMap::new();
             let image = Self::read_archived::<SeenImage>(&old);
@@ -199,45 +867,109 @@ impl Data {
                 .expect("deserialization can never fail");
             tracing::trace!("It took {}ms to deserialize", start.elapsed().as_millis());
 
+            self.update_known(&id_of_existing, old, &properties)?;
+
             return Ok(PreviouslySeen::Yes { image, times_seen });
         }
 
         // Otherwise, its new-ish. Lets see if its similar to anything else we have!
-        for entry in self.seen_hashes.iter() {
-            let (hash, id) = entry.map_err(DatabaseError::Recording)?;
+        //
+        // Only hashes the index finds close enough to the hash, or any of its variants or frames, are looked at.
+        // They're checked in the same order as they're stored, so the first match is the same as
+        // if every stored hash was.
+        let radius = self.search_radius(threshold);
+        let mut candidates: Vec<Vec<u8>> = {
+            let index = self.index();
+            hashes
+                .candidates()
+                .flat_map(|candidate| {
+                    index.within(self.hash_prefix(), candidate.as_bytes(), radius)
+                })
+                .map(|(_, hash)| self.hash_key(hash))
+                .collect()
+        };
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let mut nearest: Option<(u32, IVec)> = None;
+        // Only the outcome is logged, since logging every comparison floods the logs on big databases.
+        let mut comparisons = 0;
+
+        for key in candidates {
+            let id = match self
+                .seen_hashes
+                .get(&key)
+                .map_err(DatabaseError::Recording)?
+            {
+                Some(id) => id,
+                // Forgotten since it was looked up.
+                None => continue,
+            };
+            let hash = Self::unprefixed(&key);
 
             // Skip what was just inserted above.
             if hash == image_hash.as_bytes() {
                 continue;
             }
 
+            comparisons += 1;
+            let threshold = self.threshold_for(&id, threshold)?;
+            let near_threshold = threshold + self.config.confirm_grace;
+
             // If it was similar, record it as a duplicate and tell the caller.
-            if image_processing::similar_enough(image_hash, &hash) {
-                // Update the count...
-                let times_seen = self
-                    .seen_counts
-                    .update_and_fetch(&id, |old_count| {
-                        let mut new =
-                            Self::read_int(old_count.expect("bug: image existed but hash didn't"));
-                        new += 1;
-
-                        Some(IVec::from(&new.to_ne_bytes()))
-                    })
-                    .map_err(DatabaseError::Recording)?
-                    .expect("bug: record_image update_and_fetch 2 returned None");
+            let similar = hashes
+                .candidates()
+                .any(|candidate| self.is_match(candidate, hash, threshold));
 
-                let times_seen = Self::read_int(&times_seen);
+            if !similar && self.config.confirm_grace > 0 {
+                let distance = hashes
+                    .candidates()
+                    .map(|candidate| image_processing::hash_distance(candidate, hash))
+                    .min()
+                    .expect("there's always at least one hash");
 
+                if distance <= near_threshold
+                    && nearest.as_ref().is_none_or(|(best, _)| distance < *best)
+                {
+                    nearest = Some((distance, IVec::from(hash)));
+                }
+            }
+
+            if similar {
                 let old = self
                     .stored_images
                     .get(&id)
                     .map_err(DatabaseError::Recording)?
                     .expect("bug: database ID pointed at dead image");
 
-                // Now mark this hash as the same image.
-                self.seen_hashes
-                    .insert(image_hash.as_bytes(), id)
-                    .map_err(DatabaseError::Recording)?;
+                // Aliasing this hash to an ignored record would make it ignored too, so
+                // only do that if configured. Otherwise, it gets a record of its own.
+                if Self::read_archived::<SeenImage>(&old).ignored
+                    && !self.config.similar_inherits_ignored
+                {
+                    continue;
+                }
+
+                tracing::debug!(
+                    "Similarity check matched a stored image {} apart after {} comparisons",
+                    hashes
+                        .candidates()
+                        .map(|candidate| image_processing::hash_distance(candidate, hash))
+                        .min()
+                        .expect("there's always at least one hash"),
+                    comparisons
+                );
+
+                // Now mark this hash as the same image, and update the count.
+                self.insert_hash(self.hash_key(image_hash.as_bytes()), &id)?;
+                self.insert_frames(hashes, &id)?;
+
+                if self.outside_repost_window(&old, &properties) {
+                    self.restart_sightings(&id, &old, properties)?;
+                    return Ok(PreviouslySeen::No);
+                }
+
+                let times_seen = self.count_sighting(&id, &properties)?;
 
                 let start = std::time::Instant::now();
                 let mut deserializer = SharedDeserializeMap::new();
@@ -247,10 +979,21 @@ impl Data {
                     .expect("deserialization can never fail"); // reuturns rkyv::Unreachable
                 tracing::trace!("It took {}ms to deserialize", start.elapsed().as_millis());
 
+                self.update_known(&id, old, &properties)?;
+
                 return Ok(PreviouslySeen::Yes { image, times_seen });
             }
         }
 
+        tracing::debug!(
+            "Similarity check found no match in {} comparisons",
+            comparisons
+        );
+
+        if !store_new || !self.make_room()? {
+            return Ok(PreviouslySeen::No);
+        }
+
         let mut serializer = WriteSerializer::new(Vec::new());
         serializer
             .serialize_value(&properties)
@@ -263,9 +1006,9 @@ impl Data {
             .db
             .generate_id()
             .map_err(DatabaseError::Recording)?
-            .to_ne_bytes();
+            .to_le_bytes();
 
-        const NEW_IMAGE_COUNT: &[u8] = &1u64.to_ne_bytes();
+        const NEW_IMAGE_COUNT: &[u8] = &1u64.to_le_bytes();
 
         self.seen_counts
             .insert(id, NEW_IMAGE_COUNT)
@@ -273,227 +1016,4096 @@ impl Data {
         self.stored_images
             .insert(id, value)
             .map_err(DatabaseError::Recording)?;
-        self.seen_hashes
-            .insert(image_hash.as_bytes(), &id)
-            .map_err(DatabaseError::Recording)?;
+        self.insert_hash(self.hash_key(image_hash.as_bytes()), &id)?;
+        self.insert_frames(hashes, &id)?;
+        if let Some(format) = hashes.format {
+            self.image_formats
+                .insert(id, format.extensions_str()[0])
+                .map_err(DatabaseError::Recording)?;
+        }
+        self.record_occurrence(&id, &properties)?;
 
-        Ok(PreviouslySeen::No)
+        if let Some(guild_id) = self.guild {
+            self.attribute_to_guild(guild_id, &id)?;
+        }
+
+        // It's stored on its own for now, but might be a repost that someone needs to confirm.
+        if let Some((_, original)) = nearest {
+            if let Some(image) = self.image_with_hash(&original)? {
+                if !image.ignored {
+                    return Ok(PreviouslySeen::NearMatch {
+                        image,
+                        original: original.to_vec(),
+                        new: image_hash.as_bytes().to_vec(),
+                    });
+                }
+            }
+        }
+
+        Ok(PreviouslySeen::No)
     }
 
-    pub fn access_image<F: Fn(Pin<&mut ArchivedSeenImage>) -> bool>(
+    /// Remembers which hash an image file was recorded with, if it was.
+    fn remember_content(
         &self,
-        image_hash: &[u8],
-        f: F,
+        content: &ContentHash,
+        hash: &ImageHash,
+    ) -> Result<(), DatabaseError> {
+        let recorded = self
+            .seen_hashes
+            .contains_key(self.hash_key(hash.as_bytes()))
+            .map_err(DatabaseError::Accessing)?;
+
+        if recorded {
+            self.content_hashes
+                .insert(self.hash_key(content), hash.as_bytes())
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        Ok(())
+    }
+
+    /// The hash an exact copy of an image file was recorded with, if it's still stored.
+    pub fn hash_of_content(
+        &self,
+        content: &ContentHash,
+    ) -> Result<Option<ImageHash>, DatabaseError> {
+        let key = self.hash_key(content);
+        let hash = match self
+            .content_hashes
+            .get(&key)
+            .map_err(DatabaseError::Accessing)?
+        {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+
+        let stored = self
+            .seen_hashes
+            .contains_key(self.hash_key(&hash))
+            .map_err(DatabaseError::Accessing)?;
+        if !stored {
+            // The image was deleted since, so this copy has to be hashed again.
+            self.content_hashes
+                .remove(key)
+                .map_err(DatabaseError::Recording)?;
+            return Ok(None);
+        }
+
+        Ok(ImageHash::from_bytes(&hash).ok())
+    }
+
+    /// Finds the stored image first posted in a message, along with every hash that points at it.
+    ///
+    /// Through a guild's handle, only images first seen in that guild are found.
+    pub fn lookup(&self, original_message_id: u64) -> Result<Option<StoredRecord>, DatabaseError> {
+        let mut found = None;
+        for entry in self.stored_images.iter() {
+            let (id, image) = entry.map_err(DatabaseError::Accessing)?;
+            let archived = Self::read_archived::<SeenImage>(&image);
+
+            if archived.original_message_id == original_message_id && self.in_guild(&id)? {
+                let mut deserializer = SharedDeserializeMap::new();
+                let image = archived
+                    .deserialize(&mut deserializer)
+                    .expect("deserialization can never fail");
+                found = Some((id, image));
+                break;
+            }
+        }
+
+        let (id, image) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let mut hashes = Vec::new();
+        for entry in self.seen_hashes.iter() {
+            let (key, hash_id) = entry.map_err(DatabaseError::Accessing)?;
+            if hash_id == id {
+                hashes.push(Self::unprefixed(&key).to_vec());
+            }
+        }
+
+        let threshold = self.threshold_for(&id, self.config.similarity_threshold)?;
+        Ok(Some(StoredRecord {
+            image,
+            hashes,
+            threshold,
+        }))
+    }
+
+    /// If the image with database ID `id` was first seen in this handle's guild, or there isn't one.
+    fn in_guild(&self, id: &[u8]) -> Result<bool, DatabaseError> {
+        match self.guild {
+            Some(guild_id) => self
+                .guild_images
+                .contains_key([&guild_id.to_be_bytes()[..], id].concat())
+                .map_err(DatabaseError::Accessing),
+            None => Ok(true),
+        }
+    }
+
+    /// If `hash` would be matched with a looked up image, the same way new images are when they're recorded.
+    pub fn matches_record(&self, record: &StoredRecord, hash: &ImageHash) -> bool {
+        record
+            .hashes
+            .iter()
+            .any(|seen| self.is_match(hash, seen, record.threshold))
+    }
+
+    /// Finds the stored image closest to a hash, or any of its variants or frames, and how far away it is.
+    ///
+    /// Unlike [Data::record_raw], nothing is counted or stored, and the closest image is returned
+    /// even if it's past the similarity threshold.
+    pub fn nearest(&self, hashes: &Hashes) -> Result<Option<(u32, SeenImage)>, DatabaseError> {
+        match self.nearest_hash(hashes) {
+            Some((distance, hash)) => {
+                Ok(self.image_with_hash(&hash)?.map(|image| (distance, image)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The stored hash closest to a hash, or any of its variants or frames, and how far away it is.
+    fn nearest_hash(&self, hashes: &Hashes) -> Option<(u32, IVec)> {
+        let index = self.index();
+        hashes
+            .candidates()
+            .filter_map(|candidate| index.nearest(self.hash_prefix(), candidate.as_bytes()))
+            .min()
+            .map(|(distance, hash)| (distance, IVec::from(hash)))
+    }
+
+    /// Finds the stored image posted in a message, whether it was the original or a repost,
+    /// along with how many times it's been seen.
+    pub fn posted_in(&self, message_id: u64) -> Result<Option<(SeenImage, u64)>, DatabaseError> {
+        let id = match self.id_posted_in(message_id)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        match self.stored_image(&id)? {
+            Some(image) => Ok(Some((image, self.stored_count(&id)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds the stored image posted in a message, along with every time it's been posted, oldest first.
+    ///
+    /// Images stored before occurrences were tracked only have their original post.
+    pub fn history(
+        &self,
+        message_id: u64,
+    ) -> Result<Option<(SeenImage, Vec<Occurrence>)>, DatabaseError> {
+        match self.id_posted_in(message_id)? {
+            Some(id) => self.history_of(&id),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [Data::history], but for the stored image within the similarity threshold of a hash, or any of its
+    /// variants or frames, like the one it would be counted as a repost of.
+    pub fn history_matching(
+        &self,
+        hashes: &Hashes,
+    ) -> Result<Option<(SeenImage, Vec<Occurrence>)>, DatabaseError> {
+        let (distance, hash) = match self.nearest_hash(hashes) {
+            Some(nearest) => nearest,
+            None => return Ok(None),
+        };
+
+        let id = match self
+            .seen_hashes
+            .get(self.hash_key(&hash))
+            .map_err(DatabaseError::Accessing)?
+        {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        if distance > self.threshold_for(&id, self.config.similarity_threshold)? {
+            return Ok(None);
+        }
+
+        self.history_of(&id)
+    }
+
+    /// The image with database ID `id`, along with every time it's been posted, oldest first.
+    fn history_of(&self, id: &[u8]) -> Result<Option<(SeenImage, Vec<Occurrence>)>, DatabaseError> {
+        let image = match self.stored_image(id)? {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+
+        // Images stored before occurrences were tracked only know their original post.
+        let mut occurrences = self.occurrences_of(id)?;
+        if occurrences.is_empty() {
+            occurrences.push(Occurrence::from(&image));
+        }
+
+        Ok(Some((image, occurrences)))
+    }
+
+    /// The database ID of the image posted in a message, whether it was the original or a repost.
+    fn id_posted_in(&self, message_id: u64) -> Result<Option<IVec>, DatabaseError> {
+        for entry in self.occurrences.iter() {
+            let (key, occurrence) = entry.map_err(DatabaseError::Accessing)?;
+            let occurrence: Occurrence =
+                serde_json::from_slice(&occurrence).map_err(DatabaseError::CorruptOccurrence)?;
+
+            if occurrence.message_id == message_id {
+                // Occurrence keys start with the ID of the image they belong to.
+                return Ok(Some(IVec::from(
+                    &key[..key.len() - std::mem::size_of::<u64>()],
+                )));
+            }
+        }
+
+        // Images stored before occurrences were tracked only know their original message.
+        for entry in self.stored_images.iter() {
+            let (id, image) = entry.map_err(DatabaseError::Accessing)?;
+            if Self::read_archived::<SeenImage>(&image).original_message_id == message_id {
+                return Ok(Some(id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The image stored with database ID `id`, if it's still there.
+    fn stored_image(&self, id: &[u8]) -> Result<Option<SeenImage>, DatabaseError> {
+        let image = match self
+            .stored_images
+            .get(id)
+            .map_err(DatabaseError::Accessing)?
+        {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+
+        let mut deserializer = SharedDeserializeMap::new();
+        let image = Self::read_archived::<SeenImage>(&image)
+            .deserialize(&mut deserializer)
+            .expect("deserialization can never fail");
+
+        Ok(Some(image))
+    }
+
+    /// Updates what's stored about an image that was just seen again, if configured to.
+    ///
+    /// Who first posted an image and when never change, and neither does if it's ignored.
+    fn update_known(
+        &self,
+        id: &[u8],
+        mut stored: IVec,
+        seen_again: &SeenImage,
+    ) -> Result<(), DatabaseError> {
+        match self.config.known_image_updates {
+            KnownImageUpdates::Preserve => return Ok(()),
+            KnownImageUpdates::LatestLocation => {
+                // SAFETY: We know we're pulling out of the images table, which are the right type, and this is tested.
+                let mut archived =
+                    unsafe { rkyv::archived_root_mut::<SeenImage>(Pin::new(stored.as_mut())) };
+                archived.original_message_id = seen_again.original_message_id.into();
+                archived.channel_id = seen_again.channel_id.into();
+            }
+        }
+
+        self.stored_images
+            .insert(id, stored)
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(())
+    }
+
+    /// If a stored image was first posted too long before it was seen again for that to be a repost.
+    fn outside_repost_window(&self, stored: &IVec, seen_again: &SeenImage) -> bool {
+        match self.config.repost_window {
+            Some(window) => {
+                let first_sent = Self::read_archived::<SeenImage>(stored).sent.value();
+                seen_again.sent.saturating_sub(first_sent) > window
+            }
+            None => false,
+        }
+    }
+
+    /// Makes a sighting the first one of an image, as if it was never seen before.
+    ///
+    /// Its history is kept, but it's counted from one again. It stays ignored if it was.
+    fn restart_sightings(
+        &self,
+        id: &[u8],
+        stored: &IVec,
+        seen_again: SeenImage,
     ) -> Result<(), DatabaseError> {
-        if let Some(id) = self
+        let image = SeenImage {
+            ignored: Self::read_archived::<SeenImage>(stored).ignored,
+            ..seen_again
+        };
+
+        let mut serializer = WriteSerializer::new(Vec::new());
+        serializer
+            .serialize_value(&image)
+            .expect("bug: serialization failed");
+        self.stored_images
+            .insert(id, serializer.into_inner())
+            .map_err(DatabaseError::Recording)?;
+
+        // Increments that haven't been written yet belong to the old sightings.
+        let mut pending = self.pending_counts.as_ref().map(|pending| pending.lock());
+        if let Some(pending) = &mut pending {
+            pending.remove(id);
+        }
+        self.seen_counts
+            .insert(id, &1u64.to_le_bytes())
+            .map_err(DatabaseError::Recording)?;
+        drop(pending);
+
+        tracing::debug!("Recorded an image seen again after the repost window as new");
+        self.record_occurrence(id, &image)
+    }
+
+    /// The image a hash points at, if it's known.
+    fn image_with_hash(&self, hash: &[u8]) -> Result<Option<SeenImage>, DatabaseError> {
+        let id = match self
             .seen_hashes
-            .get(image_hash)
+            .get(self.hash_key(hash))
             .map_err(DatabaseError::Accessing)?
         {
-            let mut buf = self
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let image = self
+            .stored_images
+            .get(&id)
+            .map_err(DatabaseError::Accessing)?
+            .expect("bug: database ID pointed at dead image");
+
+        let mut deserializer = SharedDeserializeMap::new();
+        let image = Self::read_archived::<SeenImage>(&image)
+            .deserialize(&mut deserializer)
+            .expect("deserialization can never fail");
+
+        Ok(Some(image))
+    }
+
+    /// Merges the image stored for a near-match into the image it was confirmed to be a repost of,
+    /// returning how many times the original has been seen now.
+    ///
+    /// The near-match's sightings carry over to the original, so confirming it counts the repost
+    /// exactly once. Merging images that were already merged changes nothing. Returns `None` if either
+    /// hash isn't known.
+    pub fn merge_near_match(
+        &self,
+        new: &[u8],
+        original: &[u8],
+    ) -> Result<Option<u64>, DatabaseError> {
+        self.flush_counts()?;
+        let id_of = |hash: &[u8]| {
+            self.seen_hashes
+                .get(self.hash_key(hash))
+                .map_err(DatabaseError::Accessing)
+        };
+
+        let (new_id, original_id) = match (id_of(new)?, id_of(original)?) {
+            (Some(new_id), Some(original_id)) => (new_id, original_id),
+            _ => return Ok(None),
+        };
+
+        let count_of = |id: &IVec| -> Result<u64, DatabaseError> {
+            Ok(self
+                .seen_counts
+                .get(id)
+                .map_err(DatabaseError::Accessing)?
+                .map_or(0, |count| Self::read_int(&count)))
+        };
+
+        if new_id == original_id {
+            return count_of(&original_id).map(Some);
+        }
+
+        let times_seen = count_of(&original_id)? + count_of(&new_id)?;
+
+        // Everything pointing at the near-match now points at the original.
+        for entry in self.seen_hashes.iter() {
+            let (hash, id) = entry.map_err(DatabaseError::Accessing)?;
+            if id == new_id {
+                self.seen_hashes
+                    .insert(hash, &original_id)
+                    .map_err(DatabaseError::Recording)?;
+            }
+        }
+
+        for occurrence in self.occurrences_of(&new_id)? {
+            self.insert_occurrence(&original_id, &occurrence)?;
+        }
+
+        for key in self.occurrences.scan_prefix(&new_id).keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            self.occurrences
+                .remove(key)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        for key in self.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            if key.ends_with(&new_id) {
+                self.guild_images
+                    .remove(key)
+                    .map_err(DatabaseError::Recording)?;
+            }
+        }
+
+        self.seen_counts
+            .insert(&original_id, &times_seen.to_le_bytes())
+            .map_err(DatabaseError::Recording)?;
+        self.seen_counts
+            .remove(&new_id)
+            .map_err(DatabaseError::Recording)?;
+        self.stored_images
+            .remove(&new_id)
+            .map_err(DatabaseError::Recording)?;
+        self.image_formats
+            .remove(&new_id)
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(Some(times_seen))
+    }
+
+    fn attribute_to_guild(&self, guild_id: u64, id: &[u8]) -> Result<(), DatabaseError> {
+        let mut key = guild_id.to_be_bytes().to_vec();
+        key.extend_from_slice(id);
+
+        self.guild_images
+            .insert(key, &[])
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(())
+    }
+
+    /// Records a link being shared, returning who shared it first and how many times it's been shared
+    /// including this one, if it was shared before.
+    ///
+    /// `link` should already be canonical, so the same link written differently is counted together.
+    pub fn record_link(
+        &self,
+        link: &str,
+        properties: &SeenImage,
+    ) -> Result<Option<(SeenImage, u64)>, DatabaseError> {
+        let read = |bytes: &[u8]| serde_json::from_slice::<SeenLink>(bytes);
+
+        let old = self
+            .seen_links
+            .fetch_and_update(self.hash_key(link.as_bytes()), |old| {
+                let seen = match old.map(read) {
+                    Some(Ok(old)) => SeenLink {
+                        times_seen: old.times_seen + 1,
+                        ..old
+                    },
+                    // A record that can't be read is started over.
+                    Some(Err(_)) | None => SeenLink {
+                        first: Occurrence::from(properties),
+                        times_seen: 1,
+                    },
+                };
+
+                Some(serde_json::to_vec(&seen).expect("bug: link serialization failed"))
+            })
+            .map_err(DatabaseError::Recording)?;
+
+        match old.as_deref().map(read) {
+            Some(Ok(old)) => Ok(Some((
+                SeenImage {
+                    author_id: old.first.author_id,
+                    ..SeenImage::new(
+                        old.first.author,
+                        old.first.sent,
+                        old.first.message_id,
+                        old.first.channel_id,
+                    )
+                },
+                old.times_seen + 1,
+            ))),
+            Some(Err(_)) | None => Ok(None),
+        }
+    }
+
+    /// Remembers that the bot is in a guild, returning if it just joined it.
+    pub fn guild_joined(&self, guild_id: u64) -> Result<bool, DatabaseError> {
+        let key = guild_id.to_be_bytes();
+
+        let known = self
+            .known_guilds
+            .insert(key, &[])
+            .map_err(DatabaseError::Recording)?
+            .is_some();
+        let has_history = self
+            .guild_settings
+            .contains_key(key)
+            .map_err(DatabaseError::Accessing)?
+            || self.guild_images.scan_prefix(key).next().is_some();
+
+        Ok(Self::newly_joined(known, has_history))
+    }
+
+    /// Remembers when the bot was removed from a guild, so its data can be purged later.
+    pub fn guild_removed(&self, guild_id: u64, now: u64) -> Result<(), DatabaseError> {
+        self.known_guilds
+            .insert(guild_id.to_be_bytes(), &now.to_be_bytes())
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(())
+    }
+
+    /// Purges the data of every guild the bot was removed from at least `grace` seconds ago,
+    /// returning each guild and how many of its images were deleted.
+    ///
+    /// Guilds the bot rejoined in the meantime are kept.
+    pub fn purge_removed_guilds(
+        &self,
+        now: u64,
+        grace: u64,
+    ) -> Result<Vec<(u64, usize)>, DatabaseError> {
+        let mut due = Vec::new();
+        for entry in self.known_guilds.iter() {
+            let (guild_id, removed_at) = entry.map_err(DatabaseError::Accessing)?;
+            let removed_at = removed_at.as_ref().try_into().ok().map(u64::from_be_bytes);
+
+            if Self::purge_due(removed_at, now, grace) {
+                due.push(u64::from_be_bytes(
+                    guild_id
+                        .as_ref()
+                        .try_into()
+                        .expect("bug: wrong number of bytes"),
+                ));
+            }
+        }
+
+        due.into_iter()
+            .map(|guild_id| Ok((guild_id, self.purge_guild(guild_id)?)))
+            .collect()
+    }
+
+    /// If a guild's data should be purged, given when the bot was removed from it if it was.
+    fn purge_due(removed_at: Option<u64>, now: u64, grace: u64) -> bool {
+        matches!(removed_at, Some(removed_at) if now.saturating_sub(removed_at) >= grace)
+    }
+
+    /// Deletes every image first seen in a guild along with its settings, returning how many images there were.
+    ///
+    /// Images recorded before they were attributed to guilds can't be told apart, so they're kept.
+    fn purge_guild(&self, guild_id: u64) -> Result<usize, DatabaseError> {
+        let prefix = guild_id.to_be_bytes();
+        let mut ids = HashSet::new();
+        for key in self.guild_images.scan_prefix(prefix).keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            ids.insert(IVec::from(&key[prefix.len()..]));
+
+            self.guild_images
+                .remove(&key)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        self.delete_images(&ids)?;
+
+        for key in self.offenders.scan_prefix(prefix).keys() {
+            self.offenders
+                .remove(key.map_err(DatabaseError::Accessing)?)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        for key in self.content_hashes.scan_prefix(prefix).keys() {
+            self.content_hashes
+                .remove(key.map_err(DatabaseError::Accessing)?)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        for key in self.seen_links.scan_prefix(prefix).keys() {
+            self.seen_links
+                .remove(key.map_err(DatabaseError::Accessing)?)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        self.guild_settings
+            .remove(prefix)
+            .map_err(DatabaseError::Recording)?;
+        self.known_guilds
+            .remove(prefix)
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(ids.len())
+    }
+
+    /// Deletes every image that hasn't been seen for at least `retention` seconds, along with its hashes,
+    /// counts, and history, returning how many were deleted.
+    ///
+    /// Ignored images are kept, since deleting them would stop them being ignored.
+    pub fn expire_images(&self, now: u64, retention: u64) -> Result<usize, DatabaseError> {
+        self.purge_images(
+            now,
+            &PurgeCriteria {
+                older_than: retention,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [Data::expire_images], but only images matching `criteria` are deleted.
+    ///
+    /// If this is scoped to a guild, only images first seen in it are looked at.
+    pub fn purge_images(&self, now: u64, criteria: &PurgeCriteria) -> Result<usize, DatabaseError> {
+        let ids: Box<dyn Iterator<Item = sled::Result<IVec>>> = match self.guild {
+            Some(guild_id) => {
+                let prefix = guild_id.to_be_bytes();
+                Box::new(
+                    self.guild_images
+                        .scan_prefix(prefix)
+                        .keys()
+                        .map(move |key| key.map(|key| IVec::from(&key[prefix.len()..]))),
+                )
+            }
+            None => Box::new(self.stored_images.iter().keys()),
+        };
+
+        let mut purged = HashSet::new();
+        for id in ids {
+            let id = id.map_err(DatabaseError::Accessing)?;
+            let image = match self
                 .stored_images
                 .get(&id)
                 .map_err(DatabaseError::Accessing)?
-                .expect("bug: image_access knew about a hash but nothing was stored");
+            {
+                Some(image) => image,
+                None => continue,
+            };
+            let image = Self::read_archived::<SeenImage>(&image);
+
+            if image.ignored
+                || criteria.channel_id.is_some_and(|id| image.channel_id != id)
+                || criteria.author_id.is_some_and(|id| image.author_id != id)
+            {
+                continue;
+            }
+
+            let last_seen = self
+                .last_occurrence(&id)?
+                .map_or(image.sent.value(), |occurrence| occurrence.sent);
+            if Self::expired(last_seen, now, criteria.older_than) {
+                purged.insert(id);
+            }
+        }
+
+        if purged.is_empty() {
+            return Ok(0);
+        }
+
+        for key in self.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            if purged.contains(&key[core::mem::size_of::<u64>()..]) {
+                self.guild_images
+                    .remove(key)
+                    .map_err(DatabaseError::Recording)?;
+            }
+        }
+
+        self.delete_images(&purged)?;
+
+        Ok(purged.len())
+    }
+
+    /// If an image last seen at `last_seen` is past the retention window.
+    fn expired(last_seen: u64, now: u64, retention: u64) -> bool {
+        now.saturating_sub(last_seen) >= retention
+    }
+
+    /// Deletes images and everything about them, other than which guild they were seen in first.
+    fn delete_images(&self, ids: &HashSet<IVec>) -> Result<(), DatabaseError> {
+        self.flush_counts()?;
+
+        for entry in self.seen_hashes.iter() {
+            let (hash, id) = entry.map_err(DatabaseError::Accessing)?;
+            if ids.contains(&id) {
+                self.seen_hashes
+                    .remove(&hash)
+                    .map_err(DatabaseError::Recording)?;
+                self.index_mut().remove(&hash);
+            }
+        }
+
+        for id in ids {
+            self.stored_images
+                .remove(id)
+                .map_err(DatabaseError::Recording)?;
+            self.seen_counts
+                .remove(id)
+                .map_err(DatabaseError::Recording)?;
+            self.image_formats
+                .remove(id)
+                .map_err(DatabaseError::Recording)?;
+
+            for key in self.occurrences.scan_prefix(id).keys() {
+                self.occurrences
+                    .remove(key.map_err(DatabaseError::Accessing)?)
+                    .map_err(DatabaseError::Recording)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the image a hash belongs to, along with every other hash that was recorded as it.
+    pub fn forget_image(&self, hash: &[u8]) -> Result<Option<ForgottenImage>, DatabaseError> {
+        let id = match self
+            .seen_hashes
+            .get(self.hash_key(hash))
+            .map_err(DatabaseError::Accessing)?
+        {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        self.flush_counts()?;
+        let times_seen = self.stored_count(&id)?;
+
+        let mut aliases = 0;
+        for alias_id in self.seen_hashes.iter().values() {
+            if alias_id.map_err(DatabaseError::Accessing)? == id {
+                aliases += 1;
+            }
+        }
+
+        for key in self.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            if key[core::mem::size_of::<u64>()..] == id[..] {
+                self.guild_images
+                    .remove(key)
+                    .map_err(DatabaseError::Recording)?;
+            }
+        }
+
+        self.delete_images(&std::iter::once(id).collect())?;
+
+        Ok(Some(ForgottenImage {
+            aliases,
+            times_seen,
+        }))
+    }
+
+    /// Makes sure there's room for another image in the guild's quota, returning if there is.
+    ///
+    /// Finding the image to evict looks at every image in the guild, but only happens once it's full.
+    fn make_room(&self) -> Result<bool, DatabaseError> {
+        let (guild_id, quota) = match (self.guild, self.record_quota) {
+            (Some(guild_id), Some(quota)) => (guild_id, quota),
+            _ => return Ok(true),
+        };
+
+        let prefix = guild_id.to_be_bytes();
+        let stored = self.guild_images.scan_prefix(prefix).count() as u64;
+        if stored < quota {
+            return Ok(true);
+        }
+
+        if self.quota_policy == QuotaPolicy::Refuse {
+            tracing::warn!(
+                "Guild {} has {} images stored, not storing more past its quota",
+                guild_id,
+                stored
+            );
+            return Ok(false);
+        }
+
+        // Evicting an ignored image would stop it being ignored, so those stay.
+        let mut candidates = Vec::new();
+        for key in self.guild_images.scan_prefix(prefix).keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let id = IVec::from(&key[prefix.len()..]);
+
+            let image = match self
+                .stored_images
+                .get(&id)
+                .map_err(DatabaseError::Accessing)?
+            {
+                Some(image) => image,
+                None => continue,
+            };
+            let image = Self::read_archived::<SeenImage>(&image);
+            if image.ignored {
+                continue;
+            }
+
+            let last_seen = self
+                .last_occurrence(&id)?
+                .map_or(image.sent.value(), |occurrence| occurrence.sent);
+            candidates.push((id, last_seen));
+        }
+
+        let evicted = Self::eviction_order(candidates, stored + 1 - quota);
+        if evicted.is_empty() {
+            tracing::warn!(
+                "Guild {} is at its quota with only ignored images, not storing more",
+                guild_id
+            );
+            return Ok(false);
+        }
+
+        for id in &evicted {
+            let mut key = prefix.to_vec();
+            key.extend_from_slice(id);
+            self.guild_images
+                .remove(key)
+                .map_err(DatabaseError::Recording)?;
+        }
+        self.delete_images(&evicted.into_iter().collect())?;
+
+        Ok(true)
+    }
+
+    /// Picks up to `count` images to evict from `(ID, last seen)` pairs, least recently seen first.
+    fn eviction_order(mut candidates: Vec<(IVec, u64)>, count: u64) -> Vec<IVec> {
+        candidates.sort_by_key(|(_, last_seen)| *last_seen);
+
+        candidates
+            .into_iter()
+            .take(count as usize)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// If a guild is new to the bot, given if it's known and if it has settings or images from before.
+    ///
+    /// Guilds the bot was in before they were remembered still have history, so they aren't new.
+    fn newly_joined(known: bool, has_history: bool) -> bool {
+        !known && !has_history
+    }
+
+    /// Sums up the images tracked in a guild.
+    pub fn guild_stats(&self, guild_id: u64) -> Result<GuildStats, DatabaseError> {
+        self.flush_counts()?;
+
+        let prefix = guild_id.to_be_bytes();
+        let mut stats = GuildStats::default();
+        let mut channels = HashMap::<u64, usize>::new();
+
+        for key in self.guild_images.scan_prefix(prefix).keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let id = &key[prefix.len()..];
+
+            let image = match self.stored_image(id)? {
+                Some(image) => image,
+                None => continue,
+            };
+            let times_seen = self.stored_count(id)?;
+
+            stats.images += 1;
+            stats.reposts += times_seen.saturating_sub(1);
+            *channels.entry(image.channel_id).or_default() += 1;
+
+            let most = stats.most_reposted.as_ref().map_or(1, |(_, most)| *most);
+            if times_seen > most {
+                stats.most_reposted = Some((image, times_seen));
+            }
+        }
+
+        // Ties go to the lowest ID, so the answer doesn't change between calls.
+        stats.busiest_channel = channels
+            .into_iter()
+            .max_by(|(a_id, a), (b_id, b)| a.cmp(b).then(b_id.cmp(a_id)));
+
+        Ok(stats)
+    }
+
+    /// Counts another sighting of the image with database ID `id`, returning how many times it's been seen.
+    ///
+    /// Sightings inside the count cooldown aren't counted or added to the image's history, so the cooldown
+    /// runs from the last counted one.
+    fn count_sighting(&self, id: &[u8], image: &SeenImage) -> Result<u64, DatabaseError> {
+        let last_counted = self.last_occurrence(id)?.map(|occurrence| occurrence.sent);
+
+        if !Self::counts_again(last_counted, image.sent, self.config.count_cooldown) {
+            let pending = match &self.pending_counts {
+                Some(pending) => pending.lock().get(id).copied().unwrap_or(0),
+                None => 0,
+            };
+
+            return Ok(self.stored_count(id)? + pending);
+        }
+
+        if let Some(pending) = &self.pending_counts {
+            // The lock is held while reading, so a flush can't count the increment twice.
+            let mut pending = pending.lock();
+            let times_seen = self.stored_count(id)?;
+            let increments = pending.entry(IVec::from(id)).or_insert(0);
+            *increments += 1;
+            let times_seen = times_seen + *increments;
+            drop(pending);
+
+            self.record_occurrence(id, image)?;
+            return Ok(times_seen);
+        }
+
+        let times_seen = self
+            .seen_counts
+            .update_and_fetch(id, |old| {
+                let mut new = Self::read_int(
+                    old.expect("bug: database ID pointed at a hash but no seen_count was found"),
+                );
+                new += 1;
+
+                Some(IVec::from(&new.to_le_bytes()))
+            })
+            .map_err(DatabaseError::Recording)?
+            .expect("bug: count_sighting update_and_fetch returned None");
+
+        self.record_occurrence(id, image)?;
+
+        Ok(Self::read_int(&times_seen))
+    }
+
+    fn stored_count(&self, id: &[u8]) -> Result<u64, DatabaseError> {
+        let times_seen = self
+            .seen_counts
+            .get(id)
+            .map_err(DatabaseError::Accessing)?
+            .expect("bug: database ID pointed at a hash but no seen_count was found");
+
+        Ok(Self::read_int(&times_seen))
+    }
+
+    /// Writes batched count increments, and everything else that's only in memory, to disk.
+    pub fn flush(&self) -> Result<(), DatabaseError> {
+        self.flush_counts()?;
+        self.db.flush().map_err(DatabaseError::Recording)?;
+
+        Ok(())
+    }
+
+    /// Writes any batched count increments to the database.
+    pub fn flush_counts(&self) -> Result<(), DatabaseError> {
+        match &self.pending_counts {
+            Some(pending) => pending.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// If a sighting at `now` counts, given when the image was last counted and the cooldown in seconds.
+    fn counts_again(last_counted: Option<u64>, now: u64, cooldown: u64) -> bool {
+        match last_counted {
+            Some(last) if cooldown > 0 => now.saturating_sub(last) >= cooldown,
+            _ => true,
+        }
+    }
+
+    fn record_occurrence(&self, id: &[u8], image: &SeenImage) -> Result<(), DatabaseError> {
+        self.insert_occurrence(id, &Occurrence::from(image))
+    }
+
+    fn insert_occurrence(&self, id: &[u8], occurrence: &Occurrence) -> Result<(), DatabaseError> {
+        // Generated IDs only increase, so occurrences are kept in the order they were recorded.
+        let mut key = id.to_vec();
+        key.extend_from_slice(
+            &self
+                .db
+                .generate_id()
+                .map_err(DatabaseError::Recording)?
+                .to_be_bytes(),
+        );
+
+        let occurrence =
+            serde_json::to_vec(occurrence).expect("bug: occurrence failed to serialize");
+
+        self.occurrences
+            .insert(key, occurrence)
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(())
+    }
+
+    /// Takes back the sightings of images recorded from a message, returning how many there were.
+    ///
+    /// Counts never drop below one, since the image is still stored.
+    pub fn uncount_message(&self, message_id: u64) -> Result<usize, DatabaseError> {
+        self.flush_counts()?;
+        let mut uncounted = 0;
+
+        for entry in self.occurrences.iter() {
+            let (key, occurrence) = entry.map_err(DatabaseError::Accessing)?;
+            let occurrence: Occurrence =
+                serde_json::from_slice(&occurrence).map_err(DatabaseError::CorruptOccurrence)?;
+
+            if occurrence.message_id != message_id {
+                continue;
+            }
+
+            // Occurrence keys start with the ID of the image they belong to.
+            let id = &key[..key.len() - std::mem::size_of::<u64>()];
+            self.seen_counts
+                .update_and_fetch(id, |count| {
+                    let count = Self::read_int(count?);
+                    Some(IVec::from(&count.saturating_sub(1).max(1).to_le_bytes()))
+                })
+                .map_err(DatabaseError::Recording)?;
+            self.occurrences
+                .remove(&key)
+                .map_err(DatabaseError::Recording)?;
+
+            uncounted += 1;
+        }
+
+        Ok(uncounted)
+    }
+
+    /// The newest recorded occurrence of the image with database ID `id`.
+    fn last_occurrence(&self, id: &[u8]) -> Result<Option<Occurrence>, DatabaseError> {
+        match self.occurrences.scan_prefix(id).values().next_back() {
+            Some(occurrence) => {
+                let occurrence = occurrence.map_err(DatabaseError::Accessing)?;
+                serde_json::from_slice(&occurrence)
+                    .map(Some)
+                    .map_err(DatabaseError::CorruptOccurrence)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Every recorded occurrence of the image with database ID `id`, oldest first.
+    fn occurrences_of(&self, id: &[u8]) -> Result<Vec<Occurrence>, DatabaseError> {
+        self.occurrences
+            .scan_prefix(id)
+            .values()
+            .map(|occurrence| {
+                let occurrence = occurrence.map_err(DatabaseError::Accessing)?;
+                serde_json::from_slice(&occurrence).map_err(DatabaseError::CorruptOccurrence)
+            })
+            .collect()
+    }
+
+    /// Fixes seen counts that don't match how many times an image's occurrences were recorded,
+    /// returning how many were repaired.
+    ///
+    /// Images recorded before occurrences were tracked don't have a full history, so they're left alone. Images
+    /// that started over after the repost window are only counted from the sighting they started over with.
+    pub fn repair_counts(&self) -> Result<usize, DatabaseError> {
+        self.flush_counts()?;
+        let mut repaired = 0;
+
+        for entry in self.stored_images.iter() {
+            let (id, image) = entry.map_err(DatabaseError::Accessing)?;
+            // Unlike where it was posted, when it was first posted is only changed by starting over.
+            let first_sent = Self::read_archived::<SeenImage>(&image).sent.value();
+
+            let history = self.occurrences_of(&id)?;
+            let first = match history.iter().position(|o| o.sent == first_sent) {
+                Some(first) => first,
+                None => continue,
+            };
+
+            let expected = (history.len() - first) as u64;
+            let count = self
+                .seen_counts
+                .get(&id)
+                .map_err(DatabaseError::Accessing)?
+                .map(|count| Self::read_int(&count));
+
+            if count != Some(expected) {
+                tracing::warn!(
+                    "Image {} had a seen count of {:?}, but {} recorded occurrences",
+                    Self::read_int(&id),
+                    count,
+                    expected
+                );
+
+                self.seen_counts
+                    .insert(&id, &expected.to_le_bytes())
+                    .map_err(DatabaseError::Recording)?;
+                repaired += 1;
+            }
+        }
+
+        Ok(repaired)
+    }
+
+    fn access_stored<F: Fn(Pin<&mut ArchivedSeenImage>) -> bool>(
+        &self,
+        id: IVec,
+        f: F,
+    ) -> Result<(), DatabaseError> {
+        let mut buf = self
+            .stored_images
+            .get(&id)
+            .map_err(DatabaseError::Accessing)?
+            .expect("bug: image_access knew about a hash but nothing was stored");
+
+        let needs_modified = {
+            let buffer = Pin::new(buf.as_mut());
+
+            // SAFETY: We know we're pulling out of the images table, which are the right type, and this is tested.
+            let archived = unsafe { rkyv::archived_root_mut::<SeenImage>(buffer) };
+
+            f(archived)
+        };
+
+        if needs_modified {
+            self.stored_images
+                .insert(id, buf)
+                .map_err(DatabaseError::Accessing)?;
+        }
+
+        Ok(())
+    }
+
+    /// Most hashes sampled by [Data::distance_histogram], keeping it to ~125k comparisons.
+    pub const MAX_HISTOGRAM_SAMPLE: usize = 500;
+
+    /// Counts the distances between pairs of stored hashes, split into `buckets` equally sized ranges.
+    ///
+    /// Up to `sample_size` hashes are picked evenly across the database and every pair of them is compared,
+    /// so the cost is quadratic in the sample size, which is capped at [Data::MAX_HISTOGRAM_SAMPLE].
+    pub fn distance_histogram(
+        &self,
+        sample_size: usize,
+        buckets: usize,
+    ) -> Result<Vec<u64>, DatabaseError> {
+        let buckets = buckets.max(1);
+        let sample_size = sample_size.min(Self::MAX_HISTOGRAM_SAMPLE);
+        let step = (self.seen_hashes.len() / sample_size.max(1)).max(1);
+
+        let sample = self
+            .seen_hashes
+            .iter()
+            .keys()
+            .step_by(step)
+            .take(sample_size)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(DatabaseError::Accessing)?;
+
+        let bucket_width = Self::histogram_bucket_width(buckets);
+        let mut histogram = vec![0; buckets];
+
+        for (i, key) in sample.iter().enumerate() {
+            let hash = ImageHash::from_bytes(Self::unprefixed(key))
+                .expect("bug: sled returned the wrong key size");
+
+            for other in &sample[i + 1..] {
+                let distance = image_processing::hash_distance(&hash, Self::unprefixed(other));
+                histogram[(distance / bucket_width) as usize] += 1;
+            }
+        }
+
+        Ok(histogram)
+    }
+
+    /// How many distances each bucket of a histogram with `buckets` buckets covers.
+    pub fn histogram_bucket_width(buckets: usize) -> u32 {
+        // Distances are inclusive of `MAX_DISTANCE`, so there's one more possible value.
+        let possible = image_processing::MAX_DISTANCE + 1;
+        let buckets = buckets.max(1) as u32;
+
+        possible.div_ceil(buckets)
+    }
+
+    /// Moves every image a guild saw in channel `old` over to channel `new`, returning how many were moved.
+    ///
+    /// This keeps jump links and replies working for channels that were recreated with a new ID. Occurrences in
+    /// the channel are moved too, so histories point at the new one.
+    pub fn remap_channel(&self, guild_id: u64, old: u64, new: u64) -> Result<usize, DatabaseError> {
+        let prefix = guild_id.to_be_bytes();
+        let mut remapped = 0;
+
+        for key in self.guild_images.scan_prefix(prefix).keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let id = &key[prefix.len()..];
+
+            for entry in self.occurrences.scan_prefix(id) {
+                let (key, occurrence) = entry.map_err(DatabaseError::Accessing)?;
+                let mut occurrence: Occurrence = serde_json::from_slice(&occurrence)
+                    .map_err(DatabaseError::CorruptOccurrence)?;
+                if occurrence.channel_id != old {
+                    continue;
+                }
+
+                occurrence.channel_id = new;
+                self.occurrences
+                    .insert(
+                        key,
+                        serde_json::to_vec(&occurrence)
+                            .expect("bug: occurrence failed to serialize"),
+                    )
+                    .map_err(DatabaseError::Recording)?;
+            }
+
+            let mut buf = match self
+                .stored_images
+                .get(id)
+                .map_err(DatabaseError::Accessing)?
+            {
+                Some(buf) => buf,
+                None => continue,
+            };
+
+            {
+                let buffer = Pin::new(buf.as_mut());
+
+                // SAFETY: We know we're pulling out of the images table, which are the right type, and this is tested.
+                let mut archived = unsafe { rkyv::archived_root_mut::<SeenImage>(buffer) };
+                if archived.channel_id != old {
+                    continue;
+                }
+
+                archived.as_mut().get_mut().channel_id = new.into();
+            }
+
+            self.stored_images
+                .insert(id, buf)
+                .map_err(DatabaseError::Recording)?;
+            remapped += 1;
+        }
+
+        Ok(remapped)
+    }
+
+    /// Fetches a guild's settings, or the defaults if it hasn't changed any.
+    pub fn guild_settings(&self, guild_id: u64) -> Result<GuildSettings, DatabaseError> {
+        match self
+            .guild_settings
+            .get(guild_id.to_be_bytes())
+            .map_err(DatabaseError::Accessing)?
+        {
+            Some(raw) => serde_json::from_slice(&raw).map_err(DatabaseError::CorruptSettings),
+            None => Ok(GuildSettings::default()),
+        }
+    }
+
+    /// Applies `f` to a guild's settings and saves them, returning the updated settings.
+    pub fn update_guild_settings<F: FnOnce(&mut GuildSettings)>(
+        &self,
+        guild_id: u64,
+        f: F,
+    ) -> Result<GuildSettings, DatabaseError> {
+        let mut settings = self.guild_settings(guild_id)?;
+        f(&mut settings);
+
+        let raw = serde_json::to_vec(&settings).expect("bug: guild settings failed to serialize");
+        self.guild_settings
+            .insert(guild_id.to_be_bytes(), raw)
+            .map_err(DatabaseError::Recording)?;
+
+        Ok(settings)
+    }
+
+    /// Writes every image, hash, link, repost offender, and guild's settings to `writer`, signed with `key`
+    /// if there is one.
+    pub fn export_to_writer(
+        &self,
+        writer: impl std::io::Write,
+        key: Option<&[u8]>,
+    ) -> Result<(), TransferError> {
+        transfer::write(&self.dump()?, writer, key)
+    }
+
+    /// Everything that's exported, in memory.
+    pub fn dump(&self) -> Result<Dump, DatabaseError> {
+        self.flush_counts()?;
+        let mut dump = Dump {
+            schema_version: CURRENT_VERSION,
+            ..Dump::default()
+        };
+        let mut hashes = std::collections::HashMap::<IVec, Vec<String>>::new();
+        let mut guilds = std::collections::HashMap::<Vec<u8>, u64>::new();
+
+        for key in self.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let (guild_id, id) = key.split_at(8);
+            guilds.insert(
+                id.to_vec(),
+                u64::from_be_bytes(guild_id.try_into().expect("bug: wrong number of bytes")),
+            );
+        }
+
+        for entry in self.seen_hashes.iter() {
+            let (key, id) = entry.map_err(DatabaseError::Accessing)?;
+            hashes
+                .entry(id)
+                .or_default()
+                .push(hex::encode(Self::unprefixed(&key)));
+        }
+
+        for entry in self.stored_images.iter() {
+            let (id, image) = entry.map_err(DatabaseError::Accessing)?;
+
+            let times_seen = self
+                .seen_counts
+                .get(&id)
+                .map_err(DatabaseError::Accessing)?
+                .expect("bug: image existed but its count didn't");
+
+            let mut deserializer = SharedDeserializeMap::new();
+            let image = Self::read_archived::<SeenImage>(&image)
+                .deserialize(&mut deserializer)
+                .expect("deserialization can never fail");
+
+            let format = self
+                .image_formats
+                .get(&id)
+                .map_err(DatabaseError::Accessing)?
+                .map(|format| String::from_utf8_lossy(&format).into_owned());
+
+            dump.images.push(DumpedImage {
+                times_seen: Self::read_int(&times_seen),
+                hashes: hashes.remove(&id).unwrap_or_default(),
+                occurrences: self.occurrences_of(&id)?,
+                guild_id: guilds.remove(id.as_ref()),
+                format,
+                image,
+            });
+        }
+
+        for entry in self.seen_links.iter() {
+            let (key, link) = entry.map_err(DatabaseError::Accessing)?;
+            // Links that can't be read would be started over anyway.
+            let link: SeenLink = match serde_json::from_slice(&link) {
+                Ok(link) => link,
+                Err(_) => continue,
+            };
+
+            let (guild_id, canonical) = key.split_at(8);
+            dump.links.push(DumpedLink {
+                guild_id: u64::from_be_bytes(
+                    guild_id.try_into().expect("bug: wrong number of bytes"),
+                ),
+                link: String::from_utf8_lossy(canonical).into_owned(),
+                first: link.first,
+                times_seen: link.times_seen,
+            });
+        }
+
+        for entry in self.offenders.iter() {
+            let (key, reposts) = entry.map_err(DatabaseError::Accessing)?;
+            let (guild_id, user_id) = key.split_at(8);
+
+            dump.offenders.push(DumpedOffender {
+                guild_id: u64::from_be_bytes(
+                    guild_id.try_into().expect("bug: wrong number of bytes"),
+                ),
+                user_id: u64::from_be_bytes(
+                    user_id.try_into().expect("bug: wrong number of bytes"),
+                ),
+                reposts: Self::read_int(&reposts),
+            });
+        }
+
+        for entry in self.guild_settings.iter() {
+            let (guild_id, settings) = entry.map_err(DatabaseError::Accessing)?;
+
+            dump.guild_settings.push(DumpedGuild {
+                guild_id: u64::from_be_bytes(
+                    guild_id
+                        .as_ref()
+                        .try_into()
+                        .expect("bug: wrong number of bytes"),
+                ),
+                settings: serde_json::from_slice(&settings)
+                    .map_err(DatabaseError::CorruptSettings)?,
+            });
+        }
+
+        Ok(dump)
+    }
+
+    pub(crate) fn decode_hash(hash: &str) -> Result<Vec<u8>, TransferError> {
+        match hex::decode(hash) {
+            Ok(hash) if hash.len() == image_processing::HASH_BYTES => Ok(hash),
+            _ => Err(TransferError::Malformed(serde::de::Error::custom(
+                "exported image hash was invalid",
+            ))),
+        }
+    }
+
+    /// Writes every guild's settings and the hashes of ignored images, leaving out everything else.
+    pub fn export_policy(&self, writer: impl std::io::Write) -> Result<(), TransferError> {
+        let mut guild_settings = Vec::new();
+        for entry in self.guild_settings.iter() {
+            let (guild_id, settings) = entry.map_err(DatabaseError::Accessing)?;
+
+            guild_settings.push(DumpedGuild {
+                guild_id: u64::from_be_bytes(
+                    guild_id
+                        .as_ref()
+                        .try_into()
+                        .expect("bug: guild settings key wasn't a u64"),
+                ),
+                settings: serde_json::from_slice(&settings)
+                    .map_err(DatabaseError::CorruptSettings)?,
+            });
+        }
+
+        let mut ignored_hashes = Vec::new();
+        for entry in self.seen_hashes.iter() {
+            let (key, id) = entry.map_err(DatabaseError::Accessing)?;
+            let image = self
+                .stored_images
+                .get(&id)
+                .map_err(DatabaseError::Accessing)?
+                .expect("bug: database ID pointed at dead image");
+
+            if Self::read_archived::<SeenImage>(&image).ignored {
+                let (guild_id, hash) = key.split_at(core::mem::size_of::<u64>());
+                ignored_hashes.push(transfer::IgnoredHash {
+                    guild_id: u64::from_be_bytes(
+                        guild_id.try_into().expect("bug: hash key was too short"),
+                    ),
+                    hash: hex::encode(hash),
+                });
+            }
+        }
+
+        transfer::Policy {
+            version: transfer::POLICY_VERSION,
+            guild_settings,
+            ignored_hashes,
+        }
+        .write(writer)
+    }
+
+    /// Applies a policy written by [Data::export_policy], returning how many ignored hashes were
+    /// known here.
+    ///
+    /// Guild settings are replaced by the policy's. Images are only ever marked as ignored, never
+    /// added, removed, or recounted, so ignored hashes this database hasn't seen are skipped.
+    pub fn import_policy(&self, reader: impl std::io::Read) -> Result<usize, TransferError> {
+        let policy = transfer::Policy::read(reader)?;
+
+        let keys = policy
+            .ignored_hashes
+            .iter()
+            .map(|ignored| {
+                let hash = Self::decode_hash(&ignored.hash)?;
+                Ok([&ignored.guild_id.to_be_bytes()[..], &hash].concat())
+            })
+            .collect::<Result<Vec<_>, TransferError>>()?;
+
+        for guild in &policy.guild_settings {
+            let settings =
+                serde_json::to_vec(&guild.settings).expect("bug: settings failed to serialize");
+
+            self.guild_settings
+                .insert(guild.guild_id.to_be_bytes(), settings)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        // Hashes are only ignored in the guild that ignored them.
+        let mut applied = 0;
+        for key in &keys {
+            if let Some(id) = self
+                .seen_hashes
+                .get(key)
+                .map_err(DatabaseError::Accessing)?
+            {
+                self.access_stored(id, |mut image| {
+                    image.ignored = true;
+                    true
+                })?;
+                applied += 1;
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Adds the contents of an export to the database, returning how many images were imported.
+    ///
+    /// The export's signature, storage format version, and hashes are checked before anything is written.
+    /// Images get new database IDs, but an export with a hash that's already stored in the same guild is
+    /// rejected, since it'd be pointed at two images.
+    pub fn import_from_reader(
+        &self,
+        reader: impl std::io::Read,
+        key: Option<&[u8]>,
+    ) -> Result<usize, TransferError> {
+        self.flush_counts()?;
+        let dump = transfer::read(reader, key)?;
+        if dump.schema_version > CURRENT_VERSION {
+            return Err(TransferError::NewerSchema(dump.schema_version));
+        }
+
+        let mut keys = HashSet::new();
+        let mut images = Vec::with_capacity(dump.images.len());
+        for dumped in &dump.images {
+            let prefix = dumped.guild_id.unwrap_or(0).to_be_bytes();
+            let mut hash_keys = Vec::with_capacity(dumped.hashes.len());
+            for hash in &dumped.hashes {
+                let key = [&prefix[..], &Self::decode_hash(hash)?].concat();
+                if !keys.insert(key.clone())
+                    || self
+                        .seen_hashes
+                        .contains_key(&key)
+                        .map_err(DatabaseError::Accessing)?
+                {
+                    return Err(TransferError::ConflictingHash(hash.clone()));
+                }
+
+                hash_keys.push(key);
+            }
+
+            images.push((dumped, hash_keys));
+        }
+
+        for (dumped, hash_keys) in images {
+            let id = self
+                .db
+                .generate_id()
+                .map_err(DatabaseError::Recording)?
+                .to_le_bytes();
+
+            // Exports from before images knew their guild only have it alongside them.
+            let image = SeenImage {
+                author: dumped.image.author.clone(),
+                source_url: dumped.image.source_url.clone(),
+                guild_id: match dumped.image.guild_id {
+                    0 => dumped.guild_id.unwrap_or(0),
+                    guild_id => guild_id,
+                },
+                ..dumped.image
+            };
+            let mut serializer = WriteSerializer::new(Vec::new());
+            serializer
+                .serialize_value(&image)
+                .expect("bug: serialization failed");
+
+            self.seen_counts
+                .insert(id, &dumped.times_seen.to_le_bytes())
+                .map_err(DatabaseError::Recording)?;
+            self.stored_images
+                .insert(id, serializer.into_inner())
+                .map_err(DatabaseError::Recording)?;
+
+            for key in hash_keys {
+                self.insert_hash(key, &id)?;
+            }
+
+            for occurrence in &dumped.occurrences {
+                self.insert_occurrence(&id, occurrence)?;
+            }
+
+            if let Some(guild_id) = dumped.guild_id {
+                self.attribute_to_guild(guild_id, &id)?;
+            }
+
+            if let Some(format) = &dumped.format {
+                self.image_formats
+                    .insert(id, format.as_bytes())
+                    .map_err(DatabaseError::Recording)?;
+            }
+        }
+
+        for dumped in &dump.links {
+            let link = SeenLink {
+                first: dumped.first.clone(),
+                times_seen: dumped.times_seen,
+            };
+
+            self.seen_links
+                .insert(
+                    [&dumped.guild_id.to_be_bytes()[..], dumped.link.as_bytes()].concat(),
+                    serde_json::to_vec(&link).expect("bug: link serialization failed"),
+                )
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        for dumped in &dump.offenders {
+            self.offenders
+                .insert(
+                    [dumped.guild_id.to_be_bytes(), dumped.user_id.to_be_bytes()].concat(),
+                    &dumped.reposts.to_le_bytes(),
+                )
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        for guild in &dump.guild_settings {
+            let settings =
+                serde_json::to_vec(&guild.settings).expect("bug: settings failed to serialize");
+
+            self.guild_settings
+                .insert(guild.guild_id.to_be_bytes(), settings)
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        Ok(dump.images.len())
+    }
+
+    /// Reads the version metadata the database was created with.
+    pub fn version_info(&self) -> Result<VersionInfo, DatabaseError> {
+        let stored_version = self
+            .db
+            .get(Self::VERSION_KEY)
+            .map_err(DatabaseError::Accessing)?
+            .expect("bug: database version wasn't set on init")[0];
+
+        let migrations_run = MIGRATORS
+            .iter()
+            .take(usize::from(stored_version))
+            .map(|(name, _)| *name)
+            .collect();
+
+        Ok(VersionInfo {
+            stored_version,
+            current_version: CURRENT_VERSION,
+            migrations_run,
+        })
+    }
+
+    /// Checks the database can still be read, without going through every image like [Data::storage_stats].
+    pub fn check(&self) -> Result<(), DatabaseError> {
+        self.db.size_on_disk().map_err(DatabaseError::Accessing)?;
+        self.known_guilds
+            .first()
+            .map_err(DatabaseError::Accessing)?;
+        Ok(())
+    }
+
+    pub fn storage_stats(&self) -> Result<StorageStats, DatabaseError> {
+        Ok(StorageStats {
+            size_on_disk: self.db.size_on_disk().map_err(DatabaseError::Accessing)?,
+            stored_images: self.stored_images.len(),
+            seen_counts: self.seen_counts.len(),
+            seen_hashes: self.seen_hashes.len(),
+        })
+    }
+}
+
+impl Storage for Data {
+    fn config(&self) -> &DetectionConfig {
+        Data::config(self)
+    }
+
+    fn record_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error> {
+        self.record_raw(hashes, properties)
+    }
+
+    fn match_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error> {
+        self.match_raw(hashes, properties)
+    }
+
+    fn access_image(
+        &self,
+        image_hash: &[u8],
+        f: &mut dyn FnMut(&mut SeenImage) -> bool,
+    ) -> Result<(), DatabaseError> {
+        let id = match self
+            .seen_hashes
+            .get(self.hash_key(image_hash))
+            .map_err(DatabaseError::Accessing)?
+        {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let buf = self
+            .stored_images
+            .get(&id)
+            .map_err(DatabaseError::Accessing)?
+            .expect("bug: image_access knew about a hash but nothing was stored");
+
+        let mut image: SeenImage = Self::read_archived::<SeenImage>(&buf)
+            .deserialize(&mut SharedDeserializeMap::new())
+            .expect("deserialization can never fail");
+
+        if f(&mut image) {
+            self.stored_images
+                .insert(id, migrations::serialize(&image))
+                .map_err(DatabaseError::Accessing)?;
+        }
+
+        Ok(())
+    }
+
+    fn total_seen(&self) -> usize {
+        self.stored_images.len()
+    }
+
+    fn record_offense(&self, guild_id: u64, user_id: u64) -> Result<u64, DatabaseError> {
+        let mut key = guild_id.to_be_bytes().to_vec();
+        key.extend_from_slice(&user_id.to_be_bytes());
+
+        let offenses = self
+            .offenders
+            .update_and_fetch(key, |old| {
+                let offenses = old.map_or(0, Self::read_int) + 1;
+                Some(IVec::from(&offenses.to_le_bytes()))
+            })
+            .map_err(DatabaseError::Recording)?
+            .expect("bug: record_offense update_and_fetch returned None");
+
+        Ok(Self::read_int(&offenses))
+    }
+
+    fn top_offenders(&self, guild_id: u64, limit: usize) -> Result<Vec<(u64, u64)>, DatabaseError> {
+        let mut offenders = Vec::new();
+        for entry in self.offenders.scan_prefix(guild_id.to_be_bytes()) {
+            let (key, offenses) = entry.map_err(DatabaseError::Accessing)?;
+            let user_id =
+                u64::from_be_bytes(key[8..].try_into().expect("bug: wrong number of bytes"));
+            offenders.push((user_id, Self::read_int(&offenses)));
+        }
+
+        // Ties are broken by ID, so the order doesn't change between calls.
+        offenders.sort_unstable_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+        offenders.truncate(limit);
+
+        Ok(offenders)
+    }
+
+    fn per_guild_counts(&self) -> Result<Vec<(u64, usize)>, DatabaseError> {
+        // Images recorded before they were attributed to guilds aren't counted.
+        let mut counts: Vec<(u64, usize)> = Vec::new();
+
+        for key in self.guild_images.iter().keys() {
+            let key = key.map_err(DatabaseError::Accessing)?;
+            let guild_id =
+                u64::from_be_bytes(key[..8].try_into().expect("bug: wrong number of bytes"));
+
+            // Keys are sorted by guild, so each guild's images are next to each other.
+            match counts.last_mut() {
+                Some((last, count)) if *last == guild_id => *count += 1,
+                _ => counts.push((guild_id, 1)),
+            }
+        }
+
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        Ok(counts)
+    }
+}
+
+/// Which version of the storage format a database is on.
+#[derive(Debug, PartialEq)]
+pub struct VersionInfo {
+    /// Version recorded in the database.
+    pub stored_version: u8,
+    /// Version this build of the bot writes.
+    pub current_version: u8,
+    /// Names of the migrations that have been applied, oldest first.
+    pub migrations_run: Vec<&'static str>,
+}
+
+impl VersionInfo {
+    pub fn render(&self) -> String {
+        format!(
+            "**Database version**\nStored version: {}\nCurrent version: {}\nMigrations run: {}",
+            self.stored_version,
+            self.current_version,
+            self.migrations_run.join(", ")
+        )
+    }
+}
+
+/// What's been tracked in a guild.
+#[derive(Debug, Default)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct GuildStats {
+    /// Number of unique images stored for the guild.
+    pub images: usize,
+    /// Number of times any of them were seen again.
+    pub reposts: u64,
+    /// The channel the most images were first posted in, and how many.
+    pub busiest_channel: Option<(u64, usize)>,
+    /// The image seen the most times, if any were seen more than once, and how many times.
+    pub most_reposted: Option<(SeenImage, u64)>,
+}
+
+/// Size information about the database and its trees.
+#[derive(Debug)]
+pub struct StorageStats {
+    /// Bytes used by the database on disk.
+    pub size_on_disk: u64,
+    /// Number of unique images stored.
+    pub stored_images: usize,
+    /// Number of seen counters stored.
+    pub seen_counts: usize,
+    /// Number of hashes pointing at stored images, including similar-image aliases.
+    pub seen_hashes: usize,
+}
+
+#[derive(Debug, Archive, Deserialize, Serialize, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(
+    test,
+    derive(Clone, PartialEq),
+    archive_attr(derive(CheckBytes, Debug))
+)]
+pub struct SeenImage {
+    /// Is this image ignored from repost checking.
+    pub ignored: bool,
+    /// Name of the user who sent the message containing an image, when they sent it.
+    pub author: String,
+    /// ID of the user who sent the message containing an image, or zero if it isn't known.
+    ///
+    /// Unlike their name, it stays right when they change it.
+    #[serde(default)]
+    pub author_id: u64,
+    /// Timestamp of message when the message was received - std::time::UNIX_EPOCH, in seconds.
+    ///
+    /// Good enough for constructing durations
+    pub sent: u64,
+    /// ID of the message that contained this message.
+    ///
+    /// This is the message that will be replied to when a repost occurs.
+    pub original_message_id: u64,
+    /// ID of the channel that an image was seen in.
+    ///
+    /// Helps determine if a reply can be used or if a jumplink is needed.
+    pub channel_id: u64,
+    /// ID of the guild the image was first seen in, or zero if it isn't known.
+    ///
+    /// Jump links to the original are built with it.
+    #[serde(default)]
+    pub guild_id: u64,
+    /// Where the image was downloaded from, like its attachment or embed URL, if it's known.
+    ///
+    /// Callouts show it as a thumbnail of the original, and it's kept in exports for auditing what was recorded.
+    #[serde(default)]
+    pub source_url: Option<String>,
+}
+
+/// Which images [Data::purge_images] deletes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PurgeCriteria {
+    /// Seconds an image has to have gone without being seen.
+    pub older_than: u64,
+    /// Only images first posted in this channel, if set.
+    pub channel_id: Option<u64>,
+    /// Only images first posted by this user, if set.
+    pub author_id: Option<u64>,
+}
+
+/// A single time an image was posted, whether it was the original or a repost.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct Occurrence {
+    pub author: String,
+    /// Missing from occurrences recorded before IDs were.
+    #[serde(default)]
+    pub author_id: u64,
+    /// When the message was received - std::time::UNIX_EPOCH, in seconds.
+    pub sent: u64,
+    pub message_id: u64,
+    pub channel_id: u64,
+}
+
+/// A link that's been shared in a guild.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SeenLink {
+    first: Occurrence,
+    times_seen: u64,
+}
+
+impl Occurrence {
+    /// Who posted the image this time, for messages, like [SeenImage::poster].
+    pub fn poster(&self) -> String {
+        match self.author_id {
+            0 => self.author.clone(),
+            id => format!("<@{}>", id),
+        }
+    }
+}
+
+impl From<&SeenImage> for Occurrence {
+    fn from(image: &SeenImage) -> Self {
+        Self {
+            author: image.author.clone(),
+            author_id: image.author_id,
+            sent: image.sent,
+            message_id: image.original_message_id,
+            channel_id: image.channel_id,
+        }
+    }
+}
+
+impl SeenImage {
+    pub fn new(author: String, sent: u64, original_message_id: u64, channel_id: u64) -> Self {
+        Self {
+            ignored: false,
+            author,
+            author_id: 0,
+            sent,
+            original_message_id,
+            channel_id,
+            guild_id: 0,
+            source_url: None,
+        }
+    }
+
+    /// Who posted the image, for messages. That's a mention if their ID is known, so it follows them
+    /// through name changes, and the name they had otherwise.
+    pub fn poster(&self) -> String {
+        match self.author_id {
+            0 => self.author.clone(),
+            id => format!("<@{}>", id),
+        }
+    }
+}
+
+/// Repost count increments held in memory, so busy servers don't write every one to disk.
+struct PendingCounts {
+    seen_counts: sled::Tree,
+    /// Database ID --> increments not written yet.
+    counts: Mutex<HashMap<IVec, u64>>,
+}
+
+impl PendingCounts {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IVec, u64>> {
+        self.counts
+            .lock()
+            .expect("bug: a thread panicked while counting reposts")
+    }
+
+    fn flush(&self) -> Result<(), DatabaseError> {
+        let mut counts = self.lock();
+
+        for (id, increments) in counts.drain() {
+            self.seen_counts
+                .update_and_fetch(&id, |old| {
+                    let count = Data::read_int(old?);
+                    Some(IVec::from(&(count + increments).to_le_bytes()))
+                })
+                .map_err(DatabaseError::Recording)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PendingCounts {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            tracing::error!("Failed to write batched repost counts: {:?}", e);
+        }
+    }
+}
+
+/// What went away with a forgotten image.
+#[derive(Debug, PartialEq)]
+pub struct ForgottenImage {
+    /// How many hashes pointed at the image, including its own.
+    pub aliases: usize,
+    pub times_seen: u64,
+}
+
+/// A stored image, and every hash that's been recorded as it.
+#[derive(Debug)]
+pub struct StoredRecord {
+    pub image: SeenImage,
+    pub hashes: Vec<Vec<u8>>,
+    /// How far away an image can be to match this one, after any override for the format it was stored from.
+    pub threshold: u32,
+}
+
+impl StoredRecord {
+    /// How close the nearest of this image's hashes is to `hash`.
+    pub fn distance_to(&self, hash: &ImageHash) -> Option<u32> {
+        self.hashes
+            .iter()
+            .map(|seen| image_processing::hash_distance(hash, seen))
+            .min()
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub enum PreviouslySeen {
+    Yes {
+        image: SeenImage,
+        times_seen: u64,
+    },
+    /// Close to a stored image, but not close enough to be sure it's a repost.
+    ///
+    /// The new image was stored on its own. If it's confirmed to be a repost, it can be merged into
+    /// `image` with [Data::merge_near_match] using the `new` and `original` hashes.
+    NearMatch {
+        image: SeenImage,
+        original: Vec<u8>,
+        new: Vec<u8>,
+    },
+    No,
+}
+
+#[cfg(test)]
+impl PartialEq<SeenImage> for ArchivedSeenImage {
+    fn eq(&self, other: &SeenImage) -> bool {
+        self.ignored == other.ignored
+            && self.author == other.author
+            && self.author_id == other.author_id
+            && self.sent == other.sent
+            && self.original_message_id == other.original_message_id
+            && self.channel_id == other.channel_id
+            && self.guild_id == other.guild_id
+            && self.source_url.as_deref() == other.source_url.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sled::IVec;
+
+    #[test]
+    fn database_from_other_arch_opens() {
+        let test_path = "./target/usize_test";
+        let _ = std::fs::remove_dir_all(test_path);
+
+        // Fake a database made on a 32-bit system, before storage was little-endian.
+        let legacy = sled::Config::new().path(test_path).open().unwrap();
+        legacy.insert(Data::VERSION_KEY, &[5]).unwrap();
+        legacy
+            .insert(Data::PTR_SIZE_KEY, &4u32.to_ne_bytes())
+            .unwrap();
+        legacy
+            .open_tree(Data::SEEN_COUNT_TREE)
+            .unwrap()
+            .insert(10u64.to_ne_bytes(), &3u64.to_ne_bytes())
+            .unwrap();
+        drop(legacy);
+
+        let db = Data::init(test_path, &Config::default()).unwrap();
+        assert_eq!(db.db.get(Data::PTR_SIZE_KEY).unwrap(), None);
+        assert_eq!(db.version_info().unwrap().stored_version, CURRENT_VERSION);
+        assert_eq!(db.stored_count(&10u64.to_le_bytes()).unwrap(), 3);
+    }
+
+    #[test]
+    fn database_from_big_endian_swapped() {
+        let test_path = "./target/big_endian_test";
+        let _ = std::fs::remove_dir_all(test_path);
+
+        let guild_id = 0x0102_0304_0506_0708u64;
+        let image = SeenImage {
+            author_id: 0x1112_1314_1516_1718,
+            guild_id,
+            source_url: Some(format!("https://cdn.example/{}", "b".repeat(260))),
+            ..SeenImage::new(
+                "a".repeat(300),
+                0x2122_2324_2526_2728,
+                0x3132_3334_3536_3738,
+                0x4142_4344_4546_4748,
+            )
+        };
+
+        // Swaps the one spot a little-endian archive has `little` for `big`.
+        fn swapped(archive: &mut [u8], little: &[u8], big: &[u8]) {
+            let mut found = (0..archive.len() - little.len())
+                .filter(|&at| &archive[at..at + little.len()] == little);
+            let at = found.next().unwrap();
+            assert_eq!(found.next(), None);
+            archive[at..at + big.len()].copy_from_slice(big);
+        }
+
+        // What the image would have been archived as on a big-endian machine.
+        let mut archive = migrations::serialize(&image);
+        for field in [
+            image.author_id,
+            image.sent,
+            image.original_message_id,
+            image.channel_id,
+            image.guild_id,
+        ] {
+            swapped(&mut archive, &field.to_le_bytes(), &field.to_be_bytes());
+        }
+        for text in [&image.author, image.source_url.as_ref().unwrap()] {
+            let len = text.len() as u32;
+            swapped(&mut archive, &len.to_le_bytes(), &len.to_be_bytes());
+        }
+
+        // Fake a database made on a big-endian system, before storage was little-endian.
+        let id = 3u64.to_be_bytes();
+        let hash = [
+            &guild_id.to_be_bytes()[..],
+            &[5; image_processing::HASH_BYTES],
+        ]
+        .concat();
+        let occurrence = Occurrence {
+            message_id: 0x5152_5354_5556_5758,
+            ..Occurrence::from(&image)
+        };
+
+        let legacy = sled::Config::new().path(test_path).open().unwrap();
+        legacy.insert(Data::VERSION_KEY, &[5]).unwrap();
+        legacy
+            .insert(Data::PTR_SIZE_KEY, &8u64.to_be_bytes())
+            .unwrap();
+        let tree = |name| legacy.open_tree(name).unwrap();
+        tree(Data::STORAGE_TREE).insert(id, archive).unwrap();
+        tree(Data::SEEN_COUNT_TREE)
+            .insert(id, &4u64.to_be_bytes())
+            .unwrap();
+        tree(Data::HASH_TREE).insert(&hash, &id).unwrap();
+        tree(Data::OCCURRENCE_TREE)
+            .insert(
+                [&id[..], &9u64.to_be_bytes()].concat(),
+                serde_json::to_vec(&occurrence).unwrap(),
+            )
+            .unwrap();
+        tree(Data::GUILD_IMAGES_TREE)
+            .insert([&guild_id.to_be_bytes()[..], &id].concat(), &[])
+            .unwrap();
+        tree(Data::IMAGE_FORMATS_TREE).insert(id, "png").unwrap();
+        tree(Data::OFFENDERS_TREE)
+            .insert(
+                [guild_id.to_be_bytes(), 773u64.to_be_bytes()].concat(),
+                &2u64.to_be_bytes(),
+            )
+            .unwrap();
+        drop(legacy);
+
+        let db = Data::init(test_path, &Config::default()).unwrap();
+        let id = 3u64.to_le_bytes();
+        assert_eq!(db.db.get(Data::PTR_SIZE_KEY).unwrap(), None);
+        assert_eq!(db.version_info().unwrap().stored_version, CURRENT_VERSION);
+
+        assert_eq!(db.stored_image(&id).unwrap(), Some(image.clone()));
+        assert_eq!(
+            db.posted_in(occurrence.message_id).unwrap(),
+            Some((image, 4))
+        );
+        assert_eq!(db.seen_hashes.get(&hash).unwrap(), Some(IVec::from(&id)));
+        assert!(db.for_guild(guild_id).in_guild(&id).unwrap());
+        assert_eq!(db.image_formats.get(id).unwrap(), Some(IVec::from("png")));
+        assert_eq!(db.top_offenders(guild_id, 10).unwrap(), vec![(773, 2)]);
+    }
+
+    #[test]
+    fn failed_migration_rolled_back() {
+        let db = Data::init("", &Config::default()).unwrap();
+        for byte in [1, 2] {
+            db.record_raw(
+                ImageHash::from_bytes(&[byte; 64]).unwrap(),
+                SeenImage::new("testing".to_string(), 773, 1, 2),
+            )
+            .unwrap();
+        }
+        let before: Vec<_> = db.stored_images.iter().collect::<Result<_, _>>().unwrap();
+
+        fn finishes(data: &Data) -> Result<migrations::Changes, DatabaseError> {
+            let mut changes = migrations::Changes::default();
+            changes.to(&data.db).insert(b"finished", &[]);
+            Ok(changes)
+        }
+
+        // Only gets through the first image.
+        fn fails(data: &Data) -> Result<migrations::Changes, DatabaseError> {
+            let converted = std::cell::Cell::new(0);
+            migrations::rewrite_images(data, |_, _| {
+                converted.set(converted.get() + 1);
+                match converted.get() {
+                    1 => Ok(b"changed".to_vec()),
+                    _ => Err(DatabaseError::InvalidConfig("broken")),
+                }
+            })
+        }
+
+        let migrators: &[(&str, Migration)] = &[("finishes", finishes), ("fails", fails)];
+        assert!(db.run_migrations(0, migrators).is_err());
+
+        // What finished stays, and the version says so, but the rest is left as it was.
+        assert!(db.db.contains_key(b"finished").unwrap());
+        assert_eq!(
+            db.db.get(Data::VERSION_KEY).unwrap(),
+            Some(IVec::from(&[1]))
+        );
+        let after: Vec<_> = db.stored_images.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn migrations_written_with_version() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = [7; image_processing::HASH_BYTES];
+        db.seen_hashes.insert(hash, &5u64.to_le_bytes()).unwrap();
+        db.db.insert(Data::VERSION_KEY, &[1]).unwrap();
+
+        // Migrating doesn't write anything by itself, so stopping partway leaves nothing half done.
+        let (name, guild_scoped_hashes) = MIGRATORS[1];
+        assert_eq!(name, "guild_scoped_hashes");
+        let changes = guild_scoped_hashes(&db).unwrap();
+        assert!(db.seen_hashes.contains_key(hash).unwrap());
+        drop(changes);
+
+        db.run_migrations(1, &MIGRATORS[..2]).unwrap();
+        assert!(!db.seen_hashes.contains_key(hash).unwrap());
+        assert!(db
+            .seen_hashes
+            .contains_key([&0u64.to_be_bytes()[..], &hash].concat())
+            .unwrap());
+        assert_eq!(
+            db.db.get(Data::VERSION_KEY).unwrap(),
+            Some(IVec::from(&[2]))
+        );
+    }
+
+    #[test]
+    fn databse_version_moves() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        assert_eq!(
+            db.db.get(Data::VERSION_KEY).unwrap(),
+            Some(IVec::from(&[CURRENT_VERSION]))
+        );
+    }
+
+    #[test]
+    fn custom_storage_settings() {
+        let mut config = Config::default();
+        config.storage.cache_capacity = 64 * 1024;
+        config.storage.flush_every_ms = 10;
+
+        let db = Data::init("", &config).unwrap();
+        for i in 0..50u8 {
+            let hash = ImageHash::from_bytes(&[i; 64]).unwrap();
+            db.record_raw(&hash, SeenImage::new("a".to_string(), 1, i as u64, 1))
+                .unwrap();
+        }
+        assert!(db.total_seen() > 0);
+
+        config.storage.flush_every_ms = 0;
+        assert!(Data::init("", &config).is_ok());
+
+        config.storage.cache_capacity = 0;
+        assert!(matches!(
+            Data::init("", &config),
+            Err(DatabaseError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn policy_round_trip() {
+        let source = Data::init("", &Config::default()).unwrap();
+        let ignored = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+        let kept = ImageHash::from_bytes(&[0xF0; 64]).unwrap();
+
+        let mut ignored_image = SeenImage::new("a".to_string(), 1, 100, 10);
+        ignored_image.ignored = true;
+        source
+            .for_guild(1)
+            .record_raw(&ignored, ignored_image)
+            .unwrap();
+        source
+            .for_guild(1)
+            .record_raw(&kept, SeenImage::new("b".to_string(), 2, 200, 10))
+            .unwrap();
+        // Guild 2 has the same image, but never ignored it.
+        source
+            .for_guild(2)
+            .record_raw(&ignored, SeenImage::new("f".to_string(), 6, 600, 20))
+            .unwrap();
+        source
+            .update_guild_settings(42, |s| s.max_image_size = Some(1024))
+            .unwrap();
+
+        let mut policy = Vec::new();
+        source.export_policy(&mut policy).unwrap();
+        let document = String::from_utf8(policy.clone()).unwrap();
+        assert_eq!(
+            document.matches(&hex::encode(ignored.as_bytes())).count(),
+            1
+        );
+        assert!(!document.contains(&hex::encode(kept.as_bytes())));
+        assert!(!document.contains("\"author\""));
+
+        // Somewhere that's seen both images in both guilds, but doesn't ignore either yet.
+        let target = Data::init("", &Config::default()).unwrap();
+        for guild_id in [1, 2] {
+            let guild = target.for_guild(guild_id);
+            let message = |n: u64| guild_id * 1000 + n;
+            guild
+                .record_raw(&ignored, SeenImage::new("c".to_string(), 3, message(3), 30))
+                .unwrap();
+            guild
+                .record_raw(&ignored, SeenImage::new("d".to_string(), 4, message(4), 30))
+                .unwrap();
+            guild
+                .record_raw(&kept, SeenImage::new("e".to_string(), 5, message(5), 30))
+                .unwrap();
+        }
+        let counts_before: Vec<_> = target.seen_counts.iter().values().collect();
+
+        assert_eq!(target.import_policy(policy.as_slice()).unwrap(), 1);
+        assert_eq!(
+            target.guild_settings(42).unwrap().max_image_size,
+            Some(1024)
+        );
+
+        // The image records themselves are untouched, apart from being ignored.
+        assert_eq!(target.total_seen(), 4);
+        let counts_after: Vec<_> = target.seen_counts.iter().values().collect();
+        assert_eq!(counts_before, counts_after);
+
+        let ignored_record = target.lookup(1003).unwrap().unwrap();
+        assert!(ignored_record.image.ignored);
+        assert_eq!(ignored_record.image.author, "c");
+        assert!(!target.lookup(1005).unwrap().unwrap().image.ignored);
+        // Only the guild that ignored it does.
+        assert!(!target.lookup(2003).unwrap().unwrap().image.ignored);
+
+        // Importing into a database that's never seen the images only changes settings.
+        let empty = Data::init("", &Config::default()).unwrap();
+        assert_eq!(empty.import_policy(policy.as_slice()).unwrap(), 0);
+        assert_eq!(empty.total_seen(), 0);
+    }
+
+    #[test]
+    fn count_cooldown_decision() {
+        // Without a cooldown everything counts.
+        assert!(Data::counts_again(Some(100), 100, 0));
+        // Nor is there anything to cool down from for a brand new image.
+        assert!(Data::counts_again(None, 100, 60));
+
+        assert!(!Data::counts_again(Some(100), 100, 60));
+        assert!(!Data::counts_again(Some(100), 159, 60));
+        assert!(Data::counts_again(Some(100), 160, 60));
+        // Clocks going backwards shouldn't count it either.
+        assert!(!Data::counts_again(Some(100), 50, 60));
+    }
+
+    #[test]
+    fn reposts_within_cooldown_not_counted() {
+        let mut config = Config::default();
+        config.detection.count_cooldown = 60;
+        let db = Data::init("", &config).unwrap();
+        let hash = ImageHash::from_bytes(&[9; 64]).unwrap();
+
+        db.record_raw(&hash, SeenImage::new("a".to_string(), 1000, 100, 1))
+            .unwrap();
+
+        // Still called out, but the count doesn't move.
+        assert!(matches!(
+            db.record_raw(&hash, SeenImage::new("b".to_string(), 1030, 200, 1))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 1, .. }
+        ));
+        // The cooldown runs from the last counted sighting, not the last one.
+        assert!(matches!(
+            db.record_raw(&hash, SeenImage::new("c".to_string(), 1060, 300, 1))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
+
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.occurrences_of(&id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reposts_after_window_start_over() {
+        let mut config = Config::default();
+        config.detection.repost_window = Some(100);
+        let db = Data::init("", &config).unwrap();
+        let hash = ImageHash::from_bytes(&[9; 64]).unwrap();
+        let mut similar = [9; 64];
+        similar[63] = 8;
+        let similar = ImageHash::from_bytes(&similar).unwrap();
+
+        db.record_raw(&hash, SeenImage::new("a".to_string(), 1000, 100, 1))
+            .unwrap();
+        assert!(matches!(
+            db.record_raw(&hash, SeenImage::new("b".to_string(), 1100, 200, 1))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
+
+        // Too long after it was first posted, so it's the first sighting now.
+        assert_eq!(
+            db.record_raw(&hash, SeenImage::new("c".to_string(), 1101, 300, 2))
+                .unwrap(),
+            PreviouslySeen::No
+        );
+
+        match db
+            .record_raw(&similar, SeenImage::new("d".to_string(), 1150, 400, 2))
+            .unwrap()
+        {
+            PreviouslySeen::Yes { image, times_seen } => {
+                assert_eq!(image.author, "c");
+                assert_eq!(image.original_message_id, 300);
+                assert_eq!(times_seen, 2);
+            }
+            seen => panic!("not seen before: {:?}", seen),
+        }
+
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        // Sightings from before are still in its history.
+        assert_eq!(db.occurrences_of(&id).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn restarted_counts_left_alone_by_repairs() {
+        let mut config = Config::default();
+        config.detection.repost_window = Some(100);
+        let db = Data::init("", &config).unwrap();
+        let hash = ImageHash::from_bytes(&[9; 64]).unwrap();
+
+        db.record_raw(&hash, SeenImage::new("a".to_string(), 1000, 100, 1))
+            .unwrap();
+        db.record_raw(&hash, SeenImage::new("b".to_string(), 1050, 200, 1))
+            .unwrap();
+        db.record_raw(&hash, SeenImage::new("c".to_string(), 1101, 300, 1))
+            .unwrap();
+
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 0);
+        assert_eq!(db.stored_count(&id).unwrap(), 1);
+
+        // Only what's come since it started over is counted when a count does need fixing.
+        db.record_raw(&hash, SeenImage::new("d".to_string(), 1150, 400, 1))
+            .unwrap();
+        db.seen_counts.insert(&id, &7u64.to_le_bytes()).unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 1);
+        assert_eq!(db.stored_count(&id).unwrap(), 2);
+    }
+
+    #[test]
+    fn batched_counts_read_and_flushed() {
+        let mut config = Config::default();
+        config.storage.count_batch_ms = 1000;
+        let db = Data::init("", &config).unwrap();
+        let hash = ImageHash::from_bytes(&[11; 64]).unwrap();
+
+        db.record_raw(&hash, SeenImage::new("a".to_string(), 1, 100, 1))
+            .unwrap();
+        for times_seen in 2..=4 {
+            let seen = db
+                .record_raw(&hash, SeenImage::new("b".to_string(), 1, 200, 1))
+                .unwrap();
+            assert!(matches!(seen, PreviouslySeen::Yes { times_seen: t, .. } if t == times_seen));
+        }
+
+        // Nothing was written yet...
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.stored_count(&id).unwrap(), 1);
+
+        // ...until the batch is flushed.
+        db.flush_counts().unwrap();
+        assert_eq!(db.stored_count(&id).unwrap(), 4);
+        assert!(matches!(
+            db.record_raw(&hash, SeenImage::new("c".to_string(), 1, 300, 1))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 5, .. }
+        ));
+
+        // Whatever's left is written when the database goes away.
+        let seen_counts = db.seen_counts.clone();
+        drop(db);
+        assert_eq!(Data::read_int(&seen_counts.get(&id).unwrap().unwrap()), 5);
+    }
+
+    #[test]
+    fn offenses_counted_per_guild_and_user() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        assert_eq!(db.record_offense(1, 10).unwrap(), 1);
+        assert_eq!(db.record_offense(1, 10).unwrap(), 2);
+        assert_eq!(db.record_offense(1, 11).unwrap(), 1);
+        assert_eq!(db.record_offense(2, 10).unwrap(), 1);
+
+        // Purging a guild forgets who reposted there.
+        db.guild_removed(1, 0).unwrap();
+        db.purge_removed_guilds(0, 0).unwrap();
+        assert_eq!(db.record_offense(1, 10).unwrap(), 1);
+        assert_eq!(db.record_offense(2, 10).unwrap(), 2);
+    }
+
+    #[test]
+    fn top_offenders_ranked() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        for _ in 0..3 {
+            db.record_offense(1, 10).unwrap();
+        }
+        db.record_offense(1, 12).unwrap();
+        db.record_offense(1, 11).unwrap();
+        // Other guilds don't count.
+        for _ in 0..5 {
+            db.record_offense(2, 13).unwrap();
+        }
+
+        assert_eq!(
+            db.top_offenders(1, 10).unwrap(),
+            vec![(10, 3), (11, 1), (12, 1)]
+        );
+        assert_eq!(db.top_offenders(1, 1).unwrap(), vec![(10, 3)]);
+        assert!(db.top_offenders(3, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn guild_stats_summed() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let guild = db.for_guild(1);
+        let image = |channel_id| SeenImage {
+            guild_id: 1,
+            ..SeenImage::new("someone".to_string(), 10, 20, channel_id)
+        };
+
+        let popular = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+        for _ in 0..3 {
+            guild.record_raw(&popular, image(5)).unwrap();
+        }
+        guild
+            .record_raw(ImageHash::from_bytes(&[0xF0; 64]).unwrap(), image(6))
+            .unwrap();
+        guild
+            .record_raw(ImageHash::from_bytes(&[0xAA; 64]).unwrap(), image(6))
+            .unwrap();
+
+        assert_eq!(
+            db.guild_stats(1).unwrap(),
+            GuildStats {
+                images: 3,
+                reposts: 2,
+                busiest_channel: Some((6, 2)),
+                most_reposted: Some((image(5), 3)),
+            }
+        );
+        assert_eq!(db.guild_stats(2).unwrap(), GuildStats::default());
+    }
+
+    #[test]
+    fn newly_joined_decision() {
+        assert!(Data::newly_joined(false, false));
+        assert!(!Data::newly_joined(true, false));
+        assert!(!Data::newly_joined(false, true));
+        assert!(!Data::newly_joined(true, true));
+    }
+
+    #[test]
+    fn joined_guilds_remembered() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        // Reconnecting sends the same guild again, which isn't a new join.
+        assert!(db.guild_joined(1).unwrap());
+        assert!(!db.guild_joined(1).unwrap());
+        assert!(db.guild_joined(2).unwrap());
+
+        // Guilds with settings were around before guilds were remembered.
+        db.update_guild_settings(3, |settings| settings.cross_channel_replies = Some(false))
+            .unwrap();
+        assert!(!db.guild_joined(3).unwrap());
+
+        db.for_guild(4)
+            .record_raw(
+                ImageHash::from_bytes(&[4; 64]).unwrap(),
+                SeenImage::new("a".to_string(), 1, 100, 10),
+            )
+            .unwrap();
+        assert!(!db.guild_joined(4).unwrap());
+    }
+
+    #[test]
+    fn purge_due_decision() {
+        // Guilds the bot is still in are never purged.
+        assert!(!Data::purge_due(None, 1000, 0));
+
+        assert!(!Data::purge_due(Some(1000), 1000, 60));
+        assert!(!Data::purge_due(Some(1000), 1059, 60));
+        assert!(Data::purge_due(Some(1000), 1060, 60));
+        assert!(Data::purge_due(Some(1000), 1000, 0));
+    }
+
+    #[test]
+    fn removed_guilds_purged() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let removed = ImageHash::from_bytes(&[5; 64]).unwrap();
+        let kept = ImageHash::from_bytes(&[250; 64]).unwrap();
+
+        for guild_id in [1, 2] {
+            assert!(db.guild_joined(guild_id).unwrap());
+            db.update_guild_settings(guild_id, |settings| settings.max_image_size = Some(1))
+                .unwrap();
+        }
+        db.for_guild(1)
+            .record_raw(&removed, SeenImage::new("a".to_string(), 1, 100, 10))
+            .unwrap();
+        db.for_guild(1)
+            .record_raw(&removed, SeenImage::new("b".to_string(), 2, 200, 10))
+            .unwrap();
+        db.for_guild(2)
+            .record_raw(&kept, SeenImage::new("c".to_string(), 3, 300, 20))
+            .unwrap();
+
+        db.guild_removed(1, 1000).unwrap();
+        assert!(db.purge_removed_guilds(1059, 60).unwrap().is_empty());
+        assert_eq!(db.purge_removed_guilds(1060, 60).unwrap(), vec![(1, 1)]);
+
+        assert!(db
+            .seen_hashes
+            .get(db.for_guild(1).hash_key(removed.as_bytes()))
+            .unwrap()
+            .is_none());
+        assert_eq!(db.stored_images.len(), 1);
+        assert_eq!(db.seen_counts.len(), 1);
+        assert_eq!(db.occurrences.len(), 1);
+        assert_eq!(db.guild_settings(1).unwrap(), GuildSettings::default());
+        assert_eq!(db.guild_settings(2).unwrap().max_image_size, Some(1));
+
+        // Coming back before the grace period is up keeps everything.
+        db.guild_removed(2, 1000).unwrap();
+        assert!(!db.guild_joined(2).unwrap());
+        assert!(db.purge_removed_guilds(5000, 60).unwrap().is_empty());
+        assert!(db
+            .seen_hashes
+            .get(db.for_guild(2).hash_key(kept.as_bytes()))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn unseen_images_expired() {
+        assert!(!Data::expired(1000, 1059, 60));
+        assert!(Data::expired(1000, 1060, 60));
+
+        let db = Data::init("", &Config::default()).unwrap().for_guild(1);
+        let stale = ImageHash::from_bytes(&[5; 64]).unwrap();
+        let reposted = ImageHash::from_bytes(&[250; 64]).unwrap();
+        let ignored = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+
+        db.record_raw(&stale, SeenImage::new("a".to_string(), 100, 1, 10))
+            .unwrap();
+        db.record_raw(&reposted, SeenImage::new("b".to_string(), 100, 2, 10))
+            .unwrap();
+        db.record_raw(&reposted, SeenImage::new("c".to_string(), 900, 3, 10))
+            .unwrap();
+        db.record_raw(
+            &ignored,
+            SeenImage {
+                ignored: true,
+                ..SeenImage::new("d".to_string(), 100, 4, 10)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(db.expire_images(1000, 500).unwrap(), 1);
+
+        assert!(db
+            .seen_hashes
+            .get(db.hash_key(stale.as_bytes()))
+            .unwrap()
+            .is_none());
+        assert_eq!(db.index().len(), 2);
+        assert_eq!(db.stored_images.len(), 2);
+        assert_eq!(db.seen_counts.len(), 2);
+        assert_eq!(db.occurrences.len(), 3);
+        assert_eq!(db.per_guild_counts().unwrap(), vec![(1, 2)]);
+
+        // Coming back after it expired makes it new again.
+        assert_eq!(
+            db.record_raw(&stale, SeenImage::new("e".to_string(), 1000, 5, 10))
+                .unwrap(),
+            PreviouslySeen::No
+        );
+    }
+
+    #[test]
+    fn purged_by_criteria() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let image = |author_id, channel_id| SeenImage {
+            author_id,
+            ..SeenImage::new("poster".to_string(), 100, 1, channel_id)
+        };
+
+        let guild = db.for_guild(1);
+        for (byte, author_id, channel_id) in [(1u8, 5, 10), (2, 5, 11), (3, 6, 10), (4, 6, 11)] {
+            guild
+                .record_raw(
+                    ImageHash::from_bytes(&[byte; 64]).unwrap(),
+                    image(author_id, channel_id),
+                )
+                .unwrap();
+        }
+        db.for_guild(2)
+            .record_raw(ImageHash::from_bytes(&[1; 64]).unwrap(), image(5, 10))
+            .unwrap();
+
+        let criteria = |older_than, channel_id, author_id| PurgeCriteria {
+            older_than,
+            channel_id,
+            author_id,
+        };
+
+        // Nothing's old enough yet.
+        assert_eq!(
+            guild
+                .purge_images(1000, &criteria(1000, None, None))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            guild
+                .purge_images(1000, &criteria(500, Some(10), Some(5)))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            guild
+                .purge_images(1000, &criteria(500, None, Some(6)))
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            guild
+                .purge_images(1000, &criteria(500, None, None))
+                .unwrap(),
+            1
+        );
+
+        // The other guild's image is left alone.
+        assert_eq!(db.stored_images.len(), 1);
+        assert_eq!(db.per_guild_counts().unwrap(), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn least_recently_seen_evicted_first() {
+        let candidates = vec![
+            (IVec::from(&[1]), 300),
+            (IVec::from(&[2]), 100),
+            (IVec::from(&[3]), 200),
+        ];
+
+        assert_eq!(
+            Data::eviction_order(candidates.clone(), 1),
+            vec![IVec::from(&[2])]
+        );
+        assert_eq!(
+            Data::eviction_order(candidates.clone(), 2),
+            vec![IVec::from(&[2]), IVec::from(&[3])]
+        );
+        assert_eq!(Data::eviction_order(candidates, 5).len(), 3);
+    }
+
+    fn quota_db(policy: QuotaPolicy) -> Data {
+        let mut config = Config::default();
+        config.storage.quota_policy = policy;
+        Data::init("", &config)
+            .unwrap()
+            .for_guild(1)
+            .with_record_quota(Some(2))
+    }
+
+    #[test]
+    fn quota_evicts_least_recently_seen() {
+        let db = quota_db(QuotaPolicy::EvictOldest);
+        let hashes: Vec<_> = [0, 85, 170]
+            .iter()
+            .map(|&b| ImageHash::from_bytes(&[b; 64]).unwrap())
+            .collect();
+
+        db.record_raw(&hashes[0], SeenImage::new("a".to_string(), 100, 1, 10))
+            .unwrap();
+        db.record_raw(&hashes[1], SeenImage::new("b".to_string(), 200, 2, 10))
+            .unwrap();
+        // Reposting the first makes the second the least recently seen.
+        db.record_raw(&hashes[0], SeenImage::new("c".to_string(), 300, 3, 10))
+            .unwrap();
+
+        db.record_raw(&hashes[2], SeenImage::new("d".to_string(), 400, 4, 10))
+            .unwrap();
+        assert_eq!(db.stored_images.len(), 2);
+        assert!(db
+            .seen_hashes
+            .get(db.hash_key(hashes[1].as_bytes()))
+            .unwrap()
+            .is_none());
+        assert!(db
+            .seen_hashes
+            .get(db.hash_key(hashes[0].as_bytes()))
+            .unwrap()
+            .is_some());
+        assert!(db
+            .seen_hashes
+            .get(db.hash_key(hashes[2].as_bytes()))
+            .unwrap()
+            .is_some());
+        assert_eq!(db.per_guild_counts().unwrap(), vec![(1, 2)]);
+
+        // Other guilds have their own quota.
+        db.for_guild(2)
+            .record_raw(&hashes[1], SeenImage::new("e".to_string(), 500, 5, 10))
+            .unwrap();
+        assert_eq!(db.stored_images.len(), 3);
+    }
+
+    #[test]
+    fn quota_refuses_new_images() {
+        let db = quota_db(QuotaPolicy::Refuse);
+        let hashes: Vec<_> = [0, 85, 170]
+            .iter()
+            .map(|&b| ImageHash::from_bytes(&[b; 64]).unwrap())
+            .collect();
+
+        for (i, hash) in hashes.iter().enumerate() {
+            db.record_raw(
+                hash,
+                SeenImage::new("a".to_string(), i as u64, i as u64, 10),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(db.stored_images.len(), 2);
+        assert!(db
+            .seen_hashes
+            .get(db.hash_key(hashes[2].as_bytes()))
+            .unwrap()
+            .is_none());
+
+        // Reposts of what's already stored are still caught.
+        assert!(matches!(
+            db.record_raw(&hashes[0], SeenImage::new("b".to_string(), 9, 9, 10))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn deleted_reposts_uncounted() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[3; 64]).unwrap();
+
+        db.record_raw(&hash, SeenImage::new("a".to_string(), 1, 100, 10))
+            .unwrap();
+        db.record_raw(&hash, SeenImage::new("b".to_string(), 2, 200, 10))
+            .unwrap();
+
+        assert_eq!(db.uncount_message(200).unwrap(), 1);
+        assert_eq!(db.uncount_message(200).unwrap(), 0);
+
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Data::read_int(&db.seen_counts.get(&id).unwrap().unwrap()),
+            1
+        );
+        assert_eq!(db.occurrences_of(&id).unwrap().len(), 1);
+
+        // Posting it again counts from where it was.
+        assert!(matches!(
+            db.record_raw(&hash, SeenImage::new("c".to_string(), 3, 300, 10))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn store_and_fetch() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+
+        let hash = ImageHash::from_bytes(&[1, 1, 1, 1, 1, 1, 1, 1]).unwrap();
+        db.record_raw(&hash, original.clone()).unwrap();
+
+        db.access_image(&[1, 2, 3], &mut |fetched| {
+            assert_eq!(*fetched, original);
+            false
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn store_duplicates() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let id = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+
+        let original = SeenImage::new(
+            "testing_but_looooooooooooonnnng".to_string(),
+            26543654564,
+            59849292,
+            3424324234,
+        );
+
+        let existing = db.record_raw(&id, original.clone()).unwrap();
+        assert_eq!(existing, PreviouslySeen::No);
+
+        let (db_id, _) = db.stored_images.first().unwrap().unwrap();
+
+        let seen_count = db.seen_counts.get(&db_id).unwrap().unwrap();
+        assert_eq!(Data::read_int(&seen_count), 1);
+
+        let newer = SeenImage::new("someone else".to_string(), 555555555, 4384834303, 434343423);
+
+        let old = db.record_raw(&id, newer).unwrap();
+
+        let (old, times_seen) = match old {
+            PreviouslySeen::Yes { image, times_seen } => (image, times_seen),
+            _ => panic!("wrong seen variant"),
+        };
+
+        assert_eq!(old, original);
+        assert_eq!(times_seen, 2);
+
+        let seen_count = db.seen_counts.get(db_id).unwrap().unwrap();
+        assert_eq!(Data::read_int(&seen_count), 2);
+    }
+
+    #[test]
+    fn store_similar() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let id = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+
+        let original = SeenImage::new(
+            "testing_but_looooooooooooonnnng".to_string(),
+            26543654564,
+            59849292,
+            43434234342,
+        );
+
+        db.record_raw(&id, original.clone()).unwrap();
+
+        let newer = SeenImage::new("someone else".to_string(), 555555555, 4384834303, 323243434);
+        let newer_id = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 7]).unwrap();
+
+        let old = db.record_raw(&newer_id, newer).unwrap();
+
+        let old = match old {
+            PreviouslySeen::Yes { image, .. } => image,
+            _ => panic!("wrong seen variant"),
+        };
+
+        assert_eq!(old, original)
+    }
+
+    #[test]
+    fn confirmed_near_match_merged() {
+        let mut config = Config::default();
+        config.detection.confirm_grace = 8;
+        let db = Data::init("", &config).unwrap();
+
+        let original = SeenImage::new("first".to_string(), 1, 100, 10);
+        let original_hash = ImageHash::from_bytes(&[0; 64]).unwrap();
+        db.record_raw(&original_hash, original.clone()).unwrap();
+
+        // 12 bits apart, which is past the threshold of 8 but inside the grace band.
+        let mut near_bytes = [0; 64];
+        near_bytes[0] = 0xFF;
+        near_bytes[1] = 0x0F;
+        let near_hash = ImageHash::from_bytes(&near_bytes).unwrap();
+
+        let seen = db
+            .record_raw(&near_hash, SeenImage::new("second".to_string(), 2, 200, 10))
+            .unwrap();
+        let (image, original_bytes, new_bytes) = match seen {
+            PreviouslySeen::NearMatch {
+                image,
+                original,
+                new,
+            } => (image, original, new),
+            other => panic!("wrong seen variant: {:?}", other),
+        };
+        assert_eq!(image, original);
+        assert_eq!(db.total_seen(), 2);
+
+        // Asking about it didn't count it.
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(original_hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        let count = |id| Data::read_int(&db.seen_counts.get(id).unwrap().unwrap());
+        assert_eq!(count(&id), 1);
+
+        assert_eq!(
+            db.merge_near_match(&new_bytes, &original_bytes).unwrap(),
+            Some(2)
+        );
+        assert_eq!(count(&id), 2);
+        assert_eq!(db.total_seen(), 1);
+        assert_eq!(
+            db.seen_hashes
+                .get(db.hash_key(near_hash.as_bytes()))
+                .unwrap(),
+            Some(id.clone())
+        );
+        assert_eq!(db.occurrences_of(&id).unwrap().len(), 2);
+
+        // Confirming again doesn't count it twice.
+        assert_eq!(
+            db.merge_near_match(&new_bytes, &original_bytes).unwrap(),
+            Some(2)
+        );
+        assert_eq!(count(&id), 2);
+
+        // The aliased hash is now an exact repost of the original.
+        assert!(matches!(
+            db.record_raw(&near_hash, SeenImage::new("third".to_string(), 3, 300, 10))
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 3, .. }
+        ));
+    }
+
+    /// Collects everything logged through it, for checking what was logged.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn similarity_decision_logged_once() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        // Hashes far enough apart that none of them match each other.
+        for i in 0..20u8 {
+            let mut bytes = [0; 64];
+            bytes[i as usize * 3..i as usize * 3 + 3].copy_from_slice(&[0xFF; 3]);
+            let hash = ImageHash::from_bytes(&bytes).unwrap();
+            db.record_raw(&hash, SeenImage::new("a".to_string(), 1, i as u64, 1))
+                .unwrap();
+        }
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::FmtSubscriber::builder()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let hash = ImageHash::from_bytes(&[0x55; 64]).unwrap();
+        let seen = tracing::subscriber::with_default(subscriber, || {
+            db.record_raw(&hash, SeenImage::new("b".to_string(), 2, 99, 1))
+                .unwrap()
+        });
+        assert_eq!(seen, PreviouslySeen::No);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let decisions: Vec<_> = logs
+            .lines()
+            .filter(|line| line.contains("Similarity check"))
+            .collect();
+        assert_eq!(decisions.len(), 1, "{}", logs);
+        // None of them are close enough for the index to bring up.
+        assert!(decisions[0].contains("no match in 0 comparisons"));
+    }
+
+    fn record_twice(updates: KnownImageUpdates) -> (PreviouslySeen, SeenImage) {
+        let mut config = Config::default();
+        config.detection.known_image_updates = updates;
+        let db = Data::init("", &config).unwrap();
+
+        let hash = ImageHash::from_bytes(&[7; 64]).unwrap();
+        let mut first = SeenImage::new("first".to_string(), 1, 100, 10);
+        first.ignored = true;
+        db.record_raw(&hash, first).unwrap();
+
+        let seen = db
+            .record_raw(&hash, SeenImage::new("second".to_string(), 2, 200, 20))
+            .unwrap();
+
+        let mut deserializer = SharedDeserializeMap::new();
+        let (_, stored) = db.stored_images.first().unwrap().unwrap();
+        let stored = Data::read_archived::<SeenImage>(&stored)
+            .deserialize(&mut deserializer)
+            .unwrap();
+
+        (seen, stored)
+    }
+
+    #[test]
+    fn known_images_preserved() {
+        let (seen, stored) = record_twice(KnownImageUpdates::Preserve);
+
+        let mut original = SeenImage::new("first".to_string(), 1, 100, 10);
+        original.ignored = true;
+        assert!(matches!(seen, PreviouslySeen::Yes { ref image, .. } if *image == original));
+        assert_eq!(stored, original);
+    }
+
+    #[test]
+    fn known_images_move_to_latest_location() {
+        let (seen, stored) = record_twice(KnownImageUpdates::LatestLocation);
+
+        // The caller still hears about where it was before.
+        assert!(matches!(
+            seen,
+            PreviouslySeen::Yes { ref image, .. }
+                if image.original_message_id == 100 && image.channel_id == 10
+        ));
+
+        // Who posted it first and when stay the same, along with it being ignored.
+        assert_eq!(stored.author, "first");
+        assert_eq!(stored.sent, 1);
+        assert!(stored.ignored);
+        // But it's now where it was last posted.
+        assert_eq!(stored.original_message_id, 200);
+        assert_eq!(stored.channel_id, 20);
+    }
+
+    #[test]
+    fn distance_to_looked_up_record() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        let original = ImageHash::from_bytes(&[0; 64]).unwrap();
+        db.record_raw(&original, SeenImage::new("a".to_string(), 1, 100, 10))
+            .unwrap();
+
+        // An alias 4 bits away from the original.
+        let mut alias_bytes = [0; 64];
+        alias_bytes[0] = 0x0F;
+        let alias = ImageHash::from_bytes(&alias_bytes).unwrap();
+        db.record_raw(&alias, SeenImage::new("b".to_string(), 2, 200, 10))
+            .unwrap();
+
+        assert!(db.lookup(200).unwrap().is_none());
+        let record = db.lookup(100).unwrap().unwrap();
+        assert_eq!(record.image.author, "a");
+        assert_eq!(record.hashes.len(), 2);
+
+        // 6 bits from the alias, but 10 from the original, so the closest one counts.
+        let mut new_bytes = [0; 64];
+        new_bytes[0] = 0xFF;
+        new_bytes[1] = 0x03;
+        let new = ImageHash::from_bytes(&new_bytes).unwrap();
+        assert_eq!(record.distance_to(&new), Some(6));
+        assert_eq!(record.distance_to(&original), Some(0));
+    }
+
+    #[test]
+    fn looked_up_records_matched_like_new_images() {
+        let mut config = Config::default();
+        config.detection.format_thresholds = "jpeg=20".parse().unwrap();
+        let db = Data::init("", &config).unwrap();
+        let guild = db.for_guild(1);
+
+        let hash = |bytes: [u8; 8]| ImageHash::from_bytes(&bytes).unwrap();
+        guild
+            .record_raw(
+                Hashes {
+                    hash: hash([0; 8]),
+                    variants: Vec::new(),
+                    format: Some(ImageFormat::Jpeg),
+                    content: None,
+                    frames: Vec::new(),
+                    source: None,
+                },
+                SeenImage::new("a".to_string(), 1, 100, 10),
+            )
+            .unwrap();
+
+        // Other guilds can't look at it.
+        assert!(db.for_guild(2).lookup(100).unwrap().is_none());
+        let record = guild.lookup(100).unwrap().unwrap();
+        assert_eq!(record.threshold, 20);
+
+        // 12 away is past the default threshold of 8, but within the JPEG one.
+        let near = hash([0xFF, 0x0F, 0, 0, 0, 0, 0, 0]);
+        assert!(guild.matches_record(&record, &near));
+        assert!(!guild.matches_record(&record, &hash([0xFF; 8])));
+
+        let confident = guild.with_detection_config(DetectionConfig {
+            min_confidence: Some(0.9),
+            ..config.detection.clone()
+        });
+        assert!(!confident.matches_record(&record, &near));
+        assert!(confident.matches_record(&record, &hash([0; 8])));
+    }
+
+    #[test]
+    fn exact_copies_found_by_content() {
+        let db = Data::init("", &Config::default()).unwrap().for_guild(9);
+        let image = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let content = [7; 32];
+
+        assert_eq!(db.hash_of_content(&content).unwrap(), None);
+
+        // Images that aren't stored aren't remembered either.
+        let unstored = Hashes {
+            content: Some(content),
+            ..Hashes::from(&hash)
+        };
+        db.match_raw(unstored.clone(), image.clone()).unwrap();
+        assert_eq!(db.hash_of_content(&content).unwrap(), None);
+
+        db.record_raw(unstored, image).unwrap();
+        assert_eq!(db.hash_of_content(&content).unwrap(), Some(hash.clone()));
+        assert_eq!(db.for_guild(10).hash_of_content(&content).unwrap(), None);
+
+        db.forget_image(hash.as_bytes()).unwrap();
+        assert_eq!(db.hash_of_content(&content).unwrap(), None);
+        assert!(db.content_hashes.is_empty());
+    }
+
+    #[test]
+    fn forgotten_images_removed_with_aliases() {
+        let db = Data::init("", &Config::default()).unwrap().for_guild(9);
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let similar = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 7]).unwrap();
+        let other = ImageHash::from_bytes(&[0xF0, 0xF0, 0xF0, 0xF0, 5, 6, 7, 8]).unwrap();
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        db.record_raw(&hash, original.clone()).unwrap();
+        db.record_raw(&similar, original.clone()).unwrap();
+        db.record_raw(&hash, original.clone()).unwrap();
+        db.record_raw(&other, original).unwrap();
+
+        assert_eq!(
+            db.forget_image(similar.as_bytes()).unwrap(),
+            Some(ForgottenImage {
+                aliases: 2,
+                times_seen: 3,
+            })
+        );
+        assert_eq!(db.forget_image(hash.as_bytes()).unwrap(), None);
+
+        // Only the other image is left.
+        assert_eq!(db.seen_hashes.len(), 1);
+        assert_eq!(db.index().len(), 1);
+        assert_eq!(db.stored_images.len(), 1);
+        assert_eq!(db.seen_counts.len(), 1);
+        assert_eq!(db.guild_images.len(), 1);
+    }
+
+    #[test]
+    fn images_found_by_message() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let repost = SeenImage::new("someone else".to_string(), 800, 242343400, 238484343);
+        db.record_raw(&hash, original.clone()).unwrap();
+        db.record_raw(&hash, repost).unwrap();
+
+        assert_eq!(
+            db.posted_in(242343331).unwrap(),
+            Some((original.clone(), 2))
+        );
+        assert_eq!(db.posted_in(242343400).unwrap(), Some((original, 2)));
+        assert_eq!(db.posted_in(1).unwrap(), None);
+    }
+
+    #[test]
+    fn history_has_every_occurrence() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let repost = SeenImage::new("someone else".to_string(), 800, 242343400, 238484344);
+        db.record_raw(&hash, original.clone()).unwrap();
+        db.record_raw(&hash, repost.clone()).unwrap();
+
+        let history = Some((
+            original.clone(),
+            vec![Occurrence::from(&original), Occurrence::from(&repost)],
+        ));
+        assert_eq!(db.history(242343331).unwrap(), history);
+        assert_eq!(db.history(242343400).unwrap(), history);
+        assert_eq!(db.history(1).unwrap(), None);
+
+        // Images from before occurrences were tracked still have where they were first posted.
+        db.occurrences.clear().unwrap();
+        assert_eq!(
+            db.history(242343331).unwrap(),
+            Some((original.clone(), vec![Occurrence::from(&original)]))
+        );
+    }
+
+    #[test]
+    fn history_found_by_similar_image() {
+        let db = Data::init("", &Config::default()).unwrap().for_guild(1);
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        db.record_raw(ImageHash::from_bytes(&[0; 8]).unwrap(), original.clone())
+            .unwrap();
+
+        // A few bits off is still the same image.
+        let similar = ImageHash::from_bytes(&[0b111, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(
+            db.history_matching(&similar.into()).unwrap(),
+            Some((original.clone(), vec![Occurrence::from(&original)]))
+        );
+        // Nothing was counted by looking.
+        assert_eq!(db.history(242343331).unwrap().unwrap().1.len(), 1);
+
+        let different = ImageHash::from_bytes(&[0xFF; 8]).unwrap();
+        assert_eq!(db.history_matching(&different.into()).unwrap(), None);
+        // Or from another guild.
+        let similar = ImageHash::from_bytes(&[0b111, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(
+            db.for_guild(2).history_matching(&similar.into()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn nearest_is_read_only() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(db.nearest(&Hashes::from(&hash)).unwrap(), None);
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        db.record_raw(&hash, original.clone()).unwrap();
+
+        // Way past the similarity threshold, but still the closest there is.
+        let far = ImageHash::from_bytes(&[0xFE, 0xFD, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(
+            db.nearest(&Hashes::from(&far)).unwrap(),
+            Some((16, original.clone()))
+        );
+        assert_eq!(
+            db.nearest(&Hashes::from(&hash)).unwrap(),
+            Some((0, original))
+        );
+
+        assert_eq!(db.seen_hashes.len(), 1);
+        assert_eq!(
+            db.stored_count(
+                &db.seen_hashes
+                    .get(db.hash_key(hash.as_bytes()))
+                    .unwrap()
+                    .unwrap()
+            )
+            .unwrap(),
+            1
+        );
+    }
+
+    fn similar_to_ignored(inherits_ignored: bool) -> PreviouslySeen {
+        let mut config = Config::default();
+        config.detection.similar_inherits_ignored = inherits_ignored;
+        let db = Data::init("", &config).unwrap();
+
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        db.record_raw(&hash, original).unwrap();
+
+        db.access_image(hash.as_bytes(), &mut |seen| {
+            seen.ignored = true;
+            true
+        })
+        .unwrap();
+
+        let similar = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 7]).unwrap();
+        let newer = SeenImage::new("someone else".to_string(), 555555555, 4384834303, 323243434);
+        let seen = db.record_raw(&similar, newer).unwrap();
+
+        assert_eq!(db.seen_hashes.len(), 2);
+        assert_eq!(db.stored_images.len(), if inherits_ignored { 1 } else { 2 });
+
+        seen
+    }
+
+    #[test]
+    fn similar_images_inherit_ignored() {
+        match similar_to_ignored(true) {
+            PreviouslySeen::Yes { image, times_seen } => {
+                assert!(image.ignored);
+                assert_eq!(times_seen, 2);
+            }
+            other => panic!("similar image wasn't matched: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn similar_images_recorded_separately_from_ignored() {
+        assert_eq!(similar_to_ignored(false), PreviouslySeen::No);
+    }
+
+    #[test]
+    fn record_raw_without_messages() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        let first = ImageHash::from_bytes(&[9, 9, 9, 9, 9, 9, 9, 9]).unwrap();
+        let unrelated = ImageHash::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        let backfilled = SeenImage::new("backfill".to_string(), 1, 2, 3);
+        assert_eq!(
+            db.record_raw(&first, backfilled.clone()).unwrap(),
+            PreviouslySeen::No
+        );
+        assert_eq!(
+            db.record_raw(&unrelated, SeenImage::new("other".to_string(), 4, 5, 6))
+                .unwrap(),
+            PreviouslySeen::No
+        );
+
+        let repost = SeenImage::new("reposter".to_string(), 10, 20, 30);
+        assert_eq!(
+            db.record_raw(&first, repost).unwrap(),
+            PreviouslySeen::Yes {
+                image: backfilled,
+                times_seen: 2
+            }
+        );
+        assert_eq!(db.total_seen(), 2);
+    }
+
+    #[test]
+    fn similarity_threshold_override() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        let original = ImageHash::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        // 10 bits away, which is outside the default threshold.
+        let distant = ImageHash::from_bytes(&[0, 0, 0, 0, 0, 0, 0b11, 0xFF]).unwrap();
+
+        db.record_raw(&original, SeenImage::new("a".to_string(), 1, 2, 3))
+            .unwrap();
 
-            let needs_modified = {
-                let buffer = Pin::new(buf.as_mut());
+        let lenient = db.with_similarity_threshold(12);
+        assert!(matches!(
+            lenient
+                .record_raw(&distant, SeenImage::new("b".to_string(), 4, 5, 6))
+                .unwrap(),
+            PreviouslySeen::Yes { .. }
+        ));
+    }
 
-                // SAFETY: We know we're pulling out of the images table, which are the right type, and this is tested.
-                let archived = unsafe { rkyv::archived_root_mut::<SeenImage>(buffer) };
+    #[test]
+    fn distance_histogram_buckets() {
+        let db = Data::init("", &Config::default()).unwrap();
 
-                f(archived)
-            };
+        let mut all_bits = [0; 64];
+        all_bits[..2].copy_from_slice(&[0xFF, 0xFF]);
 
-            if needs_modified {
-                self.stored_images
-                    .insert(id, buf)
-                    .map_err(DatabaseError::Accessing)?;
-            }
+        let hashes = [
+            ImageHash::from_bytes(&[0]).unwrap(),
+            ImageHash::from_bytes(&[1]).unwrap(),
+            ImageHash::from_bytes(&all_bits).unwrap(),
+        ];
+
+        for (i, hash) in hashes.iter().enumerate() {
+            db.record_raw(hash, SeenImage::new("a".to_string(), 1, i as u64, 3))
+                .unwrap();
         }
 
-        Ok(())
+        assert_eq!(Data::histogram_bucket_width(64), 9);
+
+        // Distances are 1, 15, and 16.
+        let histogram = db.distance_histogram(10, 64).unwrap();
+        assert_eq!(histogram.len(), 64);
+        assert_eq!(histogram[0], 1);
+        assert_eq!(histogram[1], 2);
+        assert_eq!(histogram.iter().sum::<u64>(), 3);
+
+        // A sample of one hash has no pairs to compare.
+        let histogram = db.distance_histogram(1, 64).unwrap();
+        assert_eq!(histogram.iter().sum::<u64>(), 0);
     }
 
-    pub fn total_seen(&self) -> usize {
-        self.stored_images.len()
+    #[test]
+    fn guild_settings_persist() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        assert_eq!(db.guild_settings(42).unwrap(), GuildSettings::default());
+
+        db.update_guild_settings(42, |settings| settings.raid_mode_until = Some(1000))
+            .unwrap();
+
+        assert_eq!(db.guild_settings(42).unwrap().raid_mode_until, Some(1000));
+        assert_eq!(db.guild_settings(43).unwrap(), GuildSettings::default());
     }
-}
 
-#[derive(Debug, Archive, Deserialize, Serialize)]
-#[cfg_attr(
-    test,
-    derive(Clone, PartialEq),
-    archive_attr(derive(CheckBytes, Debug))
-)]
-pub struct SeenImage {
-    /// Is this image ignored from repost checking.
-    pub ignored: bool,
-    /// User who sent the message containing an image.
-    pub author: String,
-    /// Timestamp of message when the message was received - std::time::UNIX_EPOCH, in seconds.
-    ///
-    /// Good enough for constructing durations
-    pub sent: u64,
-    /// ID of the message that contained this message.
-    ///
-    /// This is the message that will be replied to when a repost occurs.
-    pub original_message_id: u64,
-    /// ID of the channel that an image was seen in.
-    ///
-    /// Helps determine if a reply can be used or if a jumplink is needed.
-    pub channel_id: u64,
-}
+    #[test]
+    fn storage_stats_count_trees() {
+        let db = Data::init("", &Config::default()).unwrap();
 
-impl SeenImage {
-    pub fn new(author: String, sent: u64, original_message_id: u64, channel_id: u64) -> Self {
-        Self {
-            ignored: false,
-            author,
-            sent,
-            original_message_id,
-            channel_id,
-        }
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let similar = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 7]).unwrap();
+
+        db.record_raw(&hash, original.clone()).unwrap();
+        db.record_raw(&similar, original).unwrap();
+
+        let stats = db.storage_stats().unwrap();
+        assert_eq!(stats.stored_images, 1);
+        assert_eq!(stats.seen_counts, 1);
+        assert_eq!(stats.seen_hashes, 2);
     }
-}
 
-#[derive(Debug)]
-#[cfg_attr(test, derive(PartialEq))]
-pub enum PreviouslySeen {
-    Yes { image: SeenImage, times_seen: u64 },
-    No,
-}
+    #[test]
+    fn export_import_round_trip() {
+        let db = Data::init("", &Config::default()).unwrap();
 
-#[cfg(test)]
-impl PartialEq<SeenImage> for ArchivedSeenImage {
-    fn eq(&self, other: &SeenImage) -> bool {
-        self.ignored == other.ignored
-            && self.author == other.author
-            && self.sent == other.sent
-            && self.original_message_id == other.original_message_id
-            && self.channel_id == other.channel_id
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let similar = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 7]).unwrap();
+
+        db.record_raw(
+            Hashes {
+                format: Some(ImageFormat::Png),
+                ..hash.clone().into()
+            },
+            original.clone(),
+        )
+        .unwrap();
+        db.record_raw(&similar, original.clone()).unwrap();
+        db.update_guild_settings(42, |s| s.max_image_size = Some(1024))
+            .unwrap();
+        db.for_guild(42)
+            .record_link("example.com/news", &original)
+            .unwrap();
+        db.record_offense(42, 5).unwrap();
+
+        let mut exported = Vec::new();
+        db.export_to_writer(&mut exported, Some(b"key")).unwrap();
+
+        let restored = Data::init("", &Config::default()).unwrap();
+        assert_eq!(
+            restored
+                .import_from_reader(exported.as_slice(), Some(b"key"))
+                .unwrap(),
+            1
+        );
+
+        assert_eq!(restored.storage_stats().unwrap().seen_hashes, 2);
+        assert_eq!(
+            restored.guild_settings(42).unwrap().max_image_size,
+            Some(1024)
+        );
+        assert_eq!(restored.image_formats.len(), 1);
+        assert_eq!(
+            restored
+                .for_guild(42)
+                .record_link("example.com/news", &original)
+                .unwrap()
+                .map(|(_, times_seen)| times_seen),
+            Some(2)
+        );
+        assert_eq!(restored.record_offense(42, 5).unwrap(), 2);
+        assert_eq!(
+            restored.record_raw(&similar, original.clone()).unwrap(),
+            PreviouslySeen::Yes {
+                image: original,
+                times_seen: 3
+            }
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use sled::IVec;
+    #[test]
+    fn conflicting_imports_rejected() {
+        let db = Data::init("", &Config::default()).unwrap().for_guild(1);
+        let hash = ImageHash::from_bytes(&[7; 64]).unwrap();
+        db.record_raw(&hash, SeenImage::new("testing".to_string(), 773, 1, 2))
+            .unwrap();
+
+        let mut exported = Vec::new();
+        db.export_to_writer(&mut exported, None).unwrap();
+
+        // Importing into the database it came from would point every hash at two images.
+        assert!(matches!(
+            db.import_from_reader(exported.as_slice(), None),
+            Err(TransferError::ConflictingHash(conflict)) if conflict == hex::encode([7; 64])
+        ));
+        assert_eq!(db.stored_images.len(), 1);
+
+        // Newer databases might store things this build would drop.
+        let mut dump = Dump {
+            schema_version: CURRENT_VERSION + 1,
+            ..Dump::default()
+        };
+        let mut newer = Vec::new();
+        transfer::write(&dump, &mut newer, None).unwrap();
+        assert!(matches!(
+            db.import_from_reader(newer.as_slice(), None),
+            Err(TransferError::NewerSchema(version)) if version == CURRENT_VERSION + 1
+        ));
+
+        // Exports from before the version was included are fine.
+        dump.schema_version = 0;
+        let mut older = Vec::new();
+        transfer::write(&dump, &mut older, None).unwrap();
+        assert_eq!(db.import_from_reader(older.as_slice(), None).unwrap(), 0);
+    }
 
     #[test]
-    fn mismatched_usize_fails_to_init() {
-        let test_path = "./target/usize_test";
+    fn channels_remapped() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let guild = db.for_guild(1);
+        let other_guild = db.for_guild(2);
 
-        // Fake a DB made on a 32-bit system.
-        let db = sled::Config::new().path(test_path).open().unwrap();
-        let db = Data {
-            stored_images: db.open_tree(Data::STORAGE_TREE).unwrap(),
-            seen_counts: db.open_tree(Data::SEEN_COUNT_TREE).unwrap(),
-            seen_hashes: db.open_tree(Data::HASH_TREE).unwrap(),
-            db,
+        let moved = ImageHash::from_bytes(&[0; 64]).unwrap();
+        let also_moved = ImageHash::from_bytes(&[0xFF; 64]).unwrap();
+        let untouched = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+
+        guild
+            .record_raw(&moved, SeenImage::new("a".to_string(), 1, 10, 100))
+            .unwrap();
+        guild
+            .record_raw(&moved, SeenImage::new("b".to_string(), 2, 11, 300))
+            .unwrap();
+        guild
+            .record_raw(&also_moved, SeenImage::new("b".to_string(), 2, 20, 100))
+            .unwrap();
+        guild
+            .record_raw(&untouched, SeenImage::new("c".to_string(), 3, 30, 300))
+            .unwrap();
+        // Another guild can't move this by naming the same channel.
+        other_guild
+            .record_raw(&moved, SeenImage::new("e".to_string(), 5, 50, 100))
+            .unwrap();
+
+        assert_eq!(db.remap_channel(1, 100, 200).unwrap(), 2);
+        assert_eq!(db.remap_channel(1, 100, 200).unwrap(), 0);
+
+        let channel_of = |data: &Data, hash: &ImageHash| match data
+            .with_similarity_threshold(0)
+            .record_raw(hash, SeenImage::new("d".to_string(), 4, 40, 400))
+            .unwrap()
+        {
+            PreviouslySeen::Yes { image, .. } => image.channel_id,
+            other => panic!("image wasn't stored: {:?}", other),
         };
 
-        let smaller_usize = core::mem::size_of::<u32>() as u32;
-        db.db
-            .insert(Data::PTR_SIZE_KEY, &smaller_usize.to_ne_bytes())
+        assert_eq!(channel_of(&guild, &moved), 200);
+        assert_eq!(channel_of(&guild, &also_moved), 200);
+        assert_eq!(channel_of(&guild, &untouched), 300);
+        assert_eq!(channel_of(&other_guild, &moved), 100);
+
+        let channels = |message_id| -> Vec<u64> {
+            let (_, history) = db.history(message_id).unwrap().unwrap();
+            history.iter().map(|o| o.channel_id).collect()
+        };
+        assert_eq!(channels(10), vec![200, 300, 400]);
+        assert_eq!(channels(50), vec![100, 400]);
+    }
+
+    #[test]
+    fn repairs_follow_moved_images() {
+        let mut config = Config::default();
+        config.detection.known_image_updates = KnownImageUpdates::LatestLocation;
+        config.detection.repost_window = Some(100);
+        let db = Data::init("", &config).unwrap();
+        let hash = ImageHash::from_bytes(&[9; 64]).unwrap();
+
+        db.record_raw(&hash, SeenImage::new("a".to_string(), 1000, 100, 1))
+            .unwrap();
+        db.record_raw(&hash, SeenImage::new("b".to_string(), 1050, 200, 2))
+            .unwrap();
+        db.record_raw(&hash, SeenImage::new("c".to_string(), 1060, 300, 3))
             .unwrap();
 
-        drop(db);
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        // Moving to where it was last posted doesn't make that the first sighting.
+        assert_eq!(db.repair_counts().unwrap(), 0);
+        assert_eq!(db.stored_count(&id).unwrap(), 3);
 
-        let failed = std::thread::spawn(move || {
-            let _db = Data::init(test_path);
-        })
-        .join();
+        db.seen_counts.insert(&id, &7u64.to_le_bytes()).unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 1);
+        assert_eq!(db.stored_count(&id).unwrap(), 3);
 
-        assert!(failed.is_err())
+        // Starting over still counts from there.
+        db.record_raw(&hash, SeenImage::new("d".to_string(), 1101, 400, 4))
+            .unwrap();
+        db.record_raw(&hash, SeenImage::new("e".to_string(), 1150, 500, 5))
+            .unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 0);
+        assert_eq!(db.stored_count(&id).unwrap(), 2);
     }
 
     #[test]
-    fn databse_version_moves() {
-        let db = Data::init("").unwrap();
+    fn mismatched_counts_repaired() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let repost = SeenImage::new("again".to_string(), 800, 242343400, 238484343);
+        let hash = ImageHash::from_bytes(&[0; 64]).unwrap();
+        let untracked = ImageHash::from_bytes(&[0xFF; 64]).unwrap();
+
+        db.record_raw(&hash, original.clone()).unwrap();
+        db.record_raw(&hash, repost).unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 0);
+
+        let id = db
+            .seen_hashes
+            .get(db.hash_key(hash.as_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.occurrences_of(&id).unwrap().len(), 2);
+        assert_eq!(
+            db.occurrences_of(&id).unwrap()[0],
+            Occurrence::from(&original)
+        );
 
+        db.seen_counts.insert(&id, &5u64.to_le_bytes()).unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 1);
         assert_eq!(
-            db.db.get(Data::VERSION_KEY).unwrap(),
-            Some(IVec::from(&[1]))
+            Data::read_int(&db.seen_counts.get(&id).unwrap().unwrap()),
+            2
+        );
+
+        // Dangling counts are recreated.
+        db.seen_counts.remove(&id).unwrap();
+        assert_eq!(db.repair_counts().unwrap(), 1);
+        assert_eq!(
+            Data::read_int(&db.seen_counts.get(&id).unwrap().unwrap()),
+            2
         );
+
+        // Images without a full history, like ones recorded before occurrences were, are left alone.
+        db.record_raw(&untracked, SeenImage::new("old".to_string(), 1, 2, 3))
+            .unwrap();
+        let untracked_id = db
+            .seen_hashes
+            .get(db.hash_key(untracked.as_bytes()))
+            .unwrap()
+            .unwrap();
+        for key in db.occurrences.scan_prefix(&untracked_id).keys() {
+            db.occurrences.remove(key.unwrap()).unwrap();
+        }
+        db.seen_counts
+            .insert(&untracked_id, &9u64.to_le_bytes())
+            .unwrap();
+
+        assert_eq!(db.repair_counts().unwrap(), 0);
     }
 
     #[test]
-    fn store_and_fetch() {
-        let db = Data::init("").unwrap();
+    fn format_thresholds_override() {
+        let mut config = Config::default();
+        config.detection.format_thresholds = "jpeg=20".parse().unwrap();
+        let db = Data::init("", &config).unwrap();
 
+        let hash = |bytes: [u8; 8]| ImageHash::from_bytes(&bytes).unwrap();
+        let stored = |bytes, format| Hashes {
+            hash: hash(bytes),
+            variants: Vec::new(),
+            format: Some(format),
+            content: None,
+            frames: Vec::new(),
+            source: None,
+        };
         let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        db.record_raw(stored([0; 8], ImageFormat::Jpeg), original.clone())
+            .unwrap();
+        db.record_raw(stored([0xFF; 8], ImageFormat::Png), original.clone())
+            .unwrap();
 
-        let hash = ImageHash::from_bytes(&[1, 1, 1, 1, 1, 1, 1, 1]).unwrap();
-        db.record_image(&hash, original.clone()).unwrap();
+        // 12 away from the JPEG is past the default threshold of 8, but within the JPEG one.
+        assert!(matches!(
+            db.match_raw(hash([0xFF, 0x0F, 0, 0, 0, 0, 0, 0]), original.clone())
+                .unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
 
-        db.access_image(&[1, 2, 3], |fetched| {
-            assert_eq!(*fetched, original);
-            false
-        })
-        .unwrap();
+        // PNGs don't have an override, so the default applies.
+        let near_png = [0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            db.match_raw(hash(near_png), original.clone()).unwrap(),
+            PreviouslySeen::No
+        );
+        let nearer_png = [0x0F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(matches!(
+            db.match_raw(hash(nearer_png), original).unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
     }
 
     #[test]
-    fn store_duplicates() {
-        let db = Data::init("").unwrap();
-        let id = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+    fn variants_matched() {
+        let db = Data::init("", &Config::default()).unwrap();
 
-        let original = SeenImage::new(
-            "testing_but_looooooooooooonnnng".to_string(),
-            26543654564,
-            59849292,
-            3424324234,
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let rotated = Hashes {
+            hash: ImageHash::from_bytes(&[0xFF; 64]).unwrap(),
+            variants: vec![
+                ImageHash::from_bytes(&[0x0F; 64]).unwrap(),
+                ImageHash::from_bytes(&[0; 64]).unwrap(),
+            ],
+            format: None,
+            content: None,
+            frames: Vec::new(),
+            source: None,
+        };
+
+        db.record_raw(ImageHash::from_bytes(&[0; 64]).unwrap(), original.clone())
+            .unwrap();
+
+        assert_eq!(
+            db.match_raw(&rotated.hash, original.clone()).unwrap(),
+            PreviouslySeen::No
+        );
+        assert_eq!(
+            db.record_raw(rotated.clone(), original.clone()).unwrap(),
+            PreviouslySeen::Yes {
+                image: original.clone(),
+                times_seen: 2
+            }
         );
 
-        let existing = db.record_image(&id, original.clone()).unwrap();
-        assert_eq!(existing, PreviouslySeen::No);
+        // The hash it was posted with is what's remembered.
+        assert_eq!(
+            db.record_raw(&rotated.hash, original.clone()).unwrap(),
+            PreviouslySeen::Yes {
+                image: original,
+                times_seen: 3
+            }
+        );
+    }
 
-        let (db_id, _) = db.stored_images.first().unwrap().unwrap();
+    #[test]
+    fn links_counted_per_guild() {
+        let db = Data::init("", &Config::default()).unwrap().for_guild(1);
+        let first = SeenImage::new("first".to_string(), 10, 100, 1000);
+        let second = SeenImage::new("second".to_string(), 20, 200, 2000);
 
-        let seen_count = db.seen_counts.get(&db_id).unwrap().unwrap();
-        assert_eq!(Data::read_int(&seen_count), 1);
+        assert_eq!(db.record_link("example.com/a", &first).unwrap(), None);
+        assert_eq!(
+            db.record_link("example.com/a", &second).unwrap(),
+            Some((first.clone(), 2))
+        );
+        assert_eq!(
+            db.record_link("example.com/a", &second).unwrap(),
+            Some((first, 3))
+        );
 
-        let newer = SeenImage::new("someone else".to_string(), 555555555, 4384834303, 434343423);
+        assert_eq!(db.record_link("example.com/b", &second).unwrap(), None);
+        assert_eq!(
+            db.for_guild(2)
+                .record_link("example.com/a", &second)
+                .unwrap(),
+            None
+        );
 
-        let old = db.record_image(&id, newer).unwrap();
+        db.purge_guild(1).unwrap();
+        assert_eq!(db.record_link("example.com/a", &second).unwrap(), None);
+    }
 
-        let (old, times_seen) = match old {
-            PreviouslySeen::Yes { image, times_seen } => (image, times_seen),
-            _ => panic!("wrong seen variant"),
+    #[test]
+    fn animations_matched_by_any_frame() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = |byte| ImageHash::from_bytes(&[byte; 64]).unwrap();
+
+        let original = SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+        let animation = Hashes {
+            frames: vec![hash(0x0F), hash(0xF0)],
+            ..Hashes::from(hash(0))
         };
+        db.record_raw(animation, original.clone()).unwrap();
+        assert_eq!(db.index().len(), 3);
 
-        assert_eq!(old, original);
-        assert_eq!(times_seen, 2);
+        // A copy cut to start on a later frame matches, whether or not it's animated itself.
+        assert!(matches!(
+            db.match_raw(hash(0xF0), original.clone()).unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
+        let cut = Hashes {
+            frames: vec![hash(0xFF)],
+            ..Hashes::from(hash(0x0F))
+        };
+        assert!(matches!(
+            db.record_raw(cut, original.clone()).unwrap(),
+            PreviouslySeen::Yes { times_seen: 3, .. }
+        ));
 
-        let seen_count = db.seen_counts.get(db_id).unwrap().unwrap();
-        assert_eq!(Data::read_int(&seen_count), 2);
+        // Its new frames were added to the same record.
+        assert!(matches!(
+            db.match_raw(hash(0xFF), original.clone()).unwrap(),
+            PreviouslySeen::Yes { times_seen: 4, .. }
+        ));
+
+        // Frames that already belong to another image stay with it.
+        let other = Hashes {
+            frames: vec![hash(0xF0)],
+            ..Hashes::from(hash(0x33))
+        };
+        db.record_raw(other, original).unwrap();
+        assert_eq!(db.index().len(), 5);
     }
 
     #[test]
-    fn store_similar() {
-        let db = Data::init("").unwrap();
-        let id = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+    fn guilds_matched_separately() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[7; 64]).unwrap();
+        let image = || SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
 
-        let original = SeenImage::new(
-            "testing_but_looooooooooooonnnng".to_string(),
-            26543654564,
-            59849292,
-            43434234342,
+        assert_eq!(
+            db.for_guild(1).record_raw(&hash, image()).unwrap(),
+            PreviouslySeen::No
         );
+        assert_eq!(
+            db.for_guild(2).record_raw(&hash, image()).unwrap(),
+            PreviouslySeen::No
+        );
+        assert!(matches!(
+            db.for_guild(1).record_raw(&hash, image()).unwrap(),
+            PreviouslySeen::Yes { times_seen: 2, .. }
+        ));
+        assert_eq!(db.stored_images.len(), 2);
+    }
 
-        db.record_image(&id, original.clone()).unwrap();
+    #[test]
+    fn hashes_migrated_into_guilds() {
+        let test_path = "./target/guild_scoped_hashes_test";
+        let _ = std::fs::remove_dir_all(test_path);
 
-        let newer = SeenImage::new("someone else".to_string(), 555555555, 4384834303, 323243434);
-        let newer_id = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 7]).unwrap();
+        // Fake a database from before hashes were scoped to guilds.
+        let legacy = sled::Config::new().path(test_path).open().unwrap();
+        legacy.insert(Data::VERSION_KEY, &[1]).unwrap();
+        let hashes = legacy.open_tree(Data::HASH_TREE).unwrap();
+        hashes.insert([1; 64], &[10]).unwrap();
+        hashes.insert([2; 64], &[10]).unwrap();
+        hashes.insert([3; 64], &[11]).unwrap();
+        legacy
+            .open_tree(Data::GUILD_IMAGES_TREE)
+            .unwrap()
+            .insert([&5u64.to_be_bytes()[..], &[10]].concat(), &[])
+            .unwrap();
+        drop((hashes, legacy));
 
-        let old = db.record_image(&newer_id, newer).unwrap();
+        let db = Data::init(test_path, &Config::default()).unwrap();
+        let keys: Vec<_> = db
+            .seen_hashes
+            .iter()
+            .map(|entry| entry.unwrap())
+            .map(|(key, id)| (key.to_vec(), id.to_vec()))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (db.hash_key(&[3; 64]), vec![11]),
+                (db.for_guild(5).hash_key(&[1; 64]), vec![10]),
+                (db.for_guild(5).hash_key(&[2; 64]), vec![10]),
+            ]
+        );
+        assert_eq!(db.version_info().unwrap().stored_version, CURRENT_VERSION);
 
-        let old = match old {
-            PreviouslySeen::Yes { image, .. } => image,
-            _ => panic!("wrong seen variant"),
+        // The index is built from what's on disk, after migrating.
+        assert_eq!(db.index().len(), 3);
+        assert_eq!(
+            db.index().within(5u64.to_be_bytes(), &[2; 64], 0),
+            vec![(0, &[2; 64][..])]
+        );
+    }
+
+    #[test]
+    fn images_migrated_with_guilds() {
+        let test_path = "./target/guild_in_images_test";
+        let _ = std::fs::remove_dir_all(test_path);
+
+        // Fake a database from before images knew their guild.
+        let legacy = sled::Config::new().path(test_path).open().unwrap();
+        legacy.insert(Data::VERSION_KEY, &[2]).unwrap();
+        let images = legacy.open_tree(Data::STORAGE_TREE).unwrap();
+        for (id, author) in [(10u64, "first"), (11, "second")] {
+            let mut serializer = WriteSerializer::new(Vec::new());
+            serializer
+                .serialize_value(&migrations::SeenImageV2 {
+                    ignored: id == 11,
+                    author: author.to_string(),
+                    sent: 100,
+                    original_message_id: 200,
+                    channel_id: 300,
+                })
+                .unwrap();
+            images
+                .insert(id.to_ne_bytes(), serializer.into_inner())
+                .unwrap();
+        }
+        legacy
+            .open_tree(Data::GUILD_IMAGES_TREE)
+            .unwrap()
+            .insert(
+                [&5u64.to_be_bytes()[..], &10u64.to_ne_bytes()].concat(),
+                &[],
+            )
+            .unwrap();
+        drop((images, legacy));
+
+        let db = Data::init(test_path, &Config::default()).unwrap();
+        let image = |id: u64| -> SeenImage {
+            let stored = db.stored_images.get(id.to_le_bytes()).unwrap().unwrap();
+            Data::read_archived::<SeenImage>(&stored)
+                .deserialize(&mut SharedDeserializeMap::new())
+                .unwrap()
         };
 
-        assert_eq!(old, original)
+        assert_eq!(
+            image(10),
+            SeenImage {
+                guild_id: 5,
+                ..SeenImage::new("first".to_string(), 100, 200, 300)
+            }
+        );
+        assert_eq!(
+            image(11),
+            SeenImage {
+                ignored: true,
+                ..SeenImage::new("second".to_string(), 100, 200, 300)
+            }
+        );
+        assert_eq!(db.version_info().unwrap().stored_version, CURRENT_VERSION);
+    }
+
+    #[test]
+    fn images_counted_per_guild() {
+        let db = Data::init("", &Config::default()).unwrap();
+        let image = || SeenImage::new("testing".to_string(), 773, 242343331, 238484343);
+
+        let small = db.for_guild(1);
+        let big = db.for_guild(2);
+
+        small
+            .record_raw(ImageHash::from_bytes(&[0; 64]).unwrap(), image())
+            .unwrap();
+        big.record_raw(ImageHash::from_bytes(&[0x0F; 64]).unwrap(), image())
+            .unwrap();
+        big.record_raw(ImageHash::from_bytes(&[0xF0; 64]).unwrap(), image())
+            .unwrap();
+
+        // Reposts and unattributed images don't count.
+        big.record_raw(ImageHash::from_bytes(&[0x0F; 64]).unwrap(), image())
+            .unwrap();
+        db.record_raw(ImageHash::from_bytes(&[0xFF; 64]).unwrap(), image())
+            .unwrap();
+
+        assert_eq!(db.per_guild_counts().unwrap(), vec![(2, 2), (1, 1)]);
+    }
+
+    #[test]
+    fn fresh_database_version() {
+        let db = Data::init("", &Config::default()).unwrap();
+
+        let info = db.version_info().unwrap();
+        assert_eq!(
+            info,
+            VersionInfo {
+                stored_version: 6,
+                current_version: 6,
+                migrations_run: vec![
+                    "inital_version",
+                    "guild_scoped_hashes",
+                    "guild_in_images",
+                    "author_ids",
+                    "source_urls",
+                    "little_endian",
+                ],
+            }
+        );
+        assert!(info
+            .render()
+            .contains("Migrations run: inital_version, guild_scoped_hashes"));
     }
 }
@@ -0,0 +1,594 @@
+use crate::data_storage::{SeenImage, StorageStats};
+use crate::image_processing::{ImageInfo, ProcessedImage};
+
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A snapshot of the bot's resource usage.
+pub struct Diagnostics {
+    pub storage: StorageStats,
+    /// Resident memory of the process in bytes, if the platform exposes it.
+    pub resident_memory: Option<u64>,
+    /// Number of entries held in the recent failures buffer.
+    pub recent_failures: usize,
+    pub hashing_threads: usize,
+    /// Images waiting for a free hashing thread.
+    pub hashing_queue_depth: usize,
+}
+
+impl Diagnostics {
+    pub fn render(&self) -> String {
+        let mut out = String::from("**Diagnostics**\n");
+
+        // Writing to a `String` can't fail.
+        let _ = writeln!(
+            out,
+            "Database size on disk: {}",
+            format_bytes(self.storage.size_on_disk)
+        );
+        let _ = writeln!(out, "Stored images: {}", self.storage.stored_images);
+        let _ = writeln!(out, "Seen counts: {}", self.storage.seen_counts);
+        let _ = writeln!(out, "Hash aliases: {}", self.storage.seen_hashes);
+        let _ = writeln!(out, "Recent failures buffered: {}", self.recent_failures);
+        let _ = writeln!(out, "Hashing threads: {}", self.hashing_threads);
+        let _ = writeln!(
+            out,
+            "Images waiting to be hashed: {}",
+            self.hashing_queue_depth
+        );
+
+        match self.resident_memory {
+            Some(rss) => {
+                let _ = write!(out, "Resident memory: {}", format_bytes(rss));
+            }
+            None => out.push_str("Resident memory: unavailable"),
+        }
+
+        out
+    }
+}
+
+/// Reads the resident set size of the current process.
+///
+/// Only Linux exposes this without extra dependencies, so other platforms get `None`.
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+/// Running totals of what the bot has done since it started.
+#[derive(Default)]
+pub struct RuntimeCounters {
+    messages_seen: AtomicU64,
+    images_processed: AtomicU64,
+    reposts_detected: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl RuntimeCounters {
+    pub fn message_seen(&self) {
+        self.messages_seen.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn image_processed(&self) {
+        self.images_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn repost_detected(&self) {
+        self.reposts_detected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The current totals, for a bot that's been running for `uptime` seconds.
+    pub fn snapshot(&self, uptime: u64) -> Runtime {
+        Runtime {
+            uptime,
+            messages_seen: self.messages_seen.load(Ordering::Relaxed),
+            images_processed: self.images_processed.load(Ordering::Relaxed),
+            reposts_detected: self.reposts_detected.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What the bot has done since it started.
+#[derive(Debug, PartialEq)]
+pub struct Runtime {
+    /// How long the bot has been running, in seconds.
+    pub uptime: u64,
+    pub messages_seen: u64,
+    pub images_processed: u64,
+    pub reposts_detected: u64,
+    /// Messages that couldn't be handled.
+    pub errors: u64,
+}
+
+impl Runtime {
+    pub fn render(&self) -> String {
+        format!(
+            "Up for {}. Since then I've seen {} messages, processed {} images, \
+             and caught {} reposts, with {} errors.",
+            format_uptime(self.uptime),
+            self.messages_seen,
+            self.images_processed,
+            self.reposts_detected,
+            self.errors
+        )
+    }
+}
+
+/// Formats a number of seconds like `2d 3h 4m`, leaving out seconds once it's been an hour.
+pub fn format_uptime(seconds: u64) -> String {
+    const UNITS: &[(u64, &str)] = &[(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m"), (1, "s")];
+
+    let show_seconds = seconds < 60 * 60;
+    let mut remaining = seconds;
+    let mut parts = Vec::new();
+
+    for &(unit_secs, suffix) in UNITS {
+        if unit_secs == 1 && !show_seconds {
+            break;
+        }
+
+        let amount = remaining / unit_secs;
+        remaining %= unit_secs;
+
+        if amount > 0 {
+            parts.push(format!("{}{}", amount, suffix));
+        }
+    }
+
+    if parts.is_empty() {
+        return "0s".to_string();
+    }
+
+    parts.join(" ")
+}
+
+/// A download or decode that didn't make it into the database.
+#[derive(Debug, PartialEq)]
+pub struct Failure {
+    pub url: String,
+    pub kind: &'static str,
+    /// When the failure happened - std::time::UNIX_EPOCH, in seconds.
+    pub at: u64,
+}
+
+/// The most recent image failures, newest first.
+pub struct RecentFailures {
+    failures: VecDeque<Failure>,
+    capacity: usize,
+}
+
+impl RecentFailures {
+    /// Longest URL shown when rendering, to keep the message under Discord's length limit.
+    const MAX_URL_LENGTH: usize = 100;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            failures: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records a failure, evicting the oldest one if full.
+    pub fn push(&mut self, failure: Failure) {
+        if self.failures.len() == self.capacity {
+            self.failures.pop_back();
+        }
+
+        self.failures.push_front(failure);
+    }
+
+    pub fn len(&self) -> usize {
+        self.failures.len()
+    }
+
+    pub fn render(&self, now: u64) -> String {
+        if self.failures.is_empty() {
+            return "No images have failed recently.".to_string();
+        }
+
+        let mut out = String::from("**Recent failures**");
+        for failure in &self.failures {
+            let url = match failure.url.char_indices().nth(Self::MAX_URL_LENGTH) {
+                Some((end, _)) => format!("{}...", &failure.url[..end]),
+                None => failure.url.clone(),
+            };
+
+            let _ = write!(
+                out,
+                "\n<{}> - {}, {}",
+                url,
+                failure.kind,
+                crate::time_since(now.saturating_sub(failure.at))
+            );
+        }
+
+        out
+    }
+}
+
+/// Lists how many images are tracked per guild as embed fields, naming the guilds that `name` knows about.
+pub fn guild_count_fields(
+    counts: &[(u64, usize)],
+    name: impl Fn(u64) -> Option<String>,
+) -> Vec<(String, String)> {
+    counts
+        .iter()
+        .map(|&(guild_id, count)| {
+            let guild = match name(guild_id) {
+                Some(name) => format!("{} ({})", name, guild_id),
+                None => guild_id.to_string(),
+            };
+
+            (guild, format!("{} images", count))
+        })
+        .collect()
+}
+
+/// What each stage of the detection pipeline made of an image, without anything being recorded.
+///
+/// Stages are filled in as they finish, so everything after the one that failed is `None`.
+pub struct PipelineTrace {
+    /// The similarity threshold the nearest match is compared against.
+    pub threshold: u32,
+    /// How many bytes were downloaded.
+    pub fetched: Option<u64>,
+    /// Missing if the image's header wasn't recognized, which doesn't stop it from being hashed.
+    pub info: Option<ImageInfo>,
+    pub processed: Option<ProcessedImage>,
+    /// The closest stored image and its distance, or `Some(None)` if nothing is stored yet.
+    pub nearest: Option<Option<(u32, SeenImage)>>,
+    /// What stopped the pipeline, if something did.
+    pub failure: Option<&'static str>,
+}
+
+impl PipelineTrace {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            fetched: None,
+            info: None,
+            processed: None,
+            nearest: None,
+            failure: None,
+        }
+    }
+
+    /// Marks the pipeline as stopped by `failure`.
+    pub fn failed(mut self, failure: &'static str) -> Self {
+        self.failure = Some(failure);
+        self
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("**Pipeline test**");
+        if let Some(fetched) = self.fetched {
+            let _ = write!(out, "\nFetched: {}", format_bytes(fetched));
+
+            match &self.info {
+                Some(info) => {
+                    let _ = write!(
+                        out,
+                        "\nFormat: {:?}, {}x{}",
+                        info.format, info.width, info.height
+                    );
+                }
+                None => out.push_str("\nFormat: unrecognized"),
+            }
+        }
+
+        match &self.processed {
+            Some(ProcessedImage::Hashed(hashes)) => {
+                let _ = write!(out, "\nHash: `{}`", hashes.hash.to_base64());
+            }
+            Some(ProcessedImage::MatchOnly(hashes)) => {
+                let _ = write!(
+                    out,
+                    "\nHash: `{}` (too small to store, only matched against)",
+                    hashes.hash.to_base64()
+                );
+            }
+            Some(ProcessedImage::Skipped(reason)) => {
+                let _ = write!(out, "\nSkipped: {:?}", reason);
+            }
+            None => {}
+        }
+
+        match &self.nearest {
+            Some(Some((distance, image))) => {
+                let verdict = if *distance <= self.threshold {
+                    "within"
+                } else {
+                    "past"
+                };
+                let _ = write!(
+                    out,
+                    "\nNearest match: posted by {} in <#{}>, {} away ({} the threshold of {})",
+                    image.poster(),
+                    image.channel_id,
+                    distance,
+                    verdict,
+                    self.threshold
+                );
+            }
+            Some(None) => out.push_str("\nNearest match: nothing is stored yet"),
+            None => {}
+        }
+
+        if let Some(failure) = self.failure {
+            let _ = write!(out, "\nFailed: {}", failure);
+        }
+
+        out
+    }
+}
+
+/// Renders a distance histogram, skipping empty buckets to keep the message short.
+pub fn render_histogram(histogram: &[u64], bucket_width: u32) -> String {
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return "There aren't enough stored images to compare yet.".to_string();
+    }
+
+    let mut out = format!("**Hash distances** ({} pairs sampled)", total);
+    for (i, &count) in histogram.iter().enumerate().filter(|(_, &c)| c != 0) {
+        let start = i as u32 * bucket_width;
+        let end = start + bucket_width - 1;
+
+        let _ = write!(out, "\n`{:>3}-{:<3}` {}", start, end, count);
+    }
+
+    out
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+
+        size /= 1024.0;
+        unit = next;
+    }
+
+    format!("{:.1} {}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processing::{Hashes, ImageHash, SkipReason};
+    use image::ImageFormat;
+
+    fn stats() -> StorageStats {
+        StorageStats {
+            size_on_disk: 3 * 1024 * 1024 + 512 * 1024,
+            stored_images: 42,
+            seen_counts: 42,
+            seen_hashes: 57,
+        }
+    }
+
+    #[test]
+    fn guild_count_listing() {
+        let counts = [(1, 20), (2, 5)];
+        let name = |id| (id == 1).then(|| "Art Club".to_string());
+
+        assert_eq!(
+            guild_count_fields(&counts, name),
+            vec![
+                ("Art Club (1)".to_string(), "20 images".to_string()),
+                ("2".to_string(), "5 images".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn histogram_rendering() {
+        let mut histogram = vec![0; 57];
+        histogram[0] = 1;
+        histogram[12] = 40;
+
+        assert_eq!(
+            render_histogram(&histogram, 9),
+            "**Hash distances** (41 pairs sampled)\n`  0-8  ` 1\n`108-116` 40"
+        );
+        assert_eq!(
+            render_histogram(&[0; 57], 9),
+            "There aren't enough stored images to compare yet."
+        );
+    }
+
+    #[test]
+    fn pipeline_trace_rendering() {
+        let hash = ImageHash::from_bytes(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let info = || ImageInfo {
+            format: ImageFormat::Png,
+            width: 64,
+            height: 32,
+        };
+
+        let mut trace = PipelineTrace::new(10);
+        trace.fetched = Some(2048);
+        trace.info = Some(info());
+        trace.processed = Some(ProcessedImage::Hashed(Hashes::from(&hash)));
+        trace.nearest = Some(Some((4, SeenImage::new("<@5>".to_string(), 0, 6, 7))));
+        assert_eq!(
+            trace.render(),
+            format!(
+                "**Pipeline test**\nFetched: 2.0 KiB\nFormat: Png, 64x32\nHash: `{}`\n\
+                 Nearest match: posted by <@5> in <#7>, 4 away (within the threshold of 10)",
+                hash.to_base64()
+            )
+        );
+
+        trace.nearest = Some(None);
+        assert!(trace
+            .render()
+            .ends_with("Nearest match: nothing is stored yet"));
+
+        let mut skipped = PipelineTrace::new(10);
+        skipped.fetched = Some(10);
+        skipped.processed = Some(ProcessedImage::Skipped(SkipReason::Animated));
+        assert_eq!(
+            skipped.render(),
+            "**Pipeline test**\nFetched: 10 B\nFormat: unrecognized\nSkipped: Animated"
+        );
+
+        assert_eq!(
+            PipelineTrace::new(10).failed("download failed").render(),
+            "**Pipeline test**\nFailed: download failed"
+        );
+
+        let mut undecodable = PipelineTrace::new(10);
+        undecodable.fetched = Some(10);
+        assert_eq!(
+            undecodable.failed("unsupported image format").render(),
+            "**Pipeline test**\nFetched: 10 B\nFormat: unrecognized\nFailed: unsupported image format"
+        );
+    }
+
+    #[test]
+    fn diagnostics_rendering() {
+        let diag = Diagnostics {
+            storage: stats(),
+            resident_memory: Some(25 * 1024 * 1024),
+            recent_failures: 3,
+            hashing_threads: 4,
+            hashing_queue_depth: 2,
+        };
+
+        assert_eq!(
+            diag.render(),
+            "**Diagnostics**\n\
+            Database size on disk: 3.5 MiB\n\
+            Stored images: 42\n\
+            Seen counts: 42\n\
+            Hash aliases: 57\n\
+            Recent failures buffered: 3\n\
+            Hashing threads: 4\n\
+            Images waiting to be hashed: 2\n\
+            Resident memory: 25.0 MiB"
+        );
+
+        let without_rss = Diagnostics {
+            storage: stats(),
+            resident_memory: None,
+            recent_failures: 0,
+            hashing_threads: 1,
+            hashing_queue_depth: 0,
+        };
+
+        assert!(without_rss
+            .render()
+            .ends_with("Resident memory: unavailable"));
+    }
+
+    fn failure(url: &str, at: u64) -> Failure {
+        Failure {
+            url: url.to_string(),
+            kind: "download failed",
+            at,
+        }
+    }
+
+    #[test]
+    fn recent_failures_are_capped() {
+        let mut failures = RecentFailures::new(3);
+
+        for i in 0..5 {
+            failures.push(failure(&format!("https://example.com/{}.png", i), i));
+        }
+
+        assert_eq!(failures.len(), 3);
+
+        let newest_first: Vec<u64> = failures.failures.iter().map(|f| f.at).collect();
+        assert_eq!(newest_first, [4, 3, 2]);
+    }
+
+    #[test]
+    fn recent_failures_rendering() {
+        let mut failures = RecentFailures::new(5);
+        assert_eq!(failures.render(0), "No images have failed recently.");
+
+        failures.push(failure("https://example.com/old.png", 100));
+        failures.push(failure(
+            &format!("https://example.com/{}", "a".repeat(200)),
+            250,
+        ));
+
+        let rendered = failures.render(400);
+        let lines: Vec<&str> = rendered.lines().collect();
+
+        assert_eq!(lines[0], "**Recent failures**");
+        assert!(lines[1].starts_with("<https://example.com/aaaa"));
+        assert!(lines[1].ends_with("...> - download failed, 2 minutes ago"));
+        assert_eq!(
+            lines[2],
+            "<https://example.com/old.png> - download failed, 5 minutes ago"
+        );
+    }
+
+    #[test]
+    fn uptime_formatting() {
+        assert_eq!(format_uptime(0), "0s");
+        assert_eq!(format_uptime(45), "45s");
+        assert_eq!(format_uptime(61), "1m 1s");
+        assert_eq!(format_uptime(60 * 60 + 1), "1h");
+        assert_eq!(format_uptime(2 * 86400 + 3 * 3600 + 4 * 60 + 5), "2d 3h 4m");
+        assert_eq!(format_uptime(86400), "1d");
+    }
+
+    #[test]
+    fn runtime_rendering() {
+        let counters = RuntimeCounters::default();
+        counters.message_seen();
+        counters.message_seen();
+        counters.image_processed();
+        counters.repost_detected();
+
+        let runtime = counters.snapshot(3 * 3600 + 120);
+        assert_eq!(
+            runtime,
+            Runtime {
+                uptime: 3 * 3600 + 120,
+                messages_seen: 2,
+                images_processed: 1,
+                reposts_detected: 1,
+                errors: 0,
+            }
+        );
+        assert_eq!(
+            runtime.render(),
+            "Up for 3h 2m. Since then I've seen 2 messages, processed 1 images, \
+             and caught 1 reposts, with 0 errors."
+        );
+    }
+
+    #[test]
+    fn byte_formatting() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(2048), "2.0 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
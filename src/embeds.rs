@@ -0,0 +1,157 @@
+use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder};
+use twilight_model::channel::embed::Embed;
+
+/// Most fields Discord allows in one embed.
+pub const MAX_FIELDS: usize = 25;
+/// Most characters Discord allows across an embed's title and fields.
+pub const MAX_TOTAL_LENGTH: usize = 6000;
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_NAME_LENGTH: usize = 256;
+const MAX_VALUE_LENGTH: usize = 1024;
+/// Room left in every title for its page number, like ` (12/34)`.
+const PAGE_SUFFIX_LENGTH: usize = 16;
+
+/// Splits `fields` into as many embeds titled `title` as it takes to stay within Discord's limits,
+/// with at most `max_fields` fields each.
+///
+/// Names and values that are too long on their own are cut short.
+pub fn paginate(title: &str, fields: Vec<(String, String)>, max_fields: usize) -> Vec<Embed> {
+    let max_fields = max_fields.clamp(1, MAX_FIELDS);
+    let title = truncate(title, MAX_TITLE_LENGTH - PAGE_SUFFIX_LENGTH);
+    let budget = MAX_TOTAL_LENGTH - title.chars().count() - PAGE_SUFFIX_LENGTH;
+
+    let mut pages: Vec<Vec<(String, String)>> = vec![Vec::new()];
+    let mut used = 0;
+
+    for (name, value) in fields {
+        let name = fit_field(&name, MAX_NAME_LENGTH);
+        let value = fit_field(&value, MAX_VALUE_LENGTH);
+        let length = name.chars().count() + value.chars().count();
+
+        let page = pages.last_mut().expect("there's always a page");
+        if !page.is_empty() && (page.len() == max_fields || used + length > budget) {
+            pages.push(Vec::new());
+            used = 0;
+        }
+
+        used += length;
+        pages
+            .last_mut()
+            .expect("there's always a page")
+            .push((name, value));
+    }
+
+    let total = pages.len();
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, fields)| {
+            let title = if total == 1 {
+                title.clone()
+            } else {
+                format!("{} ({}/{})", title, i + 1, total)
+            };
+
+            fields
+                .into_iter()
+                .fold(EmbedBuilder::new().title(title), |embed, (name, value)| {
+                    embed.field(EmbedFieldBuilder::new(name, value).inline().build())
+                })
+                .build()
+                .expect("bug: a paginated embed went over Discord's limits")
+        })
+        .collect()
+}
+
+/// Discord rejects empty field names and values, so those get a zero width space instead.
+fn fit_field(text: &str, max_length: usize) -> String {
+    if text.trim().is_empty() {
+        return "\u{200b}".to_string();
+    }
+
+    truncate(text, max_length)
+}
+
+fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+
+    let mut cut: String = text.chars().take(max_length - 1).collect();
+    cut.push('…');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(count: usize, value_length: usize) -> Vec<(String, String)> {
+        (0..count)
+            .map(|i| (i.to_string(), "v".repeat(value_length)))
+            .collect()
+    }
+
+    fn length(embed: &Embed) -> usize {
+        embed.title.as_ref().map_or(0, |t| t.chars().count())
+            + embed
+                .fields
+                .iter()
+                .map(|f| f.name.chars().count() + f.value.chars().count())
+                .sum::<usize>()
+    }
+
+    #[test]
+    fn field_limit() {
+        let embeds = paginate("Guilds", fields(MAX_FIELDS, 10), MAX_FIELDS);
+        assert_eq!(embeds.len(), 1);
+        assert_eq!(embeds[0].title.as_deref(), Some("Guilds"));
+        assert_eq!(embeds[0].fields.len(), MAX_FIELDS);
+
+        let embeds = paginate("Guilds", fields(MAX_FIELDS + 1, 10), MAX_FIELDS);
+        assert_eq!(embeds.len(), 2);
+        assert_eq!(embeds[0].fields.len(), MAX_FIELDS);
+        assert_eq!(embeds[1].fields.len(), 1);
+        assert_eq!(embeds[1].title.as_deref(), Some("Guilds (2/2)"));
+
+        // Smaller pages can be asked for, but never bigger ones.
+        assert_eq!(paginate("Guilds", fields(10, 10), 4).len(), 3);
+        assert_eq!(
+            paginate("Guilds", fields(30, 10), 100)[0].fields.len(),
+            MAX_FIELDS
+        );
+        assert_eq!(paginate("Guilds", fields(3, 10), 0).len(), 3);
+    }
+
+    #[test]
+    fn length_limit() {
+        // Five full fields fit, but the sixth goes past 6000 characters.
+        let embeds = paginate("Guilds", fields(6, MAX_VALUE_LENGTH), MAX_FIELDS);
+        assert_eq!(embeds.len(), 2);
+        assert_eq!(embeds[0].fields.len(), 5);
+
+        let embeds = paginate("Guilds", fields(40, MAX_VALUE_LENGTH), MAX_FIELDS);
+        assert!(embeds.iter().all(|e| length(e) <= MAX_TOTAL_LENGTH));
+        assert_eq!(embeds.iter().map(|e| e.fields.len()).sum::<usize>(), 40);
+    }
+
+    #[test]
+    fn oversized_fields_truncated() {
+        let embeds = paginate(
+            &"t".repeat(1000),
+            vec![
+                ("n".repeat(1000), "v".repeat(5000)),
+                (String::new(), " ".to_string()),
+            ],
+            MAX_FIELDS,
+        );
+        assert_eq!(embeds.len(), 1);
+
+        let field = &embeds[0].fields[0];
+        assert_eq!(field.name.chars().count(), MAX_NAME_LENGTH);
+        assert_eq!(field.value.chars().count(), MAX_VALUE_LENGTH);
+        assert!(field.value.ends_with('…'));
+        assert_eq!(embeds[0].fields[1].name, "\u{200b}");
+        assert!(embeds[0].title.as_ref().unwrap().chars().count() <= MAX_TITLE_LENGTH);
+    }
+}
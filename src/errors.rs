@@ -1,44 +1,139 @@
-#[derive(Debug)]
-pub enum Error {
-    Database(DatabaseError),
-    InteractionError(DiscordInteractionError),
-    DownloadingConent(hyper::Error),
-    ContentTooLarge,
-    UnsupportedChannelConfig,
-    UnsupportedImageFormat(image::error::ImageError),
-}
-
-impl From<hyper::Error> for Error {
-    fn from(e: hyper::Error) -> Self {
-        Self::DownloadingConent(e)
-    }
-}
-
-impl From<DiscordInteractionError> for Error {
-    fn from(e: DiscordInteractionError) -> Self {
-        Self::InteractionError(e)
-    }
-}
-
-impl From<DatabaseError> for Error {
-    fn from(e: DatabaseError) -> Self {
-        Self::Database(e)
-    }
-}
-
-#[derive(Debug)]
-pub enum DiscordInteractionError {
-    SendingMessage(twilight_http::Error),
-    FetchingMessage(twilight_http::Error),
-    ReactionHandling(twilight_http::Error),
-    Deserialize(twilight_http::response::DeserializeBodyError),
-    FailedToChangeStatus(twilight_gateway::cluster::ClusterCommandError),
-    MessageNotFound,
-}
-
-#[derive(Debug)]
-pub enum DatabaseError {
-    Accessing(sled::Error),
-    Initalizing(sled::Error),
-    Recording(sled::Error),
-}
+#[derive(Debug)]
+pub enum Error {
+    Database(DatabaseError),
+    InteractionError(Box<DiscordInteractionError>),
+    DownloadingConent(hyper::Error),
+    ContentTooLarge,
+    /// A download took longer than it's allowed to.
+    TimedOut,
+    /// A download redirected too many times, somewhere invalid, or to another scheme.
+    BadRedirect,
+    /// A linked page, like a Tenor GIF or a Reddit post, didn't say what media it shows.
+    MediaNotFound,
+    UnsupportedChannelConfig,
+    UnsupportedImageFormat(image::error::ImageError),
+    /// ffmpeg couldn't decode a video, with why.
+    DecodingVideo(String),
+}
+
+impl Error {
+    /// A short, human readable description of what went wrong.
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Database(_) => "database error",
+            Self::InteractionError(_) => "discord error",
+            Self::DownloadingConent(_) => "download failed",
+            Self::ContentTooLarge => "content too large",
+            Self::TimedOut => "download timed out",
+            Self::BadRedirect => "bad redirect",
+            Self::MediaNotFound => "media not found",
+            Self::UnsupportedChannelConfig => "unsupported channel",
+            Self::UnsupportedImageFormat(_) => "unsupported image format",
+            Self::DecodingVideo(_) => "video decoding failed",
+        }
+    }
+
+    /// If trying again might work, like after a network hiccup.
+    pub const fn is_transient(&self) -> bool {
+        matches!(self, Self::DownloadingConent(_) | Self::TimedOut)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Self::DownloadingConent(e)
+    }
+}
+
+impl From<DiscordInteractionError> for Error {
+    fn from(e: DiscordInteractionError) -> Self {
+        Self::InteractionError(Box::new(e))
+    }
+}
+
+impl From<DatabaseError> for Error {
+    fn from(e: DatabaseError) -> Self {
+        Self::Database(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum DiscordInteractionError {
+    SendingMessage(twilight_http::Error),
+    FetchingMessage(twilight_http::Error),
+    FetchingChannel(twilight_http::Error),
+    FetchingCurrentUser(twilight_http::Error),
+    DeletingMessage(twilight_http::Error),
+    RespondingToInteraction(twilight_http::Error),
+    Deserialize(twilight_http::response::DeserializeBodyError),
+    FailedToChangeStatus(twilight_gateway::cluster::ClusterCommandError),
+    MessageNotFound,
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    InvalidConfig(&'static str),
+    Accessing(sled::Error),
+    Initalizing(sled::Error),
+    Recording(sled::Error),
+    CorruptSettings(serde_json::Error),
+    CorruptOccurrence(serde_json::Error),
+    /// The SQLite database couldn't be read or written.
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    Io(std::io::Error),
+    Malformed(serde_json::Error),
+    UnsupportedVersion(u32),
+    /// The export's contents don't match its signature, so it was corrupted or changed.
+    SignatureMismatch,
+    /// The export was signed with a key, but there isn't one to verify it with.
+    MissingKey,
+    /// The export came from a database with a newer storage format than this build knows.
+    NewerSchema(u8),
+    /// A hash in the export already belongs to another image, here or earlier in the export.
+    ConflictingHash(String),
+    Database(DatabaseError),
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't access the export file: {}", e),
+            Self::Malformed(e) => write!(f, "the export isn't valid: {}", e),
+            Self::UnsupportedVersion(v) => write!(f, "export format version {} isn't supported", v),
+            Self::SignatureMismatch => f.write_str("the export doesn't match its signature"),
+            Self::MissingKey => f.write_str("the export is signed, but no signing key is set"),
+            Self::NewerSchema(v) => write!(
+                f,
+                "the export is from database version {}, which is newer than this build",
+                v
+            ),
+            Self::ConflictingHash(hash) => {
+                write!(f, "hash {} already belongs to another image", hash)
+            }
+            Self::Database(e) => write!(f, "database error: {:?}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for TransferError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for TransferError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Malformed(e)
+    }
+}
+
+impl From<DatabaseError> for TransferError {
+    fn from(e: DatabaseError) -> Self {
+        Self::Database(e)
+    }
+}
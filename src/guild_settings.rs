@@ -0,0 +1,285 @@
+use crate::config::Config;
+
+use serde::{Deserialize, Serialize};
+
+/// Settings a guild's moderators can change at runtime, persisted per guild.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    /// When raid mode ends - std::time::UNIX_EPOCH, in seconds.
+    pub raid_mode_until: Option<u64>,
+    /// Largest image, in bytes, that will be downloaded for checking.
+    pub max_image_size: Option<u64>,
+    /// If reposts of images first posted in another channel are called out.
+    pub cross_channel_replies: Option<bool>,
+    /// Role that can moderate the bot, on top of anyone who can manage messages.
+    pub mod_role: Option<u64>,
+    /// Most images that can be stored for the guild.
+    pub record_quota: Option<u64>,
+    /// If the guild turned off repost checking, leaving only commands.
+    pub disabled: bool,
+    /// Channels images are only checked in, or every channel if it's empty.
+    pub allowed_channels: Vec<u64>,
+    /// Channels images are never checked in.
+    pub denied_channels: Vec<u64>,
+    /// If images in channels marked NSFW are left alone.
+    pub skip_nsfw: Option<bool>,
+    /// If people reposting their own images are counted without a reply.
+    pub ignore_own_reposts: Option<bool>,
+}
+
+/// The settings that actually apply to a guild right now, after overrides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveSettings {
+    /// If messages are checked for reposts at all.
+    pub enabled: bool,
+    /// Maximum hash distance for two images to be considered the same.
+    pub similarity_threshold: u32,
+    /// If reposts are deleted after being called out.
+    pub delete_reposts: bool,
+    /// Largest image, in bytes, that will be downloaded for checking.
+    pub max_image_size: u64,
+    /// If reposts of images first posted in another channel are called out.
+    pub cross_channel_replies: bool,
+    /// Role that can moderate the bot, on top of anyone who can manage messages.
+    pub mod_role: Option<u64>,
+    /// Most images that can be stored for the guild, if there's a limit.
+    pub record_quota: Option<u64>,
+    /// Channels images are only checked in, or every channel if it's empty.
+    pub allowed_channels: Vec<u64>,
+    /// Channels images are never checked in.
+    pub denied_channels: Vec<u64>,
+    /// If images in channels marked NSFW are left alone.
+    pub skip_nsfw: bool,
+    /// If people reposting their own images are counted without a reply.
+    pub ignore_own_reposts: bool,
+}
+
+impl EffectiveSettings {
+    /// If messages in a channel are checked for reposts.
+    pub fn checks_channel(&self, channel_id: u64) -> bool {
+        (self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel_id))
+            && !self.denied_channels.contains(&channel_id)
+    }
+}
+
+impl GuildSettings {
+    pub fn raid_mode_active(&self, now: u64) -> bool {
+        matches!(self.raid_mode_until, Some(until) if now < until)
+    }
+
+    /// Merges the operator's configuration with this guild's overrides.
+    pub fn effective(&self, config: &Config, now: u64) -> EffectiveSettings {
+        let raiding = self.raid_mode_active(now);
+
+        let similarity_threshold = if raiding {
+            config.detection.raid_similarity_threshold
+        } else {
+            config.detection.similarity_threshold
+        };
+
+        EffectiveSettings {
+            enabled: !self.disabled,
+            similarity_threshold,
+            delete_reposts: raiding,
+            max_image_size: self.max_image_size(config),
+            cross_channel_replies: self
+                .cross_channel_replies
+                .unwrap_or(config.reply.cross_channel_replies),
+            mod_role: self.mod_role,
+            record_quota: self.record_quota(config),
+            allowed_channels: self.allowed_channels.clone(),
+            denied_channels: self.denied_channels.clone(),
+            skip_nsfw: self
+                .skip_nsfw
+                .unwrap_or(config.detection.skip_nsfw_channels),
+            ignore_own_reposts: self
+                .ignore_own_reposts
+                .unwrap_or(config.reply.ignore_own_reposts),
+        }
+    }
+
+    /// The guild's image quota, which can never exceed the operator's hard limit.
+    pub fn record_quota(&self, config: &Config) -> Option<u64> {
+        let quota = self.record_quota.or(config.storage.guild_record_quota);
+
+        match (quota, config.storage.guild_record_quota_limit) {
+            (Some(quota), Some(limit)) => Some(quota.min(limit)),
+            (quota, limit) => quota.or(limit),
+        }
+    }
+
+    /// The guild's image size cap, which can never exceed the operator's hard limit.
+    pub fn max_image_size(&self, config: &Config) -> u64 {
+        self.max_image_size
+            .unwrap_or(config.download.max_image_size)
+            .min(config.download.max_image_size_limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_620_000_000;
+
+    #[test]
+    fn raid_mode_activation_and_expiry() {
+        let mut settings = GuildSettings::default();
+        assert!(!settings.raid_mode_active(NOW));
+
+        settings.raid_mode_until = Some(NOW + 3600);
+        assert!(settings.raid_mode_active(NOW));
+        assert!(settings.raid_mode_active(NOW + 3599));
+        assert!(!settings.raid_mode_active(NOW + 3600));
+    }
+
+    #[test]
+    fn raid_mode_overrides_settings() {
+        let mut config = Config::default();
+        config.detection.similarity_threshold = 8;
+        config.detection.raid_similarity_threshold = 14;
+
+        let normal = EffectiveSettings {
+            enabled: true,
+            similarity_threshold: 8,
+            delete_reposts: false,
+            max_image_size: config.download.max_image_size,
+            cross_channel_replies: true,
+            mod_role: None,
+            record_quota: None,
+            allowed_channels: Vec::new(),
+            denied_channels: Vec::new(),
+            skip_nsfw: false,
+            ignore_own_reposts: false,
+        };
+
+        let mut settings = GuildSettings::default();
+        assert_eq!(settings.effective(&config, NOW), normal);
+
+        settings.raid_mode_until = Some(NOW + 60);
+        assert_eq!(
+            settings.effective(&config, NOW),
+            EffectiveSettings {
+                enabled: true,
+                similarity_threshold: 14,
+                delete_reposts: true,
+                max_image_size: config.download.max_image_size,
+                cross_channel_replies: true,
+                mod_role: None,
+                record_quota: None,
+                allowed_channels: Vec::new(),
+                denied_channels: Vec::new(),
+                skip_nsfw: false,
+                ignore_own_reposts: false,
+            }
+        );
+
+        // Once it expires everything goes back to normal.
+        assert_eq!(settings.effective(&config, NOW + 60), normal);
+    }
+
+    #[test]
+    fn disabling_survives_raid_mode() {
+        let config = Config::default();
+        let mut settings = GuildSettings {
+            disabled: true,
+            ..Default::default()
+        };
+        assert!(!settings.effective(&config, NOW).enabled);
+
+        settings.raid_mode_until = Some(NOW + 60);
+        assert!(!settings.effective(&config, NOW).enabled);
+
+        settings.disabled = false;
+        assert!(settings.effective(&config, NOW).enabled);
+    }
+
+    #[test]
+    fn channel_lists() {
+        let config = Config::default();
+        let mut settings = GuildSettings::default();
+        assert!(settings.effective(&config, NOW).checks_channel(1));
+
+        settings.denied_channels = vec![2];
+        let effective = settings.effective(&config, NOW);
+        assert!(effective.checks_channel(1));
+        assert!(!effective.checks_channel(2));
+
+        // Denying wins over allowing.
+        settings.allowed_channels = vec![1, 2];
+        let effective = settings.effective(&config, NOW);
+        assert!(effective.checks_channel(1));
+        assert!(!effective.checks_channel(2));
+        assert!(!effective.checks_channel(3));
+    }
+
+    #[test]
+    fn image_size_cap_resolution() {
+        let mut config = Config::default();
+        config.download.max_image_size = 8 * 1024 * 1024;
+        config.download.max_image_size_limit = 32 * 1024 * 1024;
+
+        let mut settings = GuildSettings::default();
+        assert_eq!(settings.max_image_size(&config), 8 * 1024 * 1024);
+
+        // Guilds can raise or lower their cap within the limit...
+        settings.max_image_size = Some(20 * 1024 * 1024);
+        assert_eq!(settings.max_image_size(&config), 20 * 1024 * 1024);
+
+        settings.max_image_size = Some(1024 * 1024);
+        assert_eq!(settings.max_image_size(&config), 1024 * 1024);
+
+        // ...but anything beyond it is clamped.
+        settings.max_image_size = Some(100 * 1024 * 1024);
+        assert_eq!(settings.max_image_size(&config), 32 * 1024 * 1024);
+    }
+
+    #[test]
+    fn cross_channel_replies_resolution() {
+        let mut config = Config::default();
+        let mut settings = GuildSettings::default();
+        assert!(settings.effective(&config, NOW).cross_channel_replies);
+
+        config.reply.cross_channel_replies = false;
+        assert!(!settings.effective(&config, NOW).cross_channel_replies);
+
+        settings.cross_channel_replies = Some(true);
+        assert!(settings.effective(&config, NOW).cross_channel_replies);
+    }
+
+    #[test]
+    fn skip_nsfw_resolution() {
+        let mut config = Config::default();
+        let mut settings = GuildSettings::default();
+        assert!(!settings.effective(&config, NOW).skip_nsfw);
+
+        config.detection.skip_nsfw_channels = true;
+        assert!(settings.effective(&config, NOW).skip_nsfw);
+
+        settings.skip_nsfw = Some(false);
+        assert!(!settings.effective(&config, NOW).skip_nsfw);
+    }
+
+    #[test]
+    fn record_quota_resolution() {
+        let mut config = Config::default();
+        let mut settings = GuildSettings::default();
+        assert_eq!(settings.record_quota(&config), None);
+
+        config.storage.guild_record_quota = Some(1000);
+        assert_eq!(settings.record_quota(&config), Some(1000));
+
+        // Guilds can change their quota within the limit...
+        config.storage.guild_record_quota_limit = Some(5000);
+        settings.record_quota = Some(2000);
+        assert_eq!(settings.record_quota(&config), Some(2000));
+
+        // ...but never past it, even without a default quota.
+        settings.record_quota = Some(9000);
+        assert_eq!(settings.record_quota(&config), Some(5000));
+        config.storage.guild_record_quota = None;
+        settings.record_quota = None;
+        assert_eq!(settings.record_quota(&config), Some(5000));
+    }
+}
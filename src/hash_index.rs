@@ -0,0 +1,415 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+};
+
+/// Hamming distance between two hashes of the same length.
+fn distance(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum()
+}
+
+struct Node {
+    hash: Vec<u8>,
+    /// Removed hashes stay in place as the path to their children, and come back if they're inserted again.
+    live: bool,
+    /// Children by their distance from this node.
+    children: Vec<(u32, usize)>,
+}
+
+/// A BK-tree of hashes, which finds everything within a distance of a hash without comparing against all of them.
+#[derive(Default)]
+pub struct BkTree {
+    nodes: Vec<Node>,
+    live: usize,
+}
+
+impl BkTree {
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn insert(&mut self, hash: &[u8]) {
+        if self.nodes.is_empty() {
+            self.push(hash);
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let d = distance(&self.nodes[current].hash, hash);
+            if d == 0 {
+                let node = &mut self.nodes[current];
+                if !node.live {
+                    node.live = true;
+                    self.live += 1;
+                }
+                return;
+            }
+
+            match self.nodes[current].children.iter().find(|(cd, _)| *cd == d) {
+                Some(&(_, child)) => current = child,
+                None => {
+                    let child = self.push(hash);
+                    self.nodes[current].children.push((d, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, hash: &[u8]) -> usize {
+        self.nodes.push(Node {
+            hash: hash.to_vec(),
+            live: true,
+            children: Vec::new(),
+        });
+        self.live += 1;
+
+        self.nodes.len() - 1
+    }
+
+    pub fn remove(&mut self, hash: &[u8]) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let d = distance(&self.nodes[current].hash, hash);
+            if d == 0 {
+                let node = &mut self.nodes[current];
+                if node.live {
+                    node.live = false;
+                    self.live -= 1;
+                }
+                return;
+            }
+
+            match self.nodes[current].children.iter().find(|(cd, _)| *cd == d) {
+                Some(&(_, child)) => current = child,
+                None => return,
+            }
+        }
+    }
+
+    /// Every hash within `radius` of `hash`, with how far away it is.
+    pub fn within(&self, hash: &[u8], radius: u32) -> Vec<(u32, &[u8])> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+
+        let mut pending = vec![0];
+        while let Some(current) = pending.pop() {
+            let node = &self.nodes[current];
+            let d = distance(&node.hash, hash);
+
+            if node.live && d <= radius {
+                found.push((d, node.hash.as_slice()));
+            }
+
+            // By the triangle inequality, only children this close to `d` can be within the radius.
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(cd, _)| {
+                        d.saturating_sub(radius) <= *cd && *cd <= d.saturating_add(radius)
+                    })
+                    .map(|&(_, child)| child),
+            );
+        }
+
+        found
+    }
+
+    /// The closest hash to `hash`, and how far away it is. Ties go to the smallest hash.
+    pub fn nearest(&self, hash: &[u8]) -> Option<(u32, &[u8])> {
+        let mut best: Option<(u32, &[u8])> = None;
+        if self.nodes.is_empty() {
+            return best;
+        }
+
+        let mut pending = vec![0];
+        while let Some(current) = pending.pop() {
+            let node = &self.nodes[current];
+            let d = distance(&node.hash, hash);
+
+            if node.live && best.is_none_or(|(bd, bh)| (d, node.hash.as_slice()) < (bd, bh)) {
+                best = Some((d, node.hash.as_slice()));
+            }
+
+            let radius = best.map_or(u32::MAX, |(bd, _)| bd);
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(cd, _)| {
+                        d.saturating_sub(radius) <= *cd && *cd <= d.saturating_add(radius)
+                    })
+                    .map(|&(_, child)| child),
+            );
+        }
+
+        best
+    }
+}
+
+/// The hashes in `seen_hashes`, with a tree for each guild prefix since images only match within their guild.
+#[derive(Default)]
+pub struct HashIndex {
+    guilds: HashMap<[u8; 8], BkTree>,
+}
+
+impl HashIndex {
+    const PREFIX_LEN: usize = core::mem::size_of::<u64>();
+
+    fn split(key: &[u8]) -> ([u8; 8], &[u8]) {
+        let (prefix, hash) = key.split_at(Self::PREFIX_LEN);
+        (
+            prefix.try_into().expect("bug: hash key was too short"),
+            hash,
+        )
+    }
+
+    /// How many hashes are indexed between progress logs.
+    const PROGRESS_EVERY: usize = 100_000;
+
+    /// Builds the index from every key in `seen_hashes`, with up to `workers` guilds' trees built at once.
+    ///
+    /// Keys come out of sled sorted, so each guild's are together and handed to a worker as one batch. A guild's
+    /// tree is always filled in key order, so the index comes out the same no matter how many workers build it.
+    pub fn build(
+        keys: impl Iterator<Item = sled::Result<sled::IVec>>,
+        workers: usize,
+    ) -> sled::Result<Self> {
+        let workers = workers.max(1);
+        // Only a few batches wait at once, so the whole tree isn't read into memory ahead of the workers.
+        let (sender, receiver) = mpsc::sync_channel::<([u8; 8], Vec<sled::IVec>)>(workers);
+        let receiver = Mutex::new(receiver);
+        let built = Mutex::new(HashMap::new());
+        let indexed = AtomicUsize::new(0);
+
+        thread::scope(|scope| -> sled::Result<()> {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    // The lock is released before building, so the others can pick up batches meanwhile.
+                    let batch = receiver
+                        .lock()
+                        .expect("index build queue lock was poisoned")
+                        .recv();
+
+                    let (prefix, keys) = match batch {
+                        Ok(batch) => batch,
+                        // Every key was read.
+                        Err(_) => break,
+                    };
+
+                    let mut tree = BkTree::default();
+                    for key in &keys {
+                        tree.insert(Self::split(key).1);
+                    }
+
+                    let before = indexed.fetch_add(keys.len(), Ordering::Relaxed);
+                    let after = before + keys.len();
+                    if before / Self::PROGRESS_EVERY != after / Self::PROGRESS_EVERY {
+                        tracing::info!("Indexed {} hashes so far", after);
+                    }
+
+                    built
+                        .lock()
+                        .expect("index build result lock was poisoned")
+                        .insert(prefix, tree);
+                });
+            }
+
+            let mut batch: Option<([u8; 8], Vec<sled::IVec>)> = None;
+            for key in keys {
+                let key = key?;
+                let prefix = Self::split(&key).0;
+
+                match &mut batch {
+                    Some((current, keys)) if *current == prefix => keys.push(key),
+                    _ => {
+                        if let Some(done) = batch.replace((prefix, vec![key])) {
+                            // Workers only stop once the sender is dropped, so this can't fail.
+                            let _ = sender.send(done);
+                        }
+                    }
+                }
+            }
+
+            if let Some(done) = batch {
+                let _ = sender.send(done);
+            }
+
+            // Lets the workers finish once the queue is empty. Returning early with an error drops it too.
+            drop(sender);
+            Ok(())
+        })?;
+
+        Ok(Self {
+            guilds: built
+                .into_inner()
+                .expect("index build result lock was poisoned"),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.guilds.values().map(BkTree::len).sum()
+    }
+
+    /// Adds a `seen_hashes` key.
+    pub fn insert(&mut self, key: &[u8]) {
+        let (prefix, hash) = Self::split(key);
+        self.guilds.entry(prefix).or_default().insert(hash);
+    }
+
+    /// Removes a `seen_hashes` key.
+    pub fn remove(&mut self, key: &[u8]) {
+        let (prefix, hash) = Self::split(key);
+        if let Some(tree) = self.guilds.get_mut(&prefix) {
+            tree.remove(hash);
+        }
+    }
+
+    /// Hashes under `prefix` within `radius` of `hash`.
+    pub fn within(&self, prefix: [u8; 8], hash: &[u8], radius: u32) -> Vec<(u32, &[u8])> {
+        self.guilds
+            .get(&prefix)
+            .map_or_else(Vec::new, |tree| tree.within(hash, radius))
+    }
+
+    /// The closest hash under `prefix` to `hash`.
+    pub fn nearest(&self, prefix: [u8; 8], hash: &[u8]) -> Option<(u32, &[u8])> {
+        self.guilds.get(&prefix)?.nearest(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic hashes that are spread around, without pulling in a random number generator.
+    fn hashes(count: u64) -> Vec<Vec<u8>> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_be_bytes().to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_what_a_scan_would() {
+        let stored = hashes(500);
+        let mut tree = BkTree::default();
+        for hash in &stored {
+            tree.insert(hash);
+        }
+        assert_eq!(tree.len(), stored.len());
+
+        for query in hashes(520).iter().skip(480) {
+            for radius in [0, 8, 20] {
+                let mut found: Vec<_> = tree.within(query, radius);
+                found.sort();
+
+                let mut scanned: Vec<_> = stored
+                    .iter()
+                    .map(|h| (distance(h, query), h.as_slice()))
+                    .filter(|(d, _)| *d <= radius)
+                    .collect();
+                scanned.sort();
+
+                assert_eq!(found, scanned);
+            }
+
+            let closest = stored
+                .iter()
+                .map(|h| (distance(h, query), h.as_slice()))
+                .min();
+            assert_eq!(tree.nearest(query), closest);
+        }
+    }
+
+    #[test]
+    fn removed_hashes_not_found() {
+        let stored = hashes(50);
+        let mut tree = BkTree::default();
+        for hash in &stored {
+            tree.insert(hash);
+        }
+
+        tree.remove(&stored[0]);
+        tree.remove(&stored[0]);
+        assert_eq!(tree.len(), 49);
+        assert!(tree.within(&stored[0], 0).is_empty());
+        // The removed root still leads to everything below it.
+        assert_eq!(tree.within(&stored[10], 0), vec![(0, &stored[10][..])]);
+
+        tree.insert(&stored[0]);
+        assert_eq!(tree.len(), 50);
+        assert_eq!(tree.nearest(&stored[0]), Some((0, &stored[0][..])));
+    }
+
+    #[test]
+    fn guilds_kept_apart() {
+        let key = |guild: u64, hash: &[u8]| [&guild.to_be_bytes()[..], hash].concat();
+        let hash = [1, 2, 3, 4];
+
+        let mut index = HashIndex::default();
+        index.insert(&key(1, &hash));
+        index.insert(&key(2, &hash));
+        assert_eq!(index.len(), 2);
+
+        index.remove(&key(2, &hash));
+        assert_eq!(
+            index.within(1u64.to_be_bytes(), &hash, 0),
+            vec![(0, &hash[..])]
+        );
+        assert!(index.within(2u64.to_be_bytes(), &hash, 0).is_empty());
+        assert_eq!(index.nearest(3u64.to_be_bytes(), &hash), None);
+    }
+
+    #[test]
+    fn built_the_same_with_any_workers() {
+        let key =
+            |guild: u64, hash: &[u8]| sled::IVec::from([&guild.to_be_bytes()[..], hash].concat());
+        // Sorted, like sled hands them out.
+        let mut keys: Vec<_> = (1..=5u64)
+            .flat_map(|guild| {
+                hashes(200 * guild)
+                    .into_iter()
+                    .map(move |hash| (guild, hash))
+            })
+            .map(|(guild, hash)| key(guild, &hash))
+            .collect();
+        keys.sort();
+
+        let build = |workers| HashIndex::build(keys.iter().cloned().map(Ok), workers).unwrap();
+        let one = build(1);
+        let four = build(4);
+        assert_eq!(one.len(), keys.len());
+        assert_eq!(four.len(), keys.len());
+
+        for guild in 1..=6u64 {
+            let prefix = guild.to_be_bytes();
+            for query in hashes(1100).iter().skip(1000) {
+                for radius in [0, 8, 20] {
+                    // Unsorted, since the trees should be built the same way too.
+                    assert_eq!(
+                        one.within(prefix, query, radius),
+                        four.within(prefix, query, radius)
+                    );
+                }
+                assert_eq!(one.nearest(prefix, query), four.nearest(prefix, query));
+            }
+        }
+    }
+}
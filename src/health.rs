@@ -0,0 +1,115 @@
+//! A tiny HTTP server answering `/healthz`, so watchdogs like Kubernetes or systemd can restart a wedged bot.
+
+use crate::bot::Context;
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+
+use std::{convert::Infallible, net::SocketAddr};
+
+/// What `/healthz` reports, as JSON.
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// How many shards are connected to the gateway, out of `shards`.
+    pub shards_connected: usize,
+    pub shards: usize,
+    /// If the database can still be read.
+    pub database_open: bool,
+    /// When the last gateway event came in, in seconds since the Unix epoch.
+    pub last_event: Option<u64>,
+}
+
+impl Health {
+    /// If every shard is connected and the database can be read.
+    ///
+    /// How long it's been since the last event is left for the watchdog to judge, since quiet guilds are normal.
+    pub fn is_healthy(&self) -> bool {
+        self.shards_connected == self.shards && self.database_open
+    }
+
+    fn response(&self) -> Response<Body> {
+        let status = if self.is_healthy() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(
+                serde_json::to_string(self).expect("health reports always serialize"),
+            ))
+            .expect("bug: invalid health response")
+    }
+}
+
+/// Answers health checks on `address` until the bot exits.
+pub async fn serve(address: SocketAddr, context: Context) {
+    let server = match Server::try_bind(&address) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Failed to listen for health checks on {}: {:?}", address, e);
+            return;
+        }
+    };
+
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&request, &context);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    tracing::info!("Answering health checks on http://{}/healthz", address);
+    if let Err(e) = server.serve(make_service).await {
+        tracing::error!("Health check server stopped: {:?}", e);
+    }
+}
+
+fn respond(request: &Request<Body>, context: &Context) -> Response<Body> {
+    if request.uri().path() != "/healthz" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("bug: invalid health response");
+    }
+
+    context.health().response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn status_follows_health() {
+        let mut health = Health {
+            shards_connected: 2,
+            shards: 2,
+            database_open: true,
+            last_event: Some(1600000000),
+        };
+
+        let response = health.response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            body,
+            r#"{"shards_connected":2,"shards":2,"database_open":true,"last_event":1600000000}"#
+        );
+
+        health.shards_connected = 1;
+        assert_eq!(health.response().status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        health.shards_connected = 2;
+        health.database_open = false;
+        assert_eq!(health.response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -1,30 +1,272 @@
+use crate::config::{AnimationMatching, DetectionConfig, SmallImages};
 use crate::Error;
 
-use image::io::Reader;
+use core::convert::TryInto;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder},
+    error::{ImageError, ParameterError, ParameterErrorKind},
+    io::Reader,
+    AnimationDecoder, DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage, Rgba,
+};
 use img_hash::{HashAlg, HasherConfig};
+use sha2::{Digest, Sha256};
 use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 type HashStorage = [u8; 64];
 pub type ImageHash = img_hash::ImageHash<HashStorage>;
 
-const DIFFERENCE_THRESHOLD: u32 = 8;
+/// Length of a stored hash in bytes.
+pub const HASH_BYTES: usize = core::mem::size_of::<HashStorage>();
 
-pub fn process_image(image: Vec<u8>) -> Result<ImageHash, Error> {
-    let hasher = HasherConfig::with_bytes_type::<HashStorage>()
-        .hash_alg(HashAlg::Blockhash)
-        .to_hasher();
+/// The largest possible distance between two hashes.
+pub const MAX_DISTANCE: u32 = (HASH_BYTES * 8) as u32;
+
+/// Frames hashed from each animation, including the first, when they're matched by several frames.
+const ANIMATION_SAMPLES: usize = 5;
+
+/// The SHA-256 of an image file, which only matches bit-for-bit copies of it.
+pub type ContentHash = [u8; 32];
+
+/// The outcome of running an image through [process_image].
+#[derive(Debug)]
+pub enum ProcessedImage {
+    Hashed(Hashes),
+    /// The image is too small to be stored, but can still match images that are.
+    MatchOnly(Hashes),
+    /// The image was decodable but deliberately not hashed.
+    Skipped(SkipReason),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SkipReason {
+    /// The image was animated and animations are configured to be skipped.
+    Animated,
+    /// The image was under the minimum dimension and small images are configured to be skipped.
+    TooSmall,
+    /// The image was nearly a single color, so its hash would match too many others.
+    LowVariance,
+}
+
+impl ProcessedImage {
+    pub fn hash(&self) -> Option<&ImageHash> {
+        self.hashes().map(|hashes| &hashes.hash)
+    }
+
+    pub fn hashes(&self) -> Option<&Hashes> {
+        match self {
+            Self::Hashed(hashes) | Self::MatchOnly(hashes) => Some(hashes),
+            Self::Skipped(_) => None,
+        }
+    }
+
+    /// Notes where the image was downloaded from.
+    pub fn with_source(mut self, url: &str) -> Self {
+        if let Self::Hashed(hashes) | Self::MatchOnly(hashes) = &mut self {
+            hashes.source = Some(url.to_string());
+        }
+
+        self
+    }
+}
+
+/// What an image's header says about it, without decoding the whole thing.
+#[derive(Debug, PartialEq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Reads an image's format and dimensions, if they're recognizable.
+pub fn inspect(image: &[u8]) -> Option<ImageInfo> {
+    let reader = Reader::new(Cursor::new(image)).with_guessed_format().ok()?;
+    let format = reader.format()?;
+    let (width, height) = reader.into_dimensions().ok()?;
+
+    Some(ImageInfo {
+        format,
+        width,
+        height,
+    })
+}
+
+/// The hash of an image as it was posted, and of any other orientations it should match in.
+#[derive(Debug, Clone)]
+pub struct Hashes {
+    pub hash: ImageHash,
+    /// Only matched against, since the image is stored as it was posted.
+    pub variants: Vec<ImageHash>,
+    /// The format the image was decoded from, if it's known.
+    pub format: Option<ImageFormat>,
+    /// The hash of the file the image came from, if it's known, so exact copies can be found without decoding them.
+    pub content: Option<ContentHash>,
+    /// Hashes of later frames of an animation, which are matched against and stored alongside the hash,
+    /// so a copy that starts on a different frame still matches.
+    pub frames: Vec<ImageHash>,
+    /// Where the image was downloaded from, if it was.
+    pub source: Option<String>,
+}
+
+impl Hashes {
+    /// The hash, and every other hash the image can be matched by.
+    pub fn candidates(&self) -> impl Iterator<Item = &ImageHash> {
+        std::iter::once(&self.hash)
+            .chain(&self.variants)
+            .chain(&self.frames)
+    }
+
+    /// The start of the hash in hex, enough to tell images apart in logs.
+    pub fn log_prefix(&self) -> String {
+        let bytes = self.hash.as_bytes();
+        hex::encode(&bytes[..bytes.len().min(8)])
+    }
+}
+
+impl From<ImageHash> for Hashes {
+    fn from(hash: ImageHash) -> Self {
+        Self {
+            hash,
+            variants: Vec::new(),
+            format: None,
+            content: None,
+            frames: Vec::new(),
+            source: None,
+        }
+    }
+}
+
+impl From<&ImageHash> for Hashes {
+    fn from(hash: &ImageHash) -> Self {
+        hash.clone().into()
+    }
+}
+
+pub fn content_hash(image: &[u8]) -> ContentHash {
+    Sha256::digest(image).into()
+}
+
+/// Like [process_image], but a file that `known` has a hash for gets that hash without being decoded.
+///
+/// Most reposts are exact copies, so this skips the slowest part of handling them.
+pub fn process_known_image(
+    image: Vec<u8>,
+    config: &DetectionConfig,
+    known: impl FnOnce(&ContentHash) -> Result<Option<ImageHash>, Error>,
+) -> Result<ProcessedImage, Error> {
+    let content = content_hash(&image);
+    if let Some(hash) = known(&content)? {
+        tracing::debug!("Found an exact copy of a known image");
+        return Ok(ProcessedImage::Hashed(Hashes {
+            content: Some(content),
+            ..Hashes::from(hash)
+        }));
+    }
+
+    let mut processed = process_image(image, config)?;
+    if let ProcessedImage::Hashed(hashes) | ProcessedImage::MatchOnly(hashes) = &mut processed {
+        hashes.content = Some(content);
+    }
+
+    Ok(processed)
+}
+
+/// Decodes and hashes an image.
+///
+/// Videos are decoded too if [DetectionConfig::ffmpeg_path] is set, by their first keyframes.
+///
+/// This blocks for as long as decoding takes, so outside of tests it's only run on the hashing
+/// threads, through [crate::bot::Context::process_image].
+pub fn process_image(image: Vec<u8>, config: &DetectionConfig) -> Result<ProcessedImage, Error> {
+    if let Some(ffmpeg) = &config.ffmpeg_path {
+        if looks_like_video(&image) {
+            return process_video(&image, ffmpeg, config);
+        }
+    }
 
     let start = std::time::Instant::now();
-    let image = Reader::new(Cursor::new(image))
+    let reader = Reader::new(Cursor::new(image))
         .with_guessed_format()
-        .expect("Cursor seeking can't fail")
-        .decode()
-        .map_err(Error::UnsupportedImageFormat)?;
+        .expect("Cursor seeking can't fail");
+    let format = reader.format();
+
+    let samples = animation_samples(config);
+
+    // Animated formats are decoded as animations so they're handled consistently,
+    // instead of whatever frame the format's default image happens to be.
+    let (image, later_frames, animated) = match format {
+        Some(ImageFormat::Gif) => {
+            let decoder =
+                GifDecoder::new(reader.into_inner()).map_err(Error::UnsupportedImageFormat)?;
+            sample_frames(decoder, samples)?
+        }
+        // APNGs, such as Discord stickers, are regular PNGs to anything that doesn't know better.
+        Some(ImageFormat::Png) => {
+            let decoder =
+                PngDecoder::new(reader.into_inner()).map_err(Error::UnsupportedImageFormat)?;
+
+            if decoder.is_apng() {
+                sample_frames(decoder.apng(), samples)?
+            } else {
+                let image =
+                    DynamicImage::from_decoder(decoder).map_err(Error::UnsupportedImageFormat)?;
+                (image, Vec::new(), false)
+            }
+        }
+        _ => (
+            reader.decode().map_err(Error::UnsupportedImageFormat)?,
+            Vec::new(),
+            false,
+        ),
+    };
 
     tracing::trace!(
         "It took {}ms to decode the image",
         start.elapsed().as_millis()
     );
+    Ok(hash_frames(image, later_frames, animated, format, config))
+}
+
+/// How many frames of an animation are hashed, including the first.
+fn animation_samples(config: &DetectionConfig) -> usize {
+    match config.animation_matching {
+        AnimationMatching::Frames => ANIMATION_SAMPLES,
+        AnimationMatching::FirstFrame | AnimationMatching::Skip => 1,
+    }
+}
+
+/// Hashes a decoded image, and the frames sampled after it if it's animated.
+fn hash_frames(
+    image: DynamicImage,
+    later_frames: Vec<DynamicImage>,
+    animated: bool,
+    format: Option<ImageFormat>,
+    config: &DetectionConfig,
+) -> ProcessedImage {
+    let hasher = HasherConfig::with_bytes_type::<HashStorage>()
+        .hash_alg(HashAlg::Blockhash)
+        .to_hasher();
+
+    if animated && config.animation_matching == AnimationMatching::Skip {
+        return ProcessedImage::Skipped(SkipReason::Animated);
+    }
+
+    let small = image.width().min(image.height()) < config.min_dimension;
+    if small && config.small_images == SmallImages::Skip {
+        return ProcessedImage::Skipped(SkipReason::TooSmall);
+    }
+
+    let image = match config.alpha_background.color() {
+        Some(background) if image.color().has_alpha() => flatten(&image, background),
+        _ => image,
+    };
+
+    if let Some(min_variance) = config.min_color_variance {
+        if color_variance(&image) < min_variance {
+            return ProcessedImage::Skipped(SkipReason::LowVariance);
+        }
+    }
+
     let start = std::time::Instant::now();
     let hash = hasher.hash_image(&image);
     tracing::trace!(
@@ -32,10 +274,357 @@ pub fn process_image(image: Vec<u8>) -> Result<ImageHash, Error> {
         start.elapsed().as_millis()
     );
 
-    Ok(hash)
+    let variants = if config.match_rotations {
+        vec![
+            hasher.hash_image(&image.rotate90()),
+            hasher.hash_image(&image.rotate180()),
+            hasher.hash_image(&image.rotate270()),
+        ]
+    } else {
+        Vec::new()
+    };
+
+    let mut frames: Vec<ImageHash> = Vec::with_capacity(later_frames.len());
+    for frame in later_frames {
+        let frame = match config.alpha_background.color() {
+            Some(background) => flatten(&frame, background),
+            None => frame,
+        };
+
+        let frame = hasher.hash_image(&frame);
+        if frame != hash && !frames.contains(&frame) {
+            frames.push(frame);
+        }
+    }
+
+    let hashes = Hashes {
+        hash,
+        variants,
+        format,
+        content: None,
+        frames,
+        source: None,
+    };
+    if small {
+        ProcessedImage::MatchOnly(hashes)
+    } else {
+        ProcessedImage::Hashed(hashes)
+    }
+}
+
+/// Extensions of the videos [process_image] can decode, with ffmpeg.
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mov", "webm"];
+
+/// If a file starts like an MP4, QuickTime, or WebM video.
+///
+/// MP4 and QuickTime files start with the size of their `ftyp` box and then its name, and WebM files
+/// with the EBML header of Matroska.
+pub fn looks_like_video(file: &[u8]) -> bool {
+    file.get(4..8) == Some(&b"ftyp"[..]) || file.starts_with(&[0x1A, 0x45, 0xDF, 0xA3])
+}
+
+/// Decodes the first keyframes of a video with ffmpeg, and hashes them like the frames of an animation.
+///
+/// Keyframes can be decoded without any of the frames around them, so they're quick to get to, and
+/// copies of a clip usually have the same first one.
+fn process_video(
+    video: &[u8],
+    ffmpeg: &str,
+    config: &DetectionConfig,
+) -> Result<ProcessedImage, Error> {
+    let start = std::time::Instant::now();
+    let samples = animation_samples(config);
+
+    // At least two, so a video with more than one keyframe is always known to be animated.
+    let mut frames = keyframes(video, ffmpeg, samples.max(2))?.into_iter();
+    let first = match frames.next() {
+        Some(first) => first,
+        None => {
+            return Err(Error::DecodingVideo(
+                "no keyframes were decoded".to_string(),
+            ))
+        }
+    };
+    let later_frames: Vec<_> = frames.collect();
+    let animated = !later_frames.is_empty();
+    let later_frames = later_frames.into_iter().take(samples - 1).collect();
+
+    tracing::trace!(
+        "It took {}ms to decode the video",
+        start.elapsed().as_millis()
+    );
+    Ok(hash_frames(first, later_frames, animated, None, config))
+}
+
+/// Runs ffmpeg to decode up to `count` keyframes from the start of a video.
+fn keyframes(video: &[u8], ffmpeg: &str, count: usize) -> Result<Vec<DynamicImage>, Error> {
+    // MP4s often have their index at the end, which ffmpeg can't seek to when reading from a pipe.
+    let file = TempFile::new(video)
+        .map_err(|e| Error::DecodingVideo(format!("couldn't write the video out: {}", e)))?;
+
+    let output = std::process::Command::new(ffmpeg)
+        .args(["-nostdin", "-v", "error", "-skip_frame", "nokey", "-i"])
+        .arg(&file.0)
+        .args([
+            "-an",
+            "-frames:v",
+            &count.to_string(),
+            "-vsync",
+            "passthrough",
+        ])
+        .args(["-f", "image2pipe", "-c:v", "png", "-"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| Error::DecodingVideo(format!("couldn't run {}: {}", ffmpeg, e)))?;
+
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::DecodingVideo(message.trim().to_string()));
+    }
+
+    split_pngs(&output.stdout)
+        .into_iter()
+        .map(|png| {
+            image::load_from_memory_with_format(png, ImageFormat::Png)
+                .map_err(Error::UnsupportedImageFormat)
+        })
+        .collect()
 }
 
-pub fn similar_enough(new: &ImageHash, seen: &[u8]) -> bool {
+/// Splits PNG files written one after another, like ffmpeg writes frames to a pipe, into each file.
+///
+/// A file that was cut off is left out.
+fn split_pngs(mut stream: &[u8]) -> Vec<&[u8]> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    let mut pngs = Vec::new();
+    while stream.starts_with(SIGNATURE) {
+        // Each chunk is its length, type, data and CRC, and the last one is always IEND.
+        let mut end = SIGNATURE.len();
+        loop {
+            let header = match stream.get(end..end + 8) {
+                Some(header) => header,
+                None => return pngs,
+            };
+            let length = u32::from_be_bytes(header[..4].try_into().expect("sliced to 4 bytes"));
+            end += 12 + length as usize;
+
+            if &header[4..] == b"IEND" {
+                break;
+            }
+        }
+
+        if end > stream.len() {
+            break;
+        }
+        let (png, rest) = stream.split_at(end);
+        pngs.push(png);
+        stream = rest;
+    }
+
+    pngs
+}
+
+/// A file in the temporary directory, which is deleted when it's dropped.
+struct TempFile(std::path::PathBuf);
+
+impl TempFile {
+    fn new(contents: &[u8]) -> std::io::Result<Self> {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!(
+            "repost-me-not-{}-{}",
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        );
+        let file = Self(std::env::temp_dir().join(name));
+        std::fs::write(&file.0, contents)?;
+
+        Ok(file)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The average variance of an image's red, green, and blue channels.
+pub fn color_variance(image: &DynamicImage) -> f32 {
+    let image = image.to_rgb8();
+    let pixels = f64::from(image.width()) * f64::from(image.height());
+    if pixels == 0.0 {
+        return 0.0;
+    }
+
+    let mut sums = [0f64; 3];
+    let mut squares = [0f64; 3];
+    for Rgb(channels) in image.pixels() {
+        for (i, &value) in channels.iter().enumerate() {
+            let value = f64::from(value);
+            sums[i] += value;
+            squares[i] += value * value;
+        }
+    }
+
+    let variance: f64 = (0..3)
+        .map(|i| {
+            let mean = sums[i] / pixels;
+            squares[i] / pixels - mean * mean
+        })
+        .sum::<f64>()
+        / 3.0;
+
+    variance.max(0.0) as f32
+}
+
+/// Composites an image with transparency over a solid background.
+fn flatten(image: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let image = image.to_rgba8();
+
+    let flattened = RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *image.get_pixel(x, y);
+        let blend = |channel: u8, background: u8| {
+            let alpha = u16::from(a);
+            ((u16::from(channel) * alpha + u16::from(background) * (255 - alpha)) / 255) as u8
+        };
+
+        Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    });
+
+    DynamicImage::ImageRgb8(flattened)
+}
+
+/// Decodes the first frame of an animation, up to `count - 1` later frames spread evenly across the rest
+/// of it, and if there were any frames after the first.
+///
+/// Long animations aren't held in memory all at once. Whenever too many frames are kept, every other one
+/// is dropped and only half as many are kept from then on, so what's left stays evenly spaced.
+fn sample_frames<'a>(
+    decoder: impl AnimationDecoder<'a>,
+    count: usize,
+) -> Result<(DynamicImage, Vec<DynamicImage>, bool), Error> {
+    let mut frames = decoder.into_frames();
+
+    let first = match frames.next() {
+        Some(frame) => frame.map_err(Error::UnsupportedImageFormat)?,
+        None => {
+            return Err(Error::UnsupportedImageFormat(ImageError::Parameter(
+                ParameterError::from_kind(ParameterErrorKind::NoMoreData),
+            )))
+        }
+    };
+    let first = DynamicImage::ImageRgba8(first.into_buffer());
+
+    let wanted = count.saturating_sub(1);
+    if wanted == 0 {
+        return Ok((first, Vec::new(), frames.next().is_some()));
+    }
+
+    let mut animated = false;
+    let mut kept = Vec::new();
+    let mut stride = 1;
+    for (position, frame) in (1usize..).zip(frames) {
+        animated = true;
+
+        // A broken frame partway through still leaves the ones before it to go by.
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+
+        if position % stride == 0 {
+            kept.push((position, frame.into_buffer()));
+
+            if kept.len() == 2 * wanted {
+                stride *= 2;
+                kept.retain(|(position, _)| position % stride == 0);
+            }
+        }
+    }
+
+    let later = if kept.len() <= wanted {
+        kept.into_iter()
+            .map(|(_, frame)| DynamicImage::ImageRgba8(frame))
+            .collect()
+    } else {
+        // Each one taken is where `i * wanted / total` steps up to the next whole number.
+        let total = kept.len();
+        kept.into_iter()
+            .enumerate()
+            .filter(|(i, _)| (i + 1) * wanted % total < wanted)
+            .map(|(_, (_, frame))| DynamicImage::ImageRgba8(frame))
+            .collect()
+    };
+
+    Ok((first, later, animated))
+}
+
+pub fn similar_enough(new: &ImageHash, seen: &[u8], threshold: u32) -> bool {
+    hash_distance(new, seen) <= threshold
+}
+
+/// Removes images from a message's set that have the same hash as an earlier one, keeping the first.
+pub fn dedupe_images(images: Vec<ProcessedImage>) -> Vec<ProcessedImage> {
+    let mut unique: Vec<ProcessedImage> = Vec::with_capacity(images.len());
+
+    for image in images {
+        let duplicate = match image.hash() {
+            Some(hash) => unique.iter().any(|seen| seen.hash() == Some(hash)),
+            None => false,
+        };
+
+        if !duplicate {
+            unique.push(image);
+        }
+    }
+
+    unique
+}
+
+/// Evidence about whether a new image is a repost of a stored one.
+///
+/// Signals that couldn't be compared are `None` and don't count either way.
+#[derive(Debug, Default)]
+pub struct Signals {
+    /// Hamming distance between the perceptual hashes.
+    pub distance: u32,
+    /// If the files were byte for byte identical.
+    pub same_content: Option<bool>,
+    pub same_dimensions: Option<bool>,
+    pub same_format: Option<bool>,
+}
+
+/// Combines detection signals into how confident we are that an image is a repost, from 0 to 1.
+pub fn repost_confidence(signals: &Signals) -> f32 {
+    // Past this many differing bits, blockhash results are unrelated images.
+    const UNRELATED_DISTANCE: f32 = MAX_DISTANCE as f32 / 10.0;
+
+    if signals.same_content == Some(true) {
+        return 1.0;
+    }
+
+    let mut confidence = 1.0 - (signals.distance as f32 / UNRELATED_DISTANCE).min(1.0);
+
+    // Matching metadata closes part of the gap to certainty, and mismatches take part of it away.
+    for (signal, weight) in [(signals.same_dimensions, 0.3), (signals.same_format, 0.1)] {
+        match signal {
+            Some(true) => confidence += (1.0 - confidence) * weight,
+            Some(false) => confidence -= confidence * weight,
+            None => {}
+        }
+    }
+
+    confidence.clamp(0.0, 1.0)
+}
+
+/// Hamming distance between a hash and one stored in the database.
+pub fn hash_distance(new: &ImageHash, seen: &[u8]) -> u32 {
     let seen = match ImageHash::from_bytes(seen) {
         Ok(h) => h,
         _ => unreachable!("bug: sled returned the wrong key size"),
@@ -48,14 +637,15 @@ pub fn similar_enough(new: &ImageHash, seen: &[u8]) -> bool {
         start.elapsed().as_millis()
     );
 
-    tracing::debug!("Distance was {}", dist);
-
-    dist <= DIFFERENCE_THRESHOLD
+    dist
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::AlphaBackground;
+    use core::convert::TryInto;
+    use image::{codecs::gif::GifEncoder, Delay, Frame, ImageOutputFormat, RgbaImage};
 
     fn set_logger() {
         let _ = tracing::subscriber::set_global_default(
@@ -65,6 +655,619 @@ mod tests {
         );
     }
 
+    /// A black and white checkerboard, which survives GIF palette quantization intact.
+    fn checkerboard(cell: u32, inverted: bool) -> RgbaImage {
+        RgbaImage::from_fn(64, 64, |x, y| {
+            if (x / cell + y / cell).is_multiple_of(2) != inverted {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        })
+    }
+
+    fn encode_still(image: RgbaImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .unwrap();
+        out
+    }
+
+    fn encode_animation(frames: Vec<RgbaImage>) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut out);
+            encoder
+                .encode_frames(
+                    frames
+                        .into_iter()
+                        .map(|f| Frame::from_parts(f, 0, 0, Delay::from_numer_denom_ms(100, 1))),
+                )
+                .unwrap();
+        }
+        out
+    }
+
+    fn png_chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind = rest[4..8].try_into().unwrap();
+            chunks.push((kind, rest[8..8 + len].to_vec()));
+            rest = &rest[12 + len..];
+        }
+
+        chunks
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = 0xFFFF_FFFFu32;
+        for byte in bytes {
+            crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                let mask = (crc & 1).wrapping_neg();
+                crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+        !crc
+    }
+
+    fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+        let start = out.len();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let crc = crc32(&out[start..]);
+
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    fn frame_control(sequence: u32, width: u32, height: u32) -> Vec<u8> {
+        let mut fctl = Vec::new();
+        fctl.extend_from_slice(&sequence.to_be_bytes());
+        fctl.extend_from_slice(&width.to_be_bytes());
+        fctl.extend_from_slice(&height.to_be_bytes());
+        fctl.extend_from_slice(&[0; 8]); // x and y offsets
+        fctl.extend_from_slice(&1u16.to_be_bytes());
+        fctl.extend_from_slice(&10u16.to_be_bytes());
+        fctl.extend_from_slice(&[0, 0]); // dispose and blend ops
+        fctl
+    }
+
+    /// `image` can't encode APNGs, so stitch one together out of regular PNGs.
+    fn encode_apng(frames: Vec<RgbaImage>) -> Vec<u8> {
+        let (width, height) = frames[0].dimensions();
+        let encoded: Vec<_> = frames
+            .into_iter()
+            .map(|f| png_chunks(&encode_still(f)))
+            .collect();
+
+        let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+
+        let (_, header) = encoded[0].iter().find(|(k, _)| k == b"IHDR").unwrap();
+        write_chunk(&mut out, b"IHDR", header);
+
+        let mut animation_control = (encoded.len() as u32).to_be_bytes().to_vec();
+        animation_control.extend_from_slice(&0u32.to_be_bytes());
+        write_chunk(&mut out, b"acTL", &animation_control);
+
+        let mut sequence = 0;
+        for (i, chunks) in encoded.iter().enumerate() {
+            write_chunk(&mut out, b"fcTL", &frame_control(sequence, width, height));
+            sequence += 1;
+
+            for (_, data) in chunks.iter().filter(|(k, _)| k == b"IDAT") {
+                if i == 0 {
+                    write_chunk(&mut out, b"IDAT", data);
+                } else {
+                    let mut frame_data = sequence.to_be_bytes().to_vec();
+                    frame_data.extend_from_slice(data);
+                    write_chunk(&mut out, b"fdAT", &frame_data);
+                    sequence += 1;
+                }
+            }
+        }
+
+        write_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    fn hashed(image: ProcessedImage) -> ImageHash {
+        match image {
+            ProcessedImage::Hashed(hashes) => hashes.hash,
+            other => panic!("image wasn't hashed for storage: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn solid_images_low_variance() {
+        let solid =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 64, Rgba([40, 90, 200, 255])));
+        let detailed = DynamicImage::ImageRgba8(checkerboard(8, false));
+        assert_eq!(color_variance(&solid), 0.0);
+        assert!(color_variance(&detailed) > 1000.0);
+
+        let config = DetectionConfig {
+            min_color_variance: Some(20.0),
+            ..Default::default()
+        };
+        let mut solid_png = Vec::new();
+        solid
+            .write_to(&mut solid_png, ImageOutputFormat::Png)
+            .unwrap();
+        assert!(matches!(
+            process_image(solid_png.clone(), &config).unwrap(),
+            ProcessedImage::Skipped(SkipReason::LowVariance)
+        ));
+        assert!(matches!(
+            process_image(encode_still(checkerboard(8, false)), &config).unwrap(),
+            ProcessedImage::Hashed(_)
+        ));
+
+        // It's off by default.
+        assert!(matches!(
+            process_image(solid_png, &DetectionConfig::default()).unwrap(),
+            ProcessedImage::Hashed(_)
+        ));
+    }
+
+    #[test]
+    fn exact_copies_skip_decoding() {
+        let config = DetectionConfig::default();
+        let image = encode_still(checkerboard(8, false));
+        let unknown = |_: &ContentHash| Ok(None);
+
+        let first = match process_known_image(image.clone(), &config, unknown).unwrap() {
+            ProcessedImage::Hashed(hashes) => hashes,
+            other => panic!("image wasn't hashed: {:?}", other),
+        };
+        assert_eq!(first.content, Some(content_hash(&image)));
+
+        // Whatever the file is, a known copy isn't decoded.
+        let known = first.hash.clone();
+        let garbage = b"definitely not an image".to_vec();
+        let copy = process_known_image(garbage, &config, |_| Ok(Some(known))).unwrap();
+        assert_eq!(hashed(copy), first.hash);
+    }
+
+    #[test]
+    fn images_inspected() {
+        assert_eq!(
+            inspect(&encode_still(checkerboard(8, false))),
+            Some(ImageInfo {
+                format: ImageFormat::Png,
+                width: 64,
+                height: 64,
+            })
+        );
+        assert_eq!(inspect(b"definitely not an image"), None);
+    }
+
+    #[test]
+    fn confidence_combines_signals() {
+        let identical = Signals {
+            distance: 40,
+            same_content: Some(true),
+            ..Signals::default()
+        };
+        assert_eq!(repost_confidence(&identical), 1.0);
+
+        let exact_hash = Signals::default();
+        assert_eq!(repost_confidence(&exact_hash), 1.0);
+
+        let unrelated = Signals {
+            distance: MAX_DISTANCE / 2,
+            same_dimensions: Some(true),
+            same_format: Some(true),
+            ..Signals::default()
+        };
+        assert!(repost_confidence(&unrelated) < 0.5);
+
+        let close = Signals {
+            distance: 8,
+            ..Signals::default()
+        };
+        let close_confidence = repost_confidence(&close);
+        assert!(close_confidence > 0.8 && close_confidence < 0.9);
+
+        let close_and_same_size = Signals {
+            same_dimensions: Some(true),
+            ..close
+        };
+        assert!(repost_confidence(&close_and_same_size) > close_confidence);
+
+        let close_but_resized = Signals {
+            distance: 8,
+            same_dimensions: Some(false),
+            same_format: Some(false),
+            ..Signals::default()
+        };
+        assert!(repost_confidence(&close_but_resized) < close_confidence);
+    }
+
+    #[test]
+    fn duplicate_hashes_removed() {
+        let first = ImageHash::from_bytes(&[1; 64]).unwrap();
+        let second = ImageHash::from_bytes(&[2; 64]).unwrap();
+
+        let deduped = dedupe_images(vec![
+            ProcessedImage::Hashed(first.clone().into()),
+            ProcessedImage::Skipped(SkipReason::Animated),
+            ProcessedImage::MatchOnly(second.clone().into()),
+            ProcessedImage::Hashed(first.clone().into()),
+            ProcessedImage::Skipped(SkipReason::Animated),
+        ]);
+
+        let hashes: Vec<_> = deduped.iter().map(ProcessedImage::hash).collect();
+        assert_eq!(hashes, vec![Some(&first), None, Some(&second), None]);
+    }
+
+    #[test]
+    fn still_matches_first_frame_of_animation() {
+        let config = DetectionConfig {
+            animation_matching: AnimationMatching::FirstFrame,
+            ..DetectionConfig::default()
+        };
+
+        let still = encode_still(checkerboard(16, false));
+        let animation = encode_animation(vec![
+            checkerboard(16, false),
+            checkerboard(16, true),
+            checkerboard(4, false),
+        ]);
+
+        let still = hashed(process_image(still, &config).unwrap());
+        let animation = hashed(process_image(animation, &config).unwrap());
+
+        assert!(similar_enough(
+            &still,
+            animation.as_bytes(),
+            config.similarity_threshold
+        ));
+    }
+
+    #[test]
+    fn frames_sampled_evenly() {
+        // Each frame has as many white columns as its position, so it can be told apart after decoding.
+        let numbered = |count: u32| {
+            encode_animation(
+                (0..count)
+                    .map(|i| {
+                        RgbaImage::from_fn(64, 64, |x, _| {
+                            if x < i {
+                                Rgba([255, 255, 255, 255])
+                            } else {
+                                Rgba([0, 0, 0, 255])
+                            }
+                        })
+                    })
+                    .collect(),
+            )
+        };
+        let positions = |frames: Vec<DynamicImage>| -> Vec<usize> {
+            frames
+                .iter()
+                .map(|f| (0..64).filter(|&x| f.get_pixel(x, 0)[0] > 127).count())
+                .collect()
+        };
+        let sample = |gif: &[u8], count| {
+            sample_frames(GifDecoder::new(Cursor::new(gif)).unwrap(), count).unwrap()
+        };
+
+        let long = numbered(23);
+        let (first, later, animated) = sample(&long, 5);
+        assert_eq!(positions(vec![first]), vec![0]);
+        assert_eq!(positions(later), vec![8, 12, 16, 20]);
+        assert!(animated);
+
+        let short = numbered(3);
+        let (_, later, animated) = sample(&short, 5);
+        assert_eq!(positions(later), vec![1, 2]);
+        assert!(animated);
+
+        let (_, later, animated) = sample(&long, 1);
+        assert!(later.is_empty());
+        assert!(animated);
+    }
+
+    #[test]
+    fn animation_matched_by_later_frames() {
+        let config = DetectionConfig::default();
+        assert_eq!(config.animation_matching, AnimationMatching::Frames);
+
+        let animation = encode_animation(vec![
+            checkerboard(16, false),
+            checkerboard(16, true),
+            checkerboard(4, false),
+        ]);
+        let cut = encode_still(checkerboard(4, false));
+
+        let animation = match process_image(animation, &config).unwrap() {
+            ProcessedImage::Hashed(hashes) => hashes,
+            other => panic!("expected a hash, got {:?}", other),
+        };
+        let cut = hashed(process_image(cut, &config).unwrap());
+
+        assert_eq!(animation.frames.len(), 2);
+        assert!(!similar_enough(
+            &cut,
+            animation.hash.as_bytes(),
+            config.similarity_threshold
+        ));
+        assert!(animation.frames.iter().any(|frame| similar_enough(
+            &cut,
+            frame.as_bytes(),
+            config.similarity_threshold
+        )));
+
+        // Only the first frame is hashed otherwise.
+        let config = DetectionConfig {
+            animation_matching: AnimationMatching::FirstFrame,
+            ..config
+        };
+        let animation = encode_animation(vec![checkerboard(16, false), checkerboard(4, false)]);
+        match process_image(animation, &config).unwrap() {
+            ProcessedImage::Hashed(hashes) => assert!(hashes.frames.is_empty()),
+            other => panic!("expected a hash, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn videos_recognized() {
+        assert!(looks_like_video(b"\0\0\0\x20ftypisom\0\0\x02\0"));
+        assert!(looks_like_video(&[
+            0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x86
+        ]));
+        assert!(!looks_like_video(&encode_still(checkerboard(8, false))));
+        assert!(!looks_like_video(b"ftyp"));
+    }
+
+    #[test]
+    fn piped_pngs_split() {
+        let first = encode_still(checkerboard(8, false));
+        let second = encode_still(checkerboard(4, true));
+        let stream = [&first[..], &second[..]].concat();
+
+        assert_eq!(split_pngs(&stream), vec![&first[..], &second[..]]);
+        // Whatever was cut off is left out.
+        assert_eq!(split_pngs(&stream[..stream.len() - 1]), vec![&first[..]]);
+        assert!(split_pngs(b"not a png").is_empty());
+    }
+
+    /// Writes a stand-in for ffmpeg that checks it was asked for keyframes, then prints `frames` or fails.
+    #[cfg(unix)]
+    fn fake_ffmpeg(name: &str, frames: Option<Vec<RgbaImage>>) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::path::Path::new("./target").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let output = match frames {
+            Some(frames) => {
+                let pngs: Vec<u8> = frames.into_iter().flat_map(encode_still).collect();
+                std::fs::write(dir.join("frames.png"), pngs).unwrap();
+                "cat \"$(dirname \"$0\")/frames.png\"".to_string()
+            }
+            None => "echo 'moov atom not found' >&2; exit 1".to_string(),
+        };
+
+        let script = dir.join("ffmpeg");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\ncase \"$*\" in *\"-skip_frame nokey\"*) ;; *) exit 2 ;; esac\n{}\n",
+                output
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        script.to_str().unwrap().to_string()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn videos_hashed_by_keyframes() {
+        let video = b"\0\0\0\x20ftypisom\0\0\x02\0".to_vec();
+        let config = DetectionConfig {
+            ffmpeg_path: Some(fake_ffmpeg(
+                "ffmpeg_keyframes",
+                Some(vec![checkerboard(16, false), checkerboard(4, false)]),
+            )),
+            ..DetectionConfig::default()
+        };
+
+        let hashes = match process_image(video.clone(), &config).unwrap() {
+            ProcessedImage::Hashed(hashes) => hashes,
+            other => panic!("expected a hash, got {:?}", other),
+        };
+        let first = hashed(process_image(encode_still(checkerboard(16, false)), &config).unwrap());
+        let later = hashed(process_image(encode_still(checkerboard(4, false)), &config).unwrap());
+        assert_eq!(hashes.hash, first);
+        assert_eq!(hashes.frames, vec![later]);
+        assert_eq!(hashes.format, None);
+
+        // Videos are animations, as far as the settings go.
+        let skipping = DetectionConfig {
+            animation_matching: AnimationMatching::Skip,
+            ..config.clone()
+        };
+        assert!(matches!(
+            process_image(video.clone(), &skipping).unwrap(),
+            ProcessedImage::Skipped(SkipReason::Animated)
+        ));
+
+        // Without ffmpeg, they're just files that aren't images.
+        let without = DetectionConfig {
+            ffmpeg_path: None,
+            ..config
+        };
+        assert!(matches!(
+            process_image(video, &without),
+            Err(Error::UnsupportedImageFormat(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn video_decoding_failures_reported() {
+        let video = b"\0\0\0\x20ftypisom\0\0\x02\0".to_vec();
+        let config = DetectionConfig {
+            ffmpeg_path: Some(fake_ffmpeg("ffmpeg_failing", None)),
+            ..DetectionConfig::default()
+        };
+
+        match process_image(video.clone(), &config) {
+            Err(Error::DecodingVideo(message)) => assert_eq!(message, "moov atom not found"),
+            other => panic!("expected a decoding error, got {:?}", other),
+        }
+
+        let missing = DetectionConfig {
+            ffmpeg_path: Some("./target/no_ffmpeg_here".to_string()),
+            ..config
+        };
+        assert!(matches!(
+            process_image(video, &missing),
+            Err(Error::DecodingVideo(_))
+        ));
+    }
+
+    #[test]
+    fn animations_can_be_skipped() {
+        let config = DetectionConfig {
+            animation_matching: AnimationMatching::Skip,
+            ..DetectionConfig::default()
+        };
+
+        let animation = encode_animation(vec![checkerboard(16, false), checkerboard(16, true)]);
+        assert!(matches!(
+            process_image(animation, &config).unwrap(),
+            ProcessedImage::Skipped(SkipReason::Animated)
+        ));
+
+        // Single frame GIFs aren't animated, so they're still tracked.
+        let still_gif = encode_animation(vec![checkerboard(16, false)]);
+        hashed(process_image(still_gif, &config).unwrap());
+    }
+
+    #[test]
+    fn rotated_copies_match() {
+        let mut config = DetectionConfig::default();
+
+        // Only the left side is dark, so rotating it looks nothing like the original.
+        let half = RgbaImage::from_fn(64, 64, |x, _| {
+            if x < 32 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        });
+        let rotated = image::imageops::rotate90(&half);
+
+        let original = hashed(process_image(encode_still(half.clone()), &config).unwrap());
+        let rotated_hash = hashed(process_image(encode_still(rotated.clone()), &config).unwrap());
+        assert!(!similar_enough(
+            &rotated_hash,
+            original.as_bytes(),
+            config.similarity_threshold
+        ));
+
+        config.match_rotations = true;
+        let rotated = match process_image(encode_still(rotated), &config).unwrap() {
+            ProcessedImage::Hashed(hashes) => hashes,
+            other => panic!("image wasn't hashed for storage: {:?}", other),
+        };
+
+        assert_eq!(rotated.variants.len(), 3);
+        assert!(rotated.variants.iter().any(|variant| similar_enough(
+            variant,
+            original.as_bytes(),
+            config.similarity_threshold
+        )));
+    }
+
+    #[test]
+    fn small_images_handled() {
+        let mut config = DetectionConfig {
+            min_dimension: 65,
+            ..DetectionConfig::default()
+        };
+
+        let image = encode_still(checkerboard(16, false));
+        assert!(matches!(
+            process_image(image.clone(), &config).unwrap(),
+            ProcessedImage::Skipped(SkipReason::TooSmall)
+        ));
+
+        config.small_images = SmallImages::MatchOnly;
+        assert!(matches!(
+            process_image(image.clone(), &config).unwrap(),
+            ProcessedImage::MatchOnly(_)
+        ));
+
+        config.min_dimension = 64;
+        hashed(process_image(image, &config).unwrap());
+    }
+
+    #[test]
+    fn apng_stickers_hash_as_animations() {
+        let config = DetectionConfig::default();
+
+        let sticker = encode_apng(vec![checkerboard(16, false), checkerboard(16, true)]);
+        let still = encode_still(checkerboard(16, false));
+
+        let sticker = hashed(process_image(sticker, &config).unwrap());
+        let still = hashed(process_image(still, &config).unwrap());
+        assert!(similar_enough(
+            &still,
+            sticker.as_bytes(),
+            config.similarity_threshold
+        ));
+
+        let skipping = DetectionConfig {
+            animation_matching: AnimationMatching::Skip,
+            ..DetectionConfig::default()
+        };
+        let sticker = encode_apng(vec![checkerboard(16, false), checkerboard(16, true)]);
+        assert!(matches!(
+            process_image(sticker, &skipping).unwrap(),
+            ProcessedImage::Skipped(SkipReason::Animated)
+        ));
+    }
+
+    #[test]
+    fn transparency_flattened_before_hashing() {
+        let config = DetectionConfig {
+            alpha_background: AlphaBackground::White,
+            ..DetectionConfig::default()
+        };
+
+        // A black checkerboard where the other squares are fully transparent.
+        let transparent = RgbaImage::from_fn(64, 64, |x, y| {
+            if (x / 16 + y / 16).is_multiple_of(2) {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([12, 200, 34, 0])
+            }
+        });
+        let over_white = DynamicImage::ImageRgba8(checkerboard(16, false)).to_rgb8();
+
+        let transparent = hashed(process_image(encode_still(transparent), &config).unwrap());
+
+        let mut flattened = Vec::new();
+        DynamicImage::ImageRgb8(over_white)
+            .write_to(&mut flattened, ImageOutputFormat::Png)
+            .unwrap();
+        let flattened = hashed(process_image(flattened, &config).unwrap());
+
+        assert_eq!(transparent.dist(&flattened), 0);
+    }
+
     // TODO: collect appropriate licensed images to use for false positive
     // checking then un-`#[ignore]` this test
     #[test]
@@ -72,6 +1275,7 @@ mod tests {
     fn false_positives() -> Result<(), Box<dyn std::error::Error>> {
         set_logger();
 
+        let config = DetectionConfig::default();
         for directory in std::fs::read_dir("false_positives")? {
             let directory = directory?;
 
@@ -85,13 +1289,13 @@ mod tests {
                 )?;
 
                 (
-                    process_image(entries[0].clone()).unwrap(),
-                    process_image(entries[1].clone()).unwrap(),
+                    hashed(process_image(entries[0].clone(), &config).unwrap()),
+                    hashed(process_image(entries[1].clone(), &config).unwrap()),
                 )
             };
 
             assert!(
-                !similar_enough(&h1, h2.as_bytes()),
+                !similar_enough(&h1, h2.as_bytes(), config.similarity_threshold),
                 "false positive found in directory {}",
                 directory.path().display()
             );
@@ -107,6 +1311,7 @@ mod tests {
     fn true_positives() -> Result<(), Box<dyn std::error::Error>> {
         set_logger();
 
+        let config = DetectionConfig::default();
         for directory in std::fs::read_dir("true_positives")? {
             let directory = directory?;
 
@@ -120,13 +1325,13 @@ mod tests {
                 )?;
 
                 (
-                    process_image(entries[0].clone()).unwrap(),
-                    process_image(entries[1].clone()).unwrap(),
+                    hashed(process_image(entries[0].clone(), &config).unwrap()),
+                    hashed(process_image(entries[1].clone(), &config).unwrap()),
                 )
             };
 
             assert!(
-                similar_enough(&h1, h2.as_bytes()),
+                similar_enough(&h1, h2.as_bytes(), config.similarity_threshold),
                 "did not detect a duplicate in directory {}",
                 directory.path().display()
             );
@@ -0,0 +1,132 @@
+use crate::commands::parse_url;
+
+use hyper::Uri;
+use std::str::FromStr;
+
+/// Query parameters that only track where a link was shared from, so they're left out when comparing links.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "igshid", "si", "feature", "ref", "ref_src",
+];
+
+/// Punctuation that ends a sentence rather than the link in it.
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', '!', '?', ';', ':'];
+
+/// The canonical form of every link in a message's text, without repeats, in the order they're found.
+pub fn links_in(content: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    for word in content.split_whitespace() {
+        let word = word.trim_end_matches(TRAILING_PUNCTUATION);
+        if let Some(link) = parse_url(word).and_then(canonicalize) {
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+    }
+
+    links
+}
+
+/// Rewrites a link so different ways of writing it come out the same.
+///
+/// The scheme, `www.`, fragments, trailing slashes, and tracking parameters are dropped, the rest of the query
+/// is sorted, and YouTube and Twitter links are written one way.
+pub fn canonicalize(url: &str) -> Option<String> {
+    let url = url.split('#').next()?;
+    let uri = Uri::from_str(url).ok()?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return None,
+    }
+
+    let host = uri.host()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let mut path = uri.path().trim_end_matches('/');
+
+    let mut params: Vec<String> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && !name.starts_with("utm_") && !TRACKING_PARAMS.contains(&name)
+        })
+        .map(str::to_string)
+        .collect();
+
+    let host = match host {
+        "youtu.be" | "youtube.com" | "m.youtube.com" => {
+            // Short links and shorts are the same videos as their watch pages.
+            let video = match host {
+                "youtu.be" => path.strip_prefix('/'),
+                _ => path.strip_prefix("/shorts/"),
+            };
+            if let Some(id) = video.filter(|id| !id.is_empty()) {
+                params.push(format!("v={}", id));
+                path = "/watch";
+            }
+
+            "youtube.com"
+        }
+        "twitter.com" | "mobile.twitter.com" | "mobile.x.com" => "x.com",
+        host => host,
+    };
+
+    params.sort();
+    if params.is_empty() {
+        Some(format!("{}{}", host, path))
+    } else {
+        Some(format!("{}{}?{}", host, path, params.join("&")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_links_match() {
+        let same = [
+            (
+                "https://www.example.com/news/story/?utm_source=discord&id=7#comments",
+                "example.com/news/story?id=7",
+            ),
+            (
+                "http://Example.com/news/story?fbclid=abc&id=7",
+                "example.com/news/story?id=7",
+            ),
+            (
+                "https://youtu.be/dQw4w9WgXcQ?si=xyz",
+                "youtube.com/watch?v=dQw4w9WgXcQ",
+            ),
+            (
+                "https://m.youtube.com/watch?v=dQw4w9WgXcQ&feature=share",
+                "youtube.com/watch?v=dQw4w9WgXcQ",
+            ),
+            (
+                "https://youtube.com/shorts/dQw4w9WgXcQ",
+                "youtube.com/watch?v=dQw4w9WgXcQ",
+            ),
+            (
+                "https://twitter.com/someone/status/1",
+                "x.com/someone/status/1",
+            ),
+            ("https://example.com/?b=2&a=1", "example.com?a=1&b=2"),
+        ];
+
+        for (url, canonical) in same {
+            assert_eq!(canonicalize(url).as_deref(), Some(canonical), "{}", url);
+        }
+
+        assert_eq!(canonicalize("ftp://example.com/file"), None);
+        assert_eq!(canonicalize("not a link"), None);
+    }
+
+    #[test]
+    fn links_found_in_text() {
+        assert_eq!(
+            links_in("look at https://example.com/a. and <https://example.com/a/> or https://example.com/b, ok"),
+            vec!["example.com/a", "example.com/b"]
+        );
+        assert!(links_in("no links here").is_empty());
+    }
+}
@@ -1,18 +1,47 @@
 mod bot;
+mod commands;
+mod config;
 mod data_storage;
+mod diagnostics;
 mod errors;
-use std::borrow::Cow;
+mod guild_settings;
+mod hash_index;
+mod health;
+mod links;
+mod message_cache;
+use std::{borrow::Cow, str::FromStr};
 
 pub use errors::Error;
+mod embeds;
 mod image_processing;
+mod recent_reposts;
+#[cfg(feature = "sqlite")]
+mod sqlite_export;
+mod startup_settle;
+mod storage;
+mod transfer;
+mod worker_pool;
+use image_processing::{Hashes, ProcessedImage};
+
+use bot::{GifLink, RedditLink};
+use commands::{Command, Privilege};
+use config::{
+    Config, CountStyle, Crossposts, DownloadConfig, FirstOffense, LogFormat, ReplyConfig,
+    ThreadReposts,
+};
+use data_storage::{
+    Data, ForgottenImage, GuildStats, Occurrence, PreviouslySeen, PurgeCriteria, SeenImage,
+};
+use guild_settings::EffectiveSettings;
+use storage::Storage;
 
-use data_storage::{Data, PreviouslySeen, SeenImage};
-
-use hyper::Client as HyperClient;
+use hyper::{Client as HyperClient, Uri};
 use hyper_rustls::HttpsConnector;
 
 use tokio_stream::StreamExt;
 
+use tracing::Instrument;
+
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 use twilight_gateway::{
     cluster::{Cluster, ShardScheme},
@@ -22,26 +51,48 @@ use twilight_http::Client;
 use twilight_model::{
     channel::{
         embed::{Embed, EmbedImage},
-        message::{AllowedMentions, Message},
+        message::{
+            sticker::{MessageSticker, StickerFormatType},
+            AllowedMentions, Message, MessageFlags,
+        },
+        Attachment,
     },
-    gateway::{payload::MessageCreate, presence::Status, Intents},
-    id::{ChannelId, GuildId, MessageId},
+    gateway::{
+        payload::{GuildDelete, MessageCreate},
+        presence::Status,
+    },
+    id::{ChannelId, GuildId, MessageId, RoleId, UserId},
 };
 
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
 
-    tracing::subscriber::set_global_default(
-        FmtSubscriber::builder()
-            .with_env_filter(EnvFilter::from_default_env())
-            .finish(),
-    )
-    .unwrap();
+    let config = Config::load();
+    init_logging(config.log_format);
+    for warning in &config.warnings {
+        tracing::warn!("{}", warning);
+    }
+
+    // Moving the database between hosts doesn't need Discord at all.
+    let mut args = std::env::args().skip(1);
+    if let Some(mode) = args.next() {
+        // Exports can name their file with `--out` too.
+        let path = match args.next().as_deref() {
+            Some("--out") => args.next(),
+            path => path.map(str::to_string),
+        }
+        .expect("no file path given");
+        transfer_database(&mode, &path, &config);
+        return;
+    }
 
     tracing::info!("Booting!");
 
-    let token = std::env::var("DISCORD_TOKEN").expect("no discord token present");
+    let token = config
+        .discord_token
+        .clone()
+        .expect("no discord token present");
 
     let web_client =
         HyperClient::builder().build::<_, hyper::Body>(HttpsConnector::with_native_roots());
@@ -52,54 +103,331 @@ async fn main() {
         .build();
 
     tracing::info!("Initalizing database...");
-    let data = Data::init("./storage").unwrap();
+    let data = Data::init(&config.storage.path, &config).unwrap();
+
+    if config.repair_counts_on_startup {
+        let repaired = data.repair_counts().expect("failed to repair seen counts");
+        tracing::info!("Repaired {} seen counts", repaired);
+    }
+
     let current_total_seen = data.total_seen();
 
-    let me = client.current_user().exec().await.unwrap();
+    let me = bot::current_user_id(&client)
+        .await
+        .unwrap_or_else(|e| exit_with("Couldn't fetch the bot's user, is the token right?", e));
+    let owner = bot::application_owner_id(&client)
+        .await
+        .unwrap_or_else(|e| exit_with("Couldn't fetch the bot's application", e));
 
-    let (cluster, mut incoming_events) = Cluster::builder(
-        token,
-        Intents::GUILD_MESSAGES | Intents::GUILD_MESSAGE_REACTIONS,
-    )
-    .shard_scheme(ShardScheme::Auto)
-    .presence(bot::presence_builder(status_message(current_total_seen), Status::Offline).d)
-    .build()
-    .await
-    .expect("failed to init cluster");
+    let (cluster, mut incoming_events) = Cluster::builder(token, bot::INTENTS)
+        .shard_scheme(ShardScheme::Auto)
+        .presence(bot::presence_builder(status_message(current_total_seen), Status::Offline).d)
+        .build()
+        .await
+        .expect("failed to init cluster");
 
     cluster.up().await;
 
     tracing::info!("Cluster is running...");
 
-    let context = bot::Context::init(
-        me.model()
-            .await
-            .expect("current user deserialize failed")
-            .id,
-        data,
-        web_client,
-        client,
-        cluster,
-    );
+    let mut context = bot::Context::init(config, me, owner, data, web_client, client, cluster);
+
+    if let Some(address) = context.config.health_check_address {
+        tokio::spawn(health::serve(address, context.clone()));
+    }
+
+    if context.config.storage.count_batch_ms > 0 {
+        let data = context.data.clone();
+        let every = std::time::Duration::from_millis(context.config.storage.count_batch_ms);
+
+        tokio::spawn(async move {
+            let mut batches = tokio::time::interval(every);
+            loop {
+                batches.tick().await;
+                if let Err(e) = data.flush_counts() {
+                    tracing::error!("Failed to write batched repost counts: {:?}", e);
+                }
+            }
+        });
+    }
+
+    if let Some(grace) = context.config.storage.purge_removed_guilds_after {
+        let data = context.data.clone();
+
+        tokio::spawn(async move {
+            let mut checks = tokio::time::interval(PURGE_CHECK_INTERVAL);
+            loop {
+                checks.tick().await;
+                match data.purge_removed_guilds(seconds_since_epoch(), grace) {
+                    Ok(purged) => {
+                        for (guild_id, images) in purged {
+                            tracing::info!(
+                                "Purged {} images from removed guild {}",
+                                images,
+                                guild_id
+                            );
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to purge removed guilds: {:?}", e),
+                }
+            }
+        });
+    }
+
+    if let Some(retention) = context.config.storage.image_retention {
+        let data = context.data.clone();
+
+        tokio::spawn(async move {
+            let mut checks = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+            loop {
+                checks.tick().await;
+                match data.expire_images(seconds_since_epoch(), retention) {
+                    Ok(0) => {}
+                    Ok(expired) => tracing::info!("Deleted {} expired images", expired),
+                    Err(e) => tracing::error!("Failed to delete expired images: {:?}", e),
+                }
+            }
+        });
+    }
+
+    // Every task that might still be writing holds a sender, so shutdown knows when they're all done.
+    let (in_flight, mut all_finished) = tokio::sync::mpsc::channel::<()>(1);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (shard_id, event) = tokio::select! {
+            event = incoming_events.next() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            _ = &mut shutdown => {
+                tracing::info!("Shutting down...");
+                break;
+            }
+        };
 
-    while let Some((shard_id, event)) = incoming_events.next().await {
+        context.refresh_config();
+        context.event_received();
         context.standby.process(&event);
+        context.cache.update(&event);
+
+        if let Event::GuildDelete(removed) = &event {
+            let purge_after = context.config.storage.purge_removed_guilds_after;
+
+            if schedules_purge(removed, purge_after) {
+                if let Err(e) = context
+                    .data
+                    .guild_removed(removed.id.0, seconds_since_epoch())
+                {
+                    tracing::error!("Failed to remember leaving a guild: {:?}", e);
+                }
+
+                tracing::info!(
+                    "Removed from guild {}, its data will be purged in {} seconds",
+                    removed.id,
+                    purge_after.unwrap_or_default()
+                );
+            }
+            continue;
+        }
+
+        if let Event::Ready(_) = &event {
+            context.shard_ready(shard_id);
+        }
+
+        if let Event::GuildCreate(guild) = &event {
+            context.guild_streamed();
+            let context = context.clone();
+            let (guild_id, system_channel) = (guild.id, guild.system_channel_id);
+
+            tokio::spawn(async move {
+                if let Err(e) = welcome_guild(guild_id, system_channel, context).await {
+                    tracing::error!("Error welcoming a new guild: {:?}", e);
+                }
+            });
+            continue;
+        }
+
+        if let Event::MessageDelete(deleted) = &event {
+            context.message_cache().remove(deleted.id);
+
+            let context = context.clone();
+            let (channel_id, message_id) = (deleted.channel_id, deleted.id);
+            let in_flight = in_flight.clone();
+
+            tokio::spawn(async move {
+                let _in_flight = in_flight;
+                if let Err(e) = take_back_repost(channel_id, message_id, context).await {
+                    tracing::error!("Error taking back a deleted repost: {:?}", e);
+                }
+            });
+            continue;
+        }
+
+        // Cached copies of edited messages are out of date, so they're fetched again if they're needed.
+        match &event {
+            Event::MessageUpdate(updated) => context.message_cache().remove(updated.id),
+            Event::MessageDeleteBulk(deleted) => {
+                let mut cache = context.message_cache();
+                for id in &deleted.ids {
+                    cache.remove(*id);
+                }
+            }
+            _ => {}
+        }
 
         // TODO: actually handle MessageUpdate events to catch more images
         if let Event::MessageCreate(msg) = event {
             let context = context.clone();
+            context.message_cache().insert(msg.0.clone());
 
             // Maybe someone has an image bot! Imagine that.
             if msg.author.bot {
                 continue;
             }
 
-            tokio::spawn(async move {
+            context.counters.message_seen();
+            let in_flight = in_flight.clone();
+
+            let span = tracing::info_span!(
+                "message",
+                guild_id = tracing::field::Empty,
+                channel_id = msg.channel_id.0,
+                message_id = msg.id.0,
+            );
+            if let Some(guild_id) = msg.guild_id {
+                span.record("guild_id", &guild_id.0);
+            }
+
+            let handling = async move {
+                let _in_flight = in_flight;
+                let counters = context.counters.clone();
                 if let Err(e) = handle_message(shard_id, msg, context).await {
+                    counters.error();
                     tracing::error!("Error handling a message: {:?}", e);
                 }
-            });
+            };
+            tokio::spawn(handling.instrument(span));
+        }
+    }
+
+    context.shut_down();
+
+    drop(in_flight);
+    if tokio::time::timeout(SHUTDOWN_DEADLINE, all_finished.recv())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Messages were still being handled after {} seconds, stopping anyway",
+            SHUTDOWN_DEADLINE.as_secs()
+        );
+    }
+
+    match context.data.flush() {
+        Ok(()) => tracing::info!("Database written, goodbye!"),
+        Err(e) => tracing::error!("Failed to write the database before exiting: {:?}", e),
+    }
+}
+
+/// Sets up logging, with `RUST_LOG` choosing what's logged.
+fn init_logging(format: LogFormat) {
+    let builder = FmtSubscriber::builder().with_env_filter(EnvFilter::from_default_env());
+
+    match format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        // The message being handled goes on every line, so they can be searched by guild or channel.
+        LogFormat::Json => tracing::subscriber::set_global_default(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .finish(),
+        ),
+    }
+    .unwrap();
+}
+
+/// Resolves when the bot is asked to stop, with Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to listen for Ctrl-C");
+}
+
+/// Stops the bot when it can't start, without a panic's noise.
+fn exit_with(reason: &str, error: Error) -> ! {
+    tracing::error!("{}: {:?}", reason, error);
+    std::process::exit(1)
+}
+
+fn transfer_database(mode: &str, path: &str, config: &Config) {
+    let data = Data::init(&config.storage.path, config).unwrap();
+    let key = config.export_signing_key.as_deref().map(str::as_bytes);
+
+    match mode {
+        "export" => {
+            let file = std::fs::File::create(path).expect("failed to create export file");
+            data.export_to_writer(std::io::BufWriter::new(file), key)
+                .unwrap_or_else(|e| panic!("failed to export the database: {}", e));
+
+            tracing::info!("Exported the database to {}", path);
+        }
+        "import" => {
+            let file = std::fs::File::open(path).expect("failed to open export file");
+            let imported = data
+                .import_from_reader(std::io::BufReader::new(file), key)
+                .unwrap_or_else(|e| panic!("failed to import the database: {}", e));
+
+            tracing::info!("Imported {} images from {}", imported, path);
+        }
+        "export-policy" => {
+            let file = std::fs::File::create(path).expect("failed to create policy file");
+            data.export_policy(std::io::BufWriter::new(file))
+                .unwrap_or_else(|e| panic!("failed to export the policy: {}", e));
+
+            tracing::info!("Exported the policy to {}", path);
+        }
+        "import-policy" => {
+            let file = std::fs::File::open(path).expect("failed to open policy file");
+            let applied = data
+                .import_policy(std::io::BufReader::new(file))
+                .unwrap_or_else(|e| panic!("failed to import the policy: {}", e));
+
+            tracing::info!(
+                "Imported the policy from {}, ignoring {} known images",
+                path,
+                applied
+            );
         }
+        #[cfg(feature = "sqlite")]
+        "export-sqlite" => {
+            let dump = data.dump().expect("failed to read the database");
+            let copied = sqlite_export::SqliteData::init(path, config)
+                .map_err(errors::TransferError::from)
+                .and_then(|sqlite| sqlite.import_dump(&dump))
+                .unwrap_or_else(|e| panic!("failed to write the SQLite export: {}", e));
+
+            tracing::info!("Copied {} images to {}", copied, path);
+        }
+        _ => panic!(
+            "unknown mode {:?}, expected export, import, export-policy, import-policy, or export-sqlite",
+            mode
+        ),
     }
 }
 
@@ -108,260 +436,2151 @@ async fn handle_message(
     message: Box<MessageCreate>,
     context: bot::Context,
 ) -> Result<(), Error> {
-    if let Some(url) = image_from_message(&message) {
-        let image = context.download_image(url).await?;
-        if let PreviouslySeen::Yes { image, times_seen } = save_image(&context, image, &message)? {
-            if !image.ignored {
-                dispatch_repost_reply(
-                    &context,
-                    &image,
-                    times_seen,
-                    message.channel_id,
-                    message.guild_id.ok_or(Error::UnsupportedChannelConfig)?,
-                )
-                .await?;
+    let guild_id = message.guild_id.ok_or(Error::UnsupportedChannelConfig)?;
+    let settings = context.effective_settings(guild_id)?;
 
-                let total_seen = if times_seen == 2 {
-                    // If its the first of a repost variant, increment our counter for the presence message
-                    context.repost_seen()
-                } else {
-                    context.total_seen()
-                };
+    // Commands still work in guilds that turned the bot off, so it can be turned back on.
+    if !settings.enabled || !settings.checks_channel(message.channel_id.0) {
+        return handle_command(message, guild_id, settings, context).await;
+    }
 
-                let status_message = status_message(total_seen);
+    if settings.skip_nsfw && context.is_nsfw(message.channel_id).await? {
+        return handle_command(message, guild_id, settings, context).await;
+    }
 
-                if let Err(e) = context
-                    .change_status(shard_id, status_message, Status::Online)
-                    .await
-                {
-                    tracing::error!("Failed to update status after a repost: {:?}", e)
-                }
+    let mut urls = images_from_message(&message, &context.config);
+    for link in gif_links(&message, &context.config) {
+        if !context.config.detection.all_images && !urls.is_empty() {
+            break;
+        }
 
-                return Ok(());
+        match context.resolve_gif_link(&link).await {
+            Ok(url) if filter_image(&url, &context.config.download).is_some() => {
+                tracing::debug!("GIF link resolved: {}", url);
+                if !urls.iter().any(|u| *u == url) {
+                    urls.push(Cow::Owned(url));
+                }
             }
+            Ok(url) => tracing::debug!("Skipping a GIF link's unsupported media: {}", url),
+            Err(e) => tracing::debug!("Couldn't resolve a GIF link {:?}: {:?}", link, e),
         }
     }
 
-    if message
-        .mentions
-        .first()
-        .or_else(|| message.mentions.get(1))
-        .filter(|m| context.is_me(m.id))
-        .is_none()
-    {
-        return Ok(());
+    for link in reddit_links(&message, &context.config) {
+        if !context.config.detection.all_images && !urls.is_empty() {
+            break;
+        }
+
+        match context.resolve_reddit_link(&link).await {
+            Ok(media) => {
+                // The post's own files replace the preview Discord shows of it.
+                let previews: Vec<_> = message
+                    .embeds
+                    .iter()
+                    .filter(|e| e.url.as_deref() == Some(link.url))
+                    .flat_map(|e| embed_images(e, &context.config))
+                    .collect();
+                urls.retain(|url| !previews.contains(url));
+
+                for url in media {
+                    if filter_image(&url, &context.config.download).is_some()
+                        && !urls.iter().any(|u| *u == url)
+                    {
+                        urls.push(Cow::Owned(url));
+                    }
+                }
+            }
+            Err(e) => tracing::debug!("Couldn't resolve a Reddit link {:?}: {:?}", link, e),
+        }
     }
 
-    if let Some(msg) = &message.referenced_message {
-        if !message.content.contains("ignore") {
+    if !urls.is_empty() {
+        let checked_as = match checked_message(&message, context.config.detection.crossposts) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        if !context.first_check(checked_as) {
+            tracing::debug!("Skipping a crosspost of an announcement that was already checked");
             return Ok(());
         }
 
-        // Support two behaviors for ignoring stuff:
-        // 1. Reply on the message containing the image itself
-        // 2. Reply to our reply notifying users of a repost.
+        let data = context
+            .data
+            .with_similarity_threshold(settings.similarity_threshold)
+            .with_record_quota(settings.record_quota)
+            .for_guild(guild_id.0);
+
+        let mut hashing = Vec::with_capacity(urls.len());
+        let mut failures = Vec::new();
+        for url in &urls {
+            match context.download_image(url, settings.max_image_size).await {
+                // Hashing starts now, while the next image downloads.
+                Ok(image) => {
+                    let job = context.process_posted_image(image, &data);
+                    let job = async move { job.await.map(|image| image.with_source(url)) };
+                    hashing.push((&**url, job));
+                }
+                Err(e) => failures.push((&**url, e)),
+            }
+        }
 
-        let msg_with_img = if let Some(parent) = &msg.reference {
-            // TODO: Run these through a cache
-            Cow::Owned(
-                context
-                    .get_message(
-                        parent.channel_id.ok_or(Error::UnsupportedChannelConfig)?,
-                        parent.message_id.ok_or(Error::UnsupportedChannelConfig)?,
-                    )
-                    .await?,
-            )
-        } else {
-            Cow::Borrowed(&**message)
-        };
+        let (images, hash_failures) = wait_for_hashes(hashing).await;
+        failures.extend(hash_failures);
+
+        let mut failures: Vec<_> = failures
+            .into_iter()
+            .map(|(url, e)| (url, context.record_failure(url, e)))
+            .collect();
+        if images.is_empty() {
+            // Nothing worked, so it's an error like it would be for a single image.
+            return match failures.pop() {
+                Some((_, e)) => Err(e),
+                None => Ok(()),
+            };
+        }
+        for (url, e) in failures {
+            tracing::warn!(
+                "Skipping an image that failed in a message with others: {} {:?}",
+                url,
+                e
+            );
+        }
 
-        let image_to_ignore = match image_from_message(&msg_with_img) {
-            Some(url) => context.download_image(url).await?,
-            None => return Ok(()),
-        };
+        let seen = save_image(&data, images, &message).map_err(|(source, e)| {
+            context.record_failure(source.as_deref().unwrap_or(&urls[0]), e)
+        })?;
+        context.counters.image_processed();
+
+        let mut called_out = CalledOut::default();
+        for seen in seen {
+            let (image, times_seen) = match seen {
+                PreviouslySeen::Yes { image, times_seen } => (image, times_seen),
+                PreviouslySeen::NearMatch {
+                    image,
+                    original,
+                    new,
+                } => {
+                    if context.config.reply.dry_run {
+                        report_dry_run(&context, &message, "asked if a near match is a repost")
+                            .await;
+                        continue;
+                    }
+
+                    let confirmed = context
+                        .confirm_action(
+                            bot::ConfirmationAction::NearMatch,
+                            message.channel_id,
+                            settings.mod_role.map(RoleId),
+                        )
+                        .await?;
+
+                    if !confirmed {
+                        continue;
+                    }
+
+                    match data.merge_near_match(&new, &original)? {
+                        Some(times_seen) => (image, times_seen),
+                        None => continue,
+                    }
+                }
+                PreviouslySeen::No => continue,
+            };
+            context.counters.repost_detected();
+            context
+                .recent_reposts()
+                .counted(message.id, seconds_since_epoch());
+
+            let in_original_thread = is_thread_of(message.channel_id, image.original_message_id);
+            if in_original_thread && context.config.reply.thread_reposts == ThreadReposts::Skip {
+                continue;
+            }
 
-        match context
-            .confirm_action(bot::ConfirmationAction::IgnoreImage, message.channel_id)
-            .await
-        {
-            Ok(confirmed) => {
-                tracing::debug!("User confirmed: {}", confirmed);
+            if !should_reply(
+                message.channel_id,
+                image.channel_id,
+                settings.cross_channel_replies,
+            ) {
+                tracing::debug!("Counted a repost from another channel without replying");
+                continue;
+            }
 
-                if confirmed {
-                    let image_hash = image_processing::process_image(image_to_ignore)?;
-                    context.data.access_image(image_hash.as_bytes(), |seen| {
-                        seen.get_mut().ignored = true;
-                        true
-                    })?;
+            if settings.ignore_own_reposts && is_own_repost(&image, message.author.id) {
+                tracing::debug!("Counted someone reposting their own image without replying");
+                continue;
+            }
+
+            if context.replies_muted(message.channel_id) {
+                tracing::debug!("Counted a repost in a channel replies aren't allowed in");
+                continue;
+            }
+
+            if !context.startup_settled() {
+                tracing::debug!("Counted a repost before startup settled without replying");
+                continue;
+            }
+
+            if !image.ignored {
+                let offense = data.record_offense(guild_id.0, message.author.id.0)?;
+
+                match offense_reply(offense, context.config.reply.first_offense) {
+                    FirstOffense::Callout if context.config.reply.dry_run => {
+                        let reply = repost_reply(
+                            &image,
+                            times_seen,
+                            message.channel_id,
+                            guild_id,
+                            seconds_since_epoch(),
+                            &context.config.reply,
+                        );
+                        let action = format!("replied \"{}\"", reply.content);
+                        report_dry_run(&context, &message, &action).await;
+                    }
+                    FirstOffense::HeadsUp if context.config.reply.dry_run => {
+                        report_dry_run(&context, &message, "sent the poster a heads up").await;
+                    }
+                    FirstOffense::Callout => {
+                        let reply = dispatch_repost_reply(
+                            &context,
+                            &image,
+                            times_seen,
+                            message.channel_id,
+                            guild_id,
+                        )
+                        .await;
+
+                        let reply_id = match reply {
+                            Err(e) if bot::is_missing_permissions(&e) => {
+                                context.reply_forbidden(message.channel_id);
+                                continue;
+                            }
+                            reply => reply?,
+                        };
+
+                        let still_posted = context
+                            .recent_reposts()
+                            .replied(message.id, (message.channel_id, reply_id));
+                        if !still_posted && context.config.reply.quick_delete_window > 0 {
+                            // The repost was deleted while replying to it, so the reply goes too.
+                            context.delete_message(message.channel_id, reply_id).await?;
+                            called_out.taken_back = true;
+                            continue;
+                        }
+                    }
+                    FirstOffense::HeadsUp => {
+                        if let Err(e) =
+                            send_heads_up(&context, &image, message.author.id, guild_id).await
+                        {
+                            // Plenty of people don't accept DMs, which is fine.
+                            tracing::debug!("Failed to send a first repost heads up: {:?}", e);
+                        }
+                    }
+                    FirstOffense::Quiet => {
+                        tracing::debug!("Let someone's first repost slide");
+                    }
                 }
 
-                context
-                    .send_message(
-                        format!("User responded with {}", confirmed),
-                        message.channel_id,
-                        None,
-                    )
-                    .await
-                    .unwrap();
+                called_out.add(times_seen);
             }
-            Err(e) => return Err(e.into()),
         }
-    }
 
-    Ok(())
-}
+        // Deleting and the status only happen once, however many images in the message were reposts.
+        if called_out.reposts > 0 {
+            let deleted = settings.delete_reposts && !called_out.taken_back;
+            if deleted && context.config.reply.dry_run {
+                report_dry_run(&context, &message, "deleted the repost").await;
+            } else if deleted {
+                // Deleting it ourselves isn't the poster taking it back.
+                context.recent_reposts().forget(message.id);
+                if let Err(e) = context.delete_message(message.channel_id, message.id).await {
+                    tracing::warn!("Failed to delete a repost: {:?}", e);
+                }
+            }
 
-async fn dispatch_repost_reply(
-    context: &bot::Context,
-    previous: &SeenImage,
-    times_seen: u64,
-    channel_id: ChannelId,
-    guild_id: GuildId,
-) -> Result<(), Error> {
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("clocks are wobbly");
-    let difference = now - std::time::Duration::from_secs(previous.sent);
+            // The first repost of an image bumps the counter for the presence message.
+            for _ in 0..called_out.first_reposts {
+                context.repost_seen();
+            }
+            let status_message = status_message(context.total_seen());
 
-    let since = time_since(difference.as_secs());
+            if let Err(e) = context
+                .change_status(shard_id, status_message, Status::Online)
+                .await
+            {
+                tracing::error!("Failed to update status after a repost: {:?}", e)
+            }
 
-    let message = format!(
-        "Hey, {} already posted that here {}. I've seen it {} times now. Try harder next time <:niko:765033287357431829>", 
-        previous.author,
-        since,
-        times_seen
-    );
+            // There's nothing left to check in a message that's gone.
+            if (deleted && !context.config.reply.dry_run) || called_out.taken_back {
+                return Ok(());
+            }
+        }
+    }
 
-    // Check if we can use replies.
-    if channel_id.0 == previous.channel_id {
-        context
-            .send_message(
-                message,
-                channel_id,
-                Some(MessageId(previous.original_message_id)),
-            )
-            .await?;
-    } else {
-        let jump_link = format!(
-            "[Jump Link](https://discordapp.com/channels/{}/{}/{})",
-            guild_id.0, previous.channel_id, previous.original_message_id
-        );
-        context.send_embed(message, jump_link, channel_id).await?;
+    if context.config.detection.link_reposts {
+        handle_link_reposts(&context, &message, guild_id, &settings).await?;
     }
 
-    Ok(())
+    handle_command(message, guild_id, settings, context).await
 }
 
-fn status_message(reposts_seen: usize) -> String {
-    match reposts_seen {
-        0 => "for a repost to appear".to_string(),
-        1 => "out for 1 repost".to_string(),
-        _ => format!("out for {} reposts", reposts_seen),
+/// Runs the command in a message, if it mentions the bot with one its author is allowed to use.
+async fn handle_command(
+    message: Box<MessageCreate>,
+    guild_id: GuildId,
+    settings: EffectiveSettings,
+    context: bot::Context,
+) -> Result<(), Error> {
+    if message
+        .mentions
+        .first()
+        .or_else(|| message.mentions.get(1))
+        .filter(|m| context.is_me(m.id))
+        .is_none()
+    {
+        return Ok(());
     }
-}
 
-fn time_since(seconds: u64) -> String {
-    let days = seconds / 86400;
+    // TODO: Commands only come in as mentions, and replies to messages can't be ephemeral. If they're
+    // ever registered as interactions too, their responses could be made visible to just the invoker.
+    let command = match Command::parse(&message.content) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
 
-    if days > 0 {
-        let unit = if days > 1 { "days" } else { "day" };
+    let authorized = match command.privilege() {
+        Privilege::Anyone => true,
+        Privilege::Moderator => {
+            let roles = message.member.as_ref().map_or(&[][..], |m| &m.roles);
+            context.is_moderator(
+                guild_id,
+                message.author.id,
+                roles,
+                settings.mod_role.map(RoleId),
+            )
+        }
+        Privilege::Owner => context.is_owner(message.author.id),
+    };
 
-        return format!("{} {} ago", days, unit);
+    if !authorized {
+        return Ok(());
     }
 
-    let hours = seconds / 3600;
+    match command {
+        Command::Ignore => {
+            let data = context.data.for_guild(guild_id.0);
+            ignore_image(&context, &data, &message, &settings).await
+        }
+        Command::Forget => {
+            let data = context.data.for_guild(guild_id.0);
+            forget_image(&context, &data, &message, &settings).await
+        }
+        Command::Uptime => {
+            context
+                .send_message(context.runtime().render(), message.channel_id, None)
+                .await?;
 
-    if hours > 0 {
-        let unit = if hours > 1 { "hours" } else { "hour" };
+            Ok(())
+        }
+        Command::Leaderboard => {
+            let offenders = context.data.top_offenders(guild_id.0, LEADERBOARD_SIZE)?;
+            context
+                .send_message(leaderboard_message(&offenders), message.channel_id, None)
+                .await?;
 
-        return format!("{} {} ago", hours, unit);
-    }
+            Ok(())
+        }
+        Command::Stats => {
+            let stats = context.data.guild_stats(guild_id.0)?;
+            context
+                .send_message(stats_message(&stats, guild_id), message.channel_id, None)
+                .await?;
 
-    let minutes = seconds / 60;
+            Ok(())
+        }
+        Command::Diagnostics => {
+            let diagnostics = context.diagnostics()?;
+            context
+                .send_message(diagnostics.render(), message.channel_id, None)
+                .await?;
 
-    if minutes > 0 {
-        let unit = if minutes > 1 { "minutes" } else { "minute" };
+            Ok(())
+        }
+        Command::Reload => {
+            let reply = match Config::try_load() {
+                Ok(loaded) => {
+                    for warning in &loaded.warnings {
+                        tracing::warn!("{}", warning);
+                    }
+                    context.reload_config(loaded);
+                    tracing::info!("Reloaded settings");
+                    "Reloaded the detection, download, and reply settings.".to_string()
+                }
+                Err(e) => format!("Couldn't reload the settings, so they're unchanged: {}", e),
+            };
 
-        return format!("{} {} ago", minutes, unit);
-    }
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
 
-    let unit = if seconds > 1 { "seconds" } else { "second" };
+            Ok(())
+        }
+        Command::Failures => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("clocks are wobbly");
+
+            let failures = context.recent_failures().render(now.as_secs());
+            context
+                .send_message(failures, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::SetEnabled(enabled) => {
+            context
+                .data
+                .update_guild_settings(guild_id.0, |settings| settings.disabled = !enabled)?;
+
+            let reply = if enabled {
+                "I'm back to checking images for reposts here."
+            } else {
+                "I'll stop checking images for reposts here, until a moderator mentions me with `enable`."
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::AllowedChannels(channels) => {
+            let reply = if channels.is_empty() {
+                "I'll check images in every channel that isn't denied.".to_string()
+            } else {
+                format!("I'll only check images in {}.", channel_list(&channels))
+            };
+
+            context.data.update_guild_settings(guild_id.0, |settings| {
+                settings.allowed_channels = channels
+            })?;
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::DeniedChannels(channels) => {
+            let reply = if channels.is_empty() {
+                "I won't skip any channels anymore.".to_string()
+            } else {
+                format!("I'll skip images in {}.", channel_list(&channels))
+            };
+
+            context.data.update_guild_settings(guild_id.0, |settings| {
+                settings.denied_channels = channels
+            })?;
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::SkipNsfw(skip) => {
+            let settings = context
+                .data
+                .update_guild_settings(guild_id.0, |settings| settings.skip_nsfw = skip)?;
+
+            let skip = settings
+                .skip_nsfw
+                .unwrap_or(context.config.detection.skip_nsfw_channels);
+
+            let reply = if skip {
+                "Images in NSFW channels won't be checked or called out."
+            } else {
+                "Images in NSFW channels will be checked like any other."
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::IgnoreOwnReposts(ignore) => {
+            let settings = context.data.update_guild_settings(guild_id.0, |settings| {
+                settings.ignore_own_reposts = ignore
+            })?;
+
+            let ignore = settings
+                .ignore_own_reposts
+                .unwrap_or(context.config.reply.ignore_own_reposts);
+
+            let reply = if ignore {
+                "People reposting their own images will be counted without a reply."
+            } else {
+                "People reposting their own images will be called out like anyone else."
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::CrossChannelReplies(enabled) => {
+            let settings = context.data.update_guild_settings(guild_id.0, |settings| {
+                settings.cross_channel_replies = enabled
+            })?;
+
+            let enabled = settings
+                .cross_channel_replies
+                .unwrap_or(context.config.reply.cross_channel_replies);
+
+            let reply = if enabled {
+                "Reposts from other channels will be called out."
+            } else {
+                "Reposts from other channels will be counted without a reply."
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::ModRole(role) => {
+            context
+                .data
+                .update_guild_settings(guild_id.0, |settings| settings.mod_role = role)?;
+
+            let reply = match role {
+                Some(role) => format!(
+                    "Members with <@&{}> can now moderate me, along with anyone who can manage messages.",
+                    role
+                ),
+                None => "Only members who can manage messages can moderate me now.".to_string(),
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::Verify(link) => {
+            if link.guild_id != guild_id.0 {
+                context
+                    .send_message(
+                        "That message is from another server.",
+                        message.channel_id,
+                        None,
+                    )
+                    .await?;
+                return Ok(());
+            }
+
+            let referenced = match &message.referenced_message {
+                Some(referenced) => referenced,
+                None => {
+                    context
+                        .send_message(
+                            "Reply to the image you want to check.",
+                            message.channel_id,
+                            None,
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let data = context
+                .data
+                .with_similarity_threshold(settings.similarity_threshold)
+                .for_guild(guild_id.0);
+            let record = match data.lookup(link.message_id)? {
+                Some(record) => record,
+                None => {
+                    context
+                        .send_message(
+                            "There isn't a stored image from that message.",
+                            message.channel_id,
+                            None,
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let url = match image_from_message(referenced, &context.config) {
+                Some(url) => url,
+                None => return Ok(()),
+            };
+            let image = context
+                .download_image(&url, settings.max_image_size)
+                .await?;
+            let hash = match context
+                .process_image(image, &context.config.detection)
+                .await?
+                .hash()
+            {
+                Some(hash) => hash.clone(),
+                None => {
+                    context
+                        .send_message(
+                            "That image is skipped, so it can't be compared.",
+                            message.channel_id,
+                            None,
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let reply = match record.distance_to(&hash) {
+                Some(distance) => {
+                    let verdict = match (
+                        data.matches_record(&record, &hash),
+                        data.config().min_confidence,
+                    ) {
+                        (true, Some(min_confidence)) => format!(
+                            "meeting the minimum confidence of {:.0}%, so it matches",
+                            min_confidence * 100.0
+                        ),
+                        (false, Some(min_confidence)) => format!(
+                            "short of the minimum confidence of {:.0}%, so it doesn't match",
+                            min_confidence * 100.0
+                        ),
+                        (true, None) => format!(
+                            "within the threshold of {}, so it matches",
+                            record.threshold
+                        ),
+                        (false, None) => format!(
+                            "past the threshold of {}, so it doesn't match",
+                            record.threshold
+                        ),
+                    };
+
+                    format!(
+                        "That image is {} away from the one {} posted, {}.",
+                        distance,
+                        record.image.poster(),
+                        verdict
+                    )
+                }
+                None => "The stored image doesn't have any hashes.".to_string(),
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::Resend(link) => {
+            if link.guild_id != guild_id.0 {
+                context
+                    .send_message(
+                        "That message is from another server.",
+                        message.channel_id,
+                        None,
+                    )
+                    .await?;
+                return Ok(());
+            }
+
+            let reply = match context.data.posted_in(link.message_id)? {
+                Some((image, times_seen)) if times_seen > 1 => {
+                    dispatch_repost_reply(
+                        &context,
+                        &image,
+                        times_seen,
+                        ChannelId(link.channel_id),
+                        guild_id,
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                Some(_) => "That image hasn't been reposted.",
+                None => "There isn't a stored image from that message.",
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::History(link) => {
+            let data = context
+                .data
+                .with_similarity_threshold(settings.similarity_threshold)
+                .for_guild(guild_id.0);
+
+            let history = match link {
+                Some(link) if link.guild_id != guild_id.0 => {
+                    context
+                        .send_message(
+                            "That message is from another server.",
+                            message.channel_id,
+                            None,
+                        )
+                        .await?;
+                    return Ok(());
+                }
+                Some(link) => data.history(link.message_id)?,
+                None => match history_image(&context, &message, &settings).await? {
+                    Some(image) => match context
+                        .process_image(image, &context.config.detection)
+                        .await?
+                        .hashes()
+                    {
+                        Some(hashes) => data.history_matching(hashes)?,
+                        None => {
+                            context
+                                .send_message(
+                                    "That image is skipped, so it isn't tracked.",
+                                    message.channel_id,
+                                    None,
+                                )
+                                .await?;
+                            return Ok(());
+                        }
+                    },
+                    None => {
+                        context
+                            .send_message(
+                                "Send an image, reply to one, or link to a message with one.",
+                                message.channel_id,
+                                None,
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+                },
+            };
+
+            let (image, occurrences) = match history {
+                Some(history) => history,
+                None => {
+                    context
+                        .send_message(
+                            "There isn't a stored image like that.",
+                            message.channel_id,
+                            None,
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let fields = history_fields(&occurrences, guild_id, seconds_since_epoch());
+            let title = match occurrences.len() {
+                1 => "Posted once".to_string(),
+                times => format!("Posted {} times", times),
+            };
+            let title = if image.ignored {
+                format!("{} (ignored)", title)
+            } else {
+                title
+            };
+            for page in embeds::paginate(&title, fields, context.config.reply.max_embed_fields) {
+                context.send_embeds(vec![page], message.channel_id).await?;
+            }
+
+            Ok(())
+        }
+        Command::Purge {
+            older_than,
+            channel_id,
+            author_id,
+        } => {
+            let confirmed = context
+                .confirm_action(
+                    bot::ConfirmationAction::PurgeImages,
+                    message.channel_id,
+                    settings.mod_role.map(RoleId),
+                )
+                .await?;
+            if !confirmed {
+                return Ok(());
+            }
+
+            let criteria = PurgeCriteria {
+                older_than: older_than.as_secs(),
+                channel_id,
+                author_id,
+            };
+            let purged = context
+                .data
+                .for_guild(guild_id.0)
+                .purge_images(seconds_since_epoch(), &criteria)?;
+
+            let reply = match purged {
+                0 => "There weren't any stored images like that.".to_string(),
+                1 => "Deleted 1 stored image.".to_string(),
+                purged => format!("Deleted {} stored images.", purged),
+            };
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::RemapChannel { old, new } => {
+            let remapped = context.data.remap_channel(guild_id.0, old, new)?;
+
+            context
+                .send_message(
+                    format!("Moved {} images from <#{}> to <#{}>.", remapped, old, new),
+                    message.channel_id,
+                    None,
+                )
+                .await?;
+
+            Ok(())
+        }
+        Command::DatabaseVersion => {
+            let info = context.data.version_info()?;
+            context
+                .send_message(info.render(), message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::Guilds => {
+            let counts = context.data.per_guild_counts()?;
+            if counts.is_empty() {
+                context
+                    .send_message(
+                        "No images are tracked in any guild yet.",
+                        message.channel_id,
+                        None,
+                    )
+                    .await?;
+
+                return Ok(());
+            }
+
+            let fields = diagnostics::guild_count_fields(&counts, |id| {
+                context
+                    .cache
+                    .guild(GuildId(id))
+                    .map(|guild| guild.name.clone())
+            });
+
+            for page in embeds::paginate(
+                "Tracked guilds",
+                fields,
+                context.config.reply.max_embed_fields,
+            ) {
+                context.send_embeds(vec![page], message.channel_id).await?;
+            }
+
+            Ok(())
+        }
+        Command::TestUrl(url) => {
+            let data = context.data.for_guild(guild_id.0);
+            let trace = trace_pipeline(&context, &data, &url, &settings).await;
+
+            context
+                .send_message(trace.render(), message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::Histogram(buckets) => {
+            let histogram = context
+                .data
+                .distance_histogram(Data::MAX_HISTOGRAM_SAMPLE, buckets)?;
+
+            let reply =
+                diagnostics::render_histogram(&histogram, Data::histogram_bucket_width(buckets));
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::MaxImageSize(size) => {
+            let settings = context
+                .data
+                .update_guild_settings(guild_id.0, |settings| settings.max_image_size = size)?;
+
+            let reply = format!(
+                "Images up to {} will be checked.",
+                diagnostics::format_bytes(settings.max_image_size(&context.config))
+            );
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::RecordQuota(quota) => {
+            let settings = context
+                .data
+                .update_guild_settings(guild_id.0, |settings| settings.record_quota = quota)?;
+
+            let reply = match settings.record_quota(&context.config) {
+                Some(quota) => format!("Up to {} images will be stored for this server.", quota),
+                None => {
+                    "There's no limit on how many images are stored for this server.".to_string()
+                }
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+        Command::RaidMode(duration) => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("clocks are wobbly");
+
+            let until = duration.map(|d| (now + d).as_secs());
+            context
+                .data
+                .update_guild_settings(guild_id.0, |settings| settings.raid_mode_until = until)?;
+
+            let reply = match until {
+                Some(until) => format!(
+                    "Raid mode is on until <t:{}:t>. Matching is more aggressive and reposts will be deleted.",
+                    until
+                ),
+                None => "Raid mode is off.".to_string(),
+            };
+
+            context
+                .send_message(reply, message.channel_id, None)
+                .await?;
+
+            Ok(())
+        }
+    }
+}
+
+async fn ignore_image(
+    context: &bot::Context,
+    data: &impl Storage,
+    message: &MessageCreate,
+    settings: &EffectiveSettings,
+) -> Result<(), Error> {
+    if let Some(image_to_ignore) = referenced_image(context, message, settings).await? {
+        match context
+            .confirm_action(
+                bot::ConfirmationAction::IgnoreImage,
+                message.channel_id,
+                settings.mod_role.map(RoleId),
+            )
+            .await
+        {
+            Ok(confirmed) => {
+                tracing::debug!("User confirmed: {}", confirmed);
+
+                if confirmed {
+                    let image_hash = match context
+                        .process_image(image_to_ignore, &context.config.detection)
+                        .await?
+                    {
+                        ProcessedImage::Hashed(hashes) | ProcessedImage::MatchOnly(hashes) => {
+                            hashes.hash
+                        }
+                        // There's nothing stored to ignore.
+                        ProcessedImage::Skipped(_) => return Ok(()),
+                    };
+                    data.access_image(image_hash.as_bytes(), &mut |seen| {
+                        seen.ignored = true;
+                        true
+                    })?;
+                }
+
+                context
+                    .send_message(
+                        format!("User responded with {}", confirmed),
+                        message.channel_id,
+                        None,
+                    )
+                    .await
+                    .unwrap();
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the image a moderation command was replied to.
+async fn referenced_image(
+    context: &bot::Context,
+    message: &MessageCreate,
+    settings: &EffectiveSettings,
+) -> Result<Option<Vec<u8>>, Error> {
+    let msg = match &message.referenced_message {
+        Some(msg) => msg,
+        None => return Ok(None),
+    };
+
+    // Support two behaviors for picking the image:
+    // 1. Reply on the message containing the image itself
+    // 2. Reply to our reply notifying users of a repost.
+    let msg_with_img = if let Some(parent) = &msg.reference {
+        Cow::Owned(
+            context
+                .get_message(
+                    parent.channel_id.ok_or(Error::UnsupportedChannelConfig)?,
+                    parent.message_id.ok_or(Error::UnsupportedChannelConfig)?,
+                )
+                .await?,
+        )
+    } else {
+        Cow::Borrowed(&message.0)
+    };
+
+    match image_from_message(&msg_with_img, &context.config) {
+        Some(url) => Ok(Some(
+            context
+                .download_image(&url, settings.max_image_size)
+                .await?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The image a history lookup is for: one sent with the command, or the one it replies to.
+async fn history_image(
+    context: &bot::Context,
+    message: &MessageCreate,
+    settings: &EffectiveSettings,
+) -> Result<Option<Vec<u8>>, Error> {
+    if message.referenced_message.is_some() {
+        return referenced_image(context, message, settings).await;
+    }
+
+    match image_from_message(message, &context.config) {
+        Some(url) => Ok(Some(
+            context
+                .download_image(&url, settings.max_image_size)
+                .await?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Lists every time an image was posted as embed fields, oldest first, with links back to each one.
+fn history_fields(
+    occurrences: &[Occurrence],
+    guild_id: GuildId,
+    now: u64,
+) -> Vec<(String, String)> {
+    occurrences
+        .iter()
+        .enumerate()
+        .map(|(i, occurrence)| {
+            let name = match i {
+                0 => "Original".to_string(),
+                i => format!("Repost {}", i),
+            };
+            let value = format!(
+                "{} posted it {} in <#{}>. [Jump Link](https://discordapp.com/channels/{}/{}/{})",
+                occurrence.poster(),
+                time_since(now.saturating_sub(occurrence.sent)),
+                occurrence.channel_id,
+                guild_id.0,
+                occurrence.channel_id,
+                occurrence.message_id
+            );
+
+            (name, value)
+        })
+        .collect()
+}
+
+async fn forget_image(
+    context: &bot::Context,
+    data: &Data,
+    message: &MessageCreate,
+    settings: &EffectiveSettings,
+) -> Result<(), Error> {
+    let image = match referenced_image(context, message, settings).await? {
+        Some(image) => image,
+        None => return Ok(()),
+    };
+
+    let confirmed = context
+        .confirm_action(
+            bot::ConfirmationAction::ForgetImage,
+            message.channel_id,
+            settings.mod_role.map(RoleId),
+        )
+        .await?;
+    if !confirmed {
+        return Ok(());
+    }
+
+    let hash = match context
+        .process_image(image, &context.config.detection)
+        .await?
+        .hash()
+    {
+        Some(hash) => hash.clone(),
+        // There's nothing stored to forget.
+        None => return Ok(()),
+    };
+
+    let reply = match data.forget_image(hash.as_bytes())? {
+        Some(forgotten) => forgotten_message(&forgotten),
+        None => "I don't have that image stored.".to_string(),
+    };
+
+    context
+        .send_message(reply, message.channel_id, None)
+        .await?;
+
+    Ok(())
+}
+
+/// How many reposters the leaderboard lists.
+const LEADERBOARD_SIZE: usize = 10;
+
+/// Mentions of channels, like `<#1>, <#2>`.
+fn channel_list(channels: &[u64]) -> String {
+    channels
+        .iter()
+        .map(|channel| format!("<#{}>", channel))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Ranks who's been caught reposting the most. Mentions don't ping anyone, so they're safe to use here.
+fn leaderboard_message(offenders: &[(u64, u64)]) -> String {
+    if offenders.is_empty() {
+        return "Nobody's been caught reposting here yet.".to_string();
+    }
+
+    let mut message = "Most reposts caught:".to_string();
+    for (rank, (user_id, reposts)) in offenders.iter().enumerate() {
+        let reposts = match reposts {
+            1 => "1 repost".to_string(),
+            reposts => format!("{} reposts", reposts),
+        };
+        message.push_str(&format!("\n{}. <@{}>: {}", rank + 1, user_id, reposts));
+    }
+
+    message
+}
+
+/// Sums up what's tracked in a guild for anyone who asks.
+fn stats_message(stats: &GuildStats, guild_id: GuildId) -> String {
+    if stats.images == 0 {
+        return "No images are tracked here yet.".to_string();
+    }
+
+    let mut message = format!(
+        "Tracking {} images here, with {} reposts caught.",
+        stats.images, stats.reposts
+    );
+
+    if let Some((channel_id, images)) = stats.busiest_channel {
+        message.push_str(&format!(
+            "\nBusiest channel: <#{}>, with {} images.",
+            channel_id, images
+        ));
+    }
+
+    if let Some((image, times_seen)) = &stats.most_reposted {
+        message.push_str(&format!(
+            "\nMost reposted: {}'s image, seen {} times: {}",
+            image.poster(),
+            times_seen,
+            jump_url(image, guild_id)
+        ));
+    }
+
+    message
+}
+
+/// Tells moderators how much went away with a forgotten image.
+fn forgotten_message(forgotten: &ForgottenImage) -> String {
+    let hashes = match forgotten.aliases {
+        1 => "its hash".to_string(),
+        aliases => format!("the {} hashes that matched it", aliases),
+    };
+    let seen = match forgotten.times_seen {
+        1 => "once".to_string(),
+        times_seen => format!("{} times", times_seen),
+    };
+
+    format!(
+        "Forgot that image and {}. It had been seen {}.",
+        hashes, seen
+    )
+}
+
+/// Runs an image through the same stages a posted one goes through, stopping at the first that fails.
+async fn trace_pipeline(
+    context: &bot::Context,
+    data: &Data,
+    url: &str,
+    settings: &EffectiveSettings,
+) -> diagnostics::PipelineTrace {
+    let mut trace = diagnostics::PipelineTrace::new(settings.similarity_threshold);
+
+    if url.parse::<hyper::Uri>().is_err() {
+        return trace.failed("invalid URL");
+    }
+
+    let image = match context.download_image(url, settings.max_image_size).await {
+        Ok(image) => image,
+        Err(e) => return trace.failed(e.kind()),
+    };
+    trace.fetched = Some(image.len() as u64);
+    trace.info = image_processing::inspect(&image);
+
+    let processed = match context
+        .process_image(image, &context.config.detection)
+        .await
+    {
+        Ok(processed) => processed,
+        Err(e) => return trace.failed(e.kind()),
+    };
+
+    if let Some(hashes) = processed.hashes() {
+        match data.nearest(hashes) {
+            Ok(nearest) => trace.nearest = Some(nearest),
+            Err(e) => trace.failure = Some(Error::from(e).kind()),
+        }
+    }
+    trace.processed = Some(processed);
+
+    trace
+}
+
+/// How to respond to someone's `offense`th repost in a guild.
+fn offense_reply(offense: u64, first_offense: FirstOffense) -> FirstOffense {
+    if offense <= 1 {
+        first_offense
+    } else {
+        FirstOffense::Callout
+    }
+}
+
+/// Lets a first time reposter know privately, instead of calling them out in front of everyone.
+async fn send_heads_up(
+    context: &bot::Context,
+    previous: &SeenImage,
+    user: UserId,
+    guild_id: GuildId,
+) -> Result<(), Error> {
+    let since = time_since(seconds_since_epoch().saturating_sub(previous.sent));
+    let message = format!(
+        "Heads up, the image you just posted was already posted by {} {}: {}\nNo worries this time, but keep an eye out for reposts!",
+        previous.poster(),
+        since,
+        jump_url(previous, guild_id)
+    );
+
+    context.send_direct_message(&message, user).await?;
+    Ok(())
+}
+
+async fn dispatch_repost_reply(
+    context: &bot::Context,
+    previous: &SeenImage,
+    times_seen: u64,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+) -> Result<MessageId, Error> {
+    let reply = repost_reply(
+        previous,
+        times_seen,
+        channel_id,
+        guild_id,
+        seconds_since_epoch(),
+        &context.config.reply,
+    );
+
+    send_repost_reply(context, reply, channel_id).await
+}
+
+/// A repost callout, before it's sent.
+#[derive(Debug, PartialEq)]
+struct RepostReply {
+    content: String,
+    /// How the callout points back at the original, or `None` if it doesn't.
+    link: Option<OriginalLink>,
+    original_message_id: MessageId,
+    jump_url: String,
+    /// Where the original image can be shown from, if it's known.
+    thumbnail: Option<String>,
+}
+
+/// Link to the message an image was first posted in.
+///
+/// Images stored before their guild was known are taken to be from `current_guild`, where they're being reposted.
+fn jump_url(original: &SeenImage, current_guild: GuildId) -> String {
+    let guild_id = match original.guild_id {
+        0 => current_guild.0,
+        guild_id => guild_id,
+    };
+
+    format!(
+        "https://discordapp.com/channels/{}/{}/{}",
+        guild_id, original.channel_id, original.original_message_id
+    )
+}
+
+/// Builds the callout for a repost in `channel_id` of `previous`, from what's stored about it.
+fn repost_reply(
+    previous: &SeenImage,
+    times_seen: u64,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    now: u64,
+    config: &ReplyConfig,
+) -> RepostReply {
+    let since = time_since(now.saturating_sub(previous.sent));
+    let original_message_id = MessageId(previous.original_message_id);
+    let jump_url = jump_url(previous, guild_id);
+
+    if is_thread_of(channel_id, previous.original_message_id)
+        && config.thread_reposts == ThreadReposts::Soft
+    {
+        return RepostReply {
+            content: format!(
+                "Just so you know, that's the same image this thread started from ({} posted it {}).",
+                previous.poster(), since
+            ),
+            link: None,
+            original_message_id,
+            jump_url,
+            thumbnail: previous.source_url.clone(),
+        };
+    }
+
+    RepostReply {
+        content: format!(
+            "Hey, {} already posted that here {}. {} Try harder next time <:niko:765033287357431829>",
+            previous.poster(),
+            since,
+            times_seen_phrase(times_seen, config.count_style)
+        ),
+        link: Some(original_link(
+            channel_id,
+            previous.channel_id,
+            config.link_buttons,
+        )),
+        original_message_id,
+        jump_url,
+        thumbnail: previous.source_url.clone(),
+    }
+}
+
+/// Counts the links shared in a message, and calls out the first one that was shared in its guild before.
+///
+/// Links to images and GIFs are checked as images instead, so they aren't counted here.
+async fn handle_link_reposts(
+    context: &bot::Context,
+    message: &Message,
+    guild_id: GuildId,
+    settings: &EffectiveSettings,
+) -> Result<(), Error> {
+    let data = context.data.for_guild(guild_id.0);
+    let properties = seen_image(message, seconds_since_epoch());
+
+    let mut first_repost = None;
+    for link in links::links_in(&message.content) {
+        if is_image_link(&link, &context.config) {
+            continue;
+        }
+
+        if let Some(seen) = data.record_link(&link, &properties)? {
+            tracing::debug!("Link shared again: {}", link);
+            first_repost.get_or_insert(seen);
+        }
+    }
+
+    let (original, times_seen) = match first_repost {
+        Some(repost) => repost,
+        None => return Ok(()),
+    };
+
+    if !should_reply(
+        message.channel_id,
+        original.channel_id,
+        settings.cross_channel_replies,
+    ) || (settings.ignore_own_reposts && is_own_repost(&original, message.author.id))
+        || context.replies_muted(message.channel_id)
+        || !context.startup_settled()
+    {
+        tracing::debug!("Counted a link repost without replying");
+        return Ok(());
+    }
+
+    let reply = link_repost_reply(
+        &original,
+        times_seen,
+        message.channel_id,
+        guild_id,
+        seconds_since_epoch(),
+        &context.config.reply,
+    );
+    if context.config.reply.dry_run {
+        let action = format!("replied \"{}\"", reply.content);
+        report_dry_run(context, message, &action).await;
+        return Ok(());
+    }
+
+    match send_repost_reply(context, reply, message.channel_id).await {
+        Err(e) if bot::is_missing_permissions(&e) => context.reply_forbidden(message.channel_id),
+        reply => {
+            reply?;
+        }
+    }
+
+    Ok(())
+}
+
+/// If a canonical link points at something that's checked as an image.
+fn is_image_link(link: &str, config: &Config) -> bool {
+    let url = format!("https://{}", link);
+    filter_image(&url, &config.download).is_some() || GifLink::parse(&url).is_some()
+}
+
+/// Builds the callout for a link shared again in `channel_id`, from who shared it first.
+fn link_repost_reply(
+    original: &SeenImage,
+    times_seen: u64,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    now: u64,
+    config: &ReplyConfig,
+) -> RepostReply {
+    RepostReply {
+        content: format!(
+            "Hey, {} already shared that link here {}. {}",
+            original.poster(),
+            time_since(now.saturating_sub(original.sent)),
+            times_seen_phrase(times_seen, config.count_style)
+        ),
+        link: Some(original_link(
+            channel_id,
+            original.channel_id,
+            config.link_buttons,
+        )),
+        original_message_id: MessageId(original.original_message_id),
+        jump_url: jump_url(original, guild_id),
+        thumbnail: None,
+    }
+}
+
+/// Says what the bot would have done about a message if it weren't a dry run, instead of doing it.
+///
+/// It's logged, and posted in the audit channel if there is one.
+async fn report_dry_run(context: &bot::Context, message: &Message, action: &str) {
+    let link = format!(
+        "https://discordapp.com/channels/{}/{}/{}",
+        message.guild_id.map_or(0, |g| g.0),
+        message.channel_id,
+        message.id
+    );
+    tracing::info!("Dry run, would have {} for {}", action, link);
+
+    if let Some(channel) = context.config.reply.audit_channel {
+        let report = format!("Would have {} for {}", action, link);
+        if let Err(e) = context.send_message(report, ChannelId(channel), None).await {
+            tracing::warn!("Failed to post a dry run report: {:?}", e);
+        }
+    }
+}
+
+async fn send_repost_reply(
+    context: &bot::Context,
+    reply: RepostReply,
+    channel_id: ChannelId,
+) -> Result<MessageId, Error> {
+    let message = reply.content;
+
+    let reply = match reply.link {
+        None => context.send_message(message, channel_id, None).await?,
+        Some(OriginalLink::Reply) => {
+            context
+                .send_message(message, channel_id, Some(reply.original_message_id))
+                .await?
+        }
+        Some(OriginalLink::Button) => {
+            context
+                .send_link_button(message, "Previous Image", reply.jump_url, channel_id)
+                .await?
+        }
+        Some(OriginalLink::EmbedField) => {
+            let jump_link = format!("[Jump Link]({})", reply.jump_url);
+            context
+                .send_embed(message, jump_link, reply.thumbnail.as_deref(), channel_id)
+                .await?
+        }
+    };
+
+    Ok(reply.id)
+}
+
+/// How often guilds the bot was removed from are checked for data to purge.
+const PURGE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// How often images are checked for having gone unseen past the retention window.
+const EXPIRY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Longest shutdown waits for messages that are still being handled before writing the database.
+const SHUTDOWN_DEADLINE: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// If the bot leaving a guild should schedule its data to be purged.
+///
+/// Guilds become unavailable during outages and come back on their own, so only real removals count.
+fn schedules_purge(event: &GuildDelete, purge_after: Option<u64>) -> bool {
+    purge_after.is_some() && !event.unavailable
+}
+
+/// Posts the welcome message in a guild the bot just joined, if there's somewhere to post it.
+async fn welcome_guild(
+    guild_id: GuildId,
+    system_channel: Option<ChannelId>,
+    context: bot::Context,
+) -> Result<(), Error> {
+    // Guilds are remembered even without a welcome, so turning it on later doesn't greet old ones.
+    if !context.data.guild_joined(guild_id.0)? {
+        return Ok(());
+    }
+
+    tracing::info!("Joined guild {}", guild_id);
+
+    if context.config.reply.dry_run {
+        return Ok(());
+    }
+
+    if let (Some(welcome), Some(channel)) = (&context.config.reply.welcome_message, system_channel)
+    {
+        context.send_message(welcome, channel, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Takes back the count of a repost deleted soon after it was posted, along with the reply to it.
+async fn take_back_repost(
+    channel_id: ChannelId,
+    message_id: MessageId,
+    context: bot::Context,
+) -> Result<(), Error> {
+    let repost = match context
+        .recent_reposts()
+        .deleted(message_id, seconds_since_epoch())
+    {
+        Some(repost) => repost,
+        None => return Ok(()),
+    };
+
+    let uncounted = context.data.uncount_message(message_id.0)?;
+    tracing::debug!(
+        "Took back {} sightings from a repost deleted in {}",
+        uncounted,
+        channel_id
+    );
+
+    if let Some((reply_channel, reply_id)) = repost.reply {
+        context.delete_message(reply_channel, reply_id).await?;
+    }
+
+    Ok(())
+}
+
+fn seconds_since_epoch() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("clocks are wobbly")
+        .as_secs()
+}
+
+/// How a callout points back at the original image.
+#[derive(Debug, PartialEq)]
+enum OriginalLink {
+    /// Reply directly to the original message.
+    Reply,
+    /// Attach a button that jumps to the original message.
+    Button,
+    /// Put a jump link to the original message in an embed.
+    EmbedField,
+}
+
+/// The message an image is checked as, or `None` if it shouldn't be checked.
+///
+/// Crossposts are checked as the announcement they came from, so the announcement and every copy
+/// of it only count once.
+fn checked_message(message: &Message, crossposts: Crossposts) -> Option<MessageId> {
+    let is_crosspost = message
+        .flags
+        .is_some_and(|flags| flags.contains(MessageFlags::IS_CROSSPOST));
+
+    if !is_crosspost {
+        return Some(message.id);
+    }
+
+    match crossposts {
+        Crossposts::Ignore => None,
+        Crossposts::Track => Some(
+            message
+                .reference
+                .as_ref()
+                .and_then(|reference| reference.message_id)
+                .unwrap_or(message.id),
+        ),
+    }
+}
+
+/// Picks how a callout links to the original image.
+///
+/// Discord only lets a reply reference a message in the same channel, so reposts from
+/// other channels have to link to the original instead.
+fn original_link(
+    channel_id: ChannelId,
+    original_channel_id: u64,
+    link_buttons: bool,
+) -> OriginalLink {
+    if channel_id.0 == original_channel_id {
+        OriginalLink::Reply
+    } else if link_buttons {
+        OriginalLink::Button
+    } else {
+        OriginalLink::EmbedField
+    }
+}
+
+/// Describes how many times an image has been posted, including the repost being called out.
+fn times_seen_phrase(times_seen: u64, style: CountStyle) -> String {
+    match style {
+        CountStyle::Total => format!("I've seen it {} times now.", times_seen),
+        CountStyle::Previous => match times_seen.saturating_sub(1) {
+            1 => "I've seen it once before.".to_string(),
+            previous => format!("I've seen it {} times before.", previous),
+        },
+    }
+}
+
+/// If a repost gets called out, based on where the original was posted.
+fn should_reply(
+    channel_id: ChannelId,
+    original_channel_id: u64,
+    cross_channel_replies: bool,
+) -> bool {
+    cross_channel_replies || channel_id.0 == original_channel_id
+}
+
+/// If someone is posting an image they were the first to post.
+///
+/// Images stored before posters' IDs were never match anyone.
+fn is_own_repost(original: &SeenImage, author: UserId) -> bool {
+    original.author_id != 0 && original.author_id == author.0
+}
+
+/// If a channel is the thread started from a message.
+///
+/// Discord gives threads created from a message the same ID as that message.
+fn is_thread_of(channel_id: ChannelId, message_id: u64) -> bool {
+    channel_id.0 == message_id
+}
+
+fn status_message(reposts_seen: usize) -> String {
+    match reposts_seen {
+        0 => "for a repost to appear".to_string(),
+        1 => "out for 1 repost".to_string(),
+        _ => format!("out for {} reposts", reposts_seen),
+    }
+}
+
+fn time_since(seconds: u64) -> String {
+    let days = seconds / 86400;
+
+    if days > 0 {
+        let unit = if days > 1 { "days" } else { "day" };
+
+        return format!("{} {} ago", days, unit);
+    }
+
+    let hours = seconds / 3600;
+
+    if hours > 0 {
+        let unit = if hours > 1 { "hours" } else { "hour" };
+
+        return format!("{} {} ago", hours, unit);
+    }
+
+    let minutes = seconds / 60;
+
+    if minutes > 0 {
+        let unit = if minutes > 1 { "minutes" } else { "minute" };
+
+        return format!("{} {} ago", minutes, unit);
+    }
+
+    let unit = if seconds > 1 { "seconds" } else { "second" };
 
     format!("{} {} ago", seconds, unit)
 }
 
-fn image_from_message(msg: &Message) -> Option<&str> {
+/// Every image in a message that gets checked, in the order they're found.
+///
+/// That's only the first one found, unless all images are checked.
+fn images_from_message<'a>(msg: &'a Message, config: &Config) -> Vec<Cow<'a, str>> {
+    if !config.detection.all_images {
+        return image_from_message(msg, config).into_iter().collect();
+    }
+
+    let mut urls: Vec<Cow<str>> = Vec::new();
+
+    let embeds = msg.embeds.iter().flat_map(|e| embed_images(e, config));
+    let attachments = msg
+        .attachments
+        .iter()
+        .filter_map(|a| filter_image(&a.url, &config.download))
+        .map(Cow::Borrowed);
+    let stickers = msg
+        .sticker_items
+        .iter()
+        .filter_map(sticker_url)
+        .map(Cow::Owned);
+
+    for url in embeds.chain(attachments).chain(stickers) {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+
+    if config.detection.video_thumbnails {
+        let embed_thumbnails = msg
+            .embeds
+            .iter()
+            .filter_map(video_embed_thumbnail)
+            .map(Cow::Borrowed);
+        // Videos that are downloaded themselves are checked by their keyframes.
+        let attachment_thumbnails = msg
+            .attachments
+            .iter()
+            .filter(|a| filter_image(&a.url, &config.download).is_none())
+            .filter_map(video_attachment_thumbnail)
+            .map(Cow::Owned);
+
+        for url in embed_thumbnails.chain(attachment_thumbnails) {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+
+    tracing::debug!("Found {} images", urls.len());
+    urls
+}
+
+fn image_from_message<'a>(msg: &'a Message, config: &Config) -> Option<Cow<'a, str>> {
     for embed in &msg.embeds {
-        if let Some(img_url) = filter_embed(embed) {
+        if let Some(img_url) = embed_images(embed, config).into_iter().next() {
             tracing::debug!("Embed image found: {:?}", img_url);
             return Some(img_url);
         }
     }
 
-    if let Some(url) = msg.attachments.iter().find_map(|a| filter_image(&a.url)) {
-        tracing::debug!("Image attachment found: {}", url);
-        return Some(url);
+    if let Some(url) = msg
+        .attachments
+        .iter()
+        .find_map(|a| filter_image(&a.url, &config.download))
+    {
+        tracing::debug!("Image attachment found: {}", url);
+        return Some(Cow::Borrowed(url));
+    }
+
+    if let Some(url) = msg.sticker_items.iter().find_map(sticker_url) {
+        tracing::debug!("Sticker found: {}", url);
+        return Some(Cow::Owned(url));
+    }
+
+    if config.detection.video_thumbnails {
+        if let Some(url) = msg.embeds.iter().find_map(video_embed_thumbnail) {
+            tracing::debug!("Video embed thumbnail found: {}", url);
+            return Some(Cow::Borrowed(url));
+        }
+
+        if let Some(url) = msg.attachments.iter().find_map(video_attachment_thumbnail) {
+            tracing::debug!("Video attachment found: {}", url);
+            return Some(Cow::Owned(url));
+        }
+    }
+
+    None
+}
+
+/// Tenor and Giphy links in a message's text, other than those Discord already shows a checked image for.
+fn gif_links<'a>(msg: &'a Message, config: &Config) -> Vec<GifLink<'a>> {
+    if !config.detection.gif_links {
+        return Vec::new();
+    }
+
+    let embedded = |url: &str| {
+        msg.embeds.iter().any(|e| {
+            e.url.as_deref() == Some(url)
+                && (filter_embed(e, &config.download).is_some()
+                    || (config.detection.video_thumbnails && video_embed_thumbnail(e).is_some()))
+        })
+    };
+
+    let mut links = Vec::new();
+    for url in msg
+        .content
+        .split_whitespace()
+        .filter_map(commands::parse_url)
+    {
+        if embedded(url) {
+            continue;
+        }
+
+        if let Some(link) = GifLink::parse(url) {
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+    }
+
+    links
+}
+
+/// Links to Reddit posts in a message's text.
+fn reddit_links<'a>(msg: &'a Message, config: &Config) -> Vec<RedditLink<'a>> {
+    if !config.detection.reddit_links {
+        return Vec::new();
+    }
+
+    let mut links: Vec<RedditLink> = Vec::new();
+    for link in msg
+        .content
+        .split_whitespace()
+        .filter_map(commands::parse_url)
+        .filter_map(RedditLink::parse)
+    {
+        if !links.iter().any(|l| l.id == link.id) {
+            links.push(link);
+        }
+    }
+
+    links
+}
+
+/// The thumbnail Discord shows for a video embed, which is usually its first frame.
+fn video_embed_thumbnail(embed: &Embed) -> Option<&str> {
+    if embed.kind != "video" && embed.video.is_none() {
+        return None;
+    }
+
+    embed.thumbnail.as_ref()?.url.as_deref()
+}
+
+/// Builds the URL of an uploaded video's first frame.
+///
+/// Discord's media proxy renders a still of a video when asked for it in an image format,
+/// so nothing needs to be decoded here.
+fn video_attachment_thumbnail(attachment: &Attachment) -> Option<String> {
+    let is_video = match &attachment.content_type {
+        Some(content_type) => content_type.starts_with("video/"),
+        None => {
+            let extension = attachment.filename.rsplit('.').next()?;
+            image_processing::VIDEO_EXTENSIONS
+                .iter()
+                .any(|ext| ext.eq_ignore_ascii_case(extension))
+        }
+    };
+
+    if is_video {
+        // Signed proxy URLs already have a query string to add to.
+        let separator = if attachment.proxy_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        Some(format!("{}{}format=jpeg", attachment.proxy_url, separator))
+    } else {
+        None
+    }
+}
+
+/// Builds the CDN URL of a sticker's image, if its format is one that can be hashed.
+///
+/// APNG stickers are served as `.png`s and get decoded as animations by `process_image`.
+fn sticker_url(sticker: &MessageSticker) -> Option<String> {
+    match sticker.format_type {
+        StickerFormatType::Png | StickerFormatType::Apng => Some(format!(
+            "https://media.discordapp.net/stickers/{}.png",
+            sticker.id.0
+        )),
+        // Lottie stickers are vector animations described in JSON, not images.
+        StickerFormatType::Lottie => None,
+    }
+}
+
+fn save_image(
+    data: &impl Storage,
+    images: Vec<ProcessedImage>,
+    msg: &Message,
+) -> Result<Vec<PreviouslySeen>, (Option<String>, Error)> {
+    for image in &images {
+        match image {
+            ProcessedImage::Hashed(hashes) | ProcessedImage::MatchOnly(hashes) => {
+                tracing::debug!(image_hash = %hashes.log_prefix(), "Hashed an image")
+            }
+            ProcessedImage::Skipped(reason) => tracing::debug!("Skipped an image: {:?}", reason),
+        }
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("clocks are wobbly");
+
+    record_message_images(data, images, msg, now.as_secs())
+}
+
+/// Waits for every image of a message being hashed, in order.
+///
+/// Each one's error is kept apart with where it came from, so one bad image doesn't stop the others.
+async fn wait_for_hashes<K, F>(hashing: Vec<(K, F)>) -> (Vec<ProcessedImage>, Vec<(K, Error)>)
+where
+    F: std::future::Future<Output = Result<ProcessedImage, Error>>,
+{
+    let mut images = Vec::with_capacity(hashing.len());
+    let mut failures = Vec::new();
+
+    for (key, job) in hashing {
+        match job.await {
+            Ok(image) => images.push(image),
+            Err(e) => failures.push((key, e)),
+        }
+    }
+
+    (images, failures)
+}
+
+/// Records every image processed from a single message, in order.
+///
+/// Unless disabled, identical images are only recorded once so a message can't repost itself.
+/// Images that were only hashed for matching are checked, but not stored if they're new.
+///
+/// Errors come with the URL of the image that failed, if it's known.
+fn record_message_images(
+    data: &impl Storage,
+    images: Vec<ProcessedImage>,
+    msg: &Message,
+    sent: u64,
+) -> Result<Vec<PreviouslySeen>, (Option<String>, Error)> {
+    let images = if data.config().dedupe_within_message {
+        image_processing::dedupe_images(images)
+    } else {
+        images
+    };
+
+    images
+        .into_iter()
+        .filter_map(|image| match image {
+            ProcessedImage::Hashed(hashes) => {
+                let source = hashes.source.clone();
+                let properties = seen_image_from(msg, sent, &hashes);
+                Some(
+                    data.record_image(hashes, properties)
+                        .map_err(|e| (source, e)),
+                )
+            }
+            ProcessedImage::MatchOnly(hashes) => {
+                let source = hashes.source.clone();
+                let properties = seen_image_from(msg, sent, &hashes);
+                Some(
+                    data.match_image(hashes, properties)
+                        .map_err(|e| (source, e)),
+                )
+            }
+            ProcessedImage::Skipped(_) => None,
+        })
+        .collect()
+}
+
+/// The reposts called out in a message, for what's only done once per message.
+#[derive(Debug, Default, PartialEq)]
+struct CalledOut {
+    reposts: usize,
+    /// How many were the first repost of their image.
+    first_reposts: usize,
+    /// If the poster deleted the message while it was being called out.
+    taken_back: bool,
+}
+
+impl CalledOut {
+    fn add(&mut self, times_seen: u64) {
+        self.reposts += 1;
+        if times_seen == 2 {
+            self.first_reposts += 1;
+        }
+    }
+}
+
+/// Builds the record of an image from the message it was posted in.
+fn seen_image(msg: &Message, sent: u64) -> SeenImage {
+    SeenImage {
+        author_id: msg.author.id.0,
+        guild_id: msg.guild_id.map_or(0, |g| g.0),
+        ..SeenImage::new(msg.author.name.clone(), sent, msg.id.0, msg.channel_id.0)
+    }
+}
+
+/// Builds the record of an image from the message it was posted in and where it was downloaded from.
+fn seen_image_from(msg: &Message, sent: u64, hashes: &Hashes) -> SeenImage {
+    SeenImage {
+        source_url: hashes.source.clone(),
+        ..seen_image(msg, sent)
+    }
+}
+
+/// The images in an embed. That's at most one, unless it's from a site that's handled specially.
+fn embed_images<'a>(embed: &'a Embed, config: &Config) -> Vec<Cow<'a, str>> {
+    if is_tweet(embed) {
+        return tweet_media(embed, config)
+            .into_iter()
+            .map(Cow::Owned)
+            .collect();
+    }
+
+    filter_embed(embed, &config.download)
+        .map(Cow::Borrowed)
+        .into_iter()
+        .collect()
+}
+
+/// Hosts that tweets are linked from, including the ones that only fix up their embeds.
+const TWITTER_HOSTS: &[&str] = &[
+    "twitter.com",
+    "x.com",
+    "mobile.twitter.com",
+    "fxtwitter.com",
+    "vxtwitter.com",
+    "fixupx.com",
+];
+
+/// If an embed is the preview of a tweet.
+fn is_tweet(embed: &Embed) -> bool {
+    let uri = match embed.url.as_deref().map(Uri::from_str) {
+        Some(Ok(uri)) => uri,
+        _ => return false,
+    };
+
+    let host = uri.host().unwrap_or_default().trim_start_matches("www.");
+    TWITTER_HOSTS.iter().any(|h| h.eq_ignore_ascii_case(host)) && uri.path().contains("/status/")
+}
+
+/// The media a tweet's embed shows, as direct links to the files on Twitter's media server.
+///
+/// Its video's thumbnail is only included if videos are checked by their thumbnails.
+fn tweet_media(embed: &Embed, config: &Config) -> Vec<String> {
+    let image = embed.image.as_ref().and_then(|i| i.url.as_deref());
+    let thumbnail = embed
+        .thumbnail
+        .as_ref()
+        .and_then(|t| t.url.as_deref())
+        .filter(|_| embed.video.is_none() || config.detection.video_thumbnails);
+
+    let mut media = Vec::new();
+    for url in image
+        .into_iter()
+        .chain(thumbnail)
+        .filter_map(twitter_media_url)
+    {
+        if filter_image(&url, &config.download).is_some() && !media.contains(&url) {
+            media.push(url);
+        }
     }
 
-    None
+    media
 }
 
-fn save_image(
-    context: &bot::Context,
-    image: Vec<u8>,
-    msg: &Message,
-) -> Result<PreviouslySeen, Error> {
-    let hash = image_processing::process_image(image)?;
-    tracing::debug!("Image hash was {:0x?}", hash.as_bytes());
+/// Rewrites a link to a file on Twitter's media server so it ends in its file extension.
+///
+/// Links like `https://pbs.twimg.com/media/ID?format=jpg&name=large` only say what they are in their query.
+fn twitter_media_url(url: &str) -> Option<String> {
+    let uri = Uri::from_str(url).ok()?;
+    if uri.host()? != "pbs.twimg.com" {
+        return None;
+    }
 
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("clocks are wobbly");
+    let path = uri.path();
+    let file = path.rsplit('/').next()?;
+    if file.contains('.') {
+        return Some(format!("https://pbs.twimg.com{}", path));
+    }
 
-    let properties = SeenImage::new(
-        msg.author.name.clone(),
-        now.as_secs(),
-        msg.id.0,
-        msg.channel_id.0,
-    );
-    let existing = context.data.record_image(&hash, properties)?;
-    Ok(existing)
+    let format = uri
+        .query()?
+        .split('&')
+        .find_map(|param| param.strip_prefix("format="))?;
+    Some(format!("https://pbs.twimg.com{}.{}", path, format))
 }
 
-fn filter_embed(embed: &Embed) -> Option<&str> {
+fn filter_embed<'a>(embed: &'a Embed, config: &DownloadConfig) -> Option<&'a str> {
     let url = match (embed.kind.as_str(), &embed.url, &embed.image) {
         ("image", Some(url), _) => url,
         (_, _, Some(EmbedImage { url: Some(url), .. })) => url,
         _ => return None,
     };
 
-    filter_image(url)
+    filter_image(url, config)
 }
 
-const SUPPORTED_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
 const EXTENSION_CLEANUP: &[char] = &[':'];
 
-fn filter_image(url: &str) -> Option<&str> {
-    let mut extension = url.split('.').last()?;
+/// The file extension at the end of a URL's path.
+///
+/// Query strings, like the signatures on Discord's CDN links, and fragments aren't part of it.
+fn url_extension(url: &str) -> Option<String> {
+    let url = url.split('#').next()?;
+    let uri = Uri::from_str(url).ok()?;
+
+    let file = uri.path().rsplit('/').next()?;
+    let (_, mut extension) = file.rsplit_once('.')?;
     for to_clean in EXTENSION_CLEANUP {
         extension = extension.split(*to_clean).next()?;
     }
 
-    for ext in SUPPORTED_EXTENSIONS.iter() {
-        if ext.eq_ignore_ascii_case(extension) {
-            return Some(url);
-        }
-    }
+    Some(extension.to_string())
+}
 
-    None
+fn filter_image<'a>(url: &'a str, config: &DownloadConfig) -> Option<&'a str> {
+    let extension = url_extension(url)?;
+
+    if config.allows_extension(&extension) {
+        Some(url)
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use image_processing::ImageHash;
+    use storage::MemoryStorage;
     use twilight_model::{
-        channel::{message::MessageType, Attachment},
-        id::{AttachmentId, ChannelId, GuildId, UserId},
+        channel::message::sticker::StickerId,
+        channel::{
+            embed::{EmbedThumbnail, EmbedVideo},
+            message::{MessageReference, MessageType},
+        },
+        id::{AttachmentId, ChannelId, GuildId},
         user::User,
     };
 
@@ -389,13 +2608,295 @@ mod tests {
         banner: None,
     };
 
+    #[test]
+    fn repost_reply_from_stored_image() {
+        let previous = SeenImage::new("<@5>".to_string(), 1000, 30, 40);
+        let config = ReplyConfig::default();
+
+        let reply = repost_reply(
+            &previous,
+            3,
+            ChannelId(40),
+            GuildId(20),
+            1000 + 2 * 86400,
+            &config,
+        );
+        assert_eq!(
+            reply,
+            RepostReply {
+                content:
+                    "Hey, <@5> already posted that here 2 days ago. I've seen it 2 times before. \
+                          Try harder next time <:niko:765033287357431829>"
+                        .to_string(),
+                link: Some(OriginalLink::Reply),
+                original_message_id: MessageId(30),
+                jump_url: "https://discordapp.com/channels/20/40/30".to_string(),
+                thumbnail: None,
+            }
+        );
+
+        // From another channel, it has to jump there instead.
+        let reply = repost_reply(&previous, 3, ChannelId(41), GuildId(20), 1000, &config);
+        assert_eq!(reply.link, Some(OriginalLink::EmbedField));
+
+        // Images that know their guild link there, wherever they're reposted.
+        let known_guild = SeenImage {
+            guild_id: 21,
+            ..previous.clone()
+        };
+        let reply = repost_reply(&known_guild, 3, ChannelId(41), GuildId(20), 1000, &config);
+        assert_eq!(reply.jump_url, "https://discordapp.com/channels/21/40/30");
+
+        // Images that know where they came from show it.
+        let known_source = SeenImage {
+            source_url: Some("https://cdn.discordapp.com/attachments/1/2/cat.png".to_string()),
+            ..previous.clone()
+        };
+        let reply = repost_reply(&known_source, 3, ChannelId(41), GuildId(20), 1000, &config);
+        assert_eq!(reply.thumbnail, known_source.source_url);
+
+        // In the thread started from the original, it's a softer plain message.
+        let soft = ReplyConfig {
+            thread_reposts: ThreadReposts::Soft,
+            ..Default::default()
+        };
+        let reply = repost_reply(&previous, 3, ChannelId(30), GuildId(20), 1000, &soft);
+        assert_eq!(reply.link, None);
+        assert!(reply.content.starts_with("Just so you know"));
+    }
+
+    #[test]
+    fn history_listed_oldest_first() {
+        let original = SeenImage {
+            author_id: 5,
+            ..SeenImage::new("first".to_string(), 1000, 30, 40)
+        };
+        let repost = SeenImage::new("second".to_string(), 1000 + 3600, 31, 41);
+        let occurrences = [Occurrence::from(&original), Occurrence::from(&repost)];
+
+        assert_eq!(
+            history_fields(&occurrences, GuildId(20), 1000 + 2 * 86400),
+            vec![
+                (
+                    "Original".to_string(),
+                    "<@5> posted it 2 days ago in <#40>. [Jump Link](https://discordapp.com/channels/20/40/30)"
+                        .to_string()
+                ),
+                (
+                    "Repost 1".to_string(),
+                    "second posted it 1 day ago in <#41>. [Jump Link](https://discordapp.com/channels/20/41/31)"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_repost_reply_from_first_share() {
+        let original = SeenImage::new("<@5>".to_string(), 1000, 30, 40);
+        let reply = link_repost_reply(
+            &original,
+            2,
+            ChannelId(41),
+            GuildId(20),
+            1000 + 3600,
+            &ReplyConfig::default(),
+        );
+
+        assert_eq!(
+            reply,
+            RepostReply {
+                content:
+                    "Hey, <@5> already shared that link here 1 hour ago. I've seen it once before."
+                        .to_string(),
+                link: Some(OriginalLink::EmbedField),
+                original_message_id: MessageId(30),
+                jump_url: "https://discordapp.com/channels/20/40/30".to_string(),
+                thumbnail: None,
+            }
+        );
+
+        let config = Config::default();
+        assert!(is_image_link(
+            "cdn.discordapp.com/attachments/1/2/cat.png",
+            &config
+        ));
+        assert!(is_image_link("tenor.com/view/cat-gif-1", &config));
+        assert!(!is_image_link("example.com/news/story", &config));
+    }
+
+    #[test]
+    fn leaderboard_ranked() {
+        assert_eq!(
+            leaderboard_message(&[(5, 3), (6, 1)]),
+            "Most reposts caught:\n1. <@5>: 3 reposts\n2. <@6>: 1 repost"
+        );
+        assert_eq!(
+            leaderboard_message(&[]),
+            "Nobody's been caught reposting here yet."
+        );
+    }
+
+    #[test]
+    fn stats_summed_up() {
+        let stats = GuildStats {
+            images: 3,
+            reposts: 2,
+            busiest_channel: Some((6, 2)),
+            most_reposted: Some((SeenImage::new("<@5>".to_string(), 10, 30, 40), 3)),
+        };
+        assert_eq!(
+            stats_message(&stats, GuildId(20)),
+            "Tracking 3 images here, with 2 reposts caught.\n\
+             Busiest channel: <#6>, with 2 images.\n\
+             Most reposted: <@5>'s image, seen 3 times: https://discordapp.com/channels/20/40/30"
+        );
+        assert_eq!(
+            stats_message(&GuildStats::default(), GuildId(20)),
+            "No images are tracked here yet."
+        );
+    }
+
+    #[test]
+    fn forgotten_image_reply() {
+        assert_eq!(
+            forgotten_message(&ForgottenImage {
+                aliases: 3,
+                times_seen: 7,
+            }),
+            "Forgot that image and the 3 hashes that matched it. It had been seen 7 times."
+        );
+        assert_eq!(
+            forgotten_message(&ForgottenImage {
+                aliases: 1,
+                times_seen: 1,
+            }),
+            "Forgot that image and its hash. It had been seen once."
+        );
+    }
+
+    #[test]
+    fn only_first_offense_spared() {
+        for first_offense in [
+            FirstOffense::Callout,
+            FirstOffense::HeadsUp,
+            FirstOffense::Quiet,
+        ] {
+            assert_eq!(offense_reply(1, first_offense), first_offense);
+            assert_eq!(offense_reply(2, first_offense), FirstOffense::Callout);
+            assert_eq!(offense_reply(10, first_offense), FirstOffense::Callout);
+        }
+    }
+
+    /// Discord CDN links as they look since attachment URLs started being signed.
+    const SIGNED_CDN_URLS: &[&str] = &[
+        "https://cdn.discordapp.com/attachments/1100000000000000000/1200000000000000000/image.png?ex=65e1c2a1&is=65cf4da1&hm=0f0e5e1b4a0c9d8f7e6d5c4b3a29180706f5e4d3c2b1a09f8e7d6c5b4a392817&",
+        "https://media.discordapp.net/attachments/1100000000000000000/1200000000000000000/IMG_1234.JPG?ex=65e1c2a1&is=65cf4da1&hm=0f0e5e1b4a0c9d8f&=&format=webp&width=540&height=960",
+        "https://cdn.discordapp.com/attachments/1100000000000000000/1200000000000000000/SPOILER_cat.jpeg?ex=65e1c2a1&is=65cf4da1&hm=0f0e5e1b#spoiler",
+    ];
+
     #[test]
     fn url_cleanup() {
-        for url in SHOULD_BE_PARSED {
-            assert!(filter_image(*url).is_some())
+        for url in SHOULD_BE_PARSED.iter().chain(SIGNED_CDN_URLS) {
+            assert!(
+                filter_image(url, &DownloadConfig::default()).is_some(),
+                "{} wasn't parsed",
+                url
+            );
+        }
+
+        // Only the path counts, not what's in the query string.
+        for url in [
+            "https://cdn.discordapp.com/attachments/1/2/notes.txt?ex=65e1c2a1&name=cat.png",
+            "https://example.com/view?image=cat.png",
+            "https://example.com/",
+            "not a url.png at all",
+        ] {
+            assert_eq!(
+                filter_image(url, &DownloadConfig::default()),
+                None,
+                "{}",
+                url
+            );
         }
     }
 
+    #[test]
+    fn denied_extensions_rejected() {
+        const GIF: &str = "https://cdn.discordapp.com/attachments/1/2/party.GIF";
+        const PNG: &str = "https://cdn.discordapp.com/attachments/1/2/lmao.png";
+
+        let mut config = DownloadConfig::default();
+        assert_eq!(filter_image(GIF, &config), Some(GIF));
+
+        config.denied_extensions = "gif".parse().unwrap();
+        assert_eq!(filter_image(GIF, &config), None);
+        assert_eq!(filter_image(PNG, &config), Some(PNG));
+
+        // Denying wins even when the extension is listed as supported.
+        config.supported_extensions = "png, .GIF".parse().unwrap();
+        assert_eq!(filter_image(GIF, &config), None);
+        assert_eq!(filter_image(PNG, &config), Some(PNG));
+    }
+
+    #[test]
+    fn purge_scheduled_on_removal() {
+        let removed = GuildDelete {
+            id: GuildId(1),
+            unavailable: false,
+        };
+        let outage = GuildDelete {
+            id: GuildId(1),
+            unavailable: true,
+        };
+
+        assert!(schedules_purge(&removed, Some(60)));
+        assert!(!schedules_purge(&outage, Some(60)));
+        // Nothing is purged unless it's turned on.
+        assert!(!schedules_purge(&removed, None));
+        assert!(!schedules_purge(&outage, None));
+    }
+
+    #[test]
+    fn crossposts_checked_once() {
+        let mut original = msg();
+        original.id = MessageId(1);
+        assert_eq!(
+            checked_message(&original, Crossposts::Ignore),
+            Some(MessageId(1))
+        );
+
+        // Publishing the original doesn't change anything about it.
+        original.flags = Some(MessageFlags::CROSSPOSTED);
+        assert_eq!(
+            checked_message(&original, Crossposts::Track),
+            Some(MessageId(1))
+        );
+
+        let mut crosspost = msg();
+        crosspost.id = MessageId(2);
+        crosspost.flags = Some(MessageFlags::IS_CROSSPOST);
+        crosspost.reference = Some(MessageReference {
+            channel_id: Some(ChannelId(10)),
+            guild_id: Some(GuildId(20)),
+            message_id: Some(MessageId(1)),
+            fail_if_not_exists: None,
+        });
+
+        assert_eq!(checked_message(&crosspost, Crossposts::Ignore), None);
+        // Tracked crossposts are checked as the original, so it's only counted once.
+        assert_eq!(
+            checked_message(&crosspost, Crossposts::Track),
+            Some(MessageId(1))
+        );
+
+        crosspost.reference = None;
+        assert_eq!(
+            checked_message(&crosspost, Crossposts::Track),
+            Some(MessageId(2))
+        );
+    }
+
     fn msg() -> Message {
         Message {
             activity: None,
@@ -477,13 +2978,581 @@ mod tests {
             width: None,
         }];
 
-        let cases = &[with_embed_only_url, with_embed_image, upload_attachment];
+        let mut sticker = msg();
+        sticker.sticker_items = vec![MessageSticker {
+            format_type: StickerFormatType::Apng,
+            id: StickerId(749054660769218631),
+            name: "Wave".to_string(),
+        }];
+
+        let cases = &[
+            with_embed_only_url,
+            with_embed_image,
+            upload_attachment,
+            sticker,
+        ];
 
         for msg in cases {
-            assert!(image_from_message(msg).is_some())
+            assert!(image_from_message(msg, &Config::default()).is_some())
+        }
+    }
+
+    #[test]
+    fn video_thumbnails_hashed() {
+        const THUMBNAIL: &str = "https://i.ytimg.com/vi/dQw4w9WgXcQ/maxresdefault.jpg";
+
+        let mut video_embed = embed();
+        video_embed.kind = "video".to_string();
+        video_embed.thumbnail = Some(EmbedThumbnail {
+            height: Some(720),
+            proxy_url: None,
+            url: Some(THUMBNAIL.to_string()),
+            width: Some(1280),
+        });
+        video_embed.video = Some(EmbedVideo {
+            height: Some(720),
+            proxy_url: None,
+            url: Some("https://www.youtube.com/embed/dQw4w9WgXcQ".to_string()),
+            width: Some(1280),
+        });
+
+        let mut with_video_embed = msg();
+        with_video_embed.embeds = vec![video_embed];
+        let mut config = Config::default();
+        assert_eq!(image_from_message(&with_video_embed, &config), None);
+
+        config.detection.video_thumbnails = true;
+        assert_eq!(
+            image_from_message(&with_video_embed, &config).as_deref(),
+            Some(THUMBNAIL)
+        );
+
+        let mut with_video_upload = msg();
+        with_video_upload.attachments = vec![Attachment {
+            content_type: Some("video/mp4".to_string()),
+            filename: "clip.mp4".to_string(),
+            height: Some(720),
+            id: AttachmentId(0),
+            proxy_url: "https://media.discordapp.net/attachments/1/2/clip.mp4".to_string(),
+            size: 1048576,
+            url: "https://cdn.discordapp.com/attachments/1/2/clip.mp4".to_string(),
+            width: Some(1280),
+        }];
+        assert_eq!(
+            image_from_message(&with_video_upload, &config).as_deref(),
+            Some("https://media.discordapp.net/attachments/1/2/clip.mp4?format=jpeg")
+        );
+
+        with_video_upload.attachments[0].proxy_url =
+            "https://media.discordapp.net/attachments/1/2/clip.mp4?ex=65e1c2a1&is=65cf4da1"
+                .to_string();
+        assert_eq!(
+            image_from_message(&with_video_upload, &config).as_deref(),
+            Some("https://media.discordapp.net/attachments/1/2/clip.mp4?ex=65e1c2a1&is=65cf4da1&format=jpeg")
+        );
+
+        // What the thumbnail URL would serve, run through the rest of the pipeline.
+        let frame = image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8])
+        });
+        let mut jpeg = Vec::new();
+        image::DynamicImage::ImageRgb8(frame)
+            .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))
+            .unwrap();
+
+        let data = Data::init("", &Config::default()).unwrap();
+        let message = msg();
+        let process = |image| image_processing::process_image(image, data.config()).unwrap();
+        assert_eq!(
+            save_image(&data, vec![process(jpeg.clone())], &message).unwrap(),
+            vec![PreviouslySeen::No]
+        );
+        assert!(matches!(
+            save_image(&data, vec![process(jpeg)], &message).unwrap()[..],
+            [PreviouslySeen::Yes { times_seen: 2, .. }]
+        ));
+    }
+
+    #[test]
+    fn supported_videos_downloaded_themselves() {
+        const VIDEO: &str = "https://cdn.discordapp.com/attachments/1/2/clip.mp4";
+
+        let mut message = msg();
+        message.attachments = vec![Attachment {
+            content_type: Some("video/mp4".to_string()),
+            filename: "clip.mp4".to_string(),
+            height: Some(720),
+            id: AttachmentId(0),
+            proxy_url: "https://media.discordapp.net/attachments/1/2/clip.mp4".to_string(),
+            size: 1048576,
+            url: VIDEO.to_string(),
+            width: Some(1280),
+        }];
+
+        let mut config = Config::default();
+        config.detection.video_thumbnails = true;
+        config.download.supported_extensions = "png,mp4".parse().unwrap();
+
+        // The video itself is checked, instead of its thumbnail as well.
+        assert_eq!(images_from_message(&message, &config), vec![VIDEO]);
+        assert_eq!(
+            image_from_message(&message, &config).as_deref(),
+            Some(VIDEO)
+        );
+    }
+
+    #[test]
+    fn sticker_formats() {
+        let mut sticker = MessageSticker {
+            format_type: StickerFormatType::Apng,
+            id: StickerId(749054660769218631),
+            name: "Wave".to_string(),
+        };
+
+        assert_eq!(
+            sticker_url(&sticker).as_deref(),
+            Some("https://media.discordapp.net/stickers/749054660769218631.png")
+        );
+
+        sticker.format_type = StickerFormatType::Png;
+        assert!(sticker_url(&sticker).is_some());
+
+        sticker.format_type = StickerFormatType::Lottie;
+        assert!(sticker_url(&sticker).is_none());
+    }
+
+    #[test]
+    fn seen_image_from_message() {
+        let mut message = msg();
+        message.author.name = "poster".to_string();
+        message.author.id = UserId(42);
+        message.id = MessageId(123);
+        message.channel_id = ChannelId(456);
+
+        let seen = seen_image(&message, 789);
+        assert_eq!(seen.author, "poster");
+        assert_eq!(seen.author_id, 42);
+        // Replies mention them, so the name stays right when they change it.
+        assert_eq!(seen.poster(), "<@42>");
+        assert_eq!(
+            SeenImage::new("poster".to_string(), 0, 0, 0).poster(),
+            "poster"
+        );
+        assert_eq!(seen.sent, 789);
+        assert_eq!(seen.original_message_id, 123);
+        assert_eq!(seen.channel_id, 456);
+        assert!(!seen.ignored);
+    }
+
+    #[test]
+    fn all_images_found() {
+        let attachment = |id, name: &str| Attachment {
+            content_type: None,
+            filename: name.to_string(),
+            height: None,
+            id: AttachmentId(id),
+            proxy_url: String::new(),
+            size: 0,
+            url: format!("https://cdn.discordapp.com/attachments/1/2/{}", name),
+            width: None,
+        };
+
+        let mut image_embed = embed();
+        image_embed.image = Some(EmbedImage {
+            height: None,
+            proxy_url: None,
+            url: Some("https://cdn.discordapp.com/attachments/1/2/a.png".to_string()),
+            width: None,
+        });
+
+        let mut message = msg();
+        message.embeds = vec![image_embed];
+        message.attachments = vec![
+            attachment(1, "a.png"),
+            attachment(2, "notes.txt"),
+            attachment(3, "b.jpg"),
+            attachment(4, "c.webp"),
+        ];
+        message.sticker_items = vec![MessageSticker {
+            format_type: StickerFormatType::Png,
+            id: StickerId(749054660769218631),
+            name: "Wave".to_string(),
+        }];
+
+        let mut config = Config::default();
+        // The embed of the first attachment is only checked once.
+        assert_eq!(
+            images_from_message(&message, &config),
+            vec![
+                "https://cdn.discordapp.com/attachments/1/2/a.png".to_string(),
+                "https://cdn.discordapp.com/attachments/1/2/b.jpg".to_string(),
+                "https://cdn.discordapp.com/attachments/1/2/c.webp".to_string(),
+                sticker_url(&message.sticker_items[0]).unwrap(),
+            ]
+        );
+        assert!(images_from_message(&msg(), &config).is_empty());
+
+        config.detection.all_images = false;
+        assert_eq!(
+            images_from_message(&message, &config),
+            vec!["https://cdn.discordapp.com/attachments/1/2/a.png"]
+        );
+    }
+
+    #[test]
+    fn tweet_media_found() {
+        let mut tweet = embed();
+        tweet.kind = "rich".to_string();
+        tweet.url = Some("https://twitter.com/someone/status/1390000000000000000".to_string());
+        tweet.image = Some(EmbedImage {
+            height: None,
+            proxy_url: None,
+            url: Some("https://pbs.twimg.com/media/E0abcDEFghi?format=jpg&name=large".to_string()),
+            width: None,
+        });
+        tweet.thumbnail = Some(EmbedThumbnail {
+            height: None,
+            proxy_url: None,
+            url: Some("https://pbs.twimg.com/ext_tw_video_thumb/1/pu/img/xyz.jpg".to_string()),
+            width: None,
+        });
+
+        let mut message = msg();
+        message.embeds = vec![tweet.clone()];
+        let mut config = Config::default();
+        assert_eq!(
+            images_from_message(&message, &config),
+            vec![
+                "https://pbs.twimg.com/media/E0abcDEFghi.jpg",
+                "https://pbs.twimg.com/ext_tw_video_thumb/1/pu/img/xyz.jpg",
+            ]
+        );
+        assert_eq!(
+            image_from_message(&message, &config).as_deref(),
+            Some("https://pbs.twimg.com/media/E0abcDEFghi.jpg")
+        );
+
+        // A video's thumbnail follows the same setting as other videos.
+        message.embeds[0].video = Some(EmbedVideo {
+            height: None,
+            proxy_url: None,
+            url: Some("https://video.twimg.com/ext_tw_video/1/pu/vid/clip.mp4".to_string()),
+            width: None,
+        });
+        config.detection.video_thumbnails = false;
+        assert_eq!(
+            images_from_message(&message, &config),
+            vec!["https://pbs.twimg.com/media/E0abcDEFghi.jpg"]
+        );
+
+        // Embeds of other sites aren't rewritten.
+        let mut other = tweet;
+        other.url = Some("https://example.com/someone/status/1".to_string());
+        assert!(!is_tweet(&other));
+        assert_eq!(
+            twitter_media_url("https://example.com/media/a?format=png"),
+            None
+        );
+        assert_eq!(twitter_media_url("https://pbs.twimg.com/media/a"), None);
+    }
+
+    #[test]
+    fn reddit_links_found() {
+        let mut message = msg();
+        message.content =
+            "https://redd.it/abc123 and <https://www.reddit.com/r/cats/comments/abc123/him/> \
+                           https://reddit.com/gallery/def456 https://example.com"
+                .to_string();
+
+        let mut config = Config::default();
+        let ids: Vec<_> = reddit_links(&message, &config)
+            .into_iter()
+            .map(|link| link.id)
+            .collect();
+        assert_eq!(ids, vec!["abc123", "def456"]);
+
+        config.detection.reddit_links = false;
+        assert!(reddit_links(&message, &config).is_empty());
+    }
+
+    #[test]
+    fn gif_links_found() {
+        const TENOR: &str = "https://tenor.com/view/cat-typing-gif-12002898";
+
+        let mut message = msg();
+        message.content = format!(
+            "look {} and <https://giphy.com/gifs/cat-JIX9t2j0ZTN9S> {} https://example.com/cat",
+            TENOR, TENOR
+        );
+
+        let mut config = Config::default();
+        assert_eq!(
+            gif_links(&message, &config),
+            vec![GifLink::Tenor(TENOR), GifLink::Giphy("JIX9t2j0ZTN9S")]
+        );
+
+        // Once Discord embeds the link, its thumbnail is checked instead.
+        config.detection.video_thumbnails = true;
+        let mut gifv = embed();
+        gifv.kind = "gifv".to_string();
+        gifv.url = Some(TENOR.to_string());
+        gifv.thumbnail = Some(EmbedThumbnail {
+            height: None,
+            proxy_url: None,
+            url: Some("https://media.tenor.com/abc/cat-typing.png".to_string()),
+            width: None,
+        });
+        gifv.video = Some(EmbedVideo {
+            height: None,
+            proxy_url: None,
+            url: Some("https://media.tenor.com/abc/cat-typing.mp4".to_string()),
+            width: None,
+        });
+        message.embeds = vec![gifv];
+        assert_eq!(
+            gif_links(&message, &config),
+            vec![GifLink::Giphy("JIX9t2j0ZTN9S")]
+        );
+
+        // Unless thumbnails aren't checked, in which case nothing else would catch it.
+        config.detection.video_thumbnails = false;
+        assert_eq!(gif_links(&message, &config).len(), 2);
+
+        config.detection.gif_links = false;
+        assert!(gif_links(&message, &config).is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_attachment_skipped() {
+        let encoded = |seed: u32| {
+            let image = image::RgbImage::from_fn(64, 64, |x, y| {
+                image::Rgb([(x * seed) as u8, (y * seed) as u8, ((x ^ y) * seed) as u8])
+            });
+            let mut png = Vec::new();
+            image::DynamicImage::ImageRgb8(image)
+                .write_to(&mut png, image::ImageOutputFormat::Png)
+                .unwrap();
+            png
+        };
+
+        let data = Data::init("", &Config::default()).unwrap();
+        let pool = worker_pool::WorkerPool::new(2);
+        let hashing = vec![
+            ("a.png", encoded(3)),
+            ("broken.png", b"definitely not a png".to_vec()),
+            ("b.png", encoded(7)),
+        ]
+        .into_iter()
+        .map(|(name, image)| {
+            let config = data.config().clone();
+            let job = pool.run(move || image_processing::process_image(image, &config));
+            (name, job)
+        })
+        .collect();
+
+        let (images, failures) = wait_for_hashes(hashing).await;
+        assert_eq!(images.len(), 2);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, "broken.png");
+
+        let message = msg();
+        assert_eq!(
+            save_image(&data, images, &message).unwrap(),
+            vec![PreviouslySeen::No, PreviouslySeen::No]
+        );
+        // Both good ones were stored.
+        for seed in [3, 7] {
+            let image = image_processing::process_image(encoded(seed), data.config()).unwrap();
+            assert!(matches!(
+                save_image(&data, vec![image], &message).unwrap()[..],
+                [PreviouslySeen::Yes { times_seen: 2, .. }]
+            ));
+        }
+    }
+
+    #[test]
+    fn duplicate_attachments_record_once() {
+        let message = msg();
+        let hash = ImageHash::from_bytes(&[0xAB; 64]).unwrap();
+
+        let data = MemoryStorage::default();
+        let seen = record_message_images(
+            &data,
+            vec![
+                ProcessedImage::Hashed(hash.clone().into()),
+                ProcessedImage::Hashed(hash.clone().into()),
+            ],
+            &message,
+            1,
+        )
+        .unwrap();
+        assert_eq!(seen, vec![PreviouslySeen::No]);
+
+        let mut config = Config::default();
+        config.detection.dedupe_within_message = false;
+
+        let data = MemoryStorage::new(config.detection);
+        let seen = record_message_images(
+            &data,
+            vec![
+                ProcessedImage::Hashed(hash.clone().into()),
+                ProcessedImage::Hashed(hash.into()),
+            ],
+            &message,
+            1,
+        )
+        .unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0], PreviouslySeen::No);
+        assert!(matches!(seen[1], PreviouslySeen::Yes { times_seen: 2, .. }));
+    }
+
+    #[test]
+    fn reposted_attachments_called_out_separately() {
+        let message = msg();
+        let data = Data::init("", &Config::default()).unwrap();
+        let cat = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+        let dog = ImageHash::from_bytes(&[0xF0; 64]).unwrap();
+        let images = |cat: &ImageHash, dog: &ImageHash| {
+            vec![
+                ProcessedImage::Hashed(cat.clone().into()),
+                ProcessedImage::Hashed(dog.clone().into()),
+            ]
+        };
+
+        record_message_images(&data, images(&cat, &dog), &message, 1).unwrap();
+        let seen = record_message_images(&data, images(&cat, &dog), &message, 2).unwrap();
+
+        let mut called_out = CalledOut::default();
+        for seen in seen {
+            match seen {
+                PreviouslySeen::Yes { times_seen, .. } => called_out.add(times_seen),
+                seen => panic!("not seen before: {:?}", seen),
+            }
+        }
+        assert_eq!(
+            called_out,
+            CalledOut {
+                reposts: 2,
+                first_reposts: 2,
+                taken_back: false,
+            }
+        );
+    }
+
+    #[test]
+    fn source_url_recorded() {
+        let message = msg();
+        let data = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[0xAB; 64]).unwrap();
+        let url = "https://cdn.discordapp.com/attachments/1/2/cat.png";
+
+        let record = |image| record_message_images(&data, vec![image], &message, 1).unwrap();
+        record(ProcessedImage::Hashed(hash.clone().into()).with_source(url));
+
+        match &record(ProcessedImage::Hashed(hash.into()))[..] {
+            [PreviouslySeen::Yes { image, .. }] => {
+                assert_eq!(image.source_url.as_deref(), Some(url))
+            }
+            seen => panic!("not seen before: {:?}", seen),
         }
     }
 
+    #[test]
+    fn small_images_match_without_recording() {
+        let message = msg();
+        let data = Data::init("", &Config::default()).unwrap();
+
+        let known = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+        let novel_large = ImageHash::from_bytes(&[0xF0; 64]).unwrap();
+        let novel_small = ImageHash::from_bytes(&[0xAA; 64]).unwrap();
+
+        let record = |image| record_message_images(&data, vec![image], &message, 1).unwrap();
+
+        record(ProcessedImage::Hashed(known.clone().into()));
+
+        // Large and known.
+        assert!(matches!(
+            record(ProcessedImage::Hashed(known.clone().into()))[..],
+            [PreviouslySeen::Yes { times_seen: 2, .. }]
+        ));
+        // Small and known.
+        assert!(matches!(
+            record(ProcessedImage::MatchOnly(known.into()))[..],
+            [PreviouslySeen::Yes { times_seen: 3, .. }]
+        ));
+        // Large and novel, which is stored.
+        assert_eq!(
+            record(ProcessedImage::Hashed(novel_large.clone().into())),
+            vec![PreviouslySeen::No]
+        );
+        assert!(matches!(
+            record(ProcessedImage::MatchOnly(novel_large.into()))[..],
+            [PreviouslySeen::Yes { times_seen: 2, .. }]
+        ));
+        // Small and novel, which isn't.
+        assert_eq!(
+            record(ProcessedImage::MatchOnly(novel_small.clone().into())),
+            vec![PreviouslySeen::No]
+        );
+        assert_eq!(
+            record(ProcessedImage::MatchOnly(novel_small.into())),
+            vec![PreviouslySeen::No]
+        );
+        assert_eq!(data.total_seen(), 2);
+    }
+
+    #[test]
+    fn times_seen_phrasing() {
+        assert_eq!(
+            times_seen_phrase(2, CountStyle::Previous),
+            "I've seen it once before."
+        );
+        assert_eq!(
+            times_seen_phrase(5, CountStyle::Previous),
+            "I've seen it 4 times before."
+        );
+        assert_eq!(
+            times_seen_phrase(2, CountStyle::Total),
+            "I've seen it 2 times now."
+        );
+    }
+
+    #[test]
+    fn original_link_styles() {
+        assert_eq!(original_link(ChannelId(1), 1, false), OriginalLink::Reply);
+        assert_eq!(original_link(ChannelId(1), 1, true), OriginalLink::Reply);
+        assert_eq!(
+            original_link(ChannelId(2), 1, false),
+            OriginalLink::EmbedField
+        );
+        assert_eq!(original_link(ChannelId(2), 1, true), OriginalLink::Button);
+    }
+
+    #[test]
+    fn cross_channel_reply_decision() {
+        assert!(should_reply(ChannelId(1), 1, false));
+        assert!(should_reply(ChannelId(1), 1, true));
+        assert!(should_reply(ChannelId(2), 1, true));
+        assert!(!should_reply(ChannelId(2), 1, false));
+    }
+
+    #[test]
+    fn own_repost_decision() {
+        let mut original = SeenImage::new("someone".to_string(), 10, 20, 30);
+        assert!(!is_own_repost(&original, UserId(5)));
+
+        original.author_id = 5;
+        assert!(is_own_repost(&original, UserId(5)));
+        assert!(!is_own_repost(&original, UserId(6)));
+    }
+
+    #[test]
+    fn thread_from_original_message() {
+        assert!(is_thread_of(ChannelId(123), 123));
+        assert!(!is_thread_of(ChannelId(456), 123));
+    }
+
     const TIME_SINCE_CASES: &[(u64, &str)] = &[
         (24, "seconds"),
         (1, "second"),
@@ -0,0 +1,139 @@
+use std::collections::{HashMap, VecDeque};
+
+use twilight_model::{channel::Message, id::MessageId};
+
+/// The most recent messages the bot saw or fetched, so looking up what a reply points at
+/// doesn't need a request to Discord every time.
+pub struct MessageCache {
+    capacity: usize,
+    messages: HashMap<MessageId, Message>,
+    /// Cached IDs, oldest first, for evicting when it's full.
+    order: VecDeque<MessageId>,
+}
+
+impl MessageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers a message, forgetting the oldest one if there's no room left.
+    pub fn insert(&mut self, message: Message) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let id = message.id;
+        if self.messages.insert(id, message).is_some() {
+            // It's been seen again, so it's the newest now.
+            self.order.retain(|cached| *cached != id);
+        }
+        self.order.push_back(id);
+
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<Message> {
+        self.messages.get(&id).cloned()
+    }
+
+    /// Forgets a message, like when it's deleted or edited and the cached copy is out of date.
+    pub fn remove(&mut self, id: MessageId) {
+        if self.messages.remove(&id).is_some() {
+            self.order.retain(|cached| *cached != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twilight_model::{
+        channel::message::MessageType,
+        id::{ChannelId, UserId},
+        user::User,
+    };
+
+    fn message(id: u64) -> Message {
+        Message {
+            activity: None,
+            application: None,
+            application_id: None,
+            attachments: Vec::new(),
+            author: User {
+                avatar: None,
+                bot: false,
+                discriminator: String::new(),
+                email: None,
+                flags: None,
+                id: UserId(1),
+                locale: None,
+                mfa_enabled: None,
+                name: String::new(),
+                premium_type: None,
+                public_flags: None,
+                system: None,
+                verified: None,
+                accent_color: None,
+                banner: None,
+            },
+            channel_id: ChannelId(1),
+            components: Vec::new(),
+            content: String::new(),
+            edited_timestamp: None,
+            embeds: Vec::new(),
+            flags: None,
+            guild_id: None,
+            id: MessageId(id),
+            interaction: None,
+            kind: MessageType::Regular,
+            member: None,
+            mention_channels: Vec::new(),
+            mention_everyone: false,
+            mention_roles: Vec::new(),
+            mentions: Vec::new(),
+            pinned: false,
+            reactions: Vec::new(),
+            reference: None,
+            referenced_message: None,
+            sticker_items: Vec::new(),
+            timestamp: String::new(),
+            thread: None,
+            tts: false,
+            webhook_id: None,
+        }
+    }
+
+    #[test]
+    fn oldest_evicted() {
+        let mut cache = MessageCache::new(2);
+        cache.insert(message(1));
+        cache.insert(message(2));
+        // Seeing it again keeps it around longer.
+        cache.insert(message(1));
+        cache.insert(message(3));
+
+        assert!(cache.get(MessageId(1)).is_some());
+        assert!(cache.get(MessageId(2)).is_none());
+        assert!(cache.get(MessageId(3)).is_some());
+    }
+
+    #[test]
+    fn removed_messages_forgotten() {
+        let mut cache = MessageCache::new(2);
+        cache.insert(message(1));
+        cache.remove(MessageId(1));
+        assert!(cache.get(MessageId(1)).is_none());
+
+        let mut disabled = MessageCache::new(0);
+        disabled.insert(message(1));
+        assert!(disabled.get(MessageId(1)).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use twilight_model::id::{ChannelId, MessageId};
+
+/// A repost that was counted recently, and the bot's reply to it if there was one.
+#[derive(Debug, PartialEq)]
+pub struct RecentRepost {
+    /// When the repost was counted - std::time::UNIX_EPOCH, in seconds.
+    pub counted_at: u64,
+    pub reply: Option<(ChannelId, MessageId)>,
+}
+
+/// Reposts counted within the last `window` seconds, so they can be taken back if they're deleted quickly.
+pub struct RecentReposts {
+    window: u64,
+    reposts: HashMap<MessageId, RecentRepost>,
+}
+
+impl RecentReposts {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            reposts: HashMap::new(),
+        }
+    }
+
+    /// Remembers that a message was counted as a repost at `now`.
+    pub fn counted(&mut self, message: MessageId, now: u64) {
+        if self.window == 0 {
+            return;
+        }
+
+        // Anything older than the window can't be taken back anymore.
+        let window = self.window;
+        self.reposts
+            .retain(|_, repost| now.saturating_sub(repost.counted_at) <= window);
+
+        self.reposts.insert(
+            message,
+            RecentRepost {
+                counted_at: now,
+                reply: None,
+            },
+        );
+    }
+
+    /// Remembers the bot's reply to a repost, returning `false` if the repost is already gone.
+    pub fn replied(&mut self, message: MessageId, reply: (ChannelId, MessageId)) -> bool {
+        match self.reposts.get_mut(&message) {
+            Some(repost) => {
+                repost.reply = Some(reply);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets a message, like when the bot deletes it itself and it shouldn't be taken back.
+    pub fn forget(&mut self, message: MessageId) {
+        self.reposts.remove(&message);
+    }
+
+    /// Forgets a deleted message, returning it if it was counted recently enough to take back.
+    pub fn deleted(&mut self, message: MessageId, now: u64) -> Option<RecentRepost> {
+        let repost = self.reposts.remove(&message)?;
+
+        if now.saturating_sub(repost.counted_at) <= self.window {
+            Some(repost)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quick_deletes_taken_back() {
+        let mut recent = RecentReposts::new(30);
+        recent.counted(MessageId(1), 100);
+        assert!(recent.replied(MessageId(1), (ChannelId(5), MessageId(2))));
+
+        assert_eq!(
+            recent.deleted(MessageId(1), 130),
+            Some(RecentRepost {
+                counted_at: 100,
+                reply: Some((ChannelId(5), MessageId(2))),
+            })
+        );
+        // It can only be taken back once.
+        assert_eq!(recent.deleted(MessageId(1), 130), None);
+
+        recent.counted(MessageId(3), 100);
+        recent.forget(MessageId(3));
+        assert_eq!(recent.deleted(MessageId(3), 100), None);
+    }
+
+    #[test]
+    fn slow_deletes_kept() {
+        let mut recent = RecentReposts::new(30);
+        recent.counted(MessageId(1), 100);
+        assert_eq!(recent.deleted(MessageId(1), 131), None);
+
+        // Messages that were never counted are ignored.
+        assert_eq!(recent.deleted(MessageId(3), 100), None);
+    }
+
+    #[test]
+    fn deleted_before_reply() {
+        let mut recent = RecentReposts::new(30);
+        recent.counted(MessageId(1), 100);
+        assert!(recent.deleted(MessageId(1), 101).is_some());
+
+        // The reply that was on its way should be cleaned up by the caller.
+        assert!(!recent.replied(MessageId(1), (ChannelId(5), MessageId(2))));
+    }
+
+    #[test]
+    fn old_reposts_pruned() {
+        let mut recent = RecentReposts::new(30);
+        recent.counted(MessageId(1), 100);
+        recent.counted(MessageId(2), 200);
+        assert_eq!(recent.reposts.len(), 1);
+
+        let mut disabled = RecentReposts::new(0);
+        disabled.counted(MessageId(1), 100);
+        assert_eq!(disabled.deleted(MessageId(1), 100), None);
+    }
+}
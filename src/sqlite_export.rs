@@ -0,0 +1,599 @@
+//! Copies of the stored images in a single SQLite file, which can be inspected with standard tools.
+//!
+//! This is an export, not something the bot runs on: occurrences, near matches, and per-format
+//! thresholds stay in [Data](crate::data_storage::Data). Matching against the copy works like it does
+//! there, exactly and then by distance within the similarity threshold, so it can be checked with the
+//! same [Storage] tests. Hashes aren't indexed, so every hash in a guild is compared against.
+
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::config::{Config, DetectionConfig};
+use crate::data_storage::{Data, PreviouslySeen, SeenImage};
+use crate::errors::{DatabaseError, Error, TransferError};
+use crate::image_processing::{self, Hashes};
+use crate::storage::Storage;
+use crate::transfer::Dump;
+
+/// Statements that bring the schema from one version to the next, in order.
+///
+/// A database on version N has had the first N run. Each one is run in the same transaction as the
+/// version is moved past it, so a failed one leaves the database as it was.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "inital_schema",
+        "CREATE TABLE images (
+        id INTEGER PRIMARY KEY,
+        ignored INTEGER NOT NULL DEFAULT 0,
+        author TEXT NOT NULL,
+        author_id INTEGER NOT NULL,
+        sent INTEGER NOT NULL,
+        original_message_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        guild_id INTEGER NOT NULL,
+        source_url TEXT,
+        format TEXT,
+        times_seen INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE TABLE hashes (
+        guild_id INTEGER NOT NULL,
+        hash BLOB NOT NULL,
+        image_id INTEGER NOT NULL REFERENCES images (id) ON DELETE CASCADE,
+        PRIMARY KEY (guild_id, hash)
+    );",
+    ),
+    (
+        "offenders",
+        "CREATE TABLE offenders (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        offenses INTEGER NOT NULL,
+        PRIMARY KEY (guild_id, user_id)
+    );",
+    ),
+];
+
+#[derive(Clone)]
+pub struct SqliteData {
+    config: DetectionConfig,
+    /// The guild images are looked up in by hash, if known.
+    guild: Option<u64>,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteData {
+    /// Opens the database at `db_path`, creating it if needed, and brings its schema up to date.
+    ///
+    /// An empty path opens a database in memory.
+    pub fn init(db_path: &str, config: &Config) -> Result<Self, DatabaseError> {
+        let conn = if db_path.is_empty() {
+            Connection::open_in_memory()
+        } else {
+            Connection::open(db_path)
+        }
+        .map_err(DatabaseError::Sqlite)?;
+
+        conn.pragma_update(None, "foreign_keys", true)
+            .map_err(DatabaseError::Sqlite)?;
+
+        let data = Self {
+            config: config.detection.clone(),
+            guild: None,
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        data.run_migrations(MIGRATIONS)?;
+
+        Ok(data)
+    }
+
+    /// Returns a handle to the same database that looks images up by hash only in a guild.
+    ///
+    /// Only the [Storage] tests look images up in an export so far.
+    #[cfg(test)]
+    pub fn for_guild(&self, guild_id: u64) -> Self {
+        Self {
+            guild: Some(guild_id),
+            ..self.clone()
+        }
+    }
+
+    /// The schema version the database is on.
+    fn version_of(conn: &Connection) -> Result<usize, DatabaseError> {
+        conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+            .map(|version| version as usize)
+            .map_err(DatabaseError::Sqlite)
+    }
+
+    /// Runs each of `migrations` the database hasn't had yet, in order.
+    fn run_migrations(&self, migrations: &[(&str, &str)]) -> Result<(), DatabaseError> {
+        let mut conn = self.conn.lock().unwrap();
+        let version = Self::version_of(&conn)?;
+
+        if version > migrations.len() {
+            panic!("uhhh, time travel?")
+        }
+
+        for (ran, (name, statements)) in migrations.iter().enumerate().skip(version) {
+            tracing::info!("Running database migration {}", name);
+
+            let tx = conn.transaction().map_err(DatabaseError::Sqlite)?;
+            tx.execute_batch(statements)
+                .map_err(DatabaseError::Sqlite)?;
+            tx.pragma_update(None, "user_version", (ran + 1) as i64)
+                .map_err(DatabaseError::Sqlite)?;
+            tx.commit().map_err(DatabaseError::Sqlite)?;
+        }
+
+        Ok(())
+    }
+
+    fn record(
+        &self,
+        hashes: &Hashes,
+        properties: SeenImage,
+        store_new: bool,
+    ) -> Result<PreviouslySeen, DatabaseError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(DatabaseError::Sqlite)?;
+        let guild_id = properties.guild_id as i64;
+        let hash = hashes.hash.as_bytes();
+
+        let exact = tx
+            .query_row(
+                "SELECT image_id FROM hashes WHERE guild_id = ?1 AND hash = ?2",
+                params![guild_id, hash],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(DatabaseError::Sqlite)?;
+
+        let seen = match exact {
+            Some(id) => Self::count_sighting(&tx, id)?,
+            None => match self.nearest(&tx, hashes, guild_id)? {
+                Some(id) => {
+                    Self::insert_hash(&tx, guild_id, hash, id)?;
+                    Self::count_sighting(&tx, id)?
+                }
+                None if store_new => {
+                    let format = hashes.format.map(|format| format.extensions_str()[0]);
+                    let id = Self::insert_image(&tx, &properties, format, 1)?;
+                    Self::insert_hash(&tx, guild_id, hash, id)?;
+                    PreviouslySeen::No
+                }
+                None => PreviouslySeen::No,
+            },
+        };
+
+        tx.commit().map_err(DatabaseError::Sqlite)?;
+        Ok(seen)
+    }
+
+    /// The first stored image in the guild within the similarity threshold of any of the hashes.
+    fn nearest(
+        &self,
+        tx: &Transaction,
+        hashes: &Hashes,
+        guild_id: i64,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let threshold = self.config.similarity_threshold;
+        let mut stmt = tx
+            .prepare(
+                "SELECT hashes.hash, hashes.image_id, images.ignored FROM hashes
+                JOIN images ON images.id = hashes.image_id
+                WHERE hashes.guild_id = ?1 ORDER BY hashes.hash",
+            )
+            .map_err(DatabaseError::Sqlite)?;
+        let rows = stmt
+            .query_map(params![guild_id], |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            })
+            .map_err(DatabaseError::Sqlite)?;
+
+        for row in rows {
+            let (hash, id, ignored) = row.map_err(DatabaseError::Sqlite)?;
+
+            // Aliasing this hash to an ignored record would make it ignored too, so
+            // only do that if configured.
+            if ignored && !self.config.similar_inherits_ignored {
+                continue;
+            }
+
+            if hashes
+                .candidates()
+                .any(|candidate| image_processing::hash_distance(candidate, &hash) <= threshold)
+            {
+                return Ok(Some(id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn count_sighting(tx: &Transaction, id: i64) -> Result<PreviouslySeen, DatabaseError> {
+        tx.execute(
+            "UPDATE images SET times_seen = times_seen + 1 WHERE id = ?1",
+            params![id],
+        )
+        .map_err(DatabaseError::Sqlite)?;
+
+        let (image, times_seen) =
+            Self::image(tx, id)?.expect("bug: a hash pointed at an image that isn't stored");
+        Ok(PreviouslySeen::Yes { image, times_seen })
+    }
+
+    /// Stores an image on its own, returning its ID.
+    fn insert_image(
+        tx: &Transaction,
+        image: &SeenImage,
+        format: Option<&str>,
+        times_seen: u64,
+    ) -> Result<i64, DatabaseError> {
+        tx.execute(
+            "INSERT INTO images (ignored, author, author_id, sent, original_message_id, channel_id,
+                guild_id, source_url, format, times_seen)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                image.ignored,
+                image.author,
+                image.author_id as i64,
+                image.sent as i64,
+                image.original_message_id as i64,
+                image.channel_id as i64,
+                image.guild_id as i64,
+                image.source_url,
+                format,
+                times_seen as i64,
+            ],
+        )
+        .map_err(DatabaseError::Sqlite)?;
+
+        Ok(tx.last_insert_rowid())
+    }
+
+    fn insert_hash(
+        tx: &Transaction,
+        guild_id: i64,
+        hash: &[u8],
+        id: i64,
+    ) -> Result<(), DatabaseError> {
+        tx.execute(
+            "INSERT INTO hashes (guild_id, hash, image_id) VALUES (?1, ?2, ?3)",
+            params![guild_id, hash, id],
+        )
+        .map_err(DatabaseError::Sqlite)?;
+
+        Ok(())
+    }
+
+    /// Stores every image in `dump` with its hashes and how many times it's been seen, and every repost
+    /// offender, all at once.
+    ///
+    /// Nothing else in the dump has a place in this database. A hash that isn't valid fails the whole copy.
+    pub fn import_dump(&self, dump: &Dump) -> Result<usize, TransferError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(DatabaseError::Sqlite)?;
+
+        for dumped in &dump.images {
+            let id = Self::insert_image(
+                &tx,
+                &dumped.image,
+                dumped.format.as_deref(),
+                dumped.times_seen,
+            )?;
+            let guild_id = dumped.guild_id.unwrap_or(dumped.image.guild_id) as i64;
+
+            for hash in &dumped.hashes {
+                let hash = Data::decode_hash(hash)?;
+                Self::insert_hash(&tx, guild_id, &hash, id)?;
+            }
+        }
+
+        for offender in &dump.offenders {
+            tx.execute(
+                "INSERT INTO offenders (guild_id, user_id, offenses) VALUES (?1, ?2, ?3)",
+                params![
+                    offender.guild_id as i64,
+                    offender.user_id as i64,
+                    offender.reposts as i64
+                ],
+            )
+            .map_err(DatabaseError::Sqlite)?;
+        }
+
+        tx.commit().map_err(DatabaseError::Sqlite)?;
+        Ok(dump.images.len())
+    }
+
+    fn image(conn: &Connection, id: i64) -> Result<Option<(SeenImage, u64)>, DatabaseError> {
+        conn.query_row(
+            "SELECT ignored, author, author_id, sent, original_message_id, channel_id, guild_id,
+                source_url, times_seen
+            FROM images WHERE id = ?1",
+            params![id],
+            |row| {
+                let image = SeenImage {
+                    ignored: row.get(0)?,
+                    author: row.get(1)?,
+                    author_id: row.get::<_, i64>(2)? as u64,
+                    sent: row.get::<_, i64>(3)? as u64,
+                    original_message_id: row.get::<_, i64>(4)? as u64,
+                    channel_id: row.get::<_, i64>(5)? as u64,
+                    guild_id: row.get::<_, i64>(6)? as u64,
+                    source_url: row.get(7)?,
+                };
+                Ok((image, row.get::<_, i64>(8)? as u64))
+            },
+        )
+        .optional()
+        .map_err(DatabaseError::Sqlite)
+    }
+}
+
+impl Storage for SqliteData {
+    fn config(&self) -> &DetectionConfig {
+        &self.config
+    }
+
+    fn record_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error> {
+        Ok(self.record(&hashes, properties, true)?)
+    }
+
+    fn match_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error> {
+        Ok(self.record(&hashes, properties, false)?)
+    }
+
+    fn access_image(
+        &self,
+        image_hash: &[u8],
+        f: &mut dyn FnMut(&mut SeenImage) -> bool,
+    ) -> Result<(), DatabaseError> {
+        let conn = self.conn.lock().unwrap();
+
+        // Hashes are only looked up in the guild they were recorded in, like in sled.
+        let id = conn
+            .query_row(
+                "SELECT image_id FROM hashes WHERE guild_id = ?1 AND hash = ?2",
+                params![self.guild.unwrap_or(0) as i64, image_hash],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(DatabaseError::Sqlite)?;
+
+        let (mut image, _) = match id.map(|id| Self::image(&conn, id)).transpose()?.flatten() {
+            Some(image) => image,
+            None => return Ok(()),
+        };
+
+        if f(&mut image) {
+            conn.execute(
+                "UPDATE images SET ignored = ?1, author = ?2, author_id = ?3, sent = ?4,
+                    original_message_id = ?5, channel_id = ?6, guild_id = ?7, source_url = ?8
+                WHERE id = ?9",
+                params![
+                    image.ignored,
+                    image.author,
+                    image.author_id as i64,
+                    image.sent as i64,
+                    image.original_message_id as i64,
+                    image.channel_id as i64,
+                    image.guild_id as i64,
+                    image.source_url,
+                    id,
+                ],
+            )
+            .map_err(DatabaseError::Sqlite)?;
+        }
+
+        Ok(())
+    }
+
+    fn total_seen(&self) -> usize {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM images", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map_or(0, |count| count as usize)
+    }
+
+    fn record_offense(&self, guild_id: u64, user_id: u64) -> Result<u64, DatabaseError> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "INSERT INTO offenders (guild_id, user_id, offenses) VALUES (?1, ?2, 1)
+                ON CONFLICT (guild_id, user_id) DO UPDATE SET offenses = offenses + 1
+                RETURNING offenses",
+                params![guild_id as i64, user_id as i64],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|offenses| offenses as u64)
+            .map_err(DatabaseError::Sqlite)
+    }
+
+    fn top_offenders(&self, guild_id: u64, limit: usize) -> Result<Vec<(u64, u64)>, DatabaseError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT user_id, offenses FROM offenders WHERE guild_id = ?1
+                ORDER BY offenses DESC, user_id LIMIT ?2",
+            )
+            .map_err(DatabaseError::Sqlite)?;
+        let offenders = stmt
+            .query_map(params![guild_id as i64, limit as i64], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64))
+            })
+            .map_err(DatabaseError::Sqlite)?
+            .collect::<Result<_, _>>()
+            .map_err(DatabaseError::Sqlite)?;
+
+        Ok(offenders)
+    }
+
+    fn per_guild_counts(&self) -> Result<Vec<(u64, usize)>, DatabaseError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT guild_id, COUNT(*) AS images FROM images
+                GROUP BY guild_id ORDER BY images DESC, guild_id",
+            )
+            .map_err(DatabaseError::Sqlite)?;
+        let counts = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as usize))
+            })
+            .map_err(DatabaseError::Sqlite)?
+            .collect::<Result<_, _>>()
+            .map_err(DatabaseError::Sqlite)?;
+
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processing::ImageHash;
+
+    fn version(db: &SqliteData) -> usize {
+        SqliteData::version_of(&db.conn.lock().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn schema_created() {
+        let db = SqliteData::init("", &Config::default()).unwrap();
+        assert_eq!(version(&db), MIGRATIONS.len());
+
+        // Running them again on an up to date database does nothing.
+        db.run_migrations(MIGRATIONS).unwrap();
+        assert_eq!(db.total_seen(), 0);
+    }
+
+    #[test]
+    fn failed_migration_rolled_back() {
+        let db = SqliteData::init("", &Config::default()).unwrap();
+        let before = version(&db);
+
+        let broken: Vec<_> = MIGRATIONS
+            .iter()
+            .copied()
+            .chain(std::iter::once((
+                "broken",
+                "ALTER TABLE images ADD COLUMN note TEXT; SELECT * FROM nothing_here;",
+            )))
+            .collect();
+        assert!(db.run_migrations(&broken).is_err());
+
+        // Neither the version nor the half of the migration that worked were kept.
+        assert_eq!(version(&db), before);
+        db.run_migrations(
+            &[
+                MIGRATIONS,
+                &[("fixed", "ALTER TABLE images ADD COLUMN note TEXT;")],
+            ]
+            .concat(),
+        )
+        .unwrap();
+        assert_eq!(version(&db), before + 1);
+    }
+
+    #[test]
+    fn sled_database_copied() {
+        let sled = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[0x3C; 64]).unwrap();
+        let image = SeenImage::new("someone".to_string(), 10, 20, 30);
+        sled.record_raw(&hash, image.clone()).unwrap();
+        sled.record_raw(&hash, image.clone()).unwrap();
+        sled.record_offense(1, 10).unwrap();
+
+        let db = SqliteData::init("", &Config::default()).unwrap();
+        assert_eq!(db.import_dump(&sled.dump().unwrap()).unwrap(), 1);
+        assert_eq!(db.top_offenders(1, 10).unwrap(), vec![(10, 1)]);
+
+        assert_eq!(
+            db.record_image(hash.into(), image.clone()).unwrap(),
+            PreviouslySeen::Yes {
+                image,
+                times_seen: 3
+            }
+        );
+    }
+
+    #[test]
+    fn broken_dump_not_copied() {
+        let sled = Data::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[0x3C; 64]).unwrap();
+        sled.record_raw(&hash, SeenImage::new("someone".to_string(), 10, 20, 30))
+            .unwrap();
+
+        let mut dump = sled.dump().unwrap();
+        dump.images[0].hashes.push("not hex".to_string());
+
+        let db = SqliteData::init("", &Config::default()).unwrap();
+        assert!(matches!(
+            db.import_dump(&dump),
+            Err(TransferError::Malformed(_))
+        ));
+        assert_eq!(db.total_seen(), 0);
+    }
+
+    #[test]
+    fn images_accessed_in_their_guild() {
+        let db = SqliteData::init("", &Config::default()).unwrap();
+        let hash = ImageHash::from_bytes(&[0x66; 64]).unwrap();
+        let image = |guild_id| SeenImage {
+            guild_id,
+            ..SeenImage::new("someone".to_string(), 10, 20, 30)
+        };
+        db.record_image(hash.clone().into(), image(1)).unwrap();
+        db.record_image(hash.clone().into(), image(2)).unwrap();
+
+        let mut ignore = |seen: &mut SeenImage| {
+            seen.ignored = true;
+            true
+        };
+        db.for_guild(2)
+            .access_image(hash.as_bytes(), &mut ignore)
+            .unwrap();
+
+        let ignored_in = |guild_id| match db.match_image(hash.clone().into(), image(guild_id)) {
+            Ok(PreviouslySeen::Yes { image, .. }) => image.ignored,
+            seen => panic!("not seen before: {:?}", seen),
+        };
+        assert!(!ignored_in(1));
+        assert!(ignored_in(2));
+    }
+
+    #[test]
+    fn similar_images_aliased() {
+        let db = SqliteData::init("", &Config::default()).unwrap();
+        let original = ImageHash::from_bytes(&[0x0F; 64]).unwrap();
+        let mut similar = [0x0F; 64];
+        similar[0] = 0x0E;
+        let similar = ImageHash::from_bytes(&similar).unwrap();
+        let image = SeenImage::new("someone".to_string(), 10, 20, 30);
+
+        db.record_image(original.into(), image.clone()).unwrap();
+        match db.record_image(similar.clone().into(), image).unwrap() {
+            PreviouslySeen::Yes { times_seen, .. } => assert_eq!(times_seen, 2),
+            seen => panic!("not seen before: {:?}", seen),
+        }
+        assert_eq!(db.total_seen(), 1);
+
+        // The alias is an exact match now, and only in its own guild.
+        let elsewhere = SeenImage {
+            guild_id: 1,
+            ..SeenImage::new("someone".to_string(), 10, 20, 30)
+        };
+        assert_eq!(
+            db.match_image(similar.into(), elsewhere).unwrap(),
+            PreviouslySeen::No
+        );
+    }
+}
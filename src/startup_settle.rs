@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+
+/// Holds off on replying to reposts until every shard is ready and the burst of guilds
+/// streaming in at startup has settled, so backlogged messages don't cause a storm of replies.
+pub struct StartupSettle {
+    /// Seconds without startup activity before replies are allowed.
+    window: u64,
+    shards: usize,
+    ready: HashSet<u64>,
+    /// When a shard last became ready or a guild last streamed in - std::time::UNIX_EPOCH, in seconds.
+    last_activity: u64,
+    settled: bool,
+}
+
+impl StartupSettle {
+    /// Waits for `shards` shards, and then `window` quiet seconds. A zero window is settled right away.
+    pub fn new(window: u64, shards: usize) -> Self {
+        Self {
+            window,
+            shards,
+            ready: HashSet::new(),
+            last_activity: 0,
+            settled: window == 0,
+        }
+    }
+
+    pub fn shard_ready(&mut self, shard: u64, now: u64) {
+        self.ready.insert(shard);
+        self.last_activity = now;
+    }
+
+    /// Notes that a guild streamed in, which keeps the startup burst going.
+    pub fn guild_streamed(&mut self, now: u64) {
+        if !self.settled {
+            self.last_activity = now;
+        }
+    }
+
+    /// If startup is over. Once it is, it stays that way, even if shards reconnect later.
+    pub fn settled(&mut self, now: u64) -> bool {
+        if !self.settled && self.ready.len() >= self.shards {
+            self.settled = now.saturating_sub(self.last_activity) >= self.window;
+        }
+
+        self.settled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_every_shard() {
+        let mut startup = StartupSettle::new(10, 2);
+        assert!(!startup.settled(100));
+
+        startup.shard_ready(0, 100);
+        assert!(!startup.settled(200));
+
+        startup.shard_ready(1, 200);
+        assert!(!startup.settled(209));
+        assert!(startup.settled(210));
+    }
+
+    #[test]
+    fn waits_for_guilds_to_settle() {
+        let mut startup = StartupSettle::new(10, 1);
+        startup.shard_ready(0, 100);
+        startup.guild_streamed(105);
+        startup.guild_streamed(108);
+        assert!(!startup.settled(117));
+        assert!(startup.settled(118));
+
+        // Joining a guild or reconnecting afterwards doesn't start it over.
+        startup.guild_streamed(120);
+        startup.shard_ready(0, 120);
+        assert!(startup.settled(121));
+    }
+
+    #[test]
+    fn zero_window_never_waits() {
+        let mut startup = StartupSettle::new(0, 4);
+        assert!(startup.settled(0));
+    }
+}
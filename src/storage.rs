@@ -0,0 +1,242 @@
+//! What the bot needs from wherever images are kept.
+//!
+//! [Data](crate::data_storage::Data) keeps them in sled, and is what the bot runs with. Logic that only
+//! records and looks up images takes any [Storage], so it can be tested without a database.
+
+use crate::config::DetectionConfig;
+#[cfg(test)]
+use crate::data_storage::Data;
+use crate::data_storage::{PreviouslySeen, SeenImage};
+use crate::errors::{DatabaseError, Error};
+use crate::image_processing::Hashes;
+
+pub trait Storage {
+    /// The detection settings images are recorded with.
+    fn config(&self) -> &DetectionConfig;
+
+    /// Records an image, and returns what it's a repost of if it is one.
+    fn record_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error>;
+
+    /// Like [Storage::record_image], but an image that doesn't match anything isn't stored.
+    fn match_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error>;
+
+    /// Calls `f` with the image stored under `image_hash`, and saves it if `f` returns `true`.
+    ///
+    /// Nothing happens if the hash isn't known.
+    fn access_image(
+        &self,
+        image_hash: &[u8],
+        f: &mut dyn FnMut(&mut SeenImage) -> bool,
+    ) -> Result<(), DatabaseError>;
+
+    /// How many images are stored.
+    fn total_seen(&self) -> usize;
+
+    /// Counts a repost someone made in a guild, returning how many they've made there including this one.
+    fn record_offense(&self, guild_id: u64, user_id: u64) -> Result<u64, DatabaseError>;
+
+    /// The people who've made the most reposts in a guild, most first, and how many they've made.
+    ///
+    /// Ties are broken by ID, so the order doesn't change between calls.
+    fn top_offenders(&self, guild_id: u64, limit: usize) -> Result<Vec<(u64, u64)>, DatabaseError>;
+
+    /// How many images were first seen in each guild, most first, with ties broken by ID.
+    fn per_guild_counts(&self) -> Result<Vec<(u64, usize)>, DatabaseError>;
+}
+
+/// Images kept in memory, only matched by exact hash.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    config: DetectionConfig,
+    images: std::sync::Mutex<std::collections::HashMap<Vec<u8>, (SeenImage, u64)>>,
+    /// Reposts made by each guild ID and user ID.
+    offenders: std::sync::Mutex<std::collections::HashMap<(u64, u64), u64>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn new(config: DetectionConfig) -> Self {
+        Self {
+            config,
+            images: Default::default(),
+            offenders: Default::default(),
+        }
+    }
+
+    fn record(&self, hashes: Hashes, properties: SeenImage, store_new: bool) -> PreviouslySeen {
+        let mut images = self.images.lock().unwrap();
+
+        match images.get_mut(hashes.hash.as_bytes()) {
+            Some((image, times_seen)) => {
+                *times_seen += 1;
+                PreviouslySeen::Yes {
+                    image: image.clone(),
+                    times_seen: *times_seen,
+                }
+            }
+            None => {
+                if store_new {
+                    images.insert(hashes.hash.as_bytes().to_vec(), (properties, 1));
+                }
+                PreviouslySeen::No
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn config(&self) -> &DetectionConfig {
+        &self.config
+    }
+
+    fn record_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error> {
+        Ok(self.record(hashes, properties, true))
+    }
+
+    fn match_image(&self, hashes: Hashes, properties: SeenImage) -> Result<PreviouslySeen, Error> {
+        Ok(self.record(hashes, properties, false))
+    }
+
+    fn access_image(
+        &self,
+        image_hash: &[u8],
+        f: &mut dyn FnMut(&mut SeenImage) -> bool,
+    ) -> Result<(), DatabaseError> {
+        if let Some((image, _)) = self.images.lock().unwrap().get_mut(image_hash) {
+            let mut updated = image.clone();
+            if f(&mut updated) {
+                *image = updated;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn total_seen(&self) -> usize {
+        self.images.lock().unwrap().len()
+    }
+
+    fn record_offense(&self, guild_id: u64, user_id: u64) -> Result<u64, DatabaseError> {
+        let mut offenders = self.offenders.lock().unwrap();
+        let offenses = offenders.entry((guild_id, user_id)).or_insert(0);
+        *offenses += 1;
+
+        Ok(*offenses)
+    }
+
+    fn top_offenders(&self, guild_id: u64, limit: usize) -> Result<Vec<(u64, u64)>, DatabaseError> {
+        let mut offenders: Vec<_> = self
+            .offenders
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((guild, _), _)| *guild == guild_id)
+            .map(|(&(_, user_id), &offenses)| (user_id, offenses))
+            .collect();
+
+        offenders.sort_unstable_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+        offenders.truncate(limit);
+
+        Ok(offenders)
+    }
+
+    fn per_guild_counts(&self) -> Result<Vec<(u64, usize)>, DatabaseError> {
+        let mut counts = std::collections::HashMap::new();
+        for (image, _) in self.images.lock().unwrap().values() {
+            *counts.entry(image.guild_id).or_insert(0) += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::image_processing::ImageHash;
+
+    /// Both backends should agree on the basics the bot relies on.
+    fn basics(storage: &dyn Storage) {
+        let hash = ImageHash::from_bytes(&[0x5A; 64]).unwrap();
+        let image = SeenImage::new("someone".to_string(), 10, 20, 30);
+
+        assert_eq!(
+            storage
+                .match_image(hash.clone().into(), image.clone())
+                .unwrap(),
+            PreviouslySeen::No
+        );
+        assert_eq!(storage.total_seen(), 0);
+
+        storage
+            .record_image(hash.clone().into(), image.clone())
+            .unwrap();
+        assert_eq!(storage.total_seen(), 1);
+
+        let mut ignore = |seen: &mut SeenImage| {
+            seen.ignored = true;
+            true
+        };
+        storage.access_image(hash.as_bytes(), &mut ignore).unwrap();
+        // Unknown hashes are left alone.
+        storage.access_image(&[0xA5; 64], &mut ignore).unwrap();
+
+        match storage.record_image(hash.into(), image).unwrap() {
+            PreviouslySeen::Yes { image, times_seen } => {
+                assert!(image.ignored);
+                assert_eq!(times_seen, 2);
+            }
+            seen => panic!("not seen before: {:?}", seen),
+        }
+    }
+
+    /// Both backends should count the same way. `storage` records images in guild 7.
+    fn counters(storage: &dyn Storage) {
+        assert_eq!(storage.record_offense(7, 10).unwrap(), 1);
+        assert_eq!(storage.record_offense(7, 10).unwrap(), 2);
+        assert_eq!(storage.record_offense(7, 12).unwrap(), 1);
+        assert_eq!(storage.record_offense(7, 11).unwrap(), 1);
+        assert_eq!(storage.record_offense(8, 13).unwrap(), 1);
+        assert_eq!(storage.top_offenders(7, 2).unwrap(), vec![(10, 2), (11, 1)]);
+        assert_eq!(storage.top_offenders(9, 2).unwrap(), vec![]);
+
+        assert_eq!(storage.per_guild_counts().unwrap(), vec![]);
+        for byte in [1, 2] {
+            let image = SeenImage {
+                guild_id: 7,
+                ..SeenImage::new("someone".to_string(), 10, 20, 30)
+            };
+            storage
+                .record_image(ImageHash::from_bytes(&[byte; 64]).unwrap().into(), image)
+                .unwrap();
+        }
+        assert_eq!(storage.per_guild_counts().unwrap(), vec![(7, 2)]);
+    }
+
+    #[test]
+    fn sled_storage() {
+        basics(&Data::init("", &Config::default()).unwrap());
+        counters(&Data::init("", &Config::default()).unwrap().for_guild(7));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_export() {
+        use crate::sqlite_export::SqliteData;
+
+        basics(&SqliteData::init("", &Config::default()).unwrap());
+        counters(&SqliteData::init("", &Config::default()).unwrap());
+    }
+
+    #[test]
+    fn memory_storage() {
+        basics(&MemoryStorage::default());
+        counters(&MemoryStorage::default());
+    }
+}
@@ -0,0 +1,310 @@
+//! The file format used to move a database between hosts.
+//!
+//! Exports are JSON documents whose data is signed, with an HMAC if the operator set a key
+//! and a plain SHA-256 checksum otherwise, so that imports can catch corruption or tampering.
+
+use crate::data_storage::{Occurrence, SeenImage};
+use crate::errors::TransferError;
+use crate::guild_settings::GuildSettings;
+
+use std::io::{Read, Write};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+
+/// Version of the export format written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Everything stored in the database, in a form that doesn't depend on the host it came from.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct Dump {
+    /// Storage format version of the database it came from, or zero for exports made before it was included.
+    #[serde(default)]
+    pub schema_version: u8,
+    pub images: Vec<DumpedImage>,
+    pub guild_settings: Vec<DumpedGuild>,
+    /// Missing from exports made before links were included.
+    #[serde(default)]
+    pub links: Vec<DumpedLink>,
+    /// Missing from exports made before repost offenders were included.
+    #[serde(default)]
+    pub offenders: Vec<DumpedOffender>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct DumpedImage {
+    pub times_seen: u64,
+    /// Hex encoded hashes that point at this image, including similar-image aliases.
+    pub hashes: Vec<String>,
+    /// Missing from exports made before occurrences were tracked.
+    #[serde(default)]
+    pub occurrences: Vec<Occurrence>,
+    /// The guild the image was first seen in, if it's known.
+    #[serde(default)]
+    pub guild_id: Option<u64>,
+    /// Extension of the format the image was stored from, if it was known.
+    #[serde(default)]
+    pub format: Option<String>,
+    pub image: SeenImage,
+}
+
+/// A link shared in a guild, and who shared it first.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct DumpedLink {
+    pub guild_id: u64,
+    /// The canonical form of the link.
+    pub link: String,
+    pub first: Occurrence,
+    pub times_seen: u64,
+}
+
+/// How many reposts someone was caught making in a guild.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct DumpedOffender {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub reposts: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct DumpedGuild {
+    pub guild_id: u64,
+    pub settings: GuildSettings,
+}
+
+/// Version of the policy format written by this build.
+///
+/// Version 1 didn't say which guild ignored each hash, so it can't be imported.
+pub const POLICY_VERSION: u32 = 2;
+
+/// How the bot is set up to behave, without any of the image data.
+///
+/// Policies are small, readable, and unsigned, so they can be kept in version control
+/// and shared between bots.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct Policy {
+    pub version: u32,
+    pub guild_settings: Vec<DumpedGuild>,
+    /// Images that aren't called out as reposts.
+    pub ignored_hashes: Vec<IgnoredHash>,
+}
+
+/// An image a guild doesn't call out as a repost.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct IgnoredHash {
+    /// The guild it's ignored in, or zero if that isn't known.
+    pub guild_id: u64,
+    /// Hex encoded hash of the image.
+    pub hash: String,
+}
+
+impl Policy {
+    pub fn write(&self, writer: impl Write) -> Result<(), TransferError> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn read(reader: impl Read) -> Result<Self, TransferError> {
+        let policy: Self = serde_json::from_reader(reader)?;
+        if policy.version != POLICY_VERSION {
+            return Err(TransferError::UnsupportedVersion(policy.version));
+        }
+
+        Ok(policy)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<'a> {
+    version: u32,
+    signature: Signature,
+    /// Kept as the exact bytes that were signed.
+    #[serde(borrow)]
+    data: &'a RawValue,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", content = "digest", rename_all = "kebab-case")]
+enum Signature {
+    Sha256(String),
+    HmacSha256(String),
+}
+
+impl Signature {
+    fn sign(data: &[u8], key: Option<&[u8]>) -> Self {
+        match key {
+            Some(key) => {
+                let mut mac = Self::hmac(key);
+                mac.update(data);
+                Self::HmacSha256(hex::encode(mac.finalize().into_bytes()))
+            }
+            None => Self::Sha256(hex::encode(Sha256::digest(data))),
+        }
+    }
+
+    fn verify(&self, data: &[u8], key: Option<&[u8]>) -> Result<(), TransferError> {
+        let valid = match (self, key) {
+            (Self::HmacSha256(digest), Some(key)) => {
+                let digest = hex::decode(digest).map_err(|_| TransferError::SignatureMismatch)?;
+
+                let mut mac = Self::hmac(key);
+                mac.update(data);
+                mac.verify_slice(&digest).is_ok()
+            }
+            (Self::HmacSha256(_), None) => return Err(TransferError::MissingKey),
+            // Someone with a key expects exports to be signed, so a bare checksum
+            // could mean the signature was stripped.
+            (Self::Sha256(_), Some(_)) => false,
+            (Self::Sha256(digest), None) => *digest == hex::encode(Sha256::digest(data)),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(TransferError::SignatureMismatch)
+        }
+    }
+
+    fn hmac(key: &[u8]) -> Hmac<Sha256> {
+        Hmac::new_from_slice(key).expect("HMAC accepts keys of any length")
+    }
+}
+
+/// Writes a signed export of `dump`.
+pub fn write(dump: &Dump, mut writer: impl Write, key: Option<&[u8]>) -> Result<(), TransferError> {
+    let data = serde_json::to_string(dump)?;
+    let signature = Signature::sign(data.as_bytes(), key);
+
+    let envelope = Envelope {
+        version: FORMAT_VERSION,
+        signature,
+        data: &RawValue::from_string(data)?,
+    };
+
+    serde_json::to_writer(&mut writer, &envelope)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads an export, checking its version and signature before trusting any of it.
+pub fn read(mut reader: impl Read, key: Option<&[u8]>) -> Result<Dump, TransferError> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)?;
+
+    let envelope: Envelope = serde_json::from_str(&contents)?;
+    if envelope.version != FORMAT_VERSION {
+        return Err(TransferError::UnsupportedVersion(envelope.version));
+    }
+
+    let data = envelope.data.get();
+    envelope.signature.verify(data.as_bytes(), key)?;
+
+    Ok(serde_json::from_str(data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"super secret";
+
+    fn dump() -> Dump {
+        Dump {
+            schema_version: 5,
+            images: vec![DumpedImage {
+                times_seen: 3,
+                hashes: vec!["00ff".to_string()],
+                occurrences: Vec::new(),
+                guild_id: Some(789),
+                format: Some("png".to_string()),
+                image: SeenImage::new("poster".to_string(), 100, 123, 456),
+            }],
+            guild_settings: vec![DumpedGuild {
+                guild_id: 789,
+                settings: GuildSettings {
+                    raid_mode_until: Some(1000),
+                    ..GuildSettings::default()
+                },
+            }],
+            links: vec![DumpedLink {
+                guild_id: 789,
+                link: "example.com/news".to_string(),
+                first: Occurrence::from(&SeenImage::new("poster".to_string(), 100, 124, 456)),
+                times_seen: 2,
+            }],
+            offenders: vec![DumpedOffender {
+                guild_id: 789,
+                user_id: 5,
+                reposts: 1,
+            }],
+        }
+    }
+
+    fn export(key: Option<&[u8]>) -> String {
+        let mut out = Vec::new();
+        write(&dump(), &mut out, key).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let exported = export(Some(KEY));
+        assert!(exported.contains("hmac-sha256"));
+        assert_eq!(read(exported.as_bytes(), Some(KEY)).unwrap(), dump());
+
+        let exported = export(None);
+        assert!(exported.contains("\"sha256\""));
+        assert_eq!(read(exported.as_bytes(), None).unwrap(), dump());
+    }
+
+    #[test]
+    fn tampered_exports_rejected() {
+        for key in [Some(KEY), None] {
+            let tampered = export(key).replace("poster", "someone");
+
+            assert!(matches!(
+                read(tampered.as_bytes(), key),
+                Err(TransferError::SignatureMismatch)
+            ));
+        }
+    }
+
+    #[test]
+    fn signing_keys_must_match() {
+        let exported = export(Some(KEY));
+        assert!(matches!(
+            read(exported.as_bytes(), Some(b"wrong key")),
+            Err(TransferError::SignatureMismatch)
+        ));
+        assert!(matches!(
+            read(exported.as_bytes(), None),
+            Err(TransferError::MissingKey)
+        ));
+
+        // A checksum alone isn't trusted once a key is set.
+        let exported = export(None);
+        assert!(matches!(
+            read(exported.as_bytes(), Some(KEY)),
+            Err(TransferError::SignatureMismatch)
+        ));
+    }
+
+    #[test]
+    fn unknown_versions_rejected() {
+        let exported = export(None).replacen("\"version\":1", "\"version\":99", 1);
+        assert!(matches!(
+            read(exported.as_bytes(), None),
+            Err(TransferError::UnsupportedVersion(99))
+        ));
+    }
+}
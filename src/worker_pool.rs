@@ -0,0 +1,183 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads that CPU heavy work, like hashing images, runs on.
+///
+/// Keeping this apart from tokio's blocking pool means hashing never runs more than
+/// `workers` images at once, no matter how many arrive together.
+pub struct WorkerPool {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    queued: Arc<AtomicUsize>,
+    workers: usize,
+}
+
+impl WorkerPool {
+    /// Starts a pool with `workers` threads, or one if that's zero.
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let queued = Arc::clone(&queued);
+
+            thread::Builder::new()
+                .name(format!("hashing-{}", i))
+                .spawn(move || loop {
+                    // The lock is released before running the job, so the others can pick up jobs meanwhile.
+                    let job = receiver
+                        .lock()
+                        .expect("worker pool queue lock was poisoned")
+                        .recv();
+
+                    match job {
+                        Ok(job) => {
+                            queued.fetch_sub(1, Ordering::Relaxed);
+                            job();
+                        }
+                        // The pool was dropped.
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to start a worker thread");
+        }
+
+        Self {
+            jobs: Mutex::new(sender),
+            queued,
+            workers,
+        }
+    }
+
+    /// Runs `job` on the pool, resolving to what it returns once a worker has finished it.
+    pub fn run<T, F>(&self, job: F) -> impl Future<Output = T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.jobs
+            .lock()
+            .expect("worker pool sender lock was poisoned")
+            .send(Box::new(move || {
+                // Nobody is waiting for the result if this fails, so it's fine to drop.
+                let _ = sender.send(job());
+            }))
+            .expect("bug: worker pool threads exited while the pool was alive");
+
+        async move {
+            receiver
+                .await
+                .expect("a worker panicked while running a job")
+        }
+    }
+
+    /// How many jobs are waiting for a free worker.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DetectionConfig;
+    use crate::image_processing::{process_image, ProcessedImage};
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use std::time::Duration;
+
+    fn encoded(seed: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * seed) as u8, (y * seed) as u8, ((x + y) * seed) as u8])
+        });
+
+        let mut out = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .unwrap();
+        out
+    }
+
+    fn hash_of(image: Vec<u8>) -> Vec<u8> {
+        match process_image(image, &DetectionConfig::default()).unwrap() {
+            ProcessedImage::Hashed(hashes) => hashes.hash.as_bytes().to_vec(),
+            other => panic!("image wasn't hashed: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn parallelism_limited() {
+        let pool = WorkerPool::new(2);
+        assert_eq!(pool.workers(), 2);
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        let jobs: Vec<_> = (1..=8)
+            .map(|seed| {
+                let running = Arc::clone(&running);
+                let most_running = Arc::clone(&most_running);
+
+                pool.run(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+
+                    std::thread::sleep(Duration::from_millis(20));
+                    let hash = hash_of(encoded(seed));
+
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    (seed, hash)
+                })
+            })
+            .collect();
+
+        for job in jobs {
+            let (seed, hash) = job.await;
+            assert_eq!(hash, hash_of(encoded(seed)));
+        }
+
+        assert!(most_running.load(Ordering::SeqCst) <= 2);
+        assert_eq!(pool.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn queue_depth_counts_waiting_jobs() {
+        let pool = WorkerPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel();
+
+        let first = pool.run(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        has_started.recv().unwrap();
+
+        let waiting: Vec<_> = (0..3).map(|i| pool.run(move || i)).collect();
+        assert_eq!(pool.queue_depth(), 3);
+
+        release.send(()).unwrap();
+        first.await;
+        for (i, job) in waiting.into_iter().enumerate() {
+            assert_eq!(job.await, i);
+        }
+        assert_eq!(pool.queue_depth(), 0);
+    }
+}